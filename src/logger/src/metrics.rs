@@ -517,6 +517,12 @@ pub struct PerformanceMetrics {
     pub vmm_pause_vm: SharedMetric,
     /// Measures the microVM resuming duration, at the VMM level, in microseconds.
     pub vmm_resume_vm: SharedMetric,
+    /// Measures the pseudo_mm template load during snapshot load, in microseconds.
+    pub pseudo_mm_load_template: SharedMetric,
+    /// Measures the pseudo_mm attach during snapshot load, in microseconds.
+    pub pseudo_mm_attach: SharedMetric,
+    /// Measures the pseudo_mm guest region setup during snapshot load, in microseconds.
+    pub pseudo_mm_create_regions: SharedMetric,
 }
 
 /// Metrics specific to the RTC device.
@@ -587,6 +593,16 @@ pub struct VmmMetrics {
     pub device_events: SharedMetric,
     /// Metric for signaling a panic has occurred.
     pub panic_count: SharedMetric,
+    /// Number of pseudo_mm attach retries after transient busy errors.
+    pub pseudo_mm_attach_retries: SharedMetric,
+    /// Number of pseudo_mm create retries after transient busy errors.
+    pub pseudo_mm_create_retries: SharedMetric,
+    /// Number of pseudo_mm regions the requested NUMA policy couldn't be applied to.
+    pub pseudo_mm_numa_fails: SharedMetric,
+    /// Number of pseudo_mm restores whose template VM shape differed from the snapshot.
+    pub pseudo_mm_shape_mismatches: SharedMetric,
+    /// Number of pseudo_mm restores skipping the shape check for lack of a recorded shape.
+    pub pseudo_mm_shape_unchecked: SharedMetric,
 }
 
 /// Vsock-related metrics.
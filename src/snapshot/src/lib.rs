@@ -61,6 +61,15 @@ struct SnapshotHdr {
     data_version: u16,
 }
 
+/// Versions recorded in a snapshot's header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SnapshotVersions {
+    /// Snapshot format version, from the magic id.
+    pub format_version: u16,
+    /// Snapshot data version the state was saved with.
+    pub data_version: u16,
+}
+
 /// The `Snapshot` API manages serialization and deserialization of collections of objects
 /// that implement the `Versionize` trait.
 #[derive(Debug)]
@@ -102,6 +111,13 @@ impl Snapshot {
         T: Read,
         O: Versionize,
     {
+        let versions = Self::read_versions(&mut reader)?;
+        Self::load_state(reader, &version_map, versions.data_version)
+    }
+
+    /// Reads the magic id and header of a snapshot, leaving `reader` at the
+    /// start of the state.
+    pub fn read_versions<T: Read>(mut reader: &mut T) -> Result<SnapshotVersions, Error> {
         let format_version_map = Self::format_version_map();
         let magic_id =
             <u64 as Versionize>::deserialize(&mut reader, &format_version_map, 0 /* unused */)
@@ -112,8 +128,27 @@ impl Snapshot {
             SnapshotHdr::deserialize(&mut reader, &format_version_map, format_version)
                 .map_err(Error::Versionize)?;
 
-        Ok(O::deserialize(&mut reader, &version_map, hdr.data_version)
-            .map_err(Error::Versionize)?)
+        Ok(SnapshotVersions {
+            format_version,
+            data_version: hdr.data_version,
+        })
+    }
+
+    /// Loads the state following a header read by `read_versions`, as saved
+    /// with `data_version`.
+    ///
+    /// The data version is normally the header's; passing another one lets
+    /// callers load snapshots whose header doesn't describe their state.
+    pub fn load_state<T, O>(
+        mut reader: &mut T,
+        version_map: &VersionMap,
+        data_version: u16,
+    ) -> Result<O, Error>
+    where
+        T: Read,
+        O: Versionize,
+    {
+        O::deserialize(&mut reader, version_map, data_version).map_err(Error::Versionize)
     }
 
     /// Attempts to load an existing snapshot and validate CRC.
@@ -461,6 +496,42 @@ mod tests {
         assert_eq!(restored_state.field3, "test");
     }
 
+    #[test]
+    fn test_read_older_header() {
+        let mut vm = VersionMap::new();
+        vm.new_version()
+            .set_type_version(Test::type_id(), 2)
+            .new_version()
+            .set_type_version(Test::type_id(), 3);
+        let state_1 = Test1 {
+            field_x: 7,
+            field0: 0,
+            field1: 1,
+        };
+
+        // Saved by a build that only knew data version 1.
+        let mut snapshot_mem = vec![0u8; 1024];
+        Snapshot::new(VersionMap::new(), 1)
+            .save(&mut snapshot_mem.as_mut_slice(), &state_1)
+            .unwrap();
+
+        let mut reader = snapshot_mem.as_slice();
+        let versions = Snapshot::read_versions(&mut reader).unwrap();
+        assert_eq!(
+            versions,
+            SnapshotVersions {
+                format_version: SNAPSHOT_FORMAT_VERSION,
+                data_version: 1,
+            }
+        );
+        let restored_state: Test =
+            Snapshot::load_state(&mut reader, &vm, versions.data_version).unwrap();
+        assert_eq!(restored_state.field_x, 7);
+        assert_eq!(restored_state.field1, 1);
+        assert_eq!(restored_state.field2, 20);
+        assert_eq!(restored_state.field3, "default");
+    }
+
     #[test]
     fn test_crc_ok() {
         let vm = VersionMap::new();
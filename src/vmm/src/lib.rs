@@ -22,10 +22,18 @@ pub(crate) mod device_manager;
 pub mod memory_snapshot;
 /// Save/restore utilities.
 pub mod persist;
+/// Pseudo_MM address and page offset types.
+pub mod pseudo_mm_addr;
+/// Cancellation of pseudo_mm create and restore.
+pub mod pseudo_mm_cancel;
+/// NUMA placement of pseudo_mm restored memory.
+pub mod pseudo_mm_numa;
 /// Pseudo_MM restore module.
 pub mod pseudo_mm_restore;
 /// Pseudo_MM support for fast memory restoration.
 pub mod pseudo_mm_support;
+#[cfg(test)]
+mod pseudo_mm_test_utils;
 /// Resource store for configured microVM resources.
 pub mod resources;
 /// microVM RPC API adapters.
@@ -97,6 +97,10 @@ pub enum Error {
     UserPageFault(userfaultfd::Error),
     /// Overlay regions error.
     OverlayRegions(std::io::Error),
+    /// Invalid pseudo_mm template region.
+    InvalidRegion(crate::pseudo_mm_support::InvalidRegion),
+    /// Pseudo_mm restore cancelled through its token.
+    Cancelled,
 }
 
 impl Display for Error {
@@ -109,6 +113,8 @@ impl Display for Error {
             WriteMemory(err) => write!(f, "Cannot dump memory: {:?}", err),
             UserPageFault(err) => write!(f, "Cannot register memory for uPF: {:?}", err),
             OverlayRegions(err) => write!(f, "Cannot mmap overlay regions: {:?}", err),
+            InvalidRegion(err) => write!(f, "Invalid pseudo_mm region: {}", err),
+            Cancelled => write!(f, "Pseudo_mm restore cancelled"),
         }
     }
 }
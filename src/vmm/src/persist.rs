@@ -8,9 +8,12 @@
 
 use crate::builder::{self, StartMicrovmError};
 use crate::device_manager::persist::Error as DevicePersistError;
-use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
+use crate::vmm_config::snapshot::{
+    CreateSnapshotParams, LoadSnapshotParams, PseudoMmNumaConfig, PseudoMmNumaMode, SnapshotType,
+};
 use crate::vstate::{self, VcpuState, VmState};
-use logger::{info, warn};
+use logger::{info, update_metric_with_elapsed_time, warn, METRICS};
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::fs::{File, OpenOptions};
@@ -21,6 +24,9 @@ use std::sync::{Arc, Mutex};
 use crate::device_manager::persist::DeviceStates;
 use crate::memory_snapshot;
 use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
+use crate::pseudo_mm_numa::{self, NodeMask, NumaMode, NumaPolicy};
+use crate::pseudo_mm_restore::{self, RestoreObserver, RestoreOptions, RestorePhase};
+use crate::pseudo_mm_support::VmShape;
 use crate::version_map::FC_VERSION_TO_SNAP_VERSION;
 use polly::event_manager::EventManager;
 use seccomp::BpfProgramRef;
@@ -267,6 +273,9 @@ pub fn load_snapshot(
         params.load_ws,
         &params.fadvise,
         &params.pseudo_mm_template_path,
+        &vm_shape(&microvm_state),
+        params.pseudo_mm_strict_shape,
+        params.pseudo_mm_numa.as_ref(),
     )?;
     if params.enable_user_page_faults == true {
         guest_memory
@@ -288,6 +297,42 @@ pub fn load_snapshot(
     .map_err(BuildMicroVm)
 }
 
+/// Summarizes the VM shape recorded in `state`, as stored in pseudo_mm
+/// templates.
+pub fn vm_shape(state: &MicrovmState) -> VmShape {
+    VmShape {
+        vcpu_count: state.vcpu_states.len() as u32,
+        mem_size_mib: state.vm_info.mem_size_mib,
+        boot_vcpu_features: state.vcpu_states.first().map(VcpuState::cpuid_hash),
+        smt: state.vcpu_states.first().and_then(VcpuState::smt_enabled),
+    }
+}
+
+/// Resolves a requested pseudo_mm NUMA placement into a policy.
+pub fn numa_policy(config: &PseudoMmNumaConfig) -> io::Result<NumaPolicy> {
+    let nodes = if config.nodes == "auto" {
+        pseudo_mm_numa::nodes_for_allowed_cpus()?
+    } else {
+        pseudo_mm_numa::parse_list(&config.nodes, pseudo_mm_numa::MAX_NODES)
+            .map(NodeMask)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+    };
+    if nodes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("NUMA node list '{}' selects no nodes", config.nodes),
+        ));
+    }
+    Ok(NumaPolicy {
+        mode: match config.mode {
+            PseudoMmNumaMode::Preferred => NumaMode::Preferred,
+            PseudoMmNumaMode::Bind => NumaMode::Bind,
+        },
+        nodes,
+        strict: config.strict,
+    })
+}
+
 fn snapshot_state_from_file(
     snapshot_path: &PathBuf,
     version_map: VersionMap,
@@ -298,6 +343,29 @@ fn snapshot_state_from_file(
     Snapshot::load(&mut snapshot_reader, version_map).map_err(DeserializeMicrovmState)
 }
 
+/// Feeds pseudo_mm restore phase durations into the snapshot load metrics.
+#[derive(Default)]
+struct BootTimingObserver {
+    phase_start_us: Cell<u64>,
+}
+
+impl RestoreObserver for BootTimingObserver {
+    fn on_phase_start(&self, _phase: RestorePhase) {
+        self.phase_start_us
+            .set(utils::time::get_time_us(utils::time::ClockType::Monotonic));
+    }
+
+    fn on_phase_end(&self, phase: RestorePhase, _ok: bool) {
+        let metric = match phase {
+            RestorePhase::LoadTemplate => &METRICS.latencies_us.pseudo_mm_load_template,
+            RestorePhase::Attach => &METRICS.latencies_us.pseudo_mm_attach,
+            RestorePhase::CreateRegions => &METRICS.latencies_us.pseudo_mm_create_regions,
+        };
+        let elapsed_us = update_metric_with_elapsed_time(metric, self.phase_start_us.get());
+        info!("pseudo_mm restore phase {:?} took {} us", phase, elapsed_us);
+    }
+}
+
 fn guest_memory_from_file(
     mem_file_path: &PathBuf,
     mem_state: &GuestMemoryState,
@@ -309,6 +377,9 @@ fn guest_memory_from_file(
     load_ws: bool,
     fadvise: &String,
     pseudo_mm_template_path: &PathBuf,
+    vm_shape: &VmShape,
+    strict_shape: bool,
+    numa: Option<&PseudoMmNumaConfig>,
 ) -> std::result::Result<GuestMemoryMmap, LoadSnapshotError> {
     use self::LoadSnapshotError::DeserializeMemory;
 
@@ -318,7 +389,29 @@ fn guest_memory_from_file(
             "Attempting pseudo_mm fast restore from template {:?}",
             pseudo_mm_template_path
         );
-        match GuestMemoryMmap::restore_with_pseudo_mm(pseudo_mm_template_path) {
+        let observer = BootTimingObserver::default();
+        let numa = match numa.map(|config| (numa_policy(config), config.strict)) {
+            Some((Ok(policy), _)) => Ok(Some(policy)),
+            Some((Err(err), true)) => Err(err),
+            Some((Err(err), false)) => {
+                warn!("Ignoring pseudo_mm NUMA placement: {}", err);
+                Ok(None)
+            }
+            None => Ok(None),
+        };
+        let result = numa
+            .map_err(memory_snapshot::Error::FileHandle)
+            .and_then(|numa| {
+                let options = RestoreOptions {
+                    observer: Some(&observer),
+                    expected_shape: Some(vm_shape),
+                    strict_shape,
+                    numa,
+                    ..Default::default()
+                };
+                pseudo_mm_restore::restore_with_pseudo_mm_options(pseudo_mm_template_path, &options)
+            });
+        match result {
             Ok(memory) => return Ok(memory),
             Err(err) => {
                 warn!(
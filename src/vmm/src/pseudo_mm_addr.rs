@@ -0,0 +1,248 @@
+//! Pseudo_MM Address Types
+//!
+//! Newtypes for the addresses and page offsets stored in pseudo_mm templates.
+//!
+//! All of them parse from either decimal or `0x`-prefixed hex; a bare string is
+//! always decimal, so hex values must carry the prefix. Addresses (`HvaAddr`,
+//! `Gpa`) display and serialize as `0x`-hex strings, page offsets
+//! (`PageOffset`) as decimal numbers. Deserialization accepts both the string
+//! forms and plain JSON numbers written by older templates.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Error returned when an address or page offset string cannot be parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseAddrError {
+    input: String,
+}
+
+impl fmt::Display for ParseAddrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid value '{}': expected decimal or 0x-prefixed hex",
+            self.input
+        )?;
+        let looks_like_bare_hex = !self.input.is_empty()
+            && self.input.chars().all(|c| c.is_ascii_hexdigit())
+            && self.input.chars().any(|c| c.is_ascii_alphabetic());
+        if looks_like_bare_hex {
+            write!(f, " (hex values need a 0x prefix)")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseAddrError {}
+
+/// Parses decimal or `0x`-prefixed hex into a `u64`.
+pub fn parse_u64(input: &str) -> Result<u64, ParseAddrError> {
+    let trimmed = input.trim();
+    let (digits, radix) = if trimmed.starts_with("0x") || trimmed.starts_with("0X") {
+        (&trimmed[2..], 16)
+    } else {
+        (trimmed, 10)
+    };
+    // from_str_radix tolerates a leading '+', which we don't want to accept.
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(ParseAddrError {
+            input: input.to_string(),
+        });
+    }
+    u64::from_str_radix(digits, radix).map_err(|_| ParseAddrError {
+        input: input.to_string(),
+    })
+}
+
+fn serialize_hex<S: Serializer>(value: u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("0x{:x}", value))
+}
+
+fn serialize_decimal<S: Serializer>(value: u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(value)
+}
+
+macro_rules! addr_newtype {
+    ($(#[$doc:meta])* $name:ident, $fmt:literal, $serialize:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub u64);
+
+        impl $name {
+            /// Returns the raw value.
+            pub fn raw(self) -> u64 {
+                self.0
+            }
+        }
+
+        impl From<u64> for $name {
+            fn from(value: u64) -> Self {
+                $name(value)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, $fmt, self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseAddrError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                parse_u64(s).map($name)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                $serialize(self.0, serializer)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_any(U64Visitor).map($name)
+            }
+        }
+    };
+}
+
+addr_newtype!(
+    /// Host virtual address.
+    HvaAddr,
+    "0x{:x}",
+    serialize_hex
+);
+addr_newtype!(
+    /// Guest physical address.
+    Gpa,
+    "0x{:x}",
+    serialize_hex
+);
+addr_newtype!(
+    /// Offset into the remote memory image, in pages.
+    PageOffset,
+    "{}",
+    serialize_decimal
+);
+
+/// Accepts a JSON number or a decimal/`0x`-hex string.
+struct U64Visitor;
+
+impl<'de> Visitor<'de> for U64Visitor {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an unsigned integer or a decimal/0x-hex string")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<u64, E> {
+        Ok(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<u64, E> {
+        if value < 0 {
+            return Err(E::invalid_value(de::Unexpected::Signed(value), &self));
+        }
+        Ok(value as u64)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<u64, E> {
+        parse_u64(value).map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pseudo_mm_test_utils::XorShift64;
+
+    #[test]
+    fn test_parse_u64() {
+        assert_eq!(parse_u64("0"), Ok(0));
+        assert_eq!(parse_u64("4096"), Ok(4096));
+        assert_eq!(parse_u64("0x1000"), Ok(4096));
+        assert_eq!(parse_u64("0X1000"), Ok(4096));
+        assert_eq!(parse_u64(" 0x700000000000 "), Ok(0x7000_0000_0000));
+        assert_eq!(parse_u64("18446744073709551615"), Ok(u64::MAX));
+        assert_eq!(parse_u64("0xffffffffffffffff"), Ok(u64::MAX));
+
+        // A bare string is decimal; hex needs the prefix.
+        assert_eq!(parse_u64("10"), Ok(10));
+        assert!(parse_u64("7fff").is_err());
+        assert!(parse_u64("").is_err());
+        assert!(parse_u64("0x").is_err());
+        assert!(parse_u64("-1").is_err());
+        assert!(parse_u64("0x1_000").is_err());
+        assert!(parse_u64("18446744073709551616").is_err());
+        assert!(parse_u64("0x10000000000000000").is_err());
+    }
+
+    #[test]
+    fn test_parse_error_hints_prefix() {
+        let err = parse_u64("700000000abc").unwrap_err().to_string();
+        assert!(err.contains("need a 0x prefix"), "{}", err);
+        let err = parse_u64("bogus").unwrap_err().to_string();
+        assert!(!err.contains("need a 0x prefix"), "{}", err);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(HvaAddr(0x7000_0000_0000).to_string(), "0x700000000000");
+        assert_eq!(Gpa(0).to_string(), "0x0");
+        assert_eq!(PageOffset(4096).to_string(), "4096");
+    }
+
+    #[test]
+    fn test_display_from_str_round_trip() {
+        let mut rng = XorShift64::new(0x9e37_79b9_7f4a_7c15);
+        let mut samples = vec![0, 1, u64::MAX, u64::MAX - 1, 0x1000, 0x7000_0000_0000];
+        for _ in 0..1000 {
+            let state = rng.next_u64();
+            samples.push(state);
+            // Also cover small values where decimal and hex digits coincide.
+            samples.push(state % 100_000);
+        }
+
+        for value in samples {
+            assert_eq!(
+                HvaAddr::from_str(&HvaAddr(value).to_string()),
+                Ok(HvaAddr(value))
+            );
+            assert_eq!(Gpa::from_str(&Gpa(value).to_string()), Ok(Gpa(value)));
+            assert_eq!(
+                PageOffset::from_str(&PageOffset(value).to_string()),
+                Ok(PageOffset(value))
+            );
+            assert_eq!(parse_u64(&format!("{}", value)), Ok(value));
+            assert_eq!(parse_u64(&format!("0x{:x}", value)), Ok(value));
+            assert_eq!(parse_u64(&format!("0x{:X}", value)), Ok(value));
+        }
+    }
+
+    #[test]
+    fn test_serde_forms() {
+        assert_eq!(
+            serde_json::to_string(&HvaAddr(0x7000_0000_0000)).unwrap(),
+            "\"0x700000000000\""
+        );
+        assert_eq!(serde_json::to_string(&PageOffset(42)).unwrap(), "42");
+
+        // Old templates wrote plain numbers.
+        let hva: HvaAddr = serde_json::from_str("123145302310912").unwrap();
+        assert_eq!(hva, HvaAddr(0x7000_0000_0000));
+        let hva: HvaAddr = serde_json::from_str("\"0x700000000000\"").unwrap();
+        assert_eq!(hva, HvaAddr(0x7000_0000_0000));
+        let pgoff: PageOffset = serde_json::from_str("\"0x10\"").unwrap();
+        assert_eq!(pgoff, PageOffset(16));
+
+        assert!(serde_json::from_str::<Gpa>("-1").is_err());
+        assert!(serde_json::from_str::<Gpa>("\"abc\"").is_err());
+    }
+}
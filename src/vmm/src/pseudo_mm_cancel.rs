@@ -0,0 +1,115 @@
+//! Cancellation of long-running pseudo_mm operations.
+//!
+//! Template creation and restore check a `CancelToken` between upload chunks
+//! and between regions. A cancelled operation stops at the next check and
+//! returns a `Cancelled` error, which callers can tell apart from failures.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+enum Flag {
+    Shared(Arc<AtomicBool>),
+    Static(&'static AtomicBool),
+}
+
+/// Cheaply clonable handle asking an in-flight operation to stop.
+///
+/// All clones share one flag, so any of them can cancel.
+#[derive(Clone, Debug)]
+pub struct CancelToken(Flag);
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        CancelToken(Flag::Shared(Arc::new(AtomicBool::new(false))))
+    }
+}
+
+impl CancelToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wraps a static flag, e.g. one set from a signal handler, which can't
+    /// touch an `Arc`.
+    pub fn from_static(flag: &'static AtomicBool) -> Self {
+        CancelToken(Flag::Static(flag))
+    }
+
+    fn flag(&self) -> &AtomicBool {
+        match self.0 {
+            Flag::Shared(ref flag) => flag,
+            Flag::Static(flag) => flag,
+        }
+    }
+
+    /// Asks the operations holding this token to stop.
+    pub fn cancel(&self) {
+        self.flag().store(true, Ordering::SeqCst);
+    }
+
+    /// Whether `cancel` has been called on any clone.
+    pub fn is_cancelled(&self) -> bool {
+        self.flag().load(Ordering::SeqCst)
+    }
+
+    /// Fails with a `Cancelled` error once the token is cancelled.
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, Cancelled));
+        }
+        Ok(())
+    }
+}
+
+/// Error of an operation stopped through its `CancelToken`.
+#[derive(Debug, PartialEq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "operation cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+/// Whether `err` is, or wraps, a `Cancelled` error.
+pub fn is_cancelled(err: &io::Error) -> bool {
+    err.get_ref().map_or(false, |inner| inner.is::<Cancelled>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_state() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(clone.check().is_ok());
+
+        token.cancel();
+        assert!(clone.is_cancelled());
+        let err = clone.check().unwrap_err();
+        assert!(is_cancelled(&err));
+        assert_eq!(err.to_string(), "operation cancelled");
+        assert!(!is_cancelled(&io::Error::new(
+            io::ErrorKind::Interrupted,
+            "interrupted"
+        )));
+    }
+
+    #[test]
+    fn test_static_flag() {
+        static FLAG: AtomicBool = AtomicBool::new(false);
+        let token = CancelToken::from_static(&FLAG);
+        assert!(!token.is_cancelled());
+        FLAG.store(true, Ordering::SeqCst);
+        assert!(token.is_cancelled());
+    }
+}
@@ -0,0 +1,327 @@
+//! NUMA placement of restored pseudo_mm regions.
+//!
+//! Pages of an attached template are allocated as the guest faults them in.
+//! Without a policy they land on whichever node the faulting thread runs on,
+//! which on multi-socket hosts may not be the node the vcpus are pinned to.
+//! Applying `mbind` over each region after attach steers those allocations.
+
+use std::fmt;
+use std::io;
+
+use logger::{warn, Metric, METRICS};
+
+use crate::pseudo_mm_support::RegionMetadata;
+
+// See include/uapi/linux/mempolicy.h in the kernel code.
+const MPOL_PREFERRED: libc::c_long = 1;
+const MPOL_BIND: libc::c_long = 2;
+
+/// Highest node number plus one that a `NodeMask` can hold.
+pub const MAX_NODES: u32 = 64;
+
+/// Set of NUMA nodes, one bit per node.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NodeMask(pub u64);
+
+impl NodeMask {
+    /// Whether no node is set.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl fmt::Display for NodeMask {
+    /// Formats the mask as a node list, e.g. `0,2-3`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        let mut node = 0;
+        while node < MAX_NODES {
+            if self.0 & (1 << node) == 0 {
+                node += 1;
+                continue;
+            }
+            let start = node;
+            while node + 1 < MAX_NODES && self.0 & (1 << (node + 1)) != 0 {
+                node += 1;
+            }
+            if !first {
+                write!(f, ",")?;
+            }
+            first = false;
+            if start == node {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, node)?;
+            }
+            node += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a kernel-style list such as `0,2-3` into a bitmask of `limit` bits.
+pub fn parse_list(list: &str, limit: u32) -> Result<u64, String> {
+    let mut mask = 0u64;
+    for item in list.trim().split(',').filter(|item| !item.is_empty()) {
+        let mut bounds = item.splitn(2, '-');
+        let parse = |value: Option<&str>| -> Result<u32, String> {
+            value
+                .unwrap_or("")
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| format!("invalid list item '{}'", item))
+        };
+        let start = parse(bounds.next())?;
+        let end = match bounds.next() {
+            Some(end) => parse(Some(end))?,
+            None => start,
+        };
+        if start > end || end >= limit {
+            return Err(format!("list item '{}' out of range 0-{}", item, limit - 1));
+        }
+        for bit in start..=end {
+            mask |= 1 << bit;
+        }
+    }
+    Ok(mask)
+}
+
+/// Nodes whose CPUs intersect the CPUs this process may run on.
+///
+/// Read from `Cpus_allowed_list` and /sys/devices/system/node, so it follows
+/// the cpuset the VMM was placed in.
+pub fn nodes_for_allowed_cpus() -> io::Result<NodeMask> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let allowed = status
+        .lines()
+        .find(|line| line.starts_with("Cpus_allowed_list:"))
+        .map(|line| line["Cpus_allowed_list:".len()..].to_string())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no Cpus_allowed_list in status"))?;
+    let allowed = CpuSet::parse(&allowed)?;
+
+    let mut nodes = 0u64;
+    for node in 0..MAX_NODES {
+        let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+        let cpulist = match std::fs::read_to_string(&path) {
+            Ok(cpulist) => cpulist,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        if CpuSet::parse(&cpulist)?.intersects(&allowed) {
+            nodes |= 1 << node;
+        }
+    }
+    Ok(NodeMask(nodes))
+}
+
+/// CPU set of arbitrary size, as a list of 64-bit words.
+struct CpuSet(Vec<u64>);
+
+impl CpuSet {
+    fn parse(list: &str) -> io::Result<Self> {
+        let mut words = Vec::new();
+        for item in list.trim().split(',').filter(|item| !item.is_empty()) {
+            let (start, end) = match item.find('-') {
+                Some(dash) => (&item[..dash], &item[dash + 1..]),
+                None => (item, item),
+            };
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid CPU list item '{}'", item),
+                )
+            };
+            let start: usize = start.trim().parse().map_err(|_| invalid())?;
+            let end: usize = end.trim().parse().map_err(|_| invalid())?;
+            for cpu in start..=end {
+                if words.len() <= cpu / 64 {
+                    words.resize(cpu / 64 + 1, 0);
+                }
+                words[cpu / 64] |= 1 << (cpu % 64);
+            }
+        }
+        Ok(CpuSet(words))
+    }
+
+    fn intersects(&self, other: &CpuSet) -> bool {
+        self.0.iter().zip(&other.0).any(|(a, b)| a & b != 0)
+    }
+}
+
+/// How strictly guest pages are tied to the chosen nodes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NumaMode {
+    /// Allocate on the first chosen node when it has memory, else anywhere.
+    Preferred,
+    /// Allocate only on the chosen nodes.
+    Bind,
+}
+
+impl fmt::Display for NumaMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NumaMode::Preferred => write!(f, "preferred"),
+            NumaMode::Bind => write!(f, "bind"),
+        }
+    }
+}
+
+/// NUMA policy applied to every restored region.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NumaPolicy {
+    /// Policy mode.
+    pub mode: NumaMode,
+    /// Nodes to allocate guest memory on.
+    pub nodes: NodeMask,
+    /// Fail the restore if the policy can't be applied, instead of warning.
+    pub strict: bool,
+}
+
+impl fmt::Display for NumaPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} nodes {}", self.mode, self.nodes)
+    }
+}
+
+fn mbind(addr: u64, len: u64, mode: NumaMode, nodes: NodeMask) -> io::Result<()> {
+    let mode = match mode {
+        NumaMode::Preferred => MPOL_PREFERRED,
+        NumaMode::Bind => MPOL_BIND,
+    };
+    let mask = nodes.0;
+    // The kernel reads one bit less than `maxnode` from the mask, so the
+    // top node needs `MAX_NODES + 1`.
+    // Safe because the kernel only reads `MAX_NODES` bits from `mask` and
+    // changing the policy doesn't touch the range's contents.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            addr,
+            len,
+            mode,
+            &mask as *const u64,
+            libc::c_ulong::from(MAX_NODES + 1),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Applies `policy` to every region, returning how many regions took it.
+///
+/// Unless the policy is strict, a region the kernel refuses (EPERM, a node
+/// without memory) is logged and counted, and the rest are still tried.
+pub fn apply_policy(policy: &NumaPolicy, regions: &[RegionMetadata]) -> io::Result<usize> {
+    if policy.nodes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "NUMA policy has no nodes",
+        ));
+    }
+    let mut applied = 0;
+    for (idx, region) in regions.iter().enumerate() {
+        match mbind(region.hva.raw(), region.size, policy.mode, policy.nodes) {
+            Ok(()) => applied += 1,
+            Err(err) => {
+                METRICS.vmm.pseudo_mm_numa_fails.inc();
+                let msg = format!(
+                    "cannot apply NUMA policy {} to region {} ({} bytes at {}): {}",
+                    policy, idx, region.size, region.hva, err
+                );
+                if policy.strict {
+                    return Err(io::Error::new(err.kind(), msg));
+                }
+                warn!("{}", msg);
+            }
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pseudo_mm_test_utils::region;
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(parse_list("0", MAX_NODES), Ok(0b1));
+        assert_eq!(parse_list("0,2-3\n", MAX_NODES), Ok(0b1101));
+        assert_eq!(parse_list("", MAX_NODES), Ok(0));
+        assert!(parse_list("3-1", MAX_NODES).is_err());
+        assert!(parse_list("64", MAX_NODES).is_err());
+        assert!(parse_list("a", MAX_NODES).is_err());
+    }
+
+    #[test]
+    fn test_node_mask_display() {
+        assert_eq!(NodeMask(0b1101).to_string(), "0,2-3");
+        assert_eq!(NodeMask(1 << 63).to_string(), "63");
+        assert_eq!(NodeMask(0).to_string(), "");
+    }
+
+    #[test]
+    fn test_cpu_set() {
+        let allowed = CpuSet::parse("0-3,130").unwrap();
+        assert!(allowed.intersects(&CpuSet::parse("2").unwrap()));
+        assert!(allowed.intersects(&CpuSet::parse("128-131").unwrap()));
+        assert!(!allowed.intersects(&CpuSet::parse("4-127").unwrap()));
+        assert!(CpuSet::parse("1-x").is_err());
+    }
+
+    #[test]
+    fn test_lenient_policy_counts_failures() {
+        // An unmapped range makes mbind fail without needing a NUMA host.
+        let region = region(0, 0x10_0000_0000, 0x1000);
+        let mut policy = NumaPolicy {
+            mode: NumaMode::Preferred,
+            nodes: NodeMask(1),
+            strict: false,
+        };
+        let fails = METRICS.vmm.pseudo_mm_numa_fails.count();
+        assert_eq!(
+            apply_policy(&policy, std::slice::from_ref(&region)).unwrap(),
+            0
+        );
+        assert_eq!(METRICS.vmm.pseudo_mm_numa_fails.count(), fails + 1);
+
+        policy.strict = true;
+        let err = apply_policy(&policy, &[region]).unwrap_err().to_string();
+        assert!(err.contains("region 0"), "{}", err);
+    }
+
+    #[test]
+    #[ignore] // Requires a NUMA-enabled kernel
+    fn test_policy_shows_in_numa_maps() {
+        let size = 4 * 4096;
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let region = region(0, addr as u64, size as u64);
+        let policy = NumaPolicy {
+            mode: NumaMode::Bind,
+            nodes: NodeMask(1),
+            strict: true,
+        };
+        assert_eq!(apply_policy(&policy, &[region]).unwrap(), 1);
+
+        let numa_maps = std::fs::read_to_string("/proc/self/numa_maps").unwrap();
+        let line = numa_maps
+            .lines()
+            .find(|line| line.starts_with(&format!("{:x} ", addr as u64)))
+            .unwrap();
+        assert!(line.contains("bind:0"), "{}", line);
+        unsafe { libc::munmap(addr, size) };
+    }
+}
@@ -2,80 +2,389 @@
 //!
 //! Implements memory restoration using pseudo_mm and RDMA.
 
-use std::fs::File;
 use std::io;
 use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
 
-use logger::info;
-use vm_memory::{GuestAddress, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+use logger::{info, warn};
+use vm_memory::{
+    Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap, GuestRegionMmap, MmapRegion,
+};
 
 use crate::memory_snapshot::Error;
-use crate::pseudo_mm_support::{self, PseudoMmTemplate, RegionMetadata};
+use crate::pseudo_mm_addr::Gpa;
+use crate::pseudo_mm_cancel::CancelToken;
+use crate::pseudo_mm_numa::{self, NumaPolicy};
+use crate::pseudo_mm_support::{
+    self, MarkerPage, Provenance, PseudoMmTemplate, RegionMetadata, RetryPolicy, VmShape, PAGE_SIZE,
+};
+
+/// Phases of a pseudo_mm restore, in the order they run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestorePhase {
+    /// Reading the template and checking it against the module.
+    LoadTemplate,
+    /// Attaching the pseudo_mm instance to the current process.
+    Attach,
+    /// Wrapping the attached VMAs into guest memory regions.
+    CreateRegions,
+}
+
+/// Receives progress of a pseudo_mm restore.
+///
+/// Callbacks are invoked synchronously from the restore path; keep them cheap.
+pub trait RestoreObserver {
+    /// Called before `phase` starts.
+    fn on_phase_start(&self, _phase: RestorePhase) {}
+    /// Called after `phase` finished, successfully or not.
+    fn on_phase_end(&self, _phase: RestorePhase, _ok: bool) {}
+    /// Called as guest memory pages become available.
+    fn on_progress(&self, _pages_done: u64, _pages_total: u64) {}
+}
+
+/// Event forwarded by a `ChannelObserver`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RestoreEvent {
+    /// `RestoreObserver::on_phase_start`.
+    PhaseStart(RestorePhase),
+    /// `RestoreObserver::on_phase_end`, with whether the phase succeeded.
+    PhaseEnd(RestorePhase, bool),
+    /// `RestoreObserver::on_progress`.
+    Progress {
+        /// Pages available so far.
+        pages_done: u64,
+        /// Pages in the whole guest memory.
+        pages_total: u64,
+    },
+}
+
+/// Observer that forwards every callback over a channel, for consumers on
+/// another thread.
+pub struct ChannelObserver {
+    sender: Mutex<Sender<RestoreEvent>>,
+}
+
+impl ChannelObserver {
+    /// Creates the observer and the receiving end of its channel.
+    pub fn new() -> (Self, Receiver<RestoreEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        (
+            ChannelObserver {
+                sender: Mutex::new(sender),
+            },
+            receiver,
+        )
+    }
+
+    fn send(&self, event: RestoreEvent) {
+        // A dropped receiver just means nobody is listening any more.
+        let _ = self.sender.lock().expect("Poisoned lock").send(event);
+    }
+}
+
+impl RestoreObserver for ChannelObserver {
+    fn on_phase_start(&self, phase: RestorePhase) {
+        self.send(RestoreEvent::PhaseStart(phase));
+    }
+
+    fn on_phase_end(&self, phase: RestorePhase, ok: bool) {
+        self.send(RestoreEvent::PhaseEnd(phase, ok));
+    }
+
+    fn on_progress(&self, pages_done: u64, pages_total: u64) {
+        self.send(RestoreEvent::Progress {
+            pages_done,
+            pages_total,
+        });
+    }
+}
+
+/// Options for a pseudo_mm restore.
+#[derive(Default)]
+pub struct RestoreOptions<'a> {
+    /// Optional progress observer.
+    pub observer: Option<&'a dyn RestoreObserver>,
+    /// Retry policy for transient attach failures.
+    pub attach_retry: RetryPolicy,
+    /// Shape of the snapshot being restored, checked against the template's.
+    pub expected_shape: Option<&'a VmShape>,
+    /// Fail the restore on a shape mismatch instead of only warning.
+    pub strict_shape: bool,
+    /// NUMA policy applied to the attached regions, if any.
+    pub numa: Option<NumaPolicy>,
+    /// Stops the restore with `Error::Cancelled` between phases and regions.
+    ///
+    /// Regions already attached stay mapped, as after any failure past the
+    /// attach phase.
+    pub cancel: Option<CancelToken>,
+}
+
+/// Guest memory attached read-only, for inspecting a template's contents.
+///
+/// Every region is mapped `PROT_READ`, so inspection can't dirty CoW pages.
+/// There is deliberately no way to get at the underlying `GuestMemoryMmap`:
+/// this memory must never be handed to a running VM.
+pub struct ReadOnlyGuestMemory {
+    memory: GuestMemoryMmap,
+    template: PseudoMmTemplate,
+}
+
+impl ReadOnlyGuestMemory {
+    /// Copies guest memory starting at `gpa` into `buf`.
+    pub fn read(&self, gpa: Gpa, buf: &mut [u8]) -> Result<(), GuestMemoryError> {
+        self.memory.read_slice(buf, GuestAddress(gpa.raw()))
+    }
+
+    /// The template regions backing this memory.
+    pub fn regions(&self) -> &[RegionMetadata] {
+        &self.template.regions
+    }
+
+    /// Shape of the VM the template was taken from, if recorded.
+    pub fn vm_shape(&self) -> Option<&VmShape> {
+        self.template.vm_shape.as_ref()
+    }
+
+    /// When, where and by what the template was made, if recorded.
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.template.provenance.as_ref()
+    }
+
+    /// The page of the image holding a marker, if the template has one.
+    pub fn marker(&self) -> Option<&MarkerPage> {
+        self.template.marker.as_ref()
+    }
+}
 
 /// Restore GuestMemoryMmap using pseudo_mm
 pub fn restore_with_pseudo_mm(template_path: &PathBuf) -> Result<GuestMemoryMmap, Error> {
-    info!("Restoring memory using pseudo_mm from {:?}", template_path);
+    restore_with_pseudo_mm_options(template_path, &RestoreOptions::default())
+}
 
-    // 1. Load template metadata
-    let template = load_template(template_path)?;
+/// Restore GuestMemoryMmap using pseudo_mm, reporting progress to the
+/// observer in `options`.
+pub fn restore_with_pseudo_mm_options(
+    template_path: &PathBuf,
+    options: &RestoreOptions,
+) -> Result<GuestMemoryMmap, Error> {
+    restore(template_path, options, false).map(|(memory, _)| memory)
+}
+
+/// Attach a template's memory read-only for inspection.
+///
+/// The regions are write-protected after attach, whatever protection the
+/// instance was created with.
+pub fn inspect_with_pseudo_mm(
+    template_path: &PathBuf,
+    options: &RestoreOptions,
+) -> Result<ReadOnlyGuestMemory, Error> {
+    let (memory, template) = restore(template_path, options, true)?;
+    Ok(ReadOnlyGuestMemory { memory, template })
+}
+
+fn restore(
+    template_path: &PathBuf,
+    options: &RestoreOptions,
+    read_only: bool,
+) -> Result<(GuestMemoryMmap, PseudoMmTemplate), Error> {
     info!(
-        "Loaded pseudo_mm template: id={}, rdma_base_pgoff={}, size={} bytes, regions={}",
-        template.pseudo_mm_id,
-        template.rdma_base_pgoff,
-        template.rdma_image_size,
-        template.regions.len()
+        "Restoring memory using pseudo_mm from {:?}{}",
+        template_path,
+        if read_only { " (read-only)" } else { "" }
     );
+    let observer = options.observer;
+    let cancel = options.cancel.as_ref();
 
-    // 2. Attach pseudo_mm to current process
-    pseudo_mm_support::attach_to_current_process(template.pseudo_mm_id)
+    // 1. Load template metadata and refuse templates with regions that can't
+    // be mapped, that the loaded module cannot set up, whose DAX device is
+    // gone, or that belong to a differently shaped VM. Nothing touches the
+    // module before this phase has passed.
+    let template = run_phase(observer, RestorePhase::LoadTemplate, || {
+        let template = load_template(template_path)?;
+        info!(
+            "Loaded pseudo_mm template: id={}, backend={}, hva_base={}, rdma_base_pgoff={}, size={} bytes, regions={}",
+            template
+                .pseudo_mm_id
+                .map_or_else(|| "none".to_string(), |id| id.to_string()),
+            template.mem_backend,
+            template.hva_base,
+            template.rdma_base_pgoff,
+            template.rdma_image_size,
+            template.regions.len()
+        );
+        if let Some(base) = template.base_template.as_ref() {
+            // The instance's page tables already point at the base's pages;
+            // nothing else about restore changes.
+            info!("Template is layered on base template {}", base);
+        }
+        if let Some(source) = template.source.as_ref() {
+            info!("Template was made from snapshot {}", source);
+        }
+        pseudo_mm_support::validate_regions(&template.regions).map_err(Error::InvalidRegion)?;
+        pseudo_mm_support::check_required_features(
+            &template,
+            &pseudo_mm_support::probe_module_features(),
+        )
         .map_err(Error::FileHandle)?;
-    info!(
-        "Attached pseudo_mm id={} to current process",
-        template.pseudo_mm_id
-    );
+        pseudo_mm_support::check_backend(&template).map_err(Error::FileHandle)?;
+        if let Some(expected) = options.expected_shape {
+            pseudo_mm_support::check_vm_shape(&template, expected, options.strict_shape)
+                .map_err(Error::FileHandle)?;
+        }
+        Ok(template)
+    })?;
 
-    // 3. Create GuestMemoryMmap using existing VMAs
-    let mmap_regions = create_guest_regions(&template.regions)?;
-    info!("Created {} guest memory regions", mmap_regions.len());
+    // 2. Attach pseudo_mm to current process, first creating the instance
+    // of a template built without one
+    let pseudo_mm_id = run_phase(observer, RestorePhase::Attach, || {
+        check_cancel(cancel)?;
+        let (id, created) = match template.pseudo_mm_id {
+            Some(id) => (id, false),
+            None => {
+                let id = pseudo_mm_support::create_instance(&template, &options.attach_retry)
+                    .map_err(Error::FileHandle)?;
+                info!(
+                    "Created pseudo_mm id={} with {} regions for a template without an instance",
+                    id,
+                    template.regions.len()
+                );
+                (id, true)
+            }
+        };
+        let attached =
+            pseudo_mm_support::attach_to_current_process_with_retry(id, &options.attach_retry);
+        if let (Err(_), true) = (attached.as_ref(), created) {
+            // Nothing else knows the id of an instance created here, and the
+            // module can't release it.
+            warn!("pseudo_mm id={} is left behind after attach failed", id);
+        }
+        attached.map(|_| id).map_err(Error::FileHandle)
+    })?;
+    info!("Attached pseudo_mm id={} to current process", pseudo_mm_id);
 
-    let guest_memory = GuestMemoryMmap::from_regions(mmap_regions).map_err(Error::CreateMemory)?;
+    // 3. Create GuestMemoryMmap using existing VMAs
+    let guest_memory = run_phase(observer, RestorePhase::CreateRegions, || {
+        check_cancel(cancel)?;
+        let mmap_regions = create_guest_regions(&template.regions, observer, cancel, read_only)?;
+        info!("Created {} guest memory regions", mmap_regions.len());
+        if let (Some(policy), false) = (options.numa, read_only) {
+            let applied = pseudo_mm_numa::apply_policy(&policy, &template.regions)
+                .map_err(Error::FileHandle)?;
+            info!(
+                "Applied NUMA policy {} to {} of {} regions",
+                policy,
+                applied,
+                template.regions.len()
+            );
+        }
+        GuestMemoryMmap::from_regions(mmap_regions).map_err(Error::CreateMemory)
+    })?;
 
     info!("Pseudo_MM restore completed successfully");
 
-    Ok(guest_memory)
+    Ok((guest_memory, template))
+}
+
+fn run_phase<T, F>(
+    observer: Option<&dyn RestoreObserver>,
+    phase: RestorePhase,
+    f: F,
+) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    if let Some(observer) = observer {
+        observer.on_phase_start(phase);
+    }
+    let result = f();
+    if let Some(observer) = observer {
+        observer.on_phase_end(phase, result.is_ok());
+    }
+    result
 }
 
 /// Load pseudo_mm template from JSON file
 fn load_template(path: &PathBuf) -> Result<PseudoMmTemplate, Error> {
-    let file = File::open(path).map_err(Error::FileHandle)?;
-    let template: PseudoMmTemplate = serde_json::from_reader(file).map_err(|err| {
-        Error::FileHandle(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("invalid pseudo_mm template JSON: {}", err),
-        ))
-    })?;
-    Ok(template)
+    pseudo_mm_support::load_template_file(path).map_err(Error::FileHandle)
 }
 
-/// Create GuestRegionMmap instances from pseudo_mm regions
-fn create_guest_regions(regions: &[RegionMetadata]) -> Result<Vec<GuestRegionMmap>, Error> {
-    let mut mmap_regions = Vec::new();
+fn check_cancel(cancel: Option<&CancelToken>) -> Result<(), Error> {
+    match cancel {
+        Some(cancel) if cancel.is_cancelled() => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
+}
 
-    for region in regions {
-        // Use the HVA from pseudo_mm (VMA already exists)
+/// Create GuestRegionMmap instances from pseudo_mm regions
+fn create_guest_regions(
+    regions: &[RegionMetadata],
+    observer: Option<&dyn RestoreObserver>,
+    cancel: Option<&CancelToken>,
+    read_only: bool,
+) -> Result<Vec<GuestRegionMmap>, Error> {
+    map_regions(regions, observer, cancel, |region| {
+        let prot = if read_only {
+            protect_read_only(region)?;
+            libc::PROT_READ
+        } else {
+            libc::PROT_READ | libc::PROT_WRITE
+        };
+        // Use the HVA from pseudo_mm (VMA already exists). restore validated
+        // size and alignment while loading the template, and from_raw_ptr
+        // doesn't check them.
         let mmap_region = unsafe {
-            MmapRegion::from_raw_ptr(
-                region.hva as *mut u8,
-                region.size as usize,
-                libc::PROT_READ | libc::PROT_WRITE,
-            )
+            MmapRegion::from_raw_ptr(region.hva.raw() as *mut u8, region.size as usize, prot)
         }
         .map_err(Error::CreateRegion)?;
 
-        let guest_region = GuestRegionMmap::new(mmap_region, GuestAddress(region.gpa))
-            .map_err(Error::CreateMemory)?;
+        GuestRegionMmap::new(mmap_region, GuestAddress(region.gpa.raw()))
+            .map_err(Error::CreateMemory)
+    })
+}
+
+/// Drops write access to an attached region.
+fn protect_read_only(region: &RegionMetadata) -> Result<(), Error> {
+    // Safe because the region is a validated, attached pseudo_mm VMA and
+    // removing write access can't invalidate any reference into it.
+    let ret = unsafe {
+        libc::mprotect(
+            region.hva.raw() as *mut libc::c_void,
+            region.size as usize,
+            libc::PROT_READ,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::FileHandle(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Maps each region with `map`, reporting progress.
+///
+/// The regions must have passed `validate_regions`, which `restore` runs
+/// while loading the template. `cancel` is checked before each region.
+fn map_regions<T, F>(
+    regions: &[RegionMetadata],
+    observer: Option<&dyn RestoreObserver>,
+    cancel: Option<&CancelToken>,
+    mut map: F,
+) -> Result<Vec<T>, Error>
+where
+    F: FnMut(&RegionMetadata) -> Result<T, Error>,
+{
+    let mut mmap_regions = Vec::with_capacity(regions.len());
+    let pages_total: u64 = regions.iter().map(|region| region.size / PAGE_SIZE).sum();
+    let mut pages_done = 0;
+
+    for region in regions {
+        check_cancel(cancel)?;
+        mmap_regions.push(map(region)?);
 
-        mmap_regions.push(guest_region);
+        pages_done += region.size / PAGE_SIZE;
+        if let Some(observer) = observer {
+            observer.on_progress(pages_done, pages_total);
+        }
     }
 
     Ok(mmap_regions)
@@ -84,22 +393,370 @@ fn create_guest_regions(regions: &[RegionMetadata]) -> Result<Vec<GuestRegionMma
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pseudo_mm_addr::{Gpa, PageOffset};
+    use crate::pseudo_mm_support::{PageSize, PgoffExtent};
+    use crate::pseudo_mm_test_utils::{self, region, XorShift64};
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: RefCell<Vec<RestoreEvent>>,
+    }
+
+    impl RestoreObserver for RecordingObserver {
+        fn on_phase_start(&self, phase: RestorePhase) {
+            self.events
+                .borrow_mut()
+                .push(RestoreEvent::PhaseStart(phase));
+        }
+
+        fn on_phase_end(&self, phase: RestorePhase, ok: bool) {
+            self.events
+                .borrow_mut()
+                .push(RestoreEvent::PhaseEnd(phase, ok));
+        }
+
+        fn on_progress(&self, pages_done: u64, pages_total: u64) {
+            self.events.borrow_mut().push(RestoreEvent::Progress {
+                pages_done,
+                pages_total,
+            });
+        }
+    }
+
+    fn template(pseudo_mm_id: i32, required_features: Vec<String>) -> PseudoMmTemplate {
+        let mut template = pseudo_mm_test_utils::template();
+        template.pseudo_mm_id = Some(pseudo_mm_id);
+        template.required_features = required_features;
+        template
+    }
+
+    fn write_template(name: &str, template: &PseudoMmTemplate) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}_{}.json", name, std::process::id()));
+        std::fs::write(&path, serde_json::to_string(template).unwrap()).unwrap();
+        path
+    }
+
+    fn restore_recorded(path: &PathBuf) -> (bool, Vec<RestoreEvent>) {
+        let observer = RecordingObserver::default();
+        let options = RestoreOptions {
+            observer: Some(&observer),
+            ..Default::default()
+        };
+        let ok = restore_with_pseudo_mm_options(path, &options).is_ok();
+        (ok, observer.events.into_inner())
+    }
+
+    #[test]
+    fn test_observer_load_failure() {
+        let (ok, events) = restore_recorded(&PathBuf::from("/nonexistent/template.json"));
+        assert!(!ok);
+        assert_eq!(
+            events,
+            vec![
+                RestoreEvent::PhaseStart(RestorePhase::LoadTemplate),
+                RestoreEvent::PhaseEnd(RestorePhase::LoadTemplate, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observer_feature_failure() {
+        let path = write_template(
+            "pseudo_mm_observer_feature",
+            &template(1, vec!["no-such-feature".to_string()]),
+        );
+        let (ok, events) = restore_recorded(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(!ok);
+        assert_eq!(
+            events,
+            vec![
+                RestoreEvent::PhaseStart(RestorePhase::LoadTemplate),
+                RestoreEvent::PhaseEnd(RestorePhase::LoadTemplate, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observer_attach_failure() {
+        // No pseudo_mm instance has a negative id, with or without the device.
+        let path = write_template("pseudo_mm_observer_attach", &template(-1, Vec::new()));
+        let (ok, events) = restore_recorded(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(!ok);
+        assert_eq!(
+            events,
+            vec![
+                RestoreEvent::PhaseStart(RestorePhase::LoadTemplate),
+                RestoreEvent::PhaseEnd(RestorePhase::LoadTemplate, true),
+                RestoreEvent::PhaseStart(RestorePhase::Attach),
+                RestoreEvent::PhaseEnd(RestorePhase::Attach, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_restore_rejects_invalid_regions_before_attach() {
+        let overlapping = vec![
+            region(0, 0x7000_0000_0000, 2 * PAGE_SIZE),
+            region(0x10_0000, 0x7000_0000_1000, PAGE_SIZE),
+        ];
+        let unaligned = vec![region(0, 0x7000_0000_0800, PAGE_SIZE)];
+        let mut huge = region(0, 0x7000_0000_0000, 2 << 20);
+        huge.page_size = PageSize::Huge;
+        let cases = [
+            (overlapping, "hva"),
+            (unaligned, "hva"),
+            (vec![huge], "page_size"),
+        ];
+        for (regions, field) in cases.iter() {
+            // Without an instance, attach would start by creating one.
+            let mut template = template(1, Vec::new());
+            template.pseudo_mm_id = None;
+            template.regions = regions.clone();
+            let path = write_template("pseudo_mm_invalid_regions", &template);
+            let observer = RecordingObserver::default();
+            let options = RestoreOptions {
+                observer: Some(&observer),
+                ..Default::default()
+            };
+            let result = restore_with_pseudo_mm_options(&path, &options);
+            std::fs::remove_file(&path).unwrap();
+            match result {
+                Err(Error::InvalidRegion(err)) => assert_eq!(err.field, *field),
+                Err(other) => panic!("unexpected error {}", other),
+                Ok(_) => panic!("invalid regions were restored"),
+            }
+            // The attach phase, and with it every ioctl, never starts.
+            assert_eq!(
+                observer.events.into_inner(),
+                vec![
+                    RestoreEvent::PhaseStart(RestorePhase::LoadTemplate),
+                    RestoreEvent::PhaseEnd(RestorePhase::LoadTemplate, false),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_layered_regions() {
+        let mut layered = region(0, 0x7000_0000_0000, 4 * PAGE_SIZE);
+        layered.extents = vec![PgoffExtent {
+            offset: PAGE_SIZE,
+            size: 2 * PAGE_SIZE,
+            rdma_offset: PageOffset(9000),
+        }];
+        let regions = vec![layered, region(0x10_0000, 0x7000_0010_0000, PAGE_SIZE)];
+        pseudo_mm_support::validate_regions(&regions).unwrap();
+
+        // Extents are validated like the rest of the region.
+        let mut broken = regions;
+        broken[0].extents[0].size = 4 * PAGE_SIZE;
+        let err = pseudo_mm_support::validate_regions(&broken).unwrap_err();
+        assert_eq!((err.index, err.field), (0, "extents"));
+    }
+
+    #[test]
+    fn test_map_regions_cancel() {
+        let regions = vec![
+            region(0, 0x7000_0000_0000, 0x1000),
+            region(0x1000, 0x7000_0000_1000, 0x1000),
+            region(0x2000, 0x7000_0000_2000, 0x1000),
+        ];
+        let cancel = CancelToken::new();
+
+        // Cancelling while the first region is mapped stops before the next.
+        let mut mapped = Vec::new();
+        let err = map_regions(&regions, None, Some(&cancel), |region| {
+            mapped.push(region.gpa);
+            cancel.cancel();
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{}", err);
+        assert_eq!(mapped, vec![Gpa(0)]);
+    }
+
+    #[test]
+    fn test_restore_cancelled_before_attach() {
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let observer = RecordingObserver::default();
+        let path = write_template("pseudo_mm_cancelled", &template(1, Vec::new()));
+        let options = RestoreOptions {
+            observer: Some(&observer),
+            cancel: Some(cancel),
+            ..Default::default()
+        };
+        let result = restore_with_pseudo_mm_options(&path, &options);
+        std::fs::remove_file(&path).unwrap();
+        // The template loads without the module; attach stops before it
+        // touches the device.
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert_eq!(
+            observer.events.into_inner(),
+            vec![
+                RestoreEvent::PhaseStart(RestorePhase::LoadTemplate),
+                RestoreEvent::PhaseEnd(RestorePhase::LoadTemplate, true),
+                RestoreEvent::PhaseStart(RestorePhase::Attach),
+                RestoreEvent::PhaseEnd(RestorePhase::Attach, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_then_map_generated_metadata() {
+        let mut rng = XorShift64::new(0x853c_49e6_748f_ea9b);
+        let mut next = move || rng.next_u64();
+        // Mostly well-formed values, with occasional misalignment, zero
+        // sizes and addresses at the edges of the address space.
+        let mut pick = move |aligned: u64| match next() % 8 {
+            0 => 0,
+            1 => aligned + 1 + next() % (PAGE_SIZE - 1),
+            2 => u64::MAX - next() % (4 * PAGE_SIZE),
+            3 => (1 << 47) - PAGE_SIZE * (next() % 4),
+            _ => aligned,
+        };
+
+        for _ in 0..2000 {
+            let count = 1 + (pick(0) % 4) as usize;
+            let regions: Vec<RegionMetadata> = (0..count)
+                .map(|idx| {
+                    let gpa = pick(idx as u64 * 0x10_0000);
+                    let hva = pick(0x7000_0000_0000 + idx as u64 * 0x10_0000);
+                    let size = pick(PAGE_SIZE * (1 + idx as u64));
+                    region(gpa, hva, size)
+                })
+                .collect();
+
+            // As restore does: validate while loading, map after attach.
+            let mut seen = Vec::new();
+            let validated =
+                pseudo_mm_support::validate_regions(&regions).map_err(Error::InvalidRegion);
+            let result = validated.and_then(|_| {
+                map_regions(&regions, None, None, |region| {
+                    // Invariants the unsafe mapping relies on.
+                    assert!(region.size > 0 && region.size % PAGE_SIZE == 0);
+                    assert_eq!(region.hva.raw() % PAGE_SIZE, 0);
+                    assert_eq!(region.gpa.raw() % PAGE_SIZE, 0);
+                    assert!(region.hva.raw().checked_add(region.size).is_some());
+                    seen.push(region.clone());
+                    Ok(())
+                })
+            });
+
+            match result {
+                Ok(mapped) => {
+                    assert_eq!(mapped.len(), regions.len());
+                    assert_eq!(seen.len(), regions.len());
+                }
+                Err(Error::InvalidRegion(err)) => {
+                    assert!(seen.is_empty());
+                    assert!(err.index < regions.len());
+                }
+                Err(other) => panic!("unexpected error {}", other),
+            }
+        }
+    }
+
+    /// Forks a child that writes to `addr`, returning the signal it died of.
+    fn write_in_child(addr: *mut u8) -> Option<i32> {
+        match unsafe { libc::fork() } {
+            0 => unsafe {
+                std::ptr::write_volatile(addr, 1);
+                libc::_exit(0)
+            },
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                if libc::WIFSIGNALED(status) {
+                    Some(libc::WTERMSIG(status))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_protect_read_only_blocks_writes() {
+        let size = 2 * PAGE_SIZE as usize;
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let addr = addr as *mut u8;
+        assert_eq!(write_in_child(addr), None);
+
+        protect_read_only(&region(0, addr as u64, size as u64)).unwrap();
+        assert_eq!(
+            write_in_child(unsafe { addr.add(PAGE_SIZE as usize) }),
+            Some(libc::SIGSEGV)
+        );
+        // Reads are unaffected.
+        assert_eq!(unsafe { std::ptr::read_volatile(addr) }, 0);
+        unsafe { libc::munmap(addr as *mut libc::c_void, size) };
+    }
+
+    #[test]
+    #[ignore] // Requires /dev/pseudo_mm and a template path in PSEUDO_MM_TEST_TEMPLATE
+    fn test_inspect_is_read_only() {
+        let path = PathBuf::from(std::env::var("PSEUDO_MM_TEST_TEMPLATE").unwrap());
+        let memory = inspect_with_pseudo_mm(&path, &RestoreOptions::default()).unwrap();
+        let first = memory.regions()[0].clone();
+
+        let mut buf = [0u8; 64];
+        memory.read(first.gpa, &mut buf).unwrap();
+        assert_eq!(
+            write_in_child(first.hva.raw() as *mut u8),
+            Some(libc::SIGSEGV)
+        );
+    }
+
+    #[test]
+    fn test_channel_observer() {
+        let (observer, receiver) = ChannelObserver::new();
+        let options = RestoreOptions {
+            observer: Some(&observer),
+            ..Default::default()
+        };
+        assert!(restore_with_pseudo_mm_options(&PathBuf::from("/nonexistent"), &options).is_err());
+        observer.on_progress(1, 2);
+
+        let events: Vec<RestoreEvent> = receiver.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                RestoreEvent::PhaseStart(RestorePhase::LoadTemplate),
+                RestoreEvent::PhaseEnd(RestorePhase::LoadTemplate, false),
+                RestoreEvent::Progress {
+                    pages_done: 1,
+                    pages_total: 2
+                },
+            ]
+        );
+
+        // Sending after the receiver is gone is harmless.
+        drop(receiver);
+        observer.on_phase_start(RestorePhase::Attach);
+    }
 
     #[test]
     #[ignore] // Requires pseudo_mm template file
     fn test_load_template() {
         let path = PathBuf::from("/tmp/test_template.json");
         // Create a dummy template file for testing
-        let template = PseudoMmTemplate {
-            pseudo_mm_id: 1,
-            hva_base: 0x700000000000,
-            regions: vec![RegionMetadata {
-                gpa: 0,
-                hva: 0x700000000000,
-                size: 1024 * 1024,
-                rdma_offset: 0,
-            }],
-        };
+        let mut template = pseudo_mm_test_utils::template();
+        template.rdma_image_size = 1024 * 1024;
+        template.regions = vec![region(0, 0x7000_0000_0000, 1024 * 1024)];
         let json = serde_json::to_string_pretty(&template).unwrap();
         std::fs::write(&path, json).unwrap();
 
@@ -2,12 +2,20 @@
 //!
 //! Provides low-level ioctl wrappers for pseudo_mm device operations.
 
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use logger::{info, warn, Metric, METRICS};
 
 use serde::{Deserialize, Serialize};
 
+use crate::pseudo_mm_addr::{Gpa, HvaAddr, PageOffset};
+
 use libc::{c_int, c_ulong};
 
 #[cfg(target_env = "musl")]
@@ -27,32 +35,964 @@ pub const DAX_MEM: u32 = 0;
 /// Memory type flag for RDMA-backed pseudo_mm mappings.
 pub const RDMA_MEM: u32 = 1;
 
+/// Template feature: regions backed by huge pages.
+pub const FEATURE_HUGEPAGE: &str = "hugepage";
+/// Template feature: DAX-backed regions.
+pub const FEATURE_DAX: &str = "dax";
+
+/// Directory listing the host's device-dax devices.
+const DAX_DEVICES_DIR: &str = "/sys/bus/dax/devices";
+
+/// Where a template's memory image is stored.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MemBackend {
+    /// Pages fetched from the RDMA memory server.
+    Rdma,
+    /// Pages mapped from a local DAX device.
+    Dax,
+}
+
+impl Default for MemBackend {
+    /// Templates written before the backend was recorded are all RDMA.
+    fn default() -> Self {
+        MemBackend::Rdma
+    }
+}
+
+impl MemBackend {
+    /// Page table type the regions of this backend are set up with.
+    pub fn pt_type(self) -> u32 {
+        match self {
+            MemBackend::Rdma => RDMA_MEM,
+            MemBackend::Dax => DAX_MEM,
+        }
+    }
+}
+
+impl fmt::Display for MemBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemBackend::Rdma => write!(f, "rdma"),
+            MemBackend::Dax => write!(f, "dax"),
+        }
+    }
+}
+
 /// Pseudo_mm region metadata persisted alongside snapshots.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RegionMetadata {
     /// Guest physical base address associated with this region.
-    pub gpa: u64,
+    pub gpa: Gpa,
     /// Host virtual base address where the region is mapped.
-    pub hva: u64,
+    pub hva: HvaAddr,
     /// Region size in bytes (page-aligned).
     pub size: u64,
     /// RDMA page offset encoded in the pseudo_mm page tables.
-    pub rdma_offset: u64,
+    pub rdma_offset: PageOffset,
+    /// Size of the pages the region is mapped with.
+    #[serde(default, skip_serializing_if = "PageSize::is_base")]
+    pub page_size: PageSize,
+    /// Ranges of the region whose pages were all zero and weren't uploaded,
+    /// sorted. They get no page table entries, so the guest faults them in
+    /// as demand-zero anonymous memory.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub zero_ranges: Vec<ZeroRange>,
+    /// Ranges of the region whose pages are not at `rdma_offset` plus their
+    /// offset, sorted: in templates layered on a base template, the pages
+    /// shared with the base and the overlay pages packed around them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extents: Vec<PgoffExtent>,
+    /// SHA-256 of the region's bytes as the memory file held them when the
+    /// template was made, for auditing attached memory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hashes: Option<RegionHashes>,
+}
+
+/// Size of the pages a region is mapped with.
+///
+/// Pgoffs count 4 KiB pages whatever the page size; a 2 MiB page maps the
+/// 512 pgoffs from an aligned one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PageSize {
+    /// 4 KiB base pages.
+    #[serde(rename = "4k")]
+    Base,
+    /// 2 MiB pages, mapped at PMD level.
+    #[serde(rename = "2m")]
+    Huge,
+}
+
+impl Default for PageSize {
+    /// Regions written before the page size was recorded use base pages.
+    fn default() -> Self {
+        PageSize::Base
+    }
+}
+
+impl PageSize {
+    /// Page size in bytes.
+    pub fn bytes(self) -> u64 {
+        match self {
+            PageSize::Base => PAGE_SIZE,
+            PageSize::Huge => 2 << 20,
+        }
+    }
+
+    /// Number of pgoffs one page spans.
+    pub fn pgoffs(self) -> u64 {
+        self.bytes() / PAGE_SIZE
+    }
+
+    /// Fails unless the pseudo_mm module can map pages of this size.
+    ///
+    /// No `setup_page_table` flag for PMD-level mappings is defined
+    /// alongside the module's other ioctl definitions, so huge regions
+    /// can't be set up yet.
+    pub fn check_supported(self) -> Result<(), String> {
+        match self {
+            PageSize::Base => Ok(()),
+            PageSize::Huge => Err(format!(
+                "{} is unsupported by the pseudo_mm module, which has no page table flag for it",
+                self
+            )),
+        }
+    }
+
+    /// `setup_page_table` flags for regions of this page size.
+    pub fn pt_flags(self) -> io::Result<u64> {
+        self.check_supported().map_err(|reason| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("page size {}", reason))
+        })?;
+        Ok(0)
+    }
+
+    /// Whether this is `PageSize::Base`, the size left out of templates.
+    pub fn is_base(&self) -> bool {
+        *self == PageSize::Base
+    }
+}
+
+impl fmt::Display for PageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PageSize::Base => write!(f, "4k"),
+            PageSize::Huge => write!(f, "2m"),
+        }
+    }
+}
+
+impl std::str::FromStr for PageSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "4k" => Ok(PageSize::Base),
+            "2m" => Ok(PageSize::Huge),
+            _ => Err(format!("invalid page size '{}' (expected 4k or 2m)", s)),
+        }
+    }
+}
+
+/// Zero-filled range of a region.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ZeroRange {
+    /// Byte offset from the start of the region (page-aligned).
+    pub offset: u64,
+    /// Range size in bytes (page-aligned).
+    pub size: u64,
+}
+
+/// Range of a region mapped from its own run of pgoffs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PgoffExtent {
+    /// Byte offset from the start of the region (page-aligned).
+    pub offset: u64,
+    /// Range size in bytes (page-aligned).
+    pub size: u64,
+    /// Page offset the range's first page is at.
+    pub rdma_offset: PageOffset,
+}
+
+/// Content hashes of a region, one per chunk.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegionHashes {
+    /// Bytes hashed together, counted from the start of the region; the
+    /// last chunk may be shorter.
+    pub chunk_size: u64,
+    /// Hex SHA-256 of every chunk, in order.
+    pub sha256: Vec<String>,
+}
+
+impl RegionMetadata {
+    /// `(offset, size)` of the parts of the region backed by the image, in
+    /// order: everything outside `zero_ranges`.
+    pub fn populated_ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        for zero in &self.zero_ranges {
+            if zero.offset > start {
+                ranges.push((start, zero.offset - start));
+            }
+            start = zero.offset + zero.size;
+        }
+        if start < self.size {
+            ranges.push((start, self.size - start));
+        }
+        ranges
+    }
+
+    /// `(offset, size, pgoff)` of the parts of the region backed by the
+    /// image, in order, split where `extents` start and end.
+    pub fn backing_ranges(&self) -> Vec<(u64, u64, PageOffset)> {
+        let at = |offset: u64| PageOffset(self.rdma_offset.raw() + offset / PAGE_SIZE);
+        let mut ranges = Vec::new();
+        for (start, size) in self.populated_ranges() {
+            let end = start + size;
+            let mut pos = start;
+            for extent in &self.extents {
+                let extent_end = extent.offset + extent.size;
+                if extent_end <= pos || extent.offset >= end {
+                    continue;
+                }
+                if extent.offset > pos {
+                    ranges.push((pos, extent.offset - pos, at(pos)));
+                    pos = extent.offset;
+                }
+                let stop = std::cmp::min(extent_end, end);
+                let pgoff = extent.rdma_offset.raw() + (pos - extent.offset) / PAGE_SIZE;
+                ranges.push((pos, stop - pos, PageOffset(pgoff)));
+                pos = stop;
+            }
+            if pos < end {
+                ranges.push((pos, end - pos, at(pos)));
+            }
+        }
+        ranges
+    }
+}
+
+/// Newest template layout version this build reads and writes.
+///
+/// - 0: templates written before the field existed. The oldest of them
+///   have no `rdma_base_pgoff` or `rdma_image_size`; `parse_template` derives
+///   both from the regions.
+/// - 1: `template_version` is recorded.
+/// - 2: regions may have `extents`, so not every pgoff lies in the
+///   template's own image.
+/// - 3: `pseudo_mm_id` may be absent; restore creates the instance from the
+///   regions, see `create_instance`.
+///
+/// Fields added without changing what existing fields mean only need
+/// `#[serde(default)]`; the version goes up when an older build would
+/// misread a newer template. Templates are written with the oldest version
+/// that describes them, see `template_version_for`.
+pub const TEMPLATE_VERSION: u32 = 3;
+
+/// Oldest layout version that describes a template with `regions` and
+/// instance `pseudo_mm_id`.
+pub fn template_version_for(regions: &[RegionMetadata], pseudo_mm_id: Option<i32>) -> u32 {
+    if pseudo_mm_id.is_none() {
+        3
+    } else if regions.iter().any(|region| !region.extents.is_empty()) {
+        2
+    } else {
+        1
+    }
 }
 
 /// Aggregate pseudo_mm metadata describing an exported snapshot.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PseudoMmTemplate {
-    /// Identifier of the pseudo_mm instance created during checkpoint.
-    pub pseudo_mm_id: i32,
+    /// Layout version, see `TEMPLATE_VERSION`; 0 in templates predating it.
+    #[serde(default)]
+    pub template_version: u32,
+    /// Identifier of the pseudo_mm instance created during checkpoint;
+    /// absent in templates built without one, whose instance restore creates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pseudo_mm_id: Option<i32>,
     /// Base host virtual address used when creating the regions.
-    pub hva_base: u64,
+    pub hva_base: HvaAddr,
     /// Base RDMA page offset used when uploading the memory snapshot.
-    pub rdma_base_pgoff: u64,
+    #[serde(default)]
+    pub rdma_base_pgoff: PageOffset,
     /// Size of the uploaded memory snapshot in bytes.
+    #[serde(default)]
     pub rdma_image_size: u64,
+    /// Runs of pgoffs the image is split across, in image order, when it
+    /// isn't stored contiguously from `rdma_base_pgoff`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rdma_image_extents: Vec<ImageExtent>,
+    /// Pgoffs reserved for the image together with the guard pages left
+    /// unmapped on either side of it, when it was created with any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rdma_guarded_range: Option<ImageExtent>,
     /// Detailed per-region metadata required for restoration.
     pub regions: Vec<RegionMetadata>,
+    /// Tenant pgoff namespace the image was allocated in, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pgoff_namespace: Option<String>,
+    /// Module features the regions were set up with (e.g. "dax").
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_features: Vec<String>,
+    /// Shape of the VM the memory was taken from; absent in old templates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vm_shape: Option<VmShape>,
+    /// Backend holding the memory image; RDMA in templates predating it.
+    #[serde(default)]
+    pub mem_backend: MemBackend,
+    /// DAX device the image was copied into, for DAX templates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dax_device: Option<String>,
+    /// Template whose image the regions' `extents` share pages with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_template: Option<String>,
+    /// Snapshot the template was made from; absent in old templates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SnapshotSource>,
+    /// When, where and by what the template was made; absent in old
+    /// templates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// Page of the image replaced by a marker naming it; absent unless the
+    /// template was made with one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker: Option<MarkerPage>,
+}
+
+/// Run of pgoffs holding part of a template's own image.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ImageExtent {
+    /// Page offset the run starts at.
+    pub pgoff: PageOffset,
+    /// Length in 4 KiB pages.
+    pub pages: u64,
+}
+
+impl PseudoMmTemplate {
+    /// The runs of pgoffs holding the template's image, in image order.
+    pub fn image_extents(&self) -> Vec<ImageExtent> {
+        if !self.rdma_image_extents.is_empty() {
+            return self.rdma_image_extents.clone();
+        }
+        vec![ImageExtent {
+            pgoff: self.rdma_base_pgoff,
+            pages: (self.rdma_image_size + PAGE_SIZE - 1) / PAGE_SIZE,
+        }]
+    }
+}
+
+/// Fields `parse_template` needs to see before trusting the rest.
+#[derive(Deserialize)]
+struct TemplateHeader {
+    #[serde(default)]
+    template_version: u32,
+    rdma_base_pgoff: Option<PageOffset>,
+    rdma_image_size: Option<u64>,
+}
+
+/// Parses a template's JSON.
+///
+/// Templates from a newer build are refused rather than half understood.
+/// Older ones are accepted, with what their layout lacks filled in.
+pub fn parse_template(json: &str) -> io::Result<PseudoMmTemplate> {
+    let invalid = |err: serde_json::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid pseudo_mm template JSON: {}", err),
+        )
+    };
+    let header: TemplateHeader = serde_json::from_str(json).map_err(invalid)?;
+    if header.template_version > TEMPLATE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "pseudo_mm template version {} is newer than the supported version {}",
+                header.template_version, TEMPLATE_VERSION
+            ),
+        ));
+    }
+    let mut template: PseudoMmTemplate = serde_json::from_str(json).map_err(invalid)?;
+    if template.pseudo_mm_id.is_none() && template.template_version < 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "pseudo_mm template version {} has no pseudo_mm_id",
+                template.template_version
+            ),
+        ));
+    }
+
+    // The image spans the regions' pgoffs in templates that didn't record it.
+    let first = template
+        .regions
+        .iter()
+        .map(|region| region.rdma_offset.raw())
+        .min();
+    let end = template
+        .regions
+        .iter()
+        .map(|region| region.rdma_offset.raw() + region.size / PAGE_SIZE)
+        .max();
+    if header.rdma_base_pgoff.is_none() {
+        template.rdma_base_pgoff = PageOffset(first.unwrap_or(0));
+    }
+    if header.rdma_image_size.is_none() {
+        let base = template.rdma_base_pgoff.raw();
+        template.rdma_image_size = end.map_or(0, |end| end.saturating_sub(base) * PAGE_SIZE);
+    }
+    Ok(template)
+}
+
+/// Reads and parses the template at `path`; see `parse_template`.
+pub fn load_template_file(path: &std::path::Path) -> io::Result<PseudoMmTemplate> {
+    parse_template(&std::fs::read_to_string(path)?)
+}
+
+/// Minimal summary of the VM a template's memory belongs to.
+///
+/// Memory restored into a VM of a different shape boots and then crashes in
+/// confusing ways, so restore compares it with the snapshot being loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VmShape {
+    /// Number of vcpus.
+    pub vcpu_count: u32,
+    /// Guest memory size in MiB.
+    pub mem_size_mib: u64,
+    /// Hash of the boot vcpu's CPUID entries, if the architecture has them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_vcpu_features: Option<u64>,
+    /// Whether vcpus have SMT siblings, if the snapshot's CPUID tells.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smt: Option<bool>,
+}
+
+impl VmShape {
+    /// Describes every field in which `self` differs from `other`.
+    pub fn differences(&self, other: &VmShape) -> Vec<String> {
+        let mut diffs = Vec::new();
+        if self.vcpu_count != other.vcpu_count {
+            diffs.push(format!(
+                "vcpu_count {} != {}",
+                self.vcpu_count, other.vcpu_count
+            ));
+        }
+        if self.mem_size_mib != other.mem_size_mib {
+            diffs.push(format!(
+                "mem_size_mib {} != {}",
+                self.mem_size_mib, other.mem_size_mib
+            ));
+        }
+        // Only comparable when both sides recorded it.
+        if let (Some(ours), Some(theirs)) = (self.boot_vcpu_features, other.boot_vcpu_features) {
+            if ours != theirs {
+                diffs.push(format!(
+                    "boot_vcpu_features 0x{:016x} != 0x{:016x}",
+                    ours, theirs
+                ));
+            }
+        }
+        if let (Some(ours), Some(theirs)) = (self.smt, other.smt) {
+            if ours != theirs {
+                diffs.push(format!("smt {} != {}", ours, theirs));
+            }
+        }
+        diffs
+    }
+}
+
+impl fmt::Display for VmShape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} vcpus, {} MiB", self.vcpu_count, self.mem_size_mib)?;
+        match self.boot_vcpu_features {
+            Some(hash) => write!(f, ", boot vcpu features 0x{:016x}", hash)?,
+            None => write!(f, ", boot vcpu features unknown")?,
+        }
+        match self.smt {
+            Some(true) => write!(f, ", SMT on"),
+            Some(false) => write!(f, ", SMT off"),
+            None => write!(f, ", SMT unknown"),
+        }
+    }
+}
+
+/// The Firecracker snapshot a template was made from.
+///
+/// Only informational: restore logs it, so a misbehaving restore can be
+/// traced to its snapshot from the template alone.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotSource {
+    /// Path of the snapshot file when the template was made.
+    pub snapshot_path: String,
+    /// Size of the snapshot file in bytes.
+    pub snapshot_size: u64,
+    /// Snapshot format version, from its header.
+    pub format_version: u16,
+    /// Snapshot data version, from its header; the state may have been
+    /// loaded as another.
+    pub data_version: u16,
+    /// Oldest Firecracker release writing `data_version`, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firecracker_version: Option<String>,
+    /// Number of vcpus in the snapshot.
+    pub vcpu_count: u32,
+    /// Total size of the guest memory regions in bytes.
+    pub guest_memory_size: u64,
+    /// Number of guest memory regions.
+    pub region_count: u32,
+}
+
+impl fmt::Display for SnapshotSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} bytes, format version {}, data version {}",
+            self.snapshot_path, self.snapshot_size, self.format_version, self.data_version
+        )?;
+        if let Some(release) = self.firecracker_version.as_ref() {
+            write!(f, ", Firecracker {}", release)?;
+        }
+        write!(
+            f,
+            "): {} vcpus, {} bytes of guest memory in {} regions",
+            self.vcpu_count, self.guest_memory_size, self.region_count
+        )
+    }
+}
+
+/// Who made a template, and when.
+///
+/// Only informational, like `SnapshotSource`; restore never rewrites it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Provenance {
+    /// Creation time, RFC 3339 in UTC.
+    pub created_at: String,
+    /// Host the template was made on.
+    pub hostname: String,
+    /// Name and crate version of the tool that made the template.
+    pub tool: String,
+    /// Batch label of the entry, or `single`.
+    pub label: String,
+    /// Arguments the tool was run with, its own path first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command_line: Vec<String>,
+    /// The files the template was made from, as they were then; absent in
+    /// old templates and those made from stdin.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sources: Option<SourceFiles>,
+}
+
+/// The snapshot and memory file a template was made from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SourceFiles {
+    /// The snapshot file.
+    pub snapshot: SourceFile,
+    /// The memory file, or its shards in order.
+    pub mem_files: Vec<SourceFile>,
+}
+
+/// A file as it was when a template was made from it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    /// Path of the file when the template was made.
+    pub path: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Modification time, in seconds since the Unix epoch.
+    pub modified: u64,
+    /// 64-bit streaming hash of the contents, in hex. Not cryptographic:
+    /// it tells a regenerated file from the one the template was made
+    /// from, not a forged one.
+    pub hash: String,
+}
+
+/// A page of a template's image holding a marker rather than guest memory.
+///
+/// The guest reads it to tell which image it was resumed on; restore maps
+/// it like any other page.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MarkerPage {
+    /// Guest address of the 4 KiB page.
+    pub gpa: Gpa,
+    /// SHA-256 of the image before the marker was written, in hex.
+    pub image_hash: String,
+    /// Creation time written in the marker, in seconds since the Unix epoch.
+    pub created_at: u64,
+    /// The bytes the marker replaced, in hex.
+    pub original: String,
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} on {} by {} ({})",
+            self.created_at, self.hostname, self.tool, self.label
+        )
+    }
+}
+
+/// Page size of pseudo_mm mappings.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// First address past the end of the user address space.
+#[cfg(target_arch = "x86_64")]
+const USER_ADDR_LIMIT: u64 = 1 << 47;
+#[cfg(not(target_arch = "x86_64"))]
+const USER_ADDR_LIMIT: u64 = 1 << 48;
+/// The same with 5-level page tables, which widen x86_64 user addresses to
+/// 56 bits.
+#[cfg(target_arch = "x86_64")]
+const USER_ADDR_LIMIT_LA57: u64 = 1 << 56;
+
+/// `user_addr_limit` once probed; 0 before.
+static HOST_USER_ADDR_LIMIT: AtomicU64 = AtomicU64::new(0);
+
+/// First address past the end of this host's user address space.
+///
+/// On x86_64 that depends on whether the kernel runs with 5-level paging,
+/// which it reports as the `la57` CPU flag; the flag is cleared when the
+/// kernel doesn't use it.
+pub fn user_addr_limit() -> u64 {
+    match HOST_USER_ADDR_LIMIT.load(Ordering::Relaxed) {
+        0 => {
+            let limit = probe_user_addr_limit();
+            HOST_USER_ADDR_LIMIT.store(limit, Ordering::Relaxed);
+            limit
+        }
+        limit => limit,
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn probe_user_addr_limit() -> u64 {
+    match std::fs::read_to_string("/proc/cpuinfo") {
+        Ok(ref cpuinfo) if has_cpu_flag(cpuinfo, "la57") => USER_ADDR_LIMIT_LA57,
+        _ => USER_ADDR_LIMIT,
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn probe_user_addr_limit() -> u64 {
+    USER_ADDR_LIMIT
+}
+
+/// Whether the `flags` of `/proc/cpuinfo` contents list `flag`.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+fn has_cpu_flag(cpuinfo: &str, flag: &str) -> bool {
+    cpuinfo
+        .lines()
+        .filter(|line| line.starts_with("flags"))
+        .any(|line| line.split_whitespace().any(|name| name == flag))
+}
+
+/// A template region that cannot safely be mapped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidRegion {
+    /// Index of the region in the template.
+    pub index: usize,
+    /// Name of the offending `RegionMetadata` field.
+    pub field: &'static str,
+    /// What is wrong with it.
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "region {}: {} {}", self.index, self.field, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidRegion {}
+
+/// Checks that region `index` can be handed to the mmap wrappers.
+pub fn validate_region(index: usize, region: &RegionMetadata) -> Result<(), InvalidRegion> {
+    let invalid = |field, reason: String| {
+        Err(InvalidRegion {
+            index,
+            field,
+            reason,
+        })
+    };
+
+    let page = region.page_size.bytes();
+    let unaligned = match region.page_size {
+        PageSize::Base => "not page aligned".to_string(),
+        size => format!("not aligned to {} pages", size),
+    };
+
+    if region.size == 0 {
+        return invalid("size", "is zero".to_string());
+    }
+    if region.size % page != 0 {
+        return invalid(
+            "size",
+            format!("0x{:x} is not a multiple of {}", region.size, page),
+        );
+    }
+    if region.hva.raw() % page != 0 {
+        return invalid("hva", format!("{} is {}", region.hva, unaligned));
+    }
+    if region.gpa.raw() % page != 0 {
+        return invalid("gpa", format!("{} is {}", region.gpa, unaligned));
+    }
+    if region.rdma_offset.raw() % region.page_size.pgoffs() != 0 {
+        return invalid(
+            "rdma_offset",
+            format!("{} is {}", region.rdma_offset, unaligned),
+        );
+    }
+    let limit = user_addr_limit();
+    match region.hva.raw().checked_add(region.size) {
+        Some(end) if end <= limit => {}
+        _ => {
+            return invalid(
+                "hva",
+                format!(
+                    "{} + 0x{:x} extends past the user address space (0x{:x})",
+                    region.hva, region.size, limit
+                ),
+            )
+        }
+    }
+    if region.gpa.raw().checked_add(region.size).is_none() {
+        return invalid(
+            "gpa",
+            format!("{} + 0x{:x} overflows", region.gpa, region.size),
+        );
+    }
+    let mut prev_end = 0;
+    for zero in &region.zero_ranges {
+        if zero.size == 0 || zero.offset % page != 0 || zero.size % page != 0 {
+            return invalid(
+                "zero_ranges",
+                format!(
+                    "entry 0x{:x}+0x{:x} is empty or {}",
+                    zero.offset, zero.size, unaligned
+                ),
+            );
+        }
+        if zero.offset < prev_end {
+            return invalid(
+                "zero_ranges",
+                format!("entry at 0x{:x} is out of order", zero.offset),
+            );
+        }
+        match zero.offset.checked_add(zero.size) {
+            Some(end) if end <= region.size => prev_end = end,
+            _ => {
+                return invalid(
+                    "zero_ranges",
+                    format!(
+                        "entry 0x{:x}+0x{:x} extends past the region",
+                        zero.offset, zero.size
+                    ),
+                )
+            }
+        }
+    }
+    let mut prev_end = 0;
+    for extent in &region.extents {
+        if extent.size == 0 || extent.offset % page != 0 || extent.size % page != 0 {
+            return invalid(
+                "extents",
+                format!(
+                    "entry 0x{:x}+0x{:x} is empty or {}",
+                    extent.offset, extent.size, unaligned
+                ),
+            );
+        }
+        if extent.rdma_offset.raw() % region.page_size.pgoffs() != 0 {
+            return invalid(
+                "extents",
+                format!(
+                    "entry at 0x{:x} has rdma_offset {}, which is {}",
+                    extent.offset, extent.rdma_offset, unaligned
+                ),
+            );
+        }
+        if extent.offset < prev_end {
+            return invalid(
+                "extents",
+                format!("entry at 0x{:x} is out of order", extent.offset),
+            );
+        }
+        match extent.offset.checked_add(extent.size) {
+            Some(end) if end <= region.size => prev_end = end,
+            _ => {
+                return invalid(
+                    "extents",
+                    format!(
+                        "entry 0x{:x}+0x{:x} extends past the region",
+                        extent.offset, extent.size
+                    ),
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks every region of a template, see `validate_region`, that the module
+/// supports its page size, and that no two of them overlap in host or guest
+/// address space.
+pub fn validate_regions(regions: &[RegionMetadata]) -> Result<(), InvalidRegion> {
+    for (index, region) in regions.iter().enumerate() {
+        region
+            .page_size
+            .check_supported()
+            .map_err(|reason| InvalidRegion {
+                index,
+                field: "page_size",
+                reason,
+            })?;
+        validate_region(index, region)?;
+    }
+    check_disjoint(regions, "hva", |region| region.hva.raw())?;
+    check_disjoint(regions, "gpa", |region| region.gpa.raw())
+}
+
+/// Fails on the first region whose `[start, start + size)` overlaps an
+/// earlier one's. The regions must have passed `validate_region`, so the
+/// ends don't overflow.
+fn check_disjoint<F>(
+    regions: &[RegionMetadata],
+    field: &'static str,
+    start: F,
+) -> Result<(), InvalidRegion>
+where
+    F: Fn(&RegionMetadata) -> u64,
+{
+    let mut order: Vec<usize> = (0..regions.len()).collect();
+    order.sort_by_key(|&index| start(&regions[index]));
+    for pair in order.windows(2) {
+        let (prev, next) = (&regions[pair[0]], &regions[pair[1]]);
+        if start(next) < start(prev) + prev.size {
+            let (first, second) = (pair[0].min(pair[1]), pair[0].max(pair[1]));
+            return Err(InvalidRegion {
+                index: second,
+                field,
+                reason: format!("overlaps region {}", first),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the template feature implied by a page table type, if any.
+pub fn feature_for_pt_type(pt_type: u32) -> Option<&'static str> {
+    match pt_type {
+        DAX_MEM => Some(FEATURE_DAX),
+        _ => None,
+    }
+}
+
+/// Features an instance with `regions` on `backend` is set up with.
+pub fn required_features_for(regions: &[RegionMetadata], backend: MemBackend) -> Vec<String> {
+    let mut features = Vec::new();
+    match feature_for_pt_type(backend.pt_type()) {
+        Some(feature) if !regions.is_empty() => features.push(feature.to_string()),
+        _ => {}
+    }
+    if regions
+        .iter()
+        .any(|region| region.page_size == PageSize::Huge)
+    {
+        features.push(FEATURE_HUGEPAGE.to_string());
+    }
+    features
+}
+
+/// Returns the optional features supported by the loaded pseudo_mm module.
+///
+/// The module has no capability query yet, so this is conservative and only
+/// reports what every module version supports: plain RDMA page tables, and
+/// DAX page tables when the host has a device-dax device to back them.
+/// Huge pages are reported once `PageSize::Huge` has a page table flag.
+pub fn probe_module_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    let has_dax_device = std::fs::read_dir(DAX_DEVICES_DIR)
+        .map(|mut devices| devices.next().is_some())
+        .unwrap_or(false);
+    if has_dax_device {
+        features.push(FEATURE_DAX);
+    }
+    if PageSize::Huge.pt_flags().is_ok() {
+        features.push(FEATURE_HUGEPAGE);
+    }
+    features
+}
+
+/// Fails if `template` requires a feature missing from `available`, including
+/// the one implied by its memory backend.
+pub fn check_required_features(template: &PseudoMmTemplate, available: &[&str]) -> io::Result<()> {
+    let backend = feature_for_pt_type(template.mem_backend.pt_type());
+    let listed = template.required_features.iter().map(String::as_str);
+    for feature in listed.chain(backend) {
+        if !available.contains(&feature) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("module lacks feature {} required by this template", feature),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Fails if the device backing a DAX template is gone.
+///
+/// The pseudo_mm instance maps its pages from the device, so restoring after
+/// the device was removed would fault on first guest access instead.
+pub fn check_backend(template: &PseudoMmTemplate) -> io::Result<()> {
+    if template.mem_backend != MemBackend::Dax {
+        return Ok(());
+    }
+    let device = template.dax_device.as_ref().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "DAX template does not name its device",
+        )
+    })?;
+    std::fs::metadata(device).map(|_| ()).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("DAX device {} backing this template: {}", device, err),
+        )
+    })
+}
+
+/// Compares the template's VM shape with the snapshot's.
+///
+/// A mismatch is logged, or returned as an error when `strict` is set.
+/// Templates without a recorded shape are let through and counted.
+pub fn check_vm_shape(
+    template: &PseudoMmTemplate,
+    snapshot: &VmShape,
+    strict: bool,
+) -> io::Result<()> {
+    let recorded = match template.vm_shape {
+        Some(ref shape) => shape,
+        None => {
+            METRICS.vmm.pseudo_mm_shape_unchecked.inc();
+            info!("pseudo_mm template has no VM shape; skipping shape check");
+            return Ok(());
+        }
+    };
+    let diffs = recorded.differences(snapshot);
+    if diffs.is_empty() {
+        return Ok(());
+    }
+
+    METRICS.vmm.pseudo_mm_shape_mismatches.inc();
+    let msg = format!(
+        "pseudo_mm template VM shape differs from snapshot: {}",
+        diffs.join(", ")
+    );
+    if strict {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    warn!("{}", msg);
+    Ok(())
 }
 
 #[repr(C)]
@@ -66,6 +1006,16 @@ struct PseudoMmAddMapParam {
     offset: i64,
 }
 
+impl fmt::Display for PseudoMmAddMapParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "id={} start=0x{:x} end=0x{:x} prot=0x{:x} flags=0x{:x} fd={} offset={}",
+            self.id, self.start, self.end, self.prot, self.flags, self.fd, self.offset
+        )
+    }
+}
+
 #[repr(C)]
 struct PseudoMmSetupPtParam {
     id: i32,
@@ -76,12 +1026,155 @@ struct PseudoMmSetupPtParam {
     flags: u64,
 }
 
+impl fmt::Display for PseudoMmSetupPtParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "id={} start=0x{:x} size=0x{:x} pgoff={} pt_type={} flags=0x{:x}",
+            self.id, self.start, self.size, self.pgoff, self.pt_type, self.flags
+        )
+    }
+}
+
 #[repr(C)]
 struct PseudoMmAttachParam {
     pid: i32,
     id: i32,
 }
 
+impl fmt::Display for PseudoMmAttachParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pid={} id={}", self.pid, self.id)
+    }
+}
+
+/// A failed pseudo_mm ioctl, with enough context to diagnose it without
+/// going through the kernel log.
+///
+/// Returned wrapped in an `io::Error` of the errno's kind, so callers can keep
+/// matching on `io::ErrorKind` and use `errno_of`.
+#[derive(Debug)]
+pub struct IoctlError {
+    /// Name of the ioctl command.
+    pub command: &'static str,
+    /// The parameters passed, as `field=value` pairs.
+    pub params: String,
+    /// errno the ioctl failed with.
+    pub errno: i32,
+    /// Caller context, such as the region being set up.
+    pub context: Option<String>,
+}
+
+impl fmt::Display for IoctlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref context) = self.context {
+            write!(f, "{}: ", context)?;
+        }
+        write!(
+            f,
+            "{}({}) failed: {}: {}",
+            self.command,
+            self.params,
+            errno_name(self.errno),
+            io::Error::from_raw_os_error(self.errno)
+        )
+    }
+}
+
+impl std::error::Error for IoctlError {}
+
+impl IoctlError {
+    fn into_io_error(self) -> io::Error {
+        io::Error::new(io::Error::from_raw_os_error(self.errno).kind(), self)
+    }
+}
+
+/// Symbolic name of the errnos the module is known to return.
+pub fn errno_name(errno: i32) -> String {
+    let name = match errno {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::ESRCH => "ESRCH",
+        libc::EINTR => "EINTR",
+        libc::EIO => "EIO",
+        libc::ENXIO => "ENXIO",
+        libc::E2BIG => "E2BIG",
+        libc::EBADF => "EBADF",
+        libc::EAGAIN => "EAGAIN",
+        libc::ENOMEM => "ENOMEM",
+        libc::EACCES => "EACCES",
+        libc::EFAULT => "EFAULT",
+        libc::EBUSY => "EBUSY",
+        libc::EEXIST => "EEXIST",
+        libc::ENODEV => "ENODEV",
+        libc::EINVAL => "EINVAL",
+        libc::ENFILE => "ENFILE",
+        libc::EMFILE => "EMFILE",
+        libc::ENOTTY => "ENOTTY",
+        libc::ENOSPC => "ENOSPC",
+        libc::ERANGE => "ERANGE",
+        libc::ENOSYS => "ENOSYS",
+        libc::EOVERFLOW => "EOVERFLOW",
+        libc::EOPNOTSUPP => "EOPNOTSUPP",
+        _ => return format!("errno {}", errno),
+    };
+    name.to_string()
+}
+
+/// Returns the errno behind `err`, looking through `IoctlError`s.
+pub fn errno_of(err: &io::Error) -> Option<i32> {
+    err.raw_os_error().or_else(|| {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<IoctlError>())
+            .map(|ioctl| ioctl.errno)
+    })
+}
+
+/// Adds caller context, such as a region index, to a pseudo_mm error.
+pub fn with_context(err: io::Error, context: String) -> io::Error {
+    let kind = err.kind();
+    let is_ioctl = err
+        .get_ref()
+        .map_or(false, |inner| inner.is::<IoctlError>());
+    if !is_ioctl {
+        return io::Error::new(kind, format!("{}: {}", context, err));
+    }
+    let mut ioctl = *err
+        .into_inner()
+        .expect("checked above")
+        .downcast::<IoctlError>()
+        .expect("checked above");
+    ioctl.context = Some(match ioctl.context.take() {
+        Some(inner) => format!("{}: {}", context, inner),
+        None => context,
+    });
+    ioctl.into_io_error()
+}
+
+/// Issues one pseudo_mm ioctl, describing any failure with an `IoctlError`.
+///
+/// # Safety
+///
+/// `arg` must be what the kernel expects for `request`.
+unsafe fn checked_ioctl<T: fmt::Display>(
+    fd: RawFd,
+    command: &'static str,
+    request: c_ulong,
+    arg: *mut T,
+) -> io::Result<()> {
+    if libc::ioctl(fd, request as IoctlRequest, arg) == 0 {
+        return Ok(());
+    }
+    let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+    Err(IoctlError {
+        command,
+        params: (*arg).to_string(),
+        errno,
+        context: None,
+    }
+    .into_io_error())
+}
+
 /// Open pseudo_mm device
 pub fn open_device() -> io::Result<File> {
     OpenOptions::new()
@@ -90,23 +1183,32 @@ pub fn open_device() -> io::Result<File> {
         .open("/dev/pseudo_mm")
 }
 
+/// Output argument of PSEUDO_MM_IOC_CREATE, which takes no input.
+#[repr(C)]
+struct PseudoMmId(i32);
+
+impl fmt::Display for PseudoMmId {
+    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        Ok(())
+    }
+}
+
 /// Create a new pseudo_mm instance
 pub fn create_pseudo_mm() -> io::Result<i32> {
     let device = open_device()?;
-    let mut pseudo_mm_id: i32 = 0;
+    let mut pseudo_mm_id = PseudoMmId(0);
 
+    // Safe because the kernel writes a single i32 id, which PseudoMmId wraps.
     unsafe {
-        let ret = libc::ioctl(
+        checked_ioctl(
             device.as_raw_fd(),
-            PSEUDO_MM_IOC_CREATE as IoctlRequest,
-            &mut pseudo_mm_id as *mut i32,
-        );
-        if ret != 0 {
-            return Err(io::Error::last_os_error());
-        }
+            "PSEUDO_MM_IOC_CREATE",
+            PSEUDO_MM_IOC_CREATE,
+            &mut pseudo_mm_id as *mut PseudoMmId,
+        )?;
     }
 
-    Ok(pseudo_mm_id)
+    Ok(pseudo_mm_id.0)
 }
 
 /// Add memory mapping to pseudo_mm
@@ -121,7 +1223,7 @@ pub fn add_memory_map(
 ) -> io::Result<()> {
     let device = open_device()?;
 
-    let param = PseudoMmAddMapParam {
+    let mut param = PseudoMmAddMapParam {
         id,
         start,
         end,
@@ -132,17 +1234,13 @@ pub fn add_memory_map(
     };
 
     unsafe {
-        let ret = libc::ioctl(
+        checked_ioctl(
             device.as_raw_fd(),
-            PSEUDO_MM_IOC_ADD_MAP as IoctlRequest,
-            &param as *const PseudoMmAddMapParam,
-        );
-        if ret != 0 {
-            return Err(io::Error::last_os_error());
-        }
+            "PSEUDO_MM_IOC_ADD_MAP",
+            PSEUDO_MM_IOC_ADD_MAP,
+            &mut param as *mut PseudoMmAddMapParam,
+        )
     }
-
-    Ok(())
 }
 
 /// Setup page table for pseudo_mm region
@@ -156,7 +1254,7 @@ pub fn setup_page_table(
 ) -> io::Result<()> {
     let device = open_device()?;
 
-    let param = PseudoMmSetupPtParam {
+    let mut param = PseudoMmSetupPtParam {
         id,
         start,
         size,
@@ -166,37 +1264,29 @@ pub fn setup_page_table(
     };
 
     unsafe {
-        let ret = libc::ioctl(
+        checked_ioctl(
             device.as_raw_fd(),
-            PSEUDO_MM_IOC_SETUP_PT as IoctlRequest,
-            &param as *const PseudoMmSetupPtParam,
-        );
-        if ret != 0 {
-            return Err(io::Error::last_os_error());
-        }
+            "PSEUDO_MM_IOC_SETUP_PT",
+            PSEUDO_MM_IOC_SETUP_PT,
+            &mut param as *mut PseudoMmSetupPtParam,
+        )
     }
-
-    Ok(())
 }
 
 /// Attach pseudo_mm to a process
 pub fn attach_to_process(pid: i32, id: i32) -> io::Result<()> {
     let device = open_device()?;
 
-    let param = PseudoMmAttachParam { pid, id };
+    let mut param = PseudoMmAttachParam { pid, id };
 
     unsafe {
-        let ret = libc::ioctl(
+        checked_ioctl(
             device.as_raw_fd(),
-            PSEUDO_MM_IOC_ATTACH as IoctlRequest,
-            &param as *const PseudoMmAttachParam,
-        );
-        if ret != 0 {
-            return Err(io::Error::last_os_error());
-        }
+            "PSEUDO_MM_IOC_ATTACH",
+            PSEUDO_MM_IOC_ATTACH,
+            &mut param as *mut PseudoMmAttachParam,
+        )
     }
-
-    Ok(())
 }
 
 /// Attach pseudo_mm to current process
@@ -205,9 +1295,218 @@ pub fn attach_to_current_process(id: i32) -> io::Result<()> {
     attach_to_process(pid, id)
 }
 
+/// Bounded retry policy for transient pseudo_mm ioctl failures.
+///
+/// The module returns EBUSY or EAGAIN while a previous instance's teardown
+/// is still in flight; a short wait is usually enough.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of tries, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry; doubled after every further failure.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// Four retries after 2, 4, 8 and 16 ms: at most 30 ms of waiting.
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            backoff: Duration::from_millis(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            backoff: Duration::from_millis(0),
+        }
+    }
+}
+
+/// Whether `err` is a transient busy state worth retrying.
+pub fn is_transient(err: &io::Error) -> bool {
+    match errno_of(err) {
+        Some(errno) => errno == libc::EBUSY || errno == libc::EAGAIN,
+        None => false,
+    }
+}
+
+/// Runs `op`, retrying transient failures according to `policy`.
+///
+/// `on_retry` is called before every retry. Non-transient errors, and the
+/// error of the last attempt, are returned unchanged.
+pub fn retry_transient<T, F, R>(policy: &RetryPolicy, op: F, on_retry: R) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+    R: FnMut(u32, &io::Error),
+{
+    retry_if(policy, is_transient, op, on_retry)
+}
+
+/// Runs `op`, retrying the failures `retryable` accepts according to
+/// `policy`.
+///
+/// Like `retry_transient`, for callers with their own error type or notion
+/// of what is worth retrying.
+pub fn retry_if<T, E, P, F, R>(
+    policy: &RetryPolicy,
+    retryable: P,
+    mut op: F,
+    mut on_retry: R,
+) -> Result<T, E>
+where
+    P: Fn(&E) -> bool,
+    F: FnMut() -> Result<T, E>,
+    R: FnMut(u32, &E),
+{
+    let mut backoff = policy.backoff;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(ref err) if retryable(err) && attempt < policy.attempts => {
+                on_retry(attempt, err);
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Maps every region in the pseudo_mm instance `id` and points its page
+/// tables at the image on `backend`.
+///
+/// Regions are private mappings, anonymous for RDMA and of `dax_device` for
+/// DAX, so guest writes stay copy-on-write. Zero ranges get no entries and
+/// fault in as anonymous zero pages. `before_region` runs before each region
+/// and stops the setup with its error; other errors name the region.
+pub fn setup_regions(
+    id: i32,
+    regions: &[RegionMetadata],
+    backend: MemBackend,
+    dax_device: Option<&File>,
+    before_region: &mut dyn FnMut(usize, &RegionMetadata) -> io::Result<()>,
+) -> io::Result<()> {
+    let map_flags = match dax_device {
+        Some(_) => libc::MAP_PRIVATE | libc::MAP_FIXED,
+        None => libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+    };
+    let map_fd = dax_device.map_or(-1, AsRawFd::as_raw_fd);
+    let pt_type = backend.pt_type();
+    for (idx, region) in regions.iter().enumerate() {
+        before_region(idx, region)?;
+        let context = || format!("region {}/{}", idx + 1, regions.len());
+        let pt_flags = region
+            .page_size
+            .pt_flags()
+            .map_err(|err| with_context(err, context()))?;
+        let map_offset = match dax_device {
+            Some(_) => (region.rdma_offset.raw() * PAGE_SIZE) as i64,
+            None => 0,
+        };
+        add_memory_map(
+            id,
+            region.hva.raw(),
+            region.hva.raw() + region.size,
+            (libc::PROT_READ | libc::PROT_WRITE) as u64,
+            map_flags as u64,
+            map_fd,
+            map_offset,
+        )
+        .map_err(|err| with_context(err, context()))?;
+        for (offset, size, pgoff) in region.backing_ranges() {
+            setup_page_table(
+                id,
+                region.hva.raw() + offset,
+                size,
+                pgoff.raw(),
+                pt_type,
+                pt_flags,
+            )
+            .map_err(|err| with_context(err, context()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates the pseudo_mm instance of a template written without one and
+/// sets up its regions, returning the new id.
+///
+/// The module can't release an instance, so one whose setup fails is left
+/// behind, with a warning naming it. The regions are validated before the
+/// instance is created, so a malformed template leaves nothing behind.
+pub fn create_instance(template: &PseudoMmTemplate, policy: &RetryPolicy) -> io::Result<i32> {
+    validate_regions(&template.regions)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let dax_device = match template.mem_backend {
+        MemBackend::Dax => {
+            let path = template.dax_device.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "DAX template does not name its device",
+                )
+            })?;
+            let device = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .map_err(|err| {
+                    io::Error::new(err.kind(), format!("DAX device {}: {}", path, err))
+                })?;
+            Some(device)
+        }
+        MemBackend::Rdma => None,
+    };
+    let id = create_pseudo_mm_with_retry(policy)?;
+    let setup = setup_regions(
+        id,
+        &template.regions,
+        template.mem_backend,
+        dax_device.as_ref(),
+        &mut |_, _| Ok(()),
+    );
+    if let Err(err) = setup {
+        warn!("pseudo_mm id={} is left behind after its setup failed", id);
+        return Err(err);
+    }
+    Ok(id)
+}
+
+/// Create a new pseudo_mm instance, retrying transient busy states
+pub fn create_pseudo_mm_with_retry(policy: &RetryPolicy) -> io::Result<i32> {
+    retry_transient(policy, create_pseudo_mm, |attempt, err| {
+        METRICS.vmm.pseudo_mm_create_retries.inc();
+        warn!(
+            "pseudo_mm create attempt {}/{} failed ({}), retrying",
+            attempt, policy.attempts, err
+        );
+    })
+}
+
+/// Attach pseudo_mm to current process, retrying transient busy states
+pub fn attach_to_current_process_with_retry(id: i32, policy: &RetryPolicy) -> io::Result<()> {
+    retry_transient(
+        policy,
+        || attach_to_current_process(id),
+        |attempt, err| {
+            METRICS.vmm.pseudo_mm_attach_retries.inc();
+            warn!(
+                "pseudo_mm attach of id={} attempt {}/{} failed ({}), retrying",
+                id, attempt, policy.attempts, err
+            );
+        },
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pseudo_mm_test_utils::{self, region};
 
     #[test]
     #[ignore] // Requires /dev/pseudo_mm device
@@ -217,4 +1516,654 @@ mod tests {
         let id = result.unwrap();
         assert!(id > 0);
     }
+
+    fn template_with_features(features: &[&str]) -> PseudoMmTemplate {
+        let mut template = pseudo_mm_test_utils::template();
+        template.required_features = features.iter().map(|f| f.to_string()).collect();
+        template
+    }
+
+    #[test]
+    fn test_check_required_features() {
+        assert!(check_required_features(&template_with_features(&[]), &[]).is_ok());
+        assert!(
+            check_required_features(&template_with_features(&[FEATURE_DAX]), &[FEATURE_DAX])
+                .is_ok()
+        );
+
+        let err = check_required_features(
+            &template_with_features(&[FEATURE_DAX, FEATURE_HUGEPAGE]),
+            &[FEATURE_DAX],
+        )
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            err,
+            "module lacks feature hugepage required by this template"
+        );
+
+        // The backend's feature is required even when not listed.
+        let mut dax = template_with_features(&[]);
+        dax.mem_backend = MemBackend::Dax;
+        assert!(check_required_features(&dax, &[]).is_err());
+        assert!(check_required_features(&dax, &[FEATURE_DAX]).is_ok());
+
+        assert_eq!(feature_for_pt_type(DAX_MEM), Some(FEATURE_DAX));
+        assert_eq!(feature_for_pt_type(RDMA_MEM), None);
+    }
+
+    #[test]
+    fn test_required_features_for() {
+        let base = region(0, 0x7000_0000_0000, 2 << 20);
+        let mut huge = base.clone();
+        huge.page_size = PageSize::Huge;
+
+        assert!(required_features_for(&[], MemBackend::Dax).is_empty());
+        assert!(required_features_for(&[base.clone()], MemBackend::Rdma).is_empty());
+        assert_eq!(
+            required_features_for(&[base.clone()], MemBackend::Dax),
+            vec![FEATURE_DAX.to_string()]
+        );
+        assert_eq!(
+            required_features_for(&[base, huge], MemBackend::Dax),
+            vec![FEATURE_DAX.to_string(), FEATURE_HUGEPAGE.to_string()]
+        );
+
+        // Without a page table flag for them, huge regions are refused on
+        // restore instead of being set up with the wrong one.
+        assert!(PageSize::Huge.pt_flags().is_err());
+        assert!(!probe_module_features().contains(&FEATURE_HUGEPAGE));
+    }
+
+    #[test]
+    fn test_check_backend() {
+        let mut template = template_with_features(&[]);
+        assert!(check_backend(&template).is_ok());
+
+        template.mem_backend = MemBackend::Dax;
+        let err = check_backend(&template).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        template.dax_device = Some("/dev/null".to_string());
+        assert!(check_backend(&template).is_ok());
+        template.dax_device = Some("/dev/no-such-dax0.0".to_string());
+        let err = check_backend(&template).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("/dev/no-such-dax0.0"), "{}", err);
+    }
+
+    /// Operation that fails its first `failures` calls with `errno`.
+    fn flaky(failures: u32, errno: i32) -> impl FnMut() -> io::Result<u32> {
+        let mut calls = 0;
+        move || {
+            calls += 1;
+            if calls <= failures {
+                Err(io::Error::from_raw_os_error(errno))
+            } else {
+                Ok(calls)
+            }
+        }
+    }
+
+    fn fast_policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            backoff: Duration::from_millis(0),
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_recovers() {
+        for &errno in &[libc::EBUSY, libc::EAGAIN] {
+            let mut retries = Vec::new();
+            let result = retry_transient(&fast_policy(5), flaky(3, errno), |attempt, err| {
+                assert_eq!(err.raw_os_error(), Some(errno));
+                retries.push(attempt);
+            });
+            assert_eq!(result.unwrap(), 4);
+            assert_eq!(retries, vec![1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_gives_up() {
+        let mut retries = 0;
+        let err = retry_transient(&fast_policy(3), flaky(10, libc::EBUSY), |_, _| retries += 1)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+        assert_eq!(retries, 2);
+
+        let mut retries = 0;
+        let err = retry_transient(&RetryPolicy::none(), flaky(1, libc::EAGAIN), |_, _| {
+            retries += 1
+        })
+        .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+        assert_eq!(retries, 0);
+    }
+
+    #[test]
+    fn test_retry_transient_skips_permanent_errors() {
+        for &errno in &[libc::EINVAL, libc::ENOENT, libc::EPERM, libc::ENOMEM] {
+            let mut retries = 0;
+            let err =
+                retry_transient(&fast_policy(5), flaky(1, errno), |_, _| retries += 1).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(errno));
+            assert_eq!(retries, 0);
+        }
+        let custom = io::Error::new(io::ErrorKind::Other, "not an errno");
+        assert!(!is_transient(&custom));
+    }
+
+    #[test]
+    fn test_default_policy_bounded_wait() {
+        let policy = RetryPolicy::default();
+        let total: Duration = (0..policy.attempts - 1)
+            .map(|retry| policy.backoff * 2u32.pow(retry))
+            .sum();
+        assert!(total <= Duration::from_millis(50), "{:?}", total);
+    }
+
+    #[test]
+    fn test_ioctl_error_context() {
+        // /dev/null rejects every ioctl, standing in for a failing module.
+        let device = File::open("/dev/null").unwrap();
+        let mut param = PseudoMmSetupPtParam {
+            id: 3,
+            start: 0x7000_0000_0000,
+            size: 0x20_0000,
+            pgoff: 512,
+            pt_type: RDMA_MEM,
+            flags: 0,
+        };
+        let err = unsafe {
+            checked_ioctl(
+                device.as_raw_fd(),
+                "PSEUDO_MM_IOC_SETUP_PT",
+                PSEUDO_MM_IOC_SETUP_PT,
+                &mut param as *mut PseudoMmSetupPtParam,
+            )
+        }
+        .unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::ENOTTY));
+
+        let err = with_context(err, "region 5/9".to_string());
+        let msg = err.to_string();
+        for expected in &[
+            "region 5/9: ",
+            "PSEUDO_MM_IOC_SETUP_PT(",
+            "id=3 start=0x700000000000 size=0x200000 pgoff=512 pt_type=1 flags=0x0",
+            "ENOTTY",
+        ] {
+            assert!(msg.contains(expected), "missing {:?} in {}", expected, msg);
+        }
+        assert_eq!(errno_of(&err), Some(libc::ENOTTY));
+    }
+
+    #[test]
+    fn test_ioctl_error_rendering() {
+        let err = IoctlError {
+            command: "PSEUDO_MM_IOC_ADD_MAP",
+            params: "id=1".to_string(),
+            errno: libc::EBUSY,
+            context: None,
+        }
+        .into_io_error();
+        assert!(is_transient(&err));
+        assert_eq!(
+            err.to_string(),
+            "PSEUDO_MM_IOC_ADD_MAP(id=1) failed: EBUSY: Device or resource busy (os error 16)"
+        );
+
+        assert_eq!(errno_name(libc::EINVAL), "EINVAL");
+        assert_eq!(errno_name(4095), "errno 4095");
+
+        // Errors without an IoctlError inside still get the context.
+        let plain = with_context(io::Error::from_raw_os_error(libc::ENOENT), "open".into());
+        assert!(plain.to_string().starts_with("open: "), "{}", plain);
+    }
+
+    fn shape(vcpu_count: u32, boot_vcpu_features: Option<u64>) -> VmShape {
+        VmShape {
+            vcpu_count,
+            mem_size_mib: 512,
+            boot_vcpu_features,
+            smt: Some(true),
+        }
+    }
+
+    #[test]
+    fn test_vm_shape_differences() {
+        assert!(shape(2, Some(7)).differences(&shape(2, Some(7))).is_empty());
+        // A side without a features hash only compares the other fields.
+        assert!(shape(2, None).differences(&shape(2, Some(7))).is_empty());
+        let mut unknown_smt = shape(2, Some(7));
+        unknown_smt.smt = None;
+        assert!(shape(2, Some(7)).differences(&unknown_smt).is_empty());
+
+        let mut other = shape(4, Some(8));
+        other.mem_size_mib = 1024;
+        other.smt = Some(false);
+        assert_eq!(
+            shape(2, Some(7)).differences(&other),
+            vec![
+                "vcpu_count 2 != 4".to_string(),
+                "mem_size_mib 512 != 1024".to_string(),
+                "boot_vcpu_features 0x0000000000000007 != 0x0000000000000008".to_string(),
+                "smt true != false".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_vm_shape() {
+        let mut template = template_with_features(&[]);
+        let unchecked = METRICS.vmm.pseudo_mm_shape_unchecked.count();
+        assert!(check_vm_shape(&template, &shape(2, None), true).is_ok());
+        assert_eq!(METRICS.vmm.pseudo_mm_shape_unchecked.count(), unchecked + 1);
+
+        template.vm_shape = Some(shape(2, Some(7)));
+        assert!(check_vm_shape(&template, &shape(2, Some(7)), true).is_ok());
+
+        let mismatches = METRICS.vmm.pseudo_mm_shape_mismatches.count();
+        // Lenient mode only warns.
+        assert!(check_vm_shape(&template, &shape(4, Some(7)), false).is_ok());
+        let err = check_vm_shape(&template, &shape(4, Some(7)), true).unwrap_err();
+        assert!(err.to_string().contains("vcpu_count 2 != 4"), "{}", err);
+        // SMT follows the same rule as the other fields.
+        let mut no_smt = shape(2, Some(7));
+        no_smt.smt = Some(false);
+        assert!(check_vm_shape(&template, &no_smt, false).is_ok());
+        let err = check_vm_shape(&template, &no_smt, true).unwrap_err();
+        assert!(err.to_string().contains("smt true != false"), "{}", err);
+        assert_eq!(
+            METRICS.vmm.pseudo_mm_shape_mismatches.count(),
+            mismatches + 4
+        );
+    }
+
+    fn zero(offset: u64, size: u64) -> ZeroRange {
+        ZeroRange { offset, size }
+    }
+
+    fn extent(offset: u64, size: u64, rdma_offset: u64) -> PgoffExtent {
+        PgoffExtent {
+            offset,
+            size,
+            rdma_offset: PageOffset(rdma_offset),
+        }
+    }
+
+    #[test]
+    fn test_populated_ranges() {
+        let mut region = region(0, 0x7000_0000_0000, 8 * PAGE_SIZE);
+        assert_eq!(region.populated_ranges(), vec![(0, 8 * PAGE_SIZE)]);
+
+        region.zero_ranges = vec![zero(0, PAGE_SIZE), zero(3 * PAGE_SIZE, 2 * PAGE_SIZE)];
+        assert_eq!(
+            region.populated_ranges(),
+            vec![(PAGE_SIZE, 2 * PAGE_SIZE), (5 * PAGE_SIZE, 3 * PAGE_SIZE)]
+        );
+
+        region.zero_ranges = vec![zero(0, 8 * PAGE_SIZE)];
+        assert!(region.populated_ranges().is_empty());
+    }
+
+    #[test]
+    fn test_backing_ranges() {
+        let mut region = region(0, 0x7000_0000_0000, 8 * PAGE_SIZE);
+        region.rdma_offset = PageOffset(100);
+        assert_eq!(
+            region.backing_ranges(),
+            vec![(0, 8 * PAGE_SIZE, PageOffset(100))]
+        );
+
+        // Pages 2-4 come from a base image; page 4 is zero.
+        region.extents = vec![extent(2 * PAGE_SIZE, 3 * PAGE_SIZE, 7000)];
+        region.zero_ranges = vec![zero(4 * PAGE_SIZE, PAGE_SIZE)];
+        assert_eq!(
+            region.backing_ranges(),
+            vec![
+                (0, 2 * PAGE_SIZE, PageOffset(100)),
+                (2 * PAGE_SIZE, 2 * PAGE_SIZE, PageOffset(7000)),
+                (5 * PAGE_SIZE, 3 * PAGE_SIZE, PageOffset(105)),
+            ]
+        );
+        assert!(validate_region(0, &region).is_ok());
+        assert_eq!(template_version_for(&[region.clone()], Some(1)), 2);
+        assert_eq!(template_version_for(&[region.clone()], None), 3);
+        region.extents.clear();
+        assert_eq!(template_version_for(&[region], Some(1)), 1);
+    }
+
+    #[test]
+    fn test_validate_extents() {
+        let mut base_region = region(0, 0x7000_0000_0000, 4 * PAGE_SIZE);
+        for extents in &[
+            vec![extent(0, 0, 0)],
+            vec![extent(100, PAGE_SIZE, 0)],
+            vec![extent(2 * PAGE_SIZE, PAGE_SIZE, 0), extent(0, PAGE_SIZE, 0)],
+            vec![extent(3 * PAGE_SIZE, 2 * PAGE_SIZE, 0)],
+        ] {
+            base_region.extents = extents.clone();
+            let err = validate_region(0, &base_region).unwrap_err();
+            assert_eq!(err.field, "extents", "{:?}", extents);
+        }
+
+        // Huge page extents start on a huge page of the image too.
+        let huge = PageSize::Huge.bytes();
+        let mut huge_region = region(0, 0x7000_0000_0000, 2 * huge);
+        huge_region.page_size = PageSize::Huge;
+        huge_region.extents = vec![extent(huge, huge, 1024)];
+        assert!(validate_region(0, &huge_region).is_ok());
+        huge_region.extents = vec![extent(huge, huge, 1000)];
+        let err = validate_region(0, &huge_region).unwrap_err();
+        assert!(err.to_string().contains("2m pages"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_zero_ranges() {
+        let mut region = region(0, 0x7000_0000_0000, 4 * PAGE_SIZE);
+        region.zero_ranges = vec![zero(0, PAGE_SIZE), zero(2 * PAGE_SIZE, 2 * PAGE_SIZE)];
+        assert!(validate_region(0, &region).is_ok());
+
+        for ranges in &[
+            vec![zero(0, 0)],
+            vec![zero(100, PAGE_SIZE)],
+            vec![zero(0, PAGE_SIZE + 1)],
+            vec![zero(2 * PAGE_SIZE, PAGE_SIZE), zero(0, PAGE_SIZE)],
+            vec![zero(0, 2 * PAGE_SIZE), zero(PAGE_SIZE, PAGE_SIZE)],
+            vec![zero(3 * PAGE_SIZE, 2 * PAGE_SIZE)],
+            vec![zero(PAGE_SIZE, u64::MAX - PAGE_SIZE + 1)],
+        ] {
+            region.zero_ranges = ranges.clone();
+            let err = validate_region(0, &region).unwrap_err();
+            assert_eq!(err.field, "zero_ranges", "{:?}", ranges);
+        }
+    }
+
+    #[test]
+    fn test_validate_huge_region() {
+        let huge = PageSize::Huge.bytes();
+        let mut region = region(0, 0x7000_0000_0000, 2 * huge);
+        region.page_size = PageSize::Huge;
+        region.rdma_offset = PageOffset(512);
+        region.zero_ranges = vec![zero(huge, huge)];
+        assert!(validate_region(0, &region).is_ok());
+
+        // Aligned to base pages only.
+        let with = |change: fn(&mut RegionMetadata)| {
+            let mut broken = region.clone();
+            change(&mut broken);
+            broken
+        };
+        let cases = [
+            (with(|r| r.size += PAGE_SIZE), "size"),
+            (with(|r| r.hva = HvaAddr(r.hva.raw() + PAGE_SIZE)), "hva"),
+            (with(|r| r.gpa = Gpa(PAGE_SIZE)), "gpa"),
+            (with(|r| r.rdma_offset = PageOffset(513)), "rdma_offset"),
+            (
+                with(|r| r.zero_ranges = vec![zero(0, PAGE_SIZE)]),
+                "zero_ranges",
+            ),
+        ];
+        for (broken, field) in cases.iter() {
+            let err = validate_region(2, broken).unwrap_err();
+            assert_eq!(err.field, *field);
+            if *field != "size" {
+                assert!(err.to_string().contains("2m pages"), "{}", err);
+            }
+        }
+        assert_eq!("2m".parse::<PageSize>(), Ok(PageSize::Huge));
+        assert!("1g".parse::<PageSize>().is_err());
+
+        // Well-formed, but the module can't map it.
+        let err = validate_regions(&[region]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "region 0: page_size 2m is unsupported by the pseudo_mm module, which has no page \
+             table flag for it"
+        );
+    }
+
+    #[test]
+    fn test_has_cpu_flag() {
+        let cpuinfo = "processor\t: 0\nflags\t\t: fpu vme la57 pku\n\nprocessor\t: 1\n";
+        assert!(has_cpu_flag(cpuinfo, "la57"));
+        assert!(!has_cpu_flag(cpuinfo, "la5"));
+        assert!(!has_cpu_flag("model name\t: la57\n", "la57"));
+        assert!(user_addr_limit() >= USER_ADDR_LIMIT);
+    }
+
+    #[test]
+    fn test_validate_region() {
+        assert!(validate_region(0, &region(0, 0x7000_0000_0000, PAGE_SIZE)).is_ok());
+        let limit = user_addr_limit();
+        assert!(validate_region(0, &region(0, limit - PAGE_SIZE, PAGE_SIZE)).is_ok());
+
+        let cases = [
+            (region(0, 0x7000_0000_0000, 0), "size"),
+            (region(0, 0x7000_0000_0000, 100), "size"),
+            (region(0, 0x7000_0000_0010, PAGE_SIZE), "hva"),
+            (region(0x10, 0x7000_0000_0000, PAGE_SIZE), "gpa"),
+            (region(0, limit, PAGE_SIZE), "hva"),
+            (region(0, u64::MAX - PAGE_SIZE + 1, PAGE_SIZE), "hva"),
+            (
+                region(u64::MAX - PAGE_SIZE + 1, 0x7000_0000_0000, 2 * PAGE_SIZE),
+                "gpa",
+            ),
+        ];
+        for (idx, (region, field)) in cases.iter().enumerate() {
+            let err = validate_region(idx, region).unwrap_err();
+            assert_eq!(err.index, idx);
+            assert_eq!(&err.field, field, "{}", err);
+        }
+
+        let regions = vec![
+            region(0, 0x7000_0000_0000, PAGE_SIZE),
+            region(PAGE_SIZE, 0x7000_0000_1001, PAGE_SIZE),
+        ];
+        let err = validate_regions(&regions).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "region 1: hva 0x700000001001 is not page aligned"
+        );
+
+        // Overlaps are found whatever the regions' order.
+        let regions = vec![
+            region(0x20_0000, 0x7000_0000_4000, PAGE_SIZE),
+            region(0, 0x7000_0000_0000, PAGE_SIZE),
+            region(0x10_0000, 0x7000_0000_3000, 2 * PAGE_SIZE),
+        ];
+        let err = validate_regions(&regions).unwrap_err();
+        assert_eq!(err.to_string(), "region 2: hva overlaps region 0");
+        let regions = vec![
+            region(0, 0x7000_0000_0000, 2 * PAGE_SIZE),
+            region(PAGE_SIZE, 0x7000_0010_0000, PAGE_SIZE),
+        ];
+        let err = validate_regions(&regions).unwrap_err();
+        assert_eq!(err.to_string(), "region 1: gpa overlaps region 0");
+        let adjacent = vec![
+            region(0, 0x7000_0000_0000, PAGE_SIZE),
+            region(PAGE_SIZE, 0x7000_0000_1000, PAGE_SIZE),
+        ];
+        assert!(validate_regions(&adjacent).is_ok());
+    }
+
+    #[test]
+    fn test_create_instance_validates_regions() {
+        let mut template = template_with_features(&[]);
+        template.pseudo_mm_id = None;
+        template.regions = vec![
+            region(0, 0x7000_0000_0000, 2 * PAGE_SIZE),
+            region(0x10_0000, 0x7000_0000_1000, PAGE_SIZE),
+        ];
+        // Refused before /dev/pseudo_mm is opened, so no instance exists.
+        let err = create_instance(&template, &RetryPolicy::none()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "region 1: hva overlaps region 0");
+    }
+
+    #[test]
+    fn test_parse_historical_templates() {
+        // The first layout: no base pgoff or image size, plain numbers.
+        let oldest = r#"{
+            "pseudo_mm_id": 2,
+            "hva_base": 123145302310912,
+            "regions": [
+                { "gpa": 1048576, "hva": 123145303359488, "size": 8192, "rdma_offset": 260 },
+                { "gpa": 0, "hva": 123145302310912, "size": 16384, "rdma_offset": 256 }
+            ]
+        }"#;
+        let template = parse_template(oldest).unwrap();
+        assert_eq!(template.template_version, 0);
+        assert_eq!(template.rdma_base_pgoff, PageOffset(256));
+        assert_eq!(template.rdma_image_size, 6 * PAGE_SIZE);
+        assert_eq!(template.mem_backend, MemBackend::Rdma);
+        assert!(template.vm_shape.is_none());
+
+        // Later unversioned templates: string addresses, features, shape.
+        let unversioned = r#"{
+            "pseudo_mm_id": 5,
+            "hva_base": "0x700000000000",
+            "rdma_base_pgoff": "0x400",
+            "rdma_image_size": 16384,
+            "regions": [
+                { "gpa": "0x0", "hva": "0x700000000000", "size": 16384, "rdma_offset": "0x400" }
+            ],
+            "required_features": ["dax"],
+            "vm_shape": { "vcpu_count": 2, "mem_size_mib": 128 }
+        }"#;
+        let template = parse_template(unversioned).unwrap();
+        assert_eq!(template.template_version, 0);
+        assert_eq!(template.rdma_base_pgoff, PageOffset(0x400));
+        assert_eq!(template.rdma_image_size, 16384);
+        assert_eq!(template.required_features, vec![FEATURE_DAX.to_string()]);
+
+        // What this build writes reads back the same.
+        let mut current = template_with_features(&[FEATURE_DAX]);
+        current
+            .regions
+            .push(region(0, 0x7000_0000_0000, 2 * PAGE_SIZE));
+        current.rdma_image_size = 2 * PAGE_SIZE;
+        let json = serde_json::to_string(&current).unwrap();
+        assert!(json.contains(&format!("\"template_version\":{}", TEMPLATE_VERSION)));
+        let parsed = parse_template(&json).unwrap();
+        assert_eq!(parsed.template_version, TEMPLATE_VERSION);
+        assert_eq!(parsed.rdma_image_size, 2 * PAGE_SIZE);
+        assert_eq!(parsed.required_features, current.required_features);
+    }
+
+    #[test]
+    fn test_parse_rejects_newer_templates() {
+        let json = format!(
+            r#"{{ "template_version": {}, "pseudo_mm_id": 1, "hva_base": 0, "regions": [] }}"#,
+            TEMPLATE_VERSION + 1
+        );
+        let err = parse_template(&json).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            err.to_string().contains("newer than the supported"),
+            "{}",
+            err
+        );
+        assert!(parse_template("{").is_err());
+    }
+
+    #[test]
+    fn test_parse_templates_without_instance() {
+        let mut template = template_with_features(&[]);
+        template.pseudo_mm_id = None;
+        let json = serde_json::to_string(&template).unwrap();
+        assert!(!json.contains("pseudo_mm_id"), "{}", json);
+        assert_eq!(parse_template(&json).unwrap().pseudo_mm_id, None);
+
+        // Older layouts always named their instance.
+        let err = parse_template(r#"{ "template_version": 2, "hva_base": 0, "regions": [] }"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("has no pseudo_mm_id"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_template_source() {
+        let mut template = template_with_features(&[]);
+        let json = serde_json::to_string(&template).unwrap();
+        assert!(!json.contains("source"), "{}", json);
+        assert_eq!(parse_template(&json).unwrap().source, None);
+
+        let source = SnapshotSource {
+            snapshot_path: "/srv/snap/fn-a.snap".to_string(),
+            snapshot_size: 40960,
+            format_version: 1,
+            data_version: 3,
+            firecracker_version: Some("v1.0.0".to_string()),
+            vcpu_count: 2,
+            guest_memory_size: 256 << 20,
+            region_count: 1,
+        };
+        template.source = Some(source.clone());
+        let json = serde_json::to_string(&template).unwrap();
+        assert_eq!(parse_template(&json).unwrap().source, Some(source.clone()));
+        template.provenance = Some(Provenance {
+            created_at: "2026-10-14T08:30:00Z".to_string(),
+            hostname: "node-7".to_string(),
+            tool: "pseudo_mm_template_creator 0.1.0".to_string(),
+            label: "fn-a".to_string(),
+            command_line: vec!["pseudo_mm_template_creator".to_string()],
+            sources: Some(SourceFiles {
+                snapshot: SourceFile {
+                    path: "/srv/snap/fn-a.snap".to_string(),
+                    size: 40960,
+                    modified: 1_791_974_400,
+                    hash: "9e3779b97f4a7c15".to_string(),
+                },
+                mem_files: Vec::new(),
+            }),
+        });
+        let json = serde_json::to_string(&template).unwrap();
+        let parsed = parse_template(&json).unwrap();
+        assert_eq!(parsed.provenance, template.provenance);
+        assert_eq!(
+            parsed.provenance.unwrap().to_string(),
+            "2026-10-14T08:30:00Z on node-7 by pseudo_mm_template_creator 0.1.0 (fn-a)"
+        );
+        assert_eq!(
+            source.to_string(),
+            "/srv/snap/fn-a.snap (40960 bytes, format version 1, data version 3, \
+             Firecracker v1.0.0): 2 vcpus, 268435456 bytes of guest memory in 1 regions"
+        );
+    }
+
+    #[test]
+    fn test_template_address_forms() {
+        // Templates written before the address newtypes used plain numbers.
+        let old = r#"{
+            "pseudo_mm_id": 3,
+            "hva_base": 123145302310912,
+            "rdma_base_pgoff": 512,
+            "rdma_image_size": 8192,
+            "regions": [
+                { "gpa": 0, "hva": 123145302310912, "size": 8192, "rdma_offset": 512 }
+            ]
+        }"#;
+        let new = r#"{
+            "pseudo_mm_id": 3,
+            "hva_base": "0x700000000000",
+            "rdma_base_pgoff": 512,
+            "rdma_image_size": 8192,
+            "regions": [
+                { "gpa": "0x0", "hva": "0x700000000000", "size": 8192, "rdma_offset": 512 }
+            ]
+        }"#;
+
+        for json in &[old, new] {
+            let template: PseudoMmTemplate = serde_json::from_str(json).unwrap();
+            assert_eq!(template.hva_base, HvaAddr(0x7000_0000_0000));
+            assert_eq!(template.rdma_base_pgoff, PageOffset(512));
+            assert_eq!(template.regions[0].gpa, Gpa(0));
+            assert_eq!(template.regions[0].hva, HvaAddr(0x7000_0000_0000));
+            assert_eq!(template.regions[0].rdma_offset, PageOffset(512));
+            assert!(template.required_features.is_empty());
+        }
+    }
 }
@@ -0,0 +1,69 @@
+//! Templates and regions for the pseudo_mm unit tests.
+//!
+//! Tests start from these and set only the fields they are about, so a
+//! field added to `PseudoMmTemplate` or `RegionMetadata` is filled in once,
+//! here. `XorShift64` generates inputs that are the same from run to run.
+
+use crate::pseudo_mm_addr::{Gpa, HvaAddr, PageOffset};
+use crate::pseudo_mm_support::{
+    MemBackend, PageSize, PseudoMmTemplate, RegionMetadata, TEMPLATE_VERSION,
+};
+
+/// A current-version RDMA template of instance 1 at the default base,
+/// without regions or any of the optional records.
+pub fn template() -> PseudoMmTemplate {
+    PseudoMmTemplate {
+        template_version: TEMPLATE_VERSION,
+        pseudo_mm_id: Some(1),
+        hva_base: HvaAddr(0x7000_0000_0000),
+        rdma_base_pgoff: PageOffset(0),
+        rdma_image_size: 0,
+        rdma_image_extents: Vec::new(),
+        rdma_guarded_range: None,
+        regions: Vec::new(),
+        pgoff_namespace: None,
+        required_features: Vec::new(),
+        vm_shape: None,
+        mem_backend: MemBackend::Rdma,
+        dax_device: None,
+        base_template: None,
+        source: None,
+        provenance: None,
+        marker: None,
+    }
+}
+
+/// A base page region of `size` bytes at `gpa` and `hva`, with its image at
+/// pgoff 0.
+pub fn region(gpa: u64, hva: u64, size: u64) -> RegionMetadata {
+    RegionMetadata {
+        gpa: Gpa(gpa),
+        hva: HvaAddr(hva),
+        size,
+        rdma_offset: PageOffset(0),
+        page_size: PageSize::Base,
+        zero_ranges: Vec::new(),
+        extents: Vec::new(),
+        content_hashes: None,
+    }
+}
+
+/// xorshift64, for generated inputs that must be the same from run to run
+/// without a dependency on a random number crate.
+#[derive(Clone, Debug)]
+pub struct XorShift64(u64);
+
+impl XorShift64 {
+    /// Starts from `seed`, made odd since xorshift never leaves zero.
+    pub fn new(seed: u64) -> Self {
+        XorShift64(seed | 1)
+    }
+
+    /// The next number of the sequence.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
@@ -71,6 +71,45 @@ pub struct LoadSnapshotParams {
     /// Optional path to pseudo_mm template for RDMA-backed fast restore
     #[serde(default)]
     pub pseudo_mm_template_path: PathBuf,
+    /// Skip the pseudo_mm restore, rather than only warning, when the
+    /// template was taken from a differently shaped VM
+    #[serde(default)]
+    pub pseudo_mm_strict_shape: bool,
+    /// Optional NUMA placement for the pseudo_mm restored memory
+    #[serde(default)]
+    pub pseudo_mm_numa: Option<PseudoMmNumaConfig>,
+}
+
+/// NUMA placement requested for pseudo_mm restored memory.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PseudoMmNumaConfig {
+    /// Node list such as `0,2-3`, or `auto` for the nodes of the CPUs the
+    /// VMM may run on.
+    pub nodes: String,
+    /// Whether pages must stay on the nodes or only prefer them.
+    #[serde(default)]
+    pub mode: PseudoMmNumaMode,
+    /// Skip the pseudo_mm restore, rather than only warning, when the policy
+    /// can't be applied
+    #[serde(default)]
+    pub strict: bool,
+}
+
+/// NUMA policy modes for pseudo_mm restored memory.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PseudoMmNumaMode {
+    /// Prefer the first node, falling back to any other.
+    Preferred,
+    /// Only allocate on the listed nodes.
+    Bind,
+}
+
+impl Default for PseudoMmNumaMode {
+    fn default() -> Self {
+        PseudoMmNumaMode::Preferred
+    }
 }
 
 /// The microVM state options.
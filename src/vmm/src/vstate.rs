@@ -1295,6 +1295,52 @@ pub struct VcpuState {
     xsave: kvm_xsave,
 }
 
+#[cfg(target_arch = "x86_64")]
+impl VcpuState {
+    /// Hash of the vcpu's CPUID entries, to tell feature sets apart.
+    pub fn cpuid_hash(&self) -> u64 {
+        // FNV-1a, which unlike the std hasher is stable across builds.
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for entry in self.cpuid.as_slice() {
+            let words = [
+                entry.function,
+                entry.index,
+                entry.flags,
+                entry.eax,
+                entry.ebx,
+                entry.ecx,
+                entry.edx,
+            ];
+            for word in &words {
+                for byte in &word.to_le_bytes() {
+                    hash ^= u64::from(*byte);
+                    hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+                }
+            }
+        }
+        hash
+    }
+
+    /// Whether the vcpu has an SMT sibling, as the topology leaves `cpuid`
+    /// fills in tell: threads per core in leaf 0x8000_001E on AMD, logical
+    /// processors at the SMT level of leaf 0xB otherwise. `None` without
+    /// either.
+    pub fn smt_enabled(&self) -> Option<bool> {
+        let entries = self.cpuid.as_slice();
+        let leaf = |function: u32| {
+            entries
+                .iter()
+                .find(|entry| entry.function == function && entry.index == 0)
+        };
+        if let Some(entry) = leaf(0x8000_001e) {
+            // EBX[15:8] holds threads per core minus one.
+            return Some((entry.ebx >> 8) & 0xff > 0);
+        }
+        // EBX[15:0] of subleaf 0 holds logical processors per core.
+        leaf(0xb).map(|entry| entry.ebx & 0xffff > 1)
+    }
+}
+
 /// List of events that the Vcpu can receive.
 pub enum VcpuEvent {
     /// Pause the Vcpu.
@@ -0,0 +1,2258 @@
+//! `--batch-config`: creating many templates from one config.
+//!
+//! The entries are resolved and laid out before anything is uploaded:
+//! pgoffs and HVA bases are assigned and checked for overlaps, and with
+//! `--resume-from` or `--regenerate-stale` the entries already done are
+//! kept. `--jobs` workers then take entries from a shared queue, and the
+//! batch summary records how each one ended.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use clap::{Arg, ArgMatches};
+use serde::{Deserialize, Serialize};
+use vmm::memory_snapshot::GuestMemoryRegionState;
+use vmm::pseudo_mm_addr::{self, HvaAddr, PageOffset};
+use vmm::pseudo_mm_support::{self, MemBackend, PageSize, PAGE_SIZE};
+
+use crate::capacity;
+use crate::deadline::{self, Admission, EntryDeadline, RunLimits, ThroughputEstimator};
+use crate::env_expand;
+use crate::fd_budget;
+use crate::freshness::{self, CheckMode, Freshness};
+use crate::image_reuse::{self, ImageDigest, ReusedImage, UploadedImage, UploadedImages};
+use crate::json_output;
+use crate::layered::BaseFiles;
+use crate::mem_files::MemFiles;
+use crate::namespace::PgoffNamespace;
+use crate::occupancy;
+use crate::output_lock;
+use crate::page_dedup::{DedupHash, DedupSummary, PageStore};
+use crate::pgoff_alloc::{self, PgoffAllocator, PlannedRange};
+use crate::pgoff_registry::{PgoffRegistry, Reservation};
+use crate::regions::{self, HvaLayout, RegionHva};
+use crate::resume::{self, Resume, ResumedEntry};
+use crate::run_metrics::{EntryOutcome, EntryRecorder, SharedMetrics};
+use crate::snapshot_glob;
+use crate::template_error;
+use crate::upload::{rdma_guard_pages, UploadArgs};
+use crate::upload_progress::{self, ProgressStyle};
+use crate::{
+    absolute_path, check_pgoff_align, create_template, describe_instance, dry_run_template,
+    entry_summary, is_cancelled, is_entry_timeout, is_output_exists, open_memory_file,
+    pages_to_bytes, parse_snapshot, status_summary, write_layout_plan, EntryStatus, EntrySummary,
+    ImageTarget, LayoutPlan, RunOptions, TemplateArgs, DEFAULT_PSEUDO_MM_BASE,
+};
+
+/// Options shared by every entry of a batch.
+pub struct BatchOptions {
+    pub upload: UploadArgs,
+    pub coalesce_regions: bool,
+    /// See `--no-create-pseudo-mm`.
+    pub create_pseudo_mm: bool,
+    /// See `--force`.
+    pub force: bool,
+    /// See `--pgoff-align`; the config's `pgoff_align` without it.
+    pub pgoff_align: Option<u64>,
+    /// See `--pgoff-align-strict`.
+    pub pgoff_align_strict: bool,
+    pub lock_wait: Duration,
+    /// Number of entries processed at once.
+    pub jobs: usize,
+    /// Cancel entries in flight when one fails, instead of letting them
+    /// finish.
+    pub fail_fast: bool,
+    /// Skip the check that no two entries' pgoff ranges overlap.
+    pub allow_overlap: bool,
+    /// See `--dedup` and `--dedup-hash`.
+    pub dedup: Option<DedupHash>,
+    /// Map identical images of entries from one upload, unless
+    /// `--no-reuse`; see `image_reuse`.
+    pub reuse: bool,
+    /// See `--max-image-pages`.
+    pub max_image_pages: Option<u64>,
+    /// See `--guard-pages`.
+    pub guard_pages: u64,
+    /// See `--auto-hva-stride`.
+    pub auto_hva_stride: Option<u64>,
+    /// See `--snapshot-data-version`.
+    pub snapshot_data_version: Option<u16>,
+    /// See `--validate-snapshot`; entries are also validated when the
+    /// config sets `validate_snapshot`.
+    pub validate_snapshot: bool,
+    /// Where created instances are recorded.
+    pub instance_registry: PathBuf,
+    /// See `--registry`.
+    pub pgoff_registry: Option<PathBuf>,
+    /// Plan the layout without uploading or creating anything.
+    pub dry_run: bool,
+    /// Where a dry run writes its layout plan as JSON.
+    pub plan_output: Option<PathBuf>,
+    /// Where the batch summary is written as JSON.
+    pub summary_output: Option<PathBuf>,
+    /// See `--resume-from`.
+    pub resume_from: Option<PathBuf>,
+    /// See `--resume-verify`.
+    pub resume_verify: bool,
+    /// See `--regenerate-stale` and `--quick-freshness`.
+    pub regenerate_stale: Option<CheckMode>,
+}
+
+/// State shared by the workers of a batch.
+struct Batch {
+    config: BatchConfig,
+    /// Each entry's `hva_base`, see `batch_hva_bases`.
+    hva_bases: Vec<HvaAddr>,
+    pgoff_namespace: Option<PgoffNamespace>,
+    /// Pages each RDMA server holds, see `batch_pgoff_limits`.
+    pgoff_limits: BTreeMap<String, u64>,
+    options: BatchOptions,
+    /// Pages uploaded so far, with `--dedup`.
+    dedup: Option<PageStore>,
+    limits: RunLimits,
+    metrics: SharedMetrics,
+    queue: Mutex<BatchQueue>,
+    /// Worker and status of each entry, in config order; `None` for entries
+    /// that never started. Entries resumed from an earlier run start out
+    /// reported, as do fresh ones with `--regenerate-stale`.
+    reports: Mutex<Vec<Option<(usize, EntryStatus)>>>,
+    /// Why each entry `--regenerate-stale` runs again is stale; `None` for
+    /// the others.
+    stale: Vec<Option<String>>,
+}
+
+/// Entries not started yet, and the state that starting one updates.
+struct BatchQueue {
+    next: usize,
+    /// Set once an entry fails; entries not started by then are skipped.
+    stopped: bool,
+    allocator: PgoffAllocator,
+    estimator: ThroughputEstimator,
+    /// Images of entries created so far, for `image_reuse`.
+    images: UploadedImages,
+}
+
+/// The options only `--batch-config` takes.
+pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("batch-config")
+            .long("batch-config")
+            .value_name("FILE")
+            .conflicts_with("snapshot")
+            .help("JSON file describing multiple templates to generate"),
+        Arg::with_name("jobs")
+            .long("jobs")
+            .value_name("N")
+            .requires("batch-config")
+            .help("Process up to N batch entries at once (default: 1)"),
+        Arg::with_name("fail-fast")
+            .long("fail-fast")
+            .requires("batch-config")
+            .help("Cancel batch entries in flight as soon as one fails"),
+        Arg::with_name("auto-hva-stride")
+            .long("auto-hva-stride")
+            .value_name("BYTES")
+            .requires("batch-config")
+            .help(
+                "Place batch entry i at hva_base + i * BYTES, so that no two entries \
+                 share host addresses",
+            ),
+        Arg::with_name("allow-overlap")
+            .long("allow-overlap")
+            .requires("batch-config")
+            .help(
+                "Allow batch entries whose pgoff ranges overlap, e.g. entries \
+                 sharing a base image on purpose",
+            ),
+        Arg::with_name("dedup")
+            .long("dedup")
+            .requires("batch-config")
+            .help("Upload each distinct page of the batch's RDMA entries once, mapping repeats from the first upload"),
+        Arg::with_name("dedup-hash")
+            .long("dedup-hash")
+            .value_name("HASH")
+            .possible_values(&["fast", "sha256"])
+            .requires("dedup")
+            .help("How --dedup tells pages apart: a 64-bit hash checked byte for byte, or SHA-256 (default: fast)"),
+        Arg::with_name("no-reuse")
+            .long("no-reuse")
+            .requires("batch-config")
+            .help("Upload every batch entry's image, even one identical to an image uploaded before"),
+        Arg::with_name("summary-output")
+            .long("summary-output")
+            .value_name("FILE")
+            .requires("batch-config")
+            .help("Write the batch summary to FILE as JSON, even when entries fail"),
+        Arg::with_name("resume-from")
+            .long("resume-from")
+            .value_name("SUMMARY")
+            .requires("batch-config")
+            .help(
+                "Skip the entries an earlier run's --summary-output records as created, \
+                 keeping their pgoffs",
+            ),
+        Arg::with_name("resume-verify")
+            .long("resume-verify")
+            .requires("resume-from")
+            .help("Run entries resumed from again unless their template still loads"),
+        Arg::with_name("regenerate-stale")
+            .long("regenerate-stale")
+            .requires("batch-config")
+            .conflicts_with("resume-from")
+            .help(
+                "Keep the entries whose template is still fresh, see check-freshness, \
+                 and create the others again",
+            ),
+        Arg::with_name("quick-freshness")
+            .long("quick-freshness")
+            .requires("regenerate-stale")
+            .help("Compare sizes and modification times only, like check-freshness --quick"),
+        no_env_expand_arg(),
+    ]
+}
+
+pub fn no_env_expand_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("no-env-expand")
+        .long("no-env-expand")
+        .requires("batch-config")
+        .help("Take batch config paths literally, without expanding ${VAR} in them")
+}
+
+/// Runs the batch at `config_path` with the batch options of `matches` and
+/// the rest of the run's in `run`.
+pub fn run(
+    config_path: &str,
+    matches: &ArgMatches,
+    run: RunOptions,
+    limits: &RunLimits,
+    metrics: &SharedMetrics,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let jobs = match matches.value_of("jobs") {
+        Some(value) => value.parse().ok().filter(|&jobs| jobs > 0).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--jobs: invalid value '{}'", value),
+            )
+        })?,
+        None => 1,
+    };
+    let auto_hva_stride = match matches.value_of("auto-hva-stride") {
+        Some(value) => Some(
+            pseudo_mm_addr::parse_u64(value)
+                .ok()
+                .filter(|&stride| stride > 0 && stride % PAGE_SIZE == 0)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "--auto-hva-stride: invalid value '{}': expected a non-zero \
+                             multiple of 4 KiB",
+                            value
+                        ),
+                    )
+                })?,
+        ),
+        None => None,
+    };
+    run_batch(
+        config_path,
+        !matches.is_present("no-env-expand"),
+        run.pgoff_namespace.as_ref(),
+        BatchOptions {
+            upload: run.upload,
+            coalesce_regions: run.coalesce_regions,
+            create_pseudo_mm: run.create_pseudo_mm,
+            force: run.force,
+            pgoff_align: run.pgoff_align,
+            pgoff_align_strict: run.pgoff_align_strict,
+            lock_wait: run.lock_wait,
+            jobs,
+            fail_fast: matches.is_present("fail-fast"),
+            allow_overlap: matches.is_present("allow-overlap"),
+            dedup: if matches.is_present("dedup") {
+                Some(matches.value_of("dedup-hash").unwrap_or("fast").parse()?)
+            } else {
+                None
+            },
+            reuse: !matches.is_present("no-reuse"),
+            max_image_pages: run.max_image_pages,
+            guard_pages: run.guard_pages,
+            auto_hva_stride,
+            instance_registry: run.instance_registry,
+            pgoff_registry: run.pgoff_registry,
+            snapshot_data_version: run.snapshot_data_version,
+            validate_snapshot: run.validate_snapshot,
+            dry_run: run.dry_run,
+            plan_output: run.plan_output,
+            summary_output: matches.value_of("summary-output").map(PathBuf::from),
+            resume_from: matches.value_of("resume-from").map(PathBuf::from),
+            resume_verify: matches.is_present("resume-verify"),
+            regenerate_stale: if !matches.is_present("regenerate-stale") {
+                None
+            } else if matches.is_present("quick-freshness") {
+                Some(CheckMode::Quick)
+            } else {
+                Some(CheckMode::Content)
+            },
+        },
+        limits,
+        metrics,
+    )
+}
+
+pub fn run_batch(
+    config_path: &str,
+    expand_env: bool,
+    pgoff_namespace: Option<&PgoffNamespace>,
+    options: BatchOptions,
+    limits: &RunLimits,
+    metrics: &SharedMetrics,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Loading batch config from {}", config_path);
+    let mut config = load_batch_config(config_path, expand_env)?;
+
+    if config.templates.is_empty() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "batch config has no templates",
+        )));
+    }
+    let jobs = std::cmp::min(options.jobs, config.templates.len());
+    // Each worker releases an entry's descriptors before starting the next.
+    if let (Some(open), Ok(limit)) = (fd_budget::open_fds(), fd_budget::nofile_limit()) {
+        let shards = config
+            .templates
+            .iter()
+            .map(|entry| entry.mem_file_path.shard_count())
+            .max()
+            .unwrap_or(1);
+        let per_entry = fd_budget::fds_per_entry(shards as u64, options.upload.streams as u64);
+        fd_budget::check_budget(jobs as u64, per_entry, open, limit.soft)?;
+    }
+
+    let rdma_base = config
+        .default_rdma_pgoff
+        .map(PageOffset::raw)
+        .or_else(|| pgoff_namespace.map(|ns| ns.base_pgoff))
+        .unwrap_or(0);
+    println!(
+        "Processing {} templates with {} workers (starting rdma_pgoff={})",
+        config.templates.len(),
+        jobs,
+        rdma_base
+    );
+    let pgoff_align = match (options.pgoff_align, config.pgoff_align) {
+        (Some(align), _) => align,
+        (None, Some(0)) => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pgoff_align: must be non-zero",
+            )))
+        }
+        (None, Some(align)) => align,
+        (None, None) => 1,
+    };
+    if pgoff_align > 1 {
+        println!(
+            "Aligning auto-assigned rdma_pgoffs to {} pages",
+            pgoff_align
+        );
+        let explicit: Vec<(String, u64)> = config
+            .templates
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| {
+                batch_target(&config, *idx).map_or(false, |target| target.dax_device().is_none())
+            })
+            .filter_map(|(_, entry)| {
+                entry
+                    .rdma_pgoff
+                    .map(|pgoff| (entry.label().to_string(), pgoff.raw()))
+            })
+            .collect();
+        check_pgoff_align(&explicit, pgoff_align, options.pgoff_align_strict)?;
+    }
+    let mut stale = Vec::new();
+    let resume = match (options.resume_from.as_ref(), options.regenerate_stale) {
+        (Some(path), _) => Some(batch_resume(&config, path, options.resume_verify)?),
+        (None, Some(mode)) => {
+            let (fresh, entries) = batch_freshness(&mut config, mode, pgoff_namespace.is_some())?;
+            stale = entries;
+            Some(fresh)
+        }
+        (None, None) => None,
+    };
+    if options.allow_overlap {
+        println!("warning: --allow-overlap set, not checking entries' pgoff ranges");
+    } else {
+        check_batch_overlap(
+            &config,
+            rdma_base,
+            pgoff_align,
+            options.guard_pages,
+            resume.as_ref(),
+        )?;
+    }
+    let pgoff_limits = batch_pgoff_limits(&config, &options);
+    if !pgoff_limits.is_empty() {
+        let limits = pgoff_limits
+            .iter()
+            .map(|(server, &limit)| (server.as_str(), limit))
+            .collect();
+        capacity::check_ranges(
+            &batch_ranges(
+                &config,
+                rdma_base,
+                pgoff_align,
+                options.guard_pages,
+                resume.as_ref(),
+            )?,
+            &limits,
+            &|idx| config.templates[idx].label().to_string(),
+        )?;
+    }
+    let states: Vec<Option<Vec<GuestMemoryRegionState>>> = config
+        .templates
+        .iter()
+        .map(|entry| {
+            parse_snapshot(&entry.snapshot_path, options.snapshot_data_version)
+                .ok()
+                .map(|state| state.memory_state.regions)
+        })
+        .collect();
+    if let Some(stride) = options.auto_hva_stride {
+        println!("Spacing entries' hva_base 0x{:x} apart", stride);
+    }
+    let hva_bases = batch_hva_bases(&config, &states, options.auto_hva_stride)?;
+
+    if let Some(hash) = options.dedup {
+        println!("Deduplicating pages across entries ({} hash)", hash);
+    }
+
+    if options.guard_pages > 0 {
+        println!(
+            "Leaving {} guard pages around each RDMA image",
+            options.guard_pages
+        );
+    }
+    let allocator = batch_allocator(
+        &config,
+        rdma_base,
+        pgoff_align,
+        options.guard_pages,
+        resume.as_ref(),
+    )?;
+    let mut resumed = resume
+        .map_or_else(Vec::new, |resume| resume.entries)
+        .into_iter();
+    let reports = (0..config.templates.len())
+        .map(|_| {
+            let resumed = resumed.next().and_then(|entry| entry)?;
+            Some((0, EntryStatus::Resumed(resumed)))
+        })
+        .collect();
+    let batch = Arc::new(Batch {
+        config,
+        hva_bases,
+        pgoff_namespace: pgoff_namespace.cloned(),
+        pgoff_limits,
+        dedup: options.dedup.map(PageStore::new),
+        options,
+        limits: limits.clone(),
+        metrics: metrics.clone(),
+        queue: Mutex::new(BatchQueue {
+            next: 0,
+            stopped: false,
+            allocator,
+            estimator: ThroughputEstimator::default(),
+            images: UploadedImages::default(),
+        }),
+        reports: Mutex::new(reports),
+        stale,
+    });
+    let workers = (1..=jobs)
+        .map(|worker| {
+            let batch = batch.clone();
+            thread::Builder::new()
+                .name(format!("batch-worker-{}", worker))
+                .spawn(move || run_batch_worker(&batch, worker))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    for worker in workers {
+        if worker.join().is_err() {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::Other,
+                "batch worker panicked",
+            )));
+        }
+    }
+
+    println!("\nBatch summary:");
+    let reports = batch.reports.lock().expect("Poisoned lock");
+    let mut unfinished = 0;
+    // A failure explains the run's result better than the cancellations
+    // --fail-fast caused.
+    let mut first_failure = None;
+    let mut first_cancel = None;
+    let mut reused = 0;
+    let mut resumed = 0;
+    // Entries, bytes and upload time per server or device.
+    let mut throughput: BTreeMap<&str, (usize, u64, Duration)> = BTreeMap::new();
+    for (idx, report) in reports.iter().enumerate() {
+        let label = batch.config.templates[idx].label();
+        let (worker, status) = match report {
+            Some((worker, status)) => (worker, status),
+            None => {
+                // Not started because an earlier entry failed or the run was
+                // cancelled.
+                metrics
+                    .lock()
+                    .expect("Poisoned lock")
+                    .finish_entry(EntryOutcome::Skipped);
+                println!("  [{}] skipped", label);
+                unfinished += 1;
+                continue;
+            }
+        };
+        let message = match status {
+            EntryStatus::Created(summary) => {
+                println!(
+                    "  [{}] worker={} pseudo_mm_id={} {}_pgoff={} hva_base={} pages={} upload={:.2}s ({:.1} MB/s) output={}",
+                    label,
+                    worker,
+                    describe_instance(summary.pseudo_mm_id),
+                    summary.backend,
+                    summary.rdma_pgoff,
+                    summary.hva_base,
+                    summary.mem_pages,
+                    summary.upload_time.as_secs_f64(),
+                    upload_progress::rate(summary.mem_size, summary.upload_time),
+                    summary.output_path
+                );
+                if summary.reused_from.is_none() {
+                    let totals = throughput.entry(summary.target.as_str()).or_default();
+                    totals.0 += 1;
+                    totals.1 += summary.mem_size;
+                    totals.2 += summary.upload_time;
+                }
+                if let Some(peak) = summary.cache_peak {
+                    println!("      cache peak +{} bytes", peak);
+                }
+                if let Some(layered) = summary.layered.as_ref() {
+                    println!(
+                        "      overlay {} pages, {} shared with {}",
+                        layered.overlay_pages, layered.shared_pages, layered.base_template
+                    );
+                    if let (Some(dirty), Some(clean)) = (layered.dirty_pages, layered.clean_pages) {
+                        println!("      dirty {} pages, {} clean", dirty, clean);
+                    }
+                }
+                if let Some(dedup) = summary.dedup.as_ref() {
+                    println!(
+                        "      dedup {} pages uploaded, {} shared",
+                        dedup.unique_pages, dedup.shared_pages
+                    );
+                }
+                if let Some(owner) = summary.reused_from.as_ref() {
+                    println!("      reused the image uploaded for {}", owner);
+                    reused += 1;
+                }
+                continue;
+            }
+            EntryStatus::Planned(plan) => {
+                let (hva_start, hva_end) = plan.hva_window();
+                println!(
+                    "  [{}] worker={} planned {}_pgoff=[{}, {}) hva=[{}, {}) regions={} output={}",
+                    label,
+                    worker,
+                    plan.backend,
+                    plan.rdma_pgoff,
+                    plan.rdma_pgoff.raw() + plan.pages,
+                    hva_start,
+                    hva_end,
+                    plan.regions.len(),
+                    plan.output_path
+                );
+                continue;
+            }
+            EntryStatus::Resumed(entry) => {
+                println!(
+                    "  [{}] {}, {}_pgoff={} pages={} output={}",
+                    label,
+                    if batch.options.regenerate_stale.is_some() {
+                        "fresh, kept"
+                    } else {
+                        "created by the run resumed from"
+                    },
+                    entry.backend,
+                    entry.rdma_pgoff,
+                    entry.pages,
+                    entry.output_path
+                );
+                resumed += 1;
+                continue;
+            }
+            EntryStatus::Deferred(message) | EntryStatus::TimedOut(message) => message,
+            EntryStatus::Failed(message, failure) => {
+                if first_failure.is_none() {
+                    first_failure = Some(match failure {
+                        Some(failure) => {
+                            Box::new(failure.clone().context(&format!("{}: failed", label)))
+                                as Box<dyn std::error::Error>
+                        }
+                        None => Box::new(io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("{}: {}", label, message),
+                        )),
+                    });
+                }
+                message
+            }
+            EntryStatus::Cancelled(message) => {
+                first_cancel = first_cancel.or_else(|| Some(format!("{}: {}", label, message)));
+                message
+            }
+        };
+        println!("  [{}] worker={} {}", label, worker, message);
+        unfinished += 1;
+    }
+    if !throughput.is_empty() {
+        // Per upload rather than aggregate, so parallel workers don't
+        // inflate a target's figure.
+        println!("Upload throughput by target:");
+        for (target, (entries, bytes, time)) in &throughput {
+            println!(
+                "  {}: {} entries, {} bytes in {:.2}s, {:.1} MB/s",
+                target,
+                entries,
+                bytes,
+                time.as_secs_f64(),
+                upload_progress::rate(*bytes, *time)
+            );
+        }
+    }
+    let dedup = batch.dedup.as_ref().map(|store| {
+        let entries = reports.iter().filter_map(|report| match report {
+            Some((_, EntryStatus::Created(result))) => result.dedup.as_ref(),
+            Some((_, EntryStatus::Planned(plan))) => plan.dedup.as_ref(),
+            _ => None,
+        });
+        DedupSummary::new(store.hash(), entries)
+    });
+    if let Some(dedup) = dedup.as_ref() {
+        println!(
+            "Dedup: {} populated pages, {} uploaded, {} shared, ratio {:.2}",
+            dedup.pages, dedup.unique_pages, dedup.shared_pages, dedup.ratio
+        );
+    }
+    if reused > 0 {
+        println!(
+            "Reused images: {} entries mapped an identical image instead of uploading",
+            reused
+        );
+    }
+    if batch.options.regenerate_stale.is_some() {
+        let regenerated = reports
+            .iter()
+            .zip(batch.stale.iter())
+            .filter(|(report, stale)| {
+                stale.is_some() && matches!(report, Some((_, EntryStatus::Created(_))))
+            })
+            .count();
+        println!(
+            "Freshness: {} entries fresh, {} regenerated",
+            resumed, regenerated
+        );
+    } else if resumed > 0 {
+        println!(
+            "Resumed: {} entries were created by the earlier run",
+            resumed
+        );
+    }
+    if unfinished > 0 {
+        println!(
+            "{} entries have no template; rerun the batch with them to finish",
+            unfinished
+        );
+    }
+
+    let queue = batch.queue.lock().expect("Poisoned lock");
+    println!("Next available rdma_pgoff: {}", queue.allocator.next_rdma());
+    for (device, next) in queue.allocator.next_dax() {
+        println!("Next available pgoff on {}: {}", device, next);
+    }
+    if let (Some(path), None) = (batch.options.plan_output.as_ref(), first_failure.as_ref()) {
+        let plan = LayoutPlan {
+            entries: reports
+                .iter()
+                .filter_map(|report| match report {
+                    Some((_, EntryStatus::Planned(plan))) => Some(plan),
+                    _ => None,
+                })
+                .collect(),
+            next_rdma_pgoff: queue.allocator.next_rdma(),
+            next_dax_pgoffs: queue.allocator.next_dax().into_iter().collect(),
+        };
+        write_layout_plan(path, &plan, batch.options.lock_wait)?;
+    }
+
+    // Written whatever the entries' outcome, so a rerun can pick out the
+    // ones without a template.
+    let summary = BatchSummary {
+        entries: batch
+            .config
+            .templates
+            .iter()
+            .zip(reports.iter())
+            .zip(batch.stale.iter())
+            .map(|((entry, report), stale)| match report {
+                Some((_, EntryStatus::Resumed(resumed))) => SummaryEntry::Resumed(&resumed.summary),
+                _ => {
+                    let mut summary = entry_summary(entry, report);
+                    if stale.is_some() {
+                        summary.freshness = Some(match summary.status {
+                            "ok" => "regenerated",
+                            _ => "stale-skipped",
+                        });
+                    }
+                    SummaryEntry::Run(Box::new(summary))
+                }
+            })
+            .collect(),
+        next_rdma_pgoff: queue.allocator.next_rdma(),
+        next_dax_pgoffs: queue.allocator.next_dax().into_iter().collect(),
+        dedup,
+        dry_run: batch.options.dry_run,
+    };
+    let summarized = match batch.options.summary_output.as_ref() {
+        Some(path) => write_batch_summary(path, &summary, batch.options.lock_wait),
+        None => Ok(()),
+    };
+    if let Err(err) = json_output::emit(&summary) {
+        println!("warning: cannot write JSON output: {}", err);
+    }
+
+    let flushed = metrics.lock().expect("Poisoned lock").flush();
+    let first_cancel = first_cancel.map(|message| -> Box<dyn std::error::Error> {
+        Box::new(io::Error::new(io::ErrorKind::Other, message))
+    });
+    if let Some(err) = first_failure.or(first_cancel) {
+        if let Err(summary_err) = summarized {
+            println!("warning: cannot write batch summary: {}", summary_err);
+        }
+        if let Err(metrics_err) = flushed {
+            println!("warning: cannot write metrics: {}", metrics_err);
+        }
+        return Err(err);
+    }
+    summarized?;
+    flushed?;
+
+    Ok(())
+}
+
+/// Resolves where a batch entry's image is stored.
+fn batch_target<'a>(
+    config: &'a BatchConfig,
+    idx: usize,
+) -> Result<ImageTarget<'a>, Box<dyn std::error::Error>> {
+    let entry = &config.templates[idx];
+    let missing = |field| {
+        Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("template {} missing {}", idx + 1, field),
+        )) as Box<dyn std::error::Error>
+    };
+    match entry.mem_type.or(config.mem_type).unwrap_or_default() {
+        MemBackend::Rdma => entry
+            .rdma_server
+            .as_ref()
+            .or(config.rdma_server.as_ref())
+            .map(|server| ImageTarget::Rdma { server })
+            .ok_or_else(|| missing("rdma_server")),
+        MemBackend::Dax => entry
+            .dax_device
+            .as_ref()
+            .or(config.dax_device.as_ref())
+            .map(|device| ImageTarget::Dax { device })
+            .ok_or_else(|| missing("dax_device")),
+    }
+}
+
+/// Fails if two entries would be uploaded to overlapping pgoff ranges.
+fn check_batch_overlap(
+    config: &BatchConfig,
+    rdma_base: u64,
+    rdma_align: u64,
+    guard_pages: u64,
+    resume: Option<&Resume>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ranges = batch_ranges(config, rdma_base, rdma_align, guard_pages, resume)?;
+    let pairs = pgoff_alloc::overlapping_pairs(&ranges);
+    if pairs.is_empty() {
+        return Ok(());
+    }
+    let mut msg = String::from(
+        "batch entries overlap in pgoff space (use --allow-overlap if they share an image \
+         on purpose):",
+    );
+    for (a, b) in pairs {
+        msg.push_str(&format!(
+            "\n  {} [{}, {}) and {} [{}, {}) on {}",
+            config.templates[a.entry].label(),
+            a.start,
+            a.end(),
+            config.templates[b.entry].label(),
+            b.start,
+            b.end(),
+            a.target
+        ));
+    }
+    Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, msg)))
+}
+
+/// The pgoff range every batch entry will be uploaded to.
+///
+/// Replays the reservations the workers make, in the same order and from
+/// the same memory file sizes, so every entry's range is known before any
+/// of them is written. Entries without a target or a readable memory file
+/// are left for their worker to report. Resumed entries keep the range
+/// they were created at. RDMA ranges include their guard pages.
+fn batch_ranges<'a>(
+    config: &'a BatchConfig,
+    rdma_base: u64,
+    rdma_align: u64,
+    guard_pages: u64,
+    resume: Option<&Resume>,
+) -> Result<Vec<PlannedRange<'a>>, Box<dyn std::error::Error>> {
+    let mut allocator = batch_allocator(config, rdma_base, rdma_align, guard_pages, resume)?;
+    let mut ranges = Vec::with_capacity(config.templates.len());
+    let guarded = |idx, target: ImageTarget<'a>, start: u64, pages: u64| {
+        let guard = rdma_guard_pages(target, guard_pages);
+        PlannedRange {
+            entry: idx,
+            target: target.name(),
+            start: start.saturating_sub(guard),
+            pages: pages.saturating_add(guard.saturating_mul(2)),
+        }
+    };
+    for (idx, entry) in config.templates.iter().enumerate() {
+        if let Some(resumed) = resume.and_then(|resume| resume.entries[idx].as_ref()) {
+            let target = batch_target(config, idx)?;
+            ranges.push(guarded(
+                idx,
+                target,
+                resumed.rdma_pgoff.raw(),
+                resumed.pages,
+            ));
+            continue;
+        }
+        let (target, mem_size) = match (batch_target(config, idx), entry.mem_size()) {
+            (Ok(target), Ok(size)) => (target, size),
+            _ => continue,
+        };
+        let pages = (mem_size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let start = allocator
+            .reserve(
+                target.dax_device(),
+                entry.rdma_pgoff.map(PageOffset::raw),
+                pages,
+                batch_page_size(config, idx).pgoffs(),
+            )
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("batch entry {}: {}", entry.label(), err),
+                )
+            })?;
+        ranges.push(guarded(idx, target, start, pages));
+    }
+    Ok(ranges)
+}
+
+/// The allocator a batch reserves its entries' ranges from: past the
+/// ranges of the entries resumed and everything the run resumed from
+/// reserved.
+fn batch_allocator(
+    config: &BatchConfig,
+    rdma_base: u64,
+    rdma_align: u64,
+    guard_pages: u64,
+    resume: Option<&Resume>,
+) -> Result<PgoffAllocator, Box<dyn std::error::Error>> {
+    let mut allocator =
+        PgoffAllocator::with_rdma_align(rdma_base, rdma_align).with_rdma_guard(guard_pages);
+    let resume = match resume {
+        Some(resume) => resume,
+        None => return Ok(allocator),
+    };
+    for (idx, resumed) in resume.entries.iter().enumerate() {
+        if let Some(resumed) = resumed {
+            let target = batch_target(config, idx)?;
+            allocator.reserve(
+                target.dax_device(),
+                Some(resumed.rdma_pgoff.raw()),
+                resumed.pages,
+                1,
+            )?;
+        }
+    }
+    allocator.raise(None, resume.next_rdma_pgoff);
+    for (device, &next) in &resume.next_dax_pgoffs {
+        allocator.raise(Some(device), next);
+    }
+    Ok(allocator)
+}
+
+/// Loads the summary `--resume-from` names and matches its entries to
+/// those of `config`. Entries whose backend changed since, and with
+/// `verify` those whose template doesn't load, are run again.
+/// Sorts the entries whose output path holds a template by whether it is
+/// still fresh, for `--regenerate-stale`. Fresh ones are kept like entries
+/// resumed from an earlier run. Stale ones run again, and one without an
+/// `rdma_pgoff` takes its template's, so its old range isn't left unused,
+/// if its image still has as many pages. Returns why each entry to run
+/// again is stale.
+fn batch_freshness(
+    config: &mut BatchConfig,
+    mode: CheckMode,
+    namespaced: bool,
+) -> Result<(Resume, Vec<Option<String>>), Box<dyn std::error::Error>> {
+    println!("Checking the entries' templates against their sources");
+    let mut kept = Vec::with_capacity(config.templates.len());
+    let mut stale = Vec::with_capacity(config.templates.len());
+    for idx in 0..config.templates.len() {
+        let backend = batch_target(config, idx).map(ImageTarget::backend);
+        let entry = &config.templates[idx];
+        let label = entry.label().to_string();
+        let output_path = Path::new(&entry.output_path);
+        if !output_path.exists() {
+            kept.push(None);
+            stale.push(None);
+            continue;
+        }
+        let template = match pseudo_mm_support::load_template_file(output_path) {
+            Ok(template) => template,
+            Err(err) => {
+                let why = format!("template {} doesn't load: {}", entry.output_path, err);
+                println!("  [{}] {}, creating it again", label, why);
+                kept.push(None);
+                stale.push(Some(why));
+                continue;
+            }
+        };
+        let pages = (template.rdma_image_size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let freshness =
+            freshness::check_template(&template, &entry.snapshot_path, &entry.mem_file_path, mode);
+        let why = match (freshness, backend.as_ref()) {
+            (Ok(Freshness::Fresh), Ok(&backend)) if backend != template.mem_backend => format!(
+                "it was created on {}, now goes to {}",
+                template.mem_backend, backend
+            ),
+            (Ok(Freshness::Fresh), Ok(_)) => {
+                println!("  [{}] fresh, keeping {}", label, entry.output_path);
+                let status = EntryStatus::Resumed(ResumedEntry {
+                    backend: template.mem_backend,
+                    rdma_pgoff: template.rdma_base_pgoff,
+                    pages,
+                    output_path: entry.output_path.clone(),
+                    summary: serde_json::Value::Null,
+                });
+                let mut summary = status_summary(
+                    label,
+                    &entry.snapshot_path,
+                    &entry.output_path,
+                    Some(&status),
+                );
+                summary.freshness = Some("fresh");
+                let summary = serde_json::to_value(&summary)?;
+                if let EntryStatus::Resumed(mut fresh) = status {
+                    fresh.summary = summary;
+                    kept.push(Some(fresh));
+                }
+                stale.push(None);
+                continue;
+            }
+            (Ok(Freshness::Stale(why)), _) => why,
+            (Err(err), _) => err.to_string(),
+            (_, Err(err)) => err.to_string(),
+        };
+        let same_pages = entry
+            .mem_size()
+            .map_or(false, |size| (size + PAGE_SIZE - 1) / PAGE_SIZE == pages);
+        let reuse = entry.rdma_pgoff.is_none()
+            && !namespaced
+            && backend.ok() == Some(MemBackend::Rdma)
+            && template.mem_backend == MemBackend::Rdma
+            && template.rdma_image_extents.is_empty()
+            && same_pages;
+        if reuse {
+            println!(
+                "  [{}] stale: {}, creating it again at rdma_pgoff={}",
+                label, why, template.rdma_base_pgoff
+            );
+            config.templates[idx].rdma_pgoff = Some(template.rdma_base_pgoff);
+        } else if entry.rdma_pgoff.is_none() {
+            println!(
+                "  [{}] stale: {}, creating it again; its {}_pgoff=[{}, {}) is left unused",
+                label,
+                why,
+                template.mem_backend,
+                template.rdma_base_pgoff,
+                template.rdma_base_pgoff.raw() + pages
+            );
+        } else {
+            println!("  [{}] stale: {}, creating it again", label, why);
+        }
+        kept.push(None);
+        stale.push(Some(why));
+    }
+    let fresh = kept.iter().filter(|entry| entry.is_some()).count();
+    let to_run = stale.iter().filter(|entry| entry.is_some()).count();
+    println!("  {} entries fresh, {} stale", fresh, to_run);
+    let resume = Resume {
+        entries: kept,
+        next_rdma_pgoff: 0,
+        next_dax_pgoffs: BTreeMap::new(),
+    };
+    Ok((resume, stale))
+}
+
+fn batch_resume(
+    config: &BatchConfig,
+    path: &Path,
+    verify: bool,
+) -> Result<Resume, Box<dyn std::error::Error>> {
+    println!("Resuming from {}", path.display());
+    let keys: Vec<(&str, &str)> = config
+        .templates
+        .iter()
+        .map(|entry| (entry.label(), entry.output_path.as_str()))
+        .collect();
+    let mut resume = resume::resume(resume::load(path)?, &keys)?;
+    for (idx, slot) in resume.entries.iter_mut().enumerate() {
+        let resumed = match slot.as_ref() {
+            Some(resumed) => resumed,
+            None => continue,
+        };
+        let label = config.templates[idx].label();
+        let backend = batch_target(config, idx).map(ImageTarget::backend);
+        let rerun = match backend {
+            Ok(backend) if backend != resumed.backend => Some(format!(
+                "was created on {}, now goes to {}",
+                resumed.backend, backend
+            )),
+            Ok(_) if verify => {
+                pseudo_mm_support::load_template_file(Path::new(&resumed.output_path))
+                    .err()
+                    .map(|err| format!("template {} doesn't load: {}", resumed.output_path, err))
+            }
+            Ok(_) => None,
+            Err(err) => Some(err.to_string()),
+        };
+        if let Some(reason) = rerun {
+            println!("  [{}] {}, running it again", label, reason);
+            *slot = None;
+        }
+    }
+    let done = resume
+        .entries
+        .iter()
+        .filter(|entry| entry.is_some())
+        .count();
+    println!(
+        "  {} entries created by the earlier run, {} to run",
+        done,
+        resume.entries.len() - done
+    );
+    Ok(resume)
+}
+
+/// The pgoff each RDMA server of a batch may be written up to, from
+/// `--max-image-pages`.
+fn batch_pgoff_limits(config: &BatchConfig, options: &BatchOptions) -> BTreeMap<String, u64> {
+    let servers: BTreeSet<&str> = (0..config.templates.len())
+        .filter_map(|idx| match batch_target(config, idx) {
+            Ok(ImageTarget::Rdma { server }) => Some(server),
+            _ => None,
+        })
+        .collect();
+    match options.max_image_pages {
+        Some(limit) => servers
+            .into_iter()
+            .map(|server| (server.to_string(), limit))
+            .collect(),
+        None => BTreeMap::new(),
+    }
+}
+
+/// Picks every batch entry's `hva_base`, failing if two entries would place
+/// regions at the same host addresses, so that their templates can't be
+/// attached into one process.
+///
+/// `states` are the entries' snapshot regions; entries whose snapshot
+/// couldn't be read, or whose regions can't be placed, are left for their
+/// worker to report. With `auto_stride`, entry `i` without its own
+/// `hva_base` gets the batch's plus `i * auto_stride`.
+fn batch_hva_bases(
+    config: &BatchConfig,
+    states: &[Option<Vec<GuestMemoryRegionState>>],
+    auto_stride: Option<u64>,
+) -> Result<Vec<HvaAddr>, Box<dyn std::error::Error>> {
+    let default_base = config.hva_base.unwrap_or(DEFAULT_PSEUDO_MM_BASE);
+    let mut bases = Vec::with_capacity(config.templates.len());
+    // (start, end, entry) of every region of every entry.
+    let mut ranges: Vec<(u64, u64, usize)> = Vec::new();
+    for (idx, entry) in config.templates.iter().enumerate() {
+        let hva_base = match (entry.hva_base, auto_stride) {
+            (Some(hva_base), _) => hva_base,
+            (None, Some(stride)) => (idx as u64)
+                .checked_mul(stride)
+                .and_then(|offset| default_base.raw().checked_add(offset))
+                .map(HvaAddr)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "--auto-hva-stride 0x{:x}: hva_base of {} overflows",
+                            stride,
+                            entry.label()
+                        ),
+                    )
+                })?,
+            (None, None) => default_base,
+        };
+        bases.push(hva_base);
+        let states = match states[idx].as_ref() {
+            Some(states) => states,
+            None => continue,
+        };
+        let page_size = batch_page_size(config, idx);
+        let placed = regions::plan_regions(states, hva_base, PageOffset(0), page_size).and_then(
+            |mut planned| {
+                regions::place_hvas(&mut planned, hva_base, &entry.hva_layout(), page_size)?;
+                Ok(planned)
+            },
+        );
+        if let Ok(planned) = placed {
+            ranges.extend(planned.iter().map(|region| {
+                let start = region.hva.raw();
+                (start, start.saturating_add(region.size), idx)
+            }));
+        }
+    }
+
+    ranges.sort();
+    let mut pairs: Vec<(usize, usize)> = Vec::new();
+    let mut msg = String::new();
+    for (idx, &(start, end, entry)) in ranges.iter().enumerate() {
+        for &(other_start, other_end, other) in &ranges[idx + 1..] {
+            if other_start >= end {
+                break;
+            }
+            let pair = (std::cmp::min(entry, other), std::cmp::max(entry, other));
+            if entry == other || pairs.contains(&pair) {
+                continue;
+            }
+            pairs.push(pair);
+            msg.push_str(&format!(
+                "\n  {} [0x{:x}, 0x{:x}) and {} [0x{:x}, 0x{:x})",
+                config.templates[entry].label(),
+                start,
+                end,
+                config.templates[other].label(),
+                other_start,
+                other_end
+            ));
+        }
+    }
+    if pairs.is_empty() {
+        return Ok(bases);
+    }
+    let hint = match auto_stride {
+        Some(stride) => format!(
+            "--auto-hva-stride 0x{:x} is smaller than an entry's regions span",
+            stride
+        ),
+        None => "use --auto-hva-stride to space their hva_base apart".to_string(),
+    };
+    Err(Box::new(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("batch entries overlap in HVA space ({}):{}", hint, msg),
+    )))
+}
+
+/// Page size of batch entry `idx`.
+fn batch_page_size(config: &BatchConfig, idx: usize) -> PageSize {
+    config.templates[idx]
+        .page_size
+        .or(config.page_size)
+        .unwrap_or_default()
+}
+
+/// Hashes entry `idx`'s image when it may map an identical one uploaded
+/// before, see `image_reuse`. Entries whose image is made some other way,
+/// or sits at a pgoff of their own choosing, upload it themselves.
+fn batch_image_digest(batch: &Batch, idx: usize) -> Option<ImageDigest> {
+    let entry = &batch.config.templates[idx];
+    let eligible = batch.options.reuse
+        && !batch.options.dry_run
+        && batch.dedup.is_none()
+        && entry.rdma_pgoff.is_none()
+        && entry.base_template.is_none()
+        && !entry.diff_snapshot
+        && entry.dirty_bitmap.is_none();
+    match batch_target(&batch.config, idx) {
+        Ok(ImageTarget::Rdma { .. }) if eligible => {}
+        _ => return None,
+    }
+    // An entry that can't be read fails when it is planned.
+    let page_size = batch_page_size(&batch.config, idx);
+    let state = parse_snapshot(&entry.snapshot_path, batch.options.snapshot_data_version).ok()?;
+    let image = open_memory_file(&entry.mem_file_path, page_size).ok()?;
+    let windows = regions::image_windows(&state.memory_state.regions);
+    image_reuse::digest(&image, &windows, page_size).ok()
+}
+
+/// Takes entries off the batch queue until it is empty or stopped.
+fn run_batch_worker(batch: &Batch, worker: usize) {
+    loop {
+        let mut queue = batch.queue.lock().expect("Poisoned lock");
+        if queue.stopped
+            || batch.limits.cancel.is_cancelled()
+            || queue.next == batch.config.templates.len()
+        {
+            return;
+        }
+        let idx = queue.next;
+        queue.next += 1;
+        if batch.reports.lock().expect("Poisoned lock")[idx].is_some() {
+            // Resumed from an earlier run.
+            continue;
+        }
+        let entry = &batch.config.templates[idx];
+        let label = entry.label();
+        let metrics = EntryRecorder::start(&batch.metrics, label);
+
+        let mem_size = entry.mem_size();
+        let estimate = mem_size
+            .as_ref()
+            .ok()
+            .and_then(|size| queue.estimator.estimate(*size));
+        if let Admission::Defer { estimate, left } =
+            deadline::admit(SystemTime::now(), batch.limits.deadline, estimate)
+        {
+            drop(queue);
+            let status = match estimate {
+                Some(estimate) => format!(
+                    "deferred (needs ~{}s, {}s left)",
+                    estimate.as_secs(),
+                    left.as_secs()
+                ),
+                None => "deferred (deadline passed)".to_string(),
+            };
+            println!("\n=== {} :: {} ===", label, status);
+            metrics.finish(EntryOutcome::Deferred);
+            batch.report(idx, worker, EntryStatus::Deferred(status));
+            continue;
+        }
+
+        // Hashed with the queue unlocked, so other workers carry on.
+        drop(queue);
+        let digest = batch_image_digest(batch, idx);
+        let mut queue = batch.queue.lock().expect("Poisoned lock");
+
+        // The range is reserved before the upload starts, so entries running
+        // in parallel never share one.
+        let planned = batch_target(&batch.config, idx).and_then(|target| {
+            // Before reserving, so a refused entry leaves no reservation.
+            output_lock::check_overwrite(Path::new(&entry.output_path), batch.force(idx))?;
+            let mem_size = mem_size?;
+            let pages = (mem_size + PAGE_SIZE - 1) / PAGE_SIZE;
+            let explicit = entry.rdma_pgoff.map(PageOffset::raw);
+            let align = batch_page_size(&batch.config, idx).pgoffs();
+            let image_hash = digest.as_ref().map(|digest| digest.hash.as_str());
+            let mut reused = image_hash
+                .and_then(|hash| queue.images.find(target.name(), hash))
+                .cloned();
+            let pgoff = match batch.options.pgoff_registry.as_ref() {
+                Some(path) => {
+                    let mut registry = PgoffRegistry::lock(path)?;
+                    let template_path = absolute_path(&entry.output_path);
+                    if let (None, Some(hash)) = (reused.as_ref(), image_hash) {
+                        reused = registry
+                            .find_image(target.name(), hash, pseudo_mm_support::load_template_file)
+                            .map(|range| UploadedImage {
+                                pgoff: range.start_pgoff,
+                                owner: range.template_path.clone(),
+                            });
+                    }
+                    let request = Reservation {
+                        target: target.name(),
+                        dax_device: target.dax_device(),
+                        explicit,
+                        pages,
+                        align,
+                        template_path: &template_path,
+                        now: occupancy::unix_secs(SystemTime::now()),
+                        image_hash,
+                        guard_pages: rdma_guard_pages(target, batch.options.guard_pages),
+                    };
+                    let pgoff = match reused.as_ref() {
+                        Some(image) => {
+                            registry.share(&request, image.pgoff);
+                            image.pgoff
+                        }
+                        None => registry.reserve(&mut queue.allocator, &request)?,
+                    };
+                    if !batch.options.dry_run {
+                        registry.save()?;
+                    }
+                    pgoff
+                }
+                None => match reused.as_ref() {
+                    Some(image) => image.pgoff,
+                    None => queue
+                        .allocator
+                        .reserve(target.dax_device(), explicit, pages, align)?,
+                },
+            };
+            Ok((target, PageOffset(pgoff), reused))
+        });
+        drop(queue);
+        let (digest, reused) = match (digest, planned.as_ref()) {
+            (Some(digest), Ok((_, _, Some(image)))) => (
+                None,
+                Some(ReusedImage {
+                    image: image.clone(),
+                    digest,
+                }),
+            ),
+            (digest, _) => (digest, None),
+        };
+
+        let entry_deadline = EntryDeadline::new(Instant::now(), batch.limits.entry_timeout);
+        let result = planned.and_then(|(target, rdma_pgoff, _)| {
+            let hva_layout = entry.hva_layout();
+            let args = TemplateArgs {
+                label,
+                snapshot_path: &entry.snapshot_path,
+                mem_files: &entry.mem_file_path,
+                stdin_size: None,
+                output_path: &entry.output_path,
+                target,
+                rdma_pgoff,
+                hva_base: batch.hva_bases[idx],
+                hva_layout: &hva_layout,
+                page_size: batch_page_size(&batch.config, idx),
+                base: entry.base()?,
+                diff_snapshot: entry.diff_snapshot,
+                merge_base: entry.merge_base(),
+                dirty_bitmap: entry.dirty_bitmap.as_deref(),
+                marker_gpa: None,
+                dedup: batch.dedup.as_ref(),
+                reused: reused.as_ref(),
+                skip_upload: entry.skip_upload,
+                mem_pages: entry.mem_pages,
+                snapshot_data_version: batch.options.snapshot_data_version,
+                validate_snapshot: batch.options.validate_snapshot
+                    || batch.config.validate_snapshot,
+                instance_registry: &batch.options.instance_registry,
+                pgoff_namespace: batch.pgoff_namespace.as_ref(),
+                pgoff_limit: batch.pgoff_limits.get(target.name()).copied(),
+                free_extents: None,
+                guard_pages: batch.options.guard_pages,
+                upload: &batch.options.upload,
+                progress_style: ProgressStyle::detect(batch.options.jobs > 1),
+                coalesce_regions: batch.options.coalesce_regions,
+                create_pseudo_mm: batch.options.create_pseudo_mm,
+                force: batch.force(idx),
+                lock_wait: batch.options.lock_wait,
+                entry_deadline,
+                cancel: &batch.limits.cancel,
+            };
+            if batch.options.dry_run {
+                dry_run_template(&args).map(EntryStatus::Planned)
+            } else {
+                create_template(&args, &metrics).map(EntryStatus::Created)
+            }
+        });
+        let status = match result {
+            Ok(status) => {
+                metrics.finish(EntryOutcome::Succeeded);
+                if let EntryStatus::Created(ref result) = status {
+                    let mut queue = batch.queue.lock().expect("Poisoned lock");
+                    queue
+                        .estimator
+                        .record(result.mem_size, entry_deadline.elapsed());
+                    if let Some(digest) = digest.as_ref() {
+                        queue.images.add(
+                            &result.target,
+                            &digest.hash,
+                            UploadedImage {
+                                pgoff: result.rdma_pgoff.raw(),
+                                owner: label.to_string(),
+                            },
+                        );
+                    }
+                }
+                status
+            }
+            Err(err) if is_entry_timeout(err.as_ref()) => {
+                // Nothing was written for it; move on to the next entry.
+                println!("  timed out: {}", err);
+                metrics.finish(EntryOutcome::TimedOut);
+                EntryStatus::TimedOut(format!("timed out: {}", err))
+            }
+            Err(err) => {
+                // Entries not started yet never run, unless this one only
+                // refused to replace its output. Those in flight finish
+                // unless --fail-fast cancels them.
+                if batch.options.fail_fast || !is_output_exists(err.as_ref()) {
+                    batch.queue.lock().expect("Poisoned lock").stopped = true;
+                }
+                if is_cancelled(err.as_ref()) {
+                    metrics.finish(EntryOutcome::Cancelled);
+                    EntryStatus::Cancelled(format!("cancelled: {}", err))
+                } else {
+                    if batch.options.fail_fast {
+                        batch.limits.cancel.cancel();
+                    }
+                    println!("  failed   : {}", err);
+                    metrics.finish(EntryOutcome::Failed);
+                    EntryStatus::Failed(
+                        format!("failed: {}", err),
+                        template_error::find(err.as_ref()).cloned(),
+                    )
+                }
+            }
+        };
+        batch.report(idx, worker, status);
+    }
+}
+
+impl Batch {
+    /// Whether entry `idx` may overwrite its output path: with `--force`,
+    /// or when `--regenerate-stale` found its template stale.
+    fn force(&self, idx: usize) -> bool {
+        self.options.force || self.stale[idx].is_some()
+    }
+
+    fn report(&self, idx: usize, worker: usize, status: EntryStatus) {
+        self.reports.lock().expect("Poisoned lock")[idx] = Some((worker, status));
+    }
+}
+
+/// Loads the batch config at `path`, expanding environment variables in its
+/// paths if `expand_env`.
+pub fn load_batch_config(
+    path: &str,
+    expand_env: bool,
+) -> Result<BatchConfig, Box<dyn std::error::Error>> {
+    let mut config: BatchConfig = serde_json::from_reader(File::open(path)?)?;
+    let lookup: &dyn Fn(&str) -> Option<String> = &env_expand::from_env;
+    config.resolve(if expand_env { Some(lookup) } else { None })?;
+    Ok(config)
+}
+
+/// Outcome of a batch, as written by `--summary-output`.
+#[derive(Serialize)]
+struct BatchSummary<'a> {
+    entries: Vec<SummaryEntry<'a>>,
+    next_rdma_pgoff: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    next_dax_pgoffs: BTreeMap<&'a str, u64>,
+    /// Totals of `--dedup` over the entries with a template.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dedup: Option<DedupSummary>,
+    /// Set for a dry run, so that `--resume-from` refuses it.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+}
+
+/// A `BatchSummary` entry, summarized by this run or carried over as the
+/// summary resumed from recorded it.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum SummaryEntry<'a> {
+    Run(Box<EntrySummary<'a>>),
+    Resumed(&'a serde_json::Value),
+}
+
+fn write_batch_summary(
+    path: &Path,
+    summary: &BatchSummary,
+    lock_wait: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let json = serde_json::to_string_pretty(summary)?;
+    output_lock::write_locked(path, json.as_bytes(), lock_wait)?;
+    println!("Batch summary written to {}", path.display());
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct BatchConfig {
+    /// `host:port` or `unix:PATH`, as for `--rdma-server`.
+    #[serde(default)]
+    pub rdma_server: Option<String>,
+    /// Backend of entries that don't pick one (default: rdma).
+    #[serde(default)]
+    pub mem_type: Option<MemBackend>,
+    #[serde(default)]
+    pub dax_device: Option<String>,
+    #[serde(default)]
+    pub default_rdma_pgoff: Option<PageOffset>,
+    /// See `--pgoff-align`.
+    #[serde(default)]
+    pub pgoff_align: Option<u64>,
+    #[serde(default)]
+    pub hva_base: Option<HvaAddr>,
+    /// Page size of entries that don't pick one (default: 4k).
+    #[serde(default)]
+    pub page_size: Option<PageSize>,
+    /// Validate every entry's snapshot, as with `--validate-snapshot`.
+    #[serde(default)]
+    pub validate_snapshot: bool,
+    #[serde(default)]
+    pub templates: Vec<BatchTemplateEntry>,
+}
+
+impl BatchConfig {
+    /// Expands the environment variables in the config's paths with
+    /// `lookup`, or keeps them as written without one, replaces entries
+    /// with a `snapshot_glob` by their matches and finds the entries' memory
+    /// file shards. See `env_expand` and `snapshot_glob`.
+    fn resolve(&mut self, lookup: Option<&dyn Fn(&str) -> Option<String>>) -> io::Result<()> {
+        let expand = |value: &str| match lookup {
+            Some(lookup) => env_expand::expand(value, lookup),
+            None => Ok(value.to_string()),
+        };
+        let expand_field = |field: &str, value: &mut String| {
+            *value = expand(value).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", field, err))
+            })?;
+            Ok::<(), io::Error>(())
+        };
+        let check_page_size = |field: &str, page_size: Option<PageSize>| {
+            page_size
+                .map_or(Ok(()), PageSize::check_supported)
+                .map_err(|reason| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("{} {}", field, reason))
+                })
+        };
+        if let Some(server) = self.rdma_server.as_mut() {
+            expand_field("rdma_server", server)?;
+        }
+        check_page_size("page_size", self.page_size)?;
+        let written = std::mem::replace(&mut self.templates, Vec::new());
+        for (idx, mut entry) in written.into_iter().enumerate() {
+            let field = |name: &str| format!("templates[{}].{}", idx, name);
+            entry.check_skip_upload().map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("templates[{}].{}", idx, err),
+                )
+            })?;
+            check_page_size(&field("page_size"), entry.page_size)?;
+            expand_field(&field("output_path"), &mut entry.output_path)?;
+            if let Some(server) = entry.rdma_server.as_mut() {
+                expand_field(&field("rdma_server"), server)?;
+            }
+            let mut pattern = match entry.snapshot_glob.take() {
+                Some(pattern) if entry.snapshot_path.is_empty() => pattern,
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{}: can't be given with snapshot_path",
+                            field("snapshot_glob")
+                        ),
+                    ))
+                }
+                None => {
+                    expand_field(&field("snapshot_path"), &mut entry.snapshot_path)?;
+                    entry.mem_file_path.resolve(&expand).map_err(|err| {
+                        io::Error::new(err.kind(), format!("{}: {}", field("mem_file_path"), err))
+                    })?;
+                    self.templates.push(entry);
+                    continue;
+                }
+            };
+            expand_field(&field("snapshot_glob"), &mut pattern)?;
+            let matches = snapshot_glob::matching(&pattern).map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", field("snapshot_glob"), err))
+            })?;
+            let first = self.templates.len();
+            for snapshot in matches {
+                let derived = entry.derive(&snapshot, &expand).map_err(|err| {
+                    io::Error::new(err.kind(), format!("templates[{}]: {}", idx, err))
+                })?;
+                if let Some(other) = self.templates[first..]
+                    .iter()
+                    .find(|other| other.output_path == derived.output_path)
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{}: {} and {} both write {}; use {{stem}} in it",
+                            field("output_path"),
+                            other.snapshot_path,
+                            derived.snapshot_path,
+                            derived.output_path
+                        ),
+                    ));
+                }
+                self.templates.push(derived);
+            }
+        }
+        self.assign_labels()
+    }
+
+    /// Gives every entry a label, failing if two share one. Entries without
+    /// their own are named after their snapshot's file stem, followed by
+    /// `-<position>` when the stem would name more than one entry.
+    fn assign_labels(&mut self) -> io::Result<()> {
+        let stems: Vec<Option<String>> = self
+            .templates
+            .iter()
+            .map(|entry| match entry.label {
+                Some(_) => None,
+                None => Path::new(&entry.snapshot_path)
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .map(str::to_string),
+            })
+            .collect();
+        let mut labels: Vec<String> = Vec::with_capacity(self.templates.len());
+        for (idx, entry) in self.templates.iter().enumerate() {
+            let label = match (entry.label.as_ref(), stems[idx].as_ref()) {
+                (Some(label), _) => label.clone(),
+                (None, Some(stem)) => {
+                    let shared = self.templates.iter().zip(&stems).enumerate().any(
+                        |(other, (other_entry, other_stem))| {
+                            other != idx
+                                && (other_entry.label.as_ref() == Some(stem)
+                                    || other_stem.as_ref() == Some(stem))
+                        },
+                    );
+                    if shared {
+                        format!("{}-{}", stem, idx + 1)
+                    } else {
+                        stem.clone()
+                    }
+                }
+                (None, None) => format!("batch-{}", idx + 1),
+            };
+            if let Some(first) = labels.iter().position(|other| *other == label) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "templates[{}].label: '{}' is already the label of templates[{}]",
+                        idx, label, first
+                    ),
+                ));
+            }
+            labels.push(label);
+        }
+        for (entry, label) in self.templates.iter_mut().zip(labels) {
+            entry.label = Some(label);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct BatchTemplateEntry {
+    /// Names the entry in output; see `BatchConfig::assign_labels`.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Set once the config is loaded; see `snapshot_glob`.
+    #[serde(default)]
+    pub snapshot_path: String,
+    /// Stands for an entry per matching snapshot instead of `snapshot_path`;
+    /// see `snapshot_glob`.
+    #[serde(default)]
+    pub snapshot_glob: Option<String>,
+    /// A path as for `--mem-file-path`, or an array of shard paths.
+    pub mem_file_path: MemFiles,
+    pub output_path: String,
+    #[serde(default)]
+    pub rdma_pgoff: Option<PageOffset>,
+    #[serde(default)]
+    pub rdma_server: Option<String>,
+    #[serde(default)]
+    pub hva_base: Option<HvaAddr>,
+    #[serde(default)]
+    pub mem_type: Option<MemBackend>,
+    #[serde(default)]
+    pub dax_device: Option<String>,
+    #[serde(default)]
+    pub page_size: Option<PageSize>,
+    /// See `--region-stride`.
+    #[serde(default)]
+    pub region_stride: Option<u64>,
+    /// See `--region-hva`.
+    #[serde(default)]
+    pub region_hvas: Vec<RegionHva>,
+    /// See `--base-template`; needs `base_mem_file`.
+    #[serde(default)]
+    pub base_template: Option<String>,
+    /// See `--base-mem-file`.
+    #[serde(default)]
+    pub base_mem_file: Option<String>,
+    /// See `--diff-snapshot`.
+    #[serde(default)]
+    pub diff_snapshot: bool,
+    /// See `--dirty-bitmap`; needs `base_template`.
+    #[serde(default)]
+    pub dirty_bitmap: Option<String>,
+    /// See `--skip-upload`; needs `rdma_pgoff`.
+    #[serde(default)]
+    pub skip_upload: bool,
+    /// See `--mem-pages`; needs `skip_upload`.
+    #[serde(default)]
+    pub mem_pages: Option<u64>,
+}
+
+impl BatchTemplateEntry {
+    /// Set for every entry once the config is loaded.
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or_default()
+    }
+
+    /// The entry that a `snapshot_glob` entry, with its `output_path`
+    /// expanded, stands for at `snapshot`. `expand` is applied to
+    /// `mem_file_path` before its placeholders are filled.
+    fn derive(
+        &self,
+        snapshot: &str,
+        expand: &dyn Fn(&str) -> Result<String, String>,
+    ) -> io::Result<Self> {
+        let invalid = |field: &str, err: String| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", field, err))
+        };
+        let mut entry = self.clone();
+        entry.snapshot_path = snapshot.to_string();
+        entry.output_path = snapshot_glob::fill(&self.output_path, snapshot)
+            .map_err(|err| invalid("output_path", err))?;
+        if let Some(label) = self.label.as_ref() {
+            entry.label =
+                Some(snapshot_glob::fill(label, snapshot).map_err(|err| invalid("label", err))?);
+        }
+        entry
+            .mem_file_path
+            .resolve(&|value: &str| snapshot_glob::fill(&expand(value)?, snapshot))
+            .map_err(|err| io::Error::new(err.kind(), format!("mem_file_path: {}", err)))?;
+        if let Some(missing) = entry.mem_file_path.first_missing() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("snapshot {} has no memory file {}", snapshot, missing),
+            ));
+        }
+        Ok(entry)
+    }
+
+    /// Bytes of the entry's image: its memory file's size, unless
+    /// `mem_pages` gives it.
+    fn mem_size(&self) -> io::Result<u64> {
+        match self.mem_pages {
+            Some(pages) => pages_to_bytes(pages),
+            None => self.mem_file_path.size(),
+        }
+    }
+
+    /// Fails if `skip_upload` or `mem_pages` are given without what they
+    /// need.
+    fn check_skip_upload(&self) -> Result<(), String> {
+        match (self.skip_upload, self.mem_pages, self.rdma_pgoff) {
+            (false, Some(_), _) => Err("mem_pages: only used with skip_upload".to_string()),
+            (true, _, None) => Err(
+                "skip_upload: needs an explicit rdma_pgoff, where the image already is".to_string(),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn hva_layout(&self) -> HvaLayout {
+        HvaLayout {
+            stride: self.region_stride,
+            overrides: self.region_hvas.clone(),
+        }
+    }
+
+    /// The template the entry is layered on, if any.
+    fn base(&self) -> io::Result<Option<BaseFiles<'_>>> {
+        match (self.base_template.as_ref(), self.base_mem_file.as_ref()) {
+            (Some(template), Some(mem_file)) => Ok(Some(BaseFiles {
+                template,
+                mem_file: Some(mem_file),
+            })),
+            (None, None) if self.diff_snapshot => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "diff_snapshot needs base_mem_file",
+            )),
+            (None, None) => Ok(None),
+            (None, Some(_)) if self.diff_snapshot => Ok(None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "base_template and base_mem_file must be given together",
+            )),
+        }
+    }
+
+    /// The full memory file a diff snapshot entry is merged with, if it
+    /// isn't layered on a base template.
+    fn merge_base(&self) -> Option<&str> {
+        match (self.diff_snapshot, self.base_template.as_ref()) {
+            (true, None) => self.base_mem_file.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm::pseudo_mm_support::RegionMetadata;
+
+    use crate::layered::LayerStats;
+    use crate::template_error::TemplateError;
+    use crate::test_files;
+    use crate::test_templates;
+    use crate::TemplateResult;
+
+    fn batch_entry(mem_file_path: &str, rdma_pgoff: Option<u64>) -> BatchTemplateEntry {
+        BatchTemplateEntry {
+            label: None,
+            snapshot_path: "vm.snap".to_string(),
+            snapshot_glob: None,
+            mem_file_path: MemFiles::new(vec![mem_file_path.to_string()]),
+            output_path: "out.json".to_string(),
+            rdma_pgoff: rdma_pgoff.map(PageOffset),
+            rdma_server: None,
+            hva_base: None,
+            mem_type: None,
+            dax_device: None,
+            page_size: None,
+            region_stride: None,
+            region_hvas: Vec::new(),
+            base_template: None,
+            base_mem_file: None,
+            diff_snapshot: false,
+            dirty_bitmap: None,
+            skip_upload: false,
+            mem_pages: None,
+        }
+    }
+
+    #[test]
+    fn test_entry_summary() {
+        let mut entry = batch_entry("vm.mem", None);
+        entry.label = Some("fn-a".to_string());
+        let created = Some((
+            1,
+            EntryStatus::Created(TemplateResult {
+                pseudo_mm_id: Some(7),
+                backend: MemBackend::Rdma,
+                target: "10.0.0.1:9000".to_string(),
+                rdma_pgoff: PageOffset(4096),
+                hva_base: HvaAddr(0x7100_0000_0000),
+                mem_pages: 32,
+                mem_size: 32 * PAGE_SIZE,
+                upload_time: Duration::from_millis(1500),
+                total_time: Duration::from_secs(2),
+                cache_peak: None,
+                output_path: "out.json".to_string(),
+                regions: vec![RegionMetadata {
+                    hva: HvaAddr(0x7100_0000_0000),
+                    ..test_templates::region(0, 32, 4096)
+                }],
+                layered: Some(LayerStats {
+                    base_template: "/srv/base.json".to_string(),
+                    overlay_pages: 32,
+                    shared_pages: 480,
+                    dirty_pages: None,
+                    clean_pages: None,
+                }),
+                dedup: None,
+                reused_from: None,
+            }),
+        ));
+        let summary = entry_summary(&entry, &created);
+        assert_eq!(summary.label, "fn-a");
+        assert_eq!((summary.status, summary.error), ("ok", None));
+        assert_eq!(summary.pseudo_mm_id, Some(7));
+        assert_eq!(summary.rdma_pgoff, Some(PageOffset(4096)));
+        assert_eq!(summary.hva_base, Some(HvaAddr(0x7100_0000_0000)));
+        assert_eq!(
+            (summary.pages, summary.bytes),
+            (Some(32), Some(32 * PAGE_SIZE))
+        );
+        assert_eq!(summary.upload_secs, Some(1.5));
+        assert_eq!(summary.total_secs, Some(2.0));
+        assert_eq!(summary.end_pgoff, Some(4096 + 32));
+        assert_eq!(summary.regions.map(<[_]>::len), Some(1));
+        assert_eq!(summary.layered.map(|stats| stats.shared_pages), Some(480));
+        assert_eq!(summary.snapshot_path, "vm.snap");
+
+        let failed = Some((2, EntryStatus::Failed("failed: refused".to_string(), None)));
+        let summary = entry_summary(&entry, &failed);
+        assert_eq!(
+            (summary.status, summary.error, summary.error_kind),
+            ("failed", Some("failed: refused"), Some("Other"))
+        );
+        let refused = TemplateError::RdmaStatus {
+            status: -libc::EINVAL,
+            message: "refused".to_string(),
+        };
+        let failed = Some((
+            2,
+            EntryStatus::Failed("failed: refused".to_string(), Some(refused)),
+        ));
+        let summary = entry_summary(&entry, &failed);
+        assert_eq!(summary.error_kind, Some("RdmaStatus"));
+        assert_eq!((summary.pseudo_mm_id, summary.rdma_pgoff), (None, None));
+
+        let summary = entry_summary(&entry, &None);
+        assert_eq!(
+            (summary.status, summary.error),
+            ("failed", Some("skipped: not started"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_batch_config() {
+        let config = || {
+            let mut first = batch_entry("vm.mem", None);
+            first.snapshot_path = "${SNAP_ROOT}/a.snap".to_string();
+            first.output_path = "${OUT_ROOT:-/tmp/out}/a.json".to_string();
+            let mut second = batch_entry("vm.mem", None);
+            second.output_path = "${OUT_ROOT}/b.json".to_string();
+            BatchConfig {
+                rdma_server: Some("${RDMA_SERVER:-10.0.0.1:9000}".to_string()),
+                mem_type: None,
+                dax_device: None,
+                default_rdma_pgoff: None,
+                pgoff_align: None,
+                hva_base: None,
+                page_size: None,
+                validate_snapshot: false,
+                templates: vec![first, second],
+            }
+        };
+        let lookup = |name: &str| match name {
+            "SNAP_ROOT" => Some("/srv/snap".to_string()),
+            _ => None,
+        };
+
+        let mut expanded = config();
+        let err = expanded.resolve(Some(&lookup)).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "templates[1].output_path: environment variable OUT_ROOT is not set and has no default"
+        );
+        assert_eq!(expanded.rdma_server.as_deref(), Some("10.0.0.1:9000"));
+        assert_eq!(expanded.templates[0].snapshot_path, "/srv/snap/a.snap");
+        assert_eq!(expanded.templates[0].output_path, "/tmp/out/a.json");
+
+        // --no-env-expand keeps every `$` as written.
+        let mut literal = config();
+        literal.resolve(None).unwrap();
+        assert_eq!(literal.templates[1].output_path, "${OUT_ROOT}/b.json");
+
+        let mut huge = config();
+        huge.templates[1].page_size = Some(PageSize::Huge);
+        let err = huge.resolve(None).unwrap_err().to_string();
+        assert!(
+            err.starts_with("templates[1].page_size 2m is unsupported by the pseudo_mm module"),
+            "{}",
+            err
+        );
+        huge.page_size = Some(PageSize::Huge);
+        let err = huge.resolve(None).unwrap_err().to_string();
+        assert!(err.starts_with("page_size 2m is unsupported"), "{}", err);
+    }
+
+    #[test]
+    fn test_skip_upload_entries() {
+        let config = |entry: BatchTemplateEntry| BatchConfig {
+            rdma_server: Some("10.0.0.1:9000".to_string()),
+            mem_type: None,
+            dax_device: None,
+            default_rdma_pgoff: None,
+            pgoff_align: None,
+            hva_base: None,
+            page_size: None,
+            validate_snapshot: false,
+            templates: vec![batch_entry("vm.mem", None), entry],
+        };
+        let mut skipped = batch_entry("/nonexistent/vm.mem", None);
+        skipped.skip_upload = true;
+        let err = config(skipped.clone())
+            .resolve(None)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "templates[1].skip_upload: needs an explicit rdma_pgoff, where the image already is"
+        );
+        let mut uploaded = batch_entry("vm.mem", Some(0));
+        uploaded.mem_pages = Some(16);
+        let err = config(uploaded).resolve(None).unwrap_err().to_string();
+        assert_eq!(err, "templates[1].mem_pages: only used with skip_upload");
+
+        // The memory file isn't needed for the image's size.
+        skipped.rdma_pgoff = Some(PageOffset(64));
+        skipped.mem_pages = Some(16);
+        let mut config = config(skipped);
+        config.resolve(None).unwrap();
+        assert_eq!(config.templates[1].mem_size().unwrap(), 16 * PAGE_SIZE);
+        let ranges = batch_ranges(&config, 0, 1, 0, None).unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].start, ranges[0].pages), (64, 16));
+        assert!(pages_to_bytes(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_resolve_snapshot_glob() {
+        let dir = test_files::dir("glob");
+        for name in &["fn-10.snap", "fn-2.snap", "fn-2.mem", "fn-10.mem"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let config = |output_path: &str| {
+            let mut stanza = batch_entry("vm.mem", None);
+            stanza.snapshot_path = String::new();
+            stanza.snapshot_glob = Some("${SNAP_ROOT}/fn-*.snap".to_string());
+            stanza.mem_file_path = MemFiles::written_spec("{dir}/{stem}.mem");
+            stanza.output_path = output_path.to_string();
+            stanza.label = Some("vm-{stem}".to_string());
+            BatchConfig {
+                rdma_server: None,
+                mem_type: None,
+                dax_device: None,
+                default_rdma_pgoff: None,
+                pgoff_align: None,
+                hva_base: None,
+                page_size: None,
+                validate_snapshot: false,
+                templates: vec![batch_entry("vm.mem", None), stanza],
+            }
+        };
+        let root = dir.to_string_lossy().into_owned();
+        let lookup = |name: &str| match name {
+            "SNAP_ROOT" => Some(root.clone()),
+            _ => None,
+        };
+
+        let mut expanded = config("/out/{stem}.json");
+        expanded.resolve(Some(&lookup)).unwrap();
+        let entries: Vec<(&str, &str)> = expanded
+            .templates
+            .iter()
+            .map(|entry| (entry.label(), entry.output_path.as_str()))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("vm", "out.json"),
+                ("vm-fn-2", "/out/fn-2.json"),
+                ("vm-fn-10", "/out/fn-10.json"),
+            ]
+        );
+        assert_eq!(
+            expanded.templates[2].snapshot_path,
+            format!("{}/fn-10.snap", root)
+        );
+        assert_eq!(
+            expanded.templates[2].mem_file_path,
+            MemFiles::new(vec![format!("{}/fn-10.mem", root)])
+        );
+
+        let err = config("/out/all.json")
+            .resolve(Some(&lookup))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("both write /out/all.json"), "{}", err);
+
+        std::fs::remove_file(dir.join("fn-10.mem")).unwrap();
+        let err = config("/out/{stem}.json")
+            .resolve(Some(&lookup))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            format!(
+                "templates[1]: snapshot {0}/fn-10.snap has no memory file {0}/fn-10.mem",
+                root
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_assign_labels() {
+        let entry = |snapshot_path: &str, label: Option<&str>| {
+            let mut entry = batch_entry("vm.mem", None);
+            entry.snapshot_path = snapshot_path.to_string();
+            entry.label = label.map(str::to_string);
+            entry
+        };
+        let mut config = BatchConfig {
+            rdma_server: None,
+            mem_type: None,
+            dax_device: None,
+            default_rdma_pgoff: None,
+            pgoff_align: None,
+            hva_base: None,
+            page_size: None,
+            validate_snapshot: false,
+            templates: vec![
+                entry("/snap/fn-a.snapshot", None),
+                entry("/snap/a/vm.snapshot", None),
+                entry("/snap/b/vm.snapshot", None),
+                entry("/snap/c/vm.snapshot", Some("fn-c")),
+                entry("", None),
+            ],
+        };
+        config.assign_labels().unwrap();
+        let labels: Vec<&str> = config.templates.iter().map(|entry| entry.label()).collect();
+        assert_eq!(labels, vec!["fn-a", "vm-2", "vm-3", "fn-c", "batch-5"]);
+
+        config
+            .templates
+            .push(entry("/snap/d/vm.snapshot", Some("fn-a")));
+        let err = config.assign_labels().unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "templates[5].label: 'fn-a' is already the label of templates[0]"
+        );
+    }
+
+    #[test]
+    fn test_check_batch_overlap() {
+        let mem = test_files::page_file("batch_overlap", &[0; 16]);
+        let mem = mem.to_str().unwrap();
+        let mut config = BatchConfig {
+            rdma_server: Some("10.0.0.1:9000".to_string()),
+            mem_type: None,
+            dax_device: None,
+            default_rdma_pgoff: None,
+            pgoff_align: None,
+            hva_base: None,
+            page_size: None,
+            validate_snapshot: false,
+            templates: vec![
+                batch_entry(mem, None),
+                batch_entry(mem, None),
+                // Past everything allocated so far.
+                batch_entry(mem, Some(64)),
+            ],
+        };
+        check_batch_overlap(&config, 0, 1, 0, None).unwrap();
+
+        // An explicit pgoff inside the second entry's automatic range.
+        config.templates.push(batch_entry(mem, Some(20)));
+        // Unreadable entries are left to their worker.
+        config
+            .templates
+            .push(batch_entry("/nonexistent/vm.mem", Some(0)));
+        config.assign_labels().unwrap();
+        let err = check_batch_overlap(&config, 0, 1, 0, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("batch entries overlap"), "{}", err);
+        assert!(err.contains("--allow-overlap"), "{}", err);
+        assert!(
+            err.contains("vm-2 [16, 32) and vm-4 [20, 36) on 10.0.0.1:9000"),
+            "{}",
+            err
+        );
+
+        // A range past the last pgoff fails instead of wrapping to 0.
+        config.templates.truncate(3);
+        let mut top = batch_entry(mem, Some(u64::MAX - 8));
+        top.label = Some("fn-top".to_string());
+        config.templates.push(top);
+        let err = check_batch_overlap(&config, 0, 1, 0, None)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "batch entry fn-top: rdma_pgoff 18446744073709551607 + 16 pages overflows pgoff space"
+        );
+        config.templates[3].rdma_pgoff = Some(PageOffset(u64::MAX - 16));
+        check_batch_overlap(&config, 0, 1, 0, None).unwrap();
+        // So does an automatic one after it.
+        let mut next = batch_entry(mem, None);
+        next.label = Some("fn-next".to_string());
+        config.templates.push(next);
+        let err = check_batch_overlap(&config, 0, 1, 0, None)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("batch entry fn-next: next free pgoff 18446744073709551615"),
+            "{}",
+            err
+        );
+        std::fs::remove_file(mem).unwrap();
+    }
+
+    #[test]
+    fn test_batch_hva_bases() {
+        let mut config = BatchConfig {
+            rdma_server: Some("10.0.0.1:9000".to_string()),
+            mem_type: None,
+            dax_device: None,
+            default_rdma_pgoff: None,
+            pgoff_align: None,
+            hva_base: None,
+            page_size: None,
+            validate_snapshot: false,
+            templates: vec![batch_entry("a.mem", None), batch_entry("b.mem", None)],
+        };
+        config.assign_labels().unwrap();
+        let snapshot = || {
+            Some(vec![GuestMemoryRegionState {
+                base_address: 0,
+                size: 16 * PAGE_SIZE as usize,
+                offset: 0,
+            }])
+        };
+        // The third snapshot, of an entry added below, can't be read.
+        let states = vec![snapshot(), snapshot(), None];
+
+        // Both regions land at hva_base + 0.
+        let err = batch_hva_bases(&config, &states, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("use --auto-hva-stride"), "{}", err);
+        assert!(
+            err.contains("vm-1 [0x700000000000, 0x700000010000) and vm-2"),
+            "{}",
+            err
+        );
+
+        let bases = batch_hva_bases(&config, &states, Some(1 << 30)).unwrap();
+        assert_eq!(
+            bases,
+            vec![DEFAULT_PSEUDO_MM_BASE, HvaAddr(0x7000_4000_0000)]
+        );
+        let err = batch_hva_bases(&config, &states, Some(0x1000))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("smaller than"), "{}", err);
+
+        // Entries with their own hva_base keep it, and unreadable
+        // snapshots are left to their worker.
+        config.templates[1].hva_base = Some(HvaAddr(0x7100_0000_0000));
+        config.templates.push(batch_entry("c.mem", None));
+        let bases = batch_hva_bases(&config, &states, None).unwrap();
+        assert_eq!(bases[1], HvaAddr(0x7100_0000_0000));
+        assert_eq!(bases[2], DEFAULT_PSEUDO_MM_BASE);
+    }
+}
@@ -0,0 +1,108 @@
+//! Keeping layouts within what the RDMA server can hold.
+//!
+//! The server registers a fixed amount of memory for images. A pgoff past
+//! its end is refused with a bare non-zero ack or, worse, accepted and only
+//! fails once the guest faults the page in. The server can't be asked for
+//! its size, so `--max-image-pages` gives it: a batch fails before anything
+//! is reserved if the planned layout doesn't fit, and entries are checked
+//! again when planned, since `--registry` can move automatic ranges past
+//! what the batch planned.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::pgoff_alloc::PlannedRange;
+
+/// Fails if `pages` from `pgoff` go past `limit`.
+pub fn check_range(pgoff: u64, pages: u64, limit: u64) -> io::Result<()> {
+    let fits = pgoff.checked_add(pages).map_or(false, |end| end <= limit);
+    if fits {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "rdma_pgoff range [{}, +{}) goes past the server's {} pages",
+            pgoff, pages, limit
+        ),
+    ))
+}
+
+/// Fails with every one of `ranges` that goes past the limit of its server
+/// in `limits`, naming entries by `label`.
+pub fn check_ranges(
+    ranges: &[PlannedRange],
+    limits: &BTreeMap<&str, u64>,
+    label: &dyn Fn(usize) -> String,
+) -> io::Result<()> {
+    let mut msg = String::new();
+    for range in ranges {
+        let limit = match limits.get(range.target) {
+            Some(&limit) => limit,
+            None => continue,
+        };
+        if check_range(range.start, range.pages, limit).is_ok() {
+            continue;
+        }
+        msg.push_str(&format!(
+            "\n  {} [{}, {}) on {}, which holds {} pages",
+            label(range.entry),
+            range.start,
+            range.end(),
+            range.target,
+            limit
+        ));
+    }
+    if msg.is_empty() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("batch entries don't fit on their server:{}", msg),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_ranges() {
+        check_range(0, 100, 100).unwrap();
+        let err = check_range(50, 100, 100).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "rdma_pgoff range [50, +100) goes past the server's 100 pages"
+        );
+        assert!(check_range(u64::MAX, 1, u64::MAX).is_err());
+
+        let range = |entry, target, start, pages| PlannedRange {
+            entry,
+            target,
+            start,
+            pages,
+        };
+        let ranges = [
+            range(0, "10.0.0.1:9000", 0, 100),
+            range(1, "10.0.0.1:9000", 100, 50),
+            range(2, "10.0.0.2:9000", 0, 1000),
+            // No limit known.
+            range(3, "10.0.0.3:9000", 0, 1000),
+        ];
+        let mut limits = BTreeMap::new();
+        limits.insert("10.0.0.1:9000", 150);
+        limits.insert("10.0.0.2:9000", 1000);
+        let label = |entry: usize| format!("fn-{}", entry);
+        check_ranges(&ranges, &limits, &label).unwrap();
+
+        limits.insert("10.0.0.1:9000", 120);
+        let err = check_ranges(&ranges, &limits, &label)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "batch entries don't fit on their server:\n  \
+             fn-1 [100, 150) on 10.0.0.1:9000, which holds 120 pages"
+        );
+    }
+}
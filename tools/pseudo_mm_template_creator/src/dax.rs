@@ -0,0 +1,237 @@
+//! Copying memory images into a DAX device.
+//!
+//! DAX templates map their pages from a local device instead of fetching
+//! them from the RDMA memory server, so the image is copied into the device
+//! rather than uploaded. Device-dax character devices don't support
+//! `write(2)`, only `mmap`, so the copy goes through a shared mapping; a file
+//! on a DAX-mounted filesystem works the same way.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+
+use vmm::pseudo_mm_addr::PageOffset;
+
+use crate::page_cache::{self, CacheFootprint};
+use crate::{PAGE_SIZE, UPLOAD_CHUNK};
+
+/// Mappings of a device-dax device must start and end on its alignment,
+/// which is at most 2 MiB.
+const DAX_ALIGN: u64 = 2 << 20;
+
+/// Size in bytes of a device-dax device, or of a file on a DAX filesystem.
+pub fn device_size(device: &File) -> io::Result<u64> {
+    let metadata = device.metadata()?;
+    if !metadata.file_type().is_char_device() {
+        return Ok(metadata.len());
+    }
+    // Device-dax reports no size through stat or lseek; sysfs has it.
+    let rdev = metadata.rdev();
+    let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0xfff);
+    let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0xff);
+    let path = format!("/sys/dev/char/{}:{}/size", major, minor);
+    let size = std::fs::read_to_string(&path)?;
+    size.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid size '{}' in {}", size.trim(), path),
+        )
+    })
+}
+
+/// Shared mapping of a device range, unmapped on drop.
+struct Mapping {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(device: &File, offset: u64, len: u64) -> io::Result<Self> {
+        // Safe because the kernel picks a fresh range, which is only
+        // accessed through this `Mapping` and unmapped when it drops.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                device.as_raw_fd(),
+                offset as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            addr: addr as *mut u8,
+            len: len as usize,
+        })
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safe because the mapping is `len` bytes long and borrowed mutably.
+        unsafe { std::slice::from_raw_parts_mut(self.addr, self.len) }
+    }
+
+    /// Flushes `[offset, offset + len)` of the mapping to the device.
+    fn sync(&self, offset: usize, len: usize) -> io::Result<()> {
+        // Safe because the range lies within the mapping.
+        let ret = unsafe {
+            libc::msync(
+                self.addr.add(offset) as *mut libc::c_void,
+                len,
+                libc::MS_SYNC,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // Safe because `addr` and `len` describe a mapping we own.
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Copies bytes `[range.0, range.1)` of `reader` into `device` at page
+/// `pgoff`, calling `progress` after each chunk with the number of bytes
+/// copied so far.
+pub fn copy_to_dax(
+    reader: &mut File,
+    range: (u64, u64),
+    device: &File,
+    pgoff: PageOffset,
+    drop_cache_behind: bool,
+    footprint: &mut CacheFootprint,
+    progress: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    let size = range.1 - range.0;
+    let device_size = device_size(device)?;
+    let start = pgoff.raw().checked_mul(PAGE_SIZE);
+    let end = start.and_then(|start| start.checked_add(size));
+    let (start, end) = match (start, end) {
+        (Some(start), Some(end)) if end <= device_size => (start, end),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "image of {} bytes at pgoff {} does not fit in the {} byte DAX device",
+                    size, pgoff, device_size
+                ),
+            ))
+        }
+    };
+
+    let map_start = start & !(DAX_ALIGN - 1);
+    let map_len = (end - map_start + DAX_ALIGN - 1) & !(DAX_ALIGN - 1);
+    let mut mapping = Mapping::new(device, map_start, map_len)?;
+    let image_offset = (start - map_start) as usize;
+    let image = &mut mapping.as_mut_slice()[image_offset..image_offset + size as usize];
+
+    reader.seek(SeekFrom::Start(range.0))?;
+    let mut copied = 0;
+    while copied < image.len() {
+        let chunk = std::cmp::min(UPLOAD_CHUNK, image.len() - copied);
+        let read = match reader.read(&mut image[copied..copied + chunk]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "expected to copy {} bytes but the memory file ended after {} bytes",
+                        size, copied
+                    ),
+                ))
+            }
+            Ok(read) => read,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        footprint.sample();
+        if drop_cache_behind {
+            page_cache::drop_range(reader, range.0 + copied as u64, read as u64)?;
+        }
+        copied += read;
+        progress(copied as u64)?;
+    }
+    mapping.sync(image_offset, size as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+    use std::path::PathBuf;
+
+    fn open_rw(path: &PathBuf) -> File {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_copy_into_file() {
+        let page = PAGE_SIZE as usize;
+        let image: Vec<u8> = (0..2 * page).map(|idx| (idx / page + 1) as u8).collect();
+        let mem_path = test_files::file("dax_copy_mem", &image);
+        let dev_path = test_files::file("dax_copy_dev", &vec![0u8; 8 * page]);
+        let device = open_rw(&dev_path);
+        assert_eq!(device_size(&device).unwrap(), 8 * PAGE_SIZE);
+
+        let mut progress = Vec::new();
+        copy_to_dax(
+            &mut File::open(&mem_path).unwrap(),
+            (0, image.len() as u64),
+            &device,
+            PageOffset(3),
+            false,
+            &mut CacheFootprint::start(),
+            &mut |bytes| {
+                progress.push(bytes);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(progress, vec![2 * PAGE_SIZE]);
+
+        let contents = std::fs::read(&dev_path).unwrap();
+        assert!(contents[..3 * page].iter().all(|&b| b == 0));
+        assert_eq!(&contents[3 * page..5 * page], &image[..]);
+        assert!(contents[5 * page..].iter().all(|&b| b == 0));
+        std::fs::remove_file(&mem_path).unwrap();
+        std::fs::remove_file(&dev_path).unwrap();
+    }
+
+    #[test]
+    fn test_copy_out_of_range() {
+        let page = PAGE_SIZE as usize;
+        let mem_path = test_files::file("dax_range_mem", &vec![1u8; 2 * page]);
+        let dev_path = test_files::file("dax_range_dev", &vec![0u8; 4 * page]);
+        let device = open_rw(&dev_path);
+
+        for &pgoff in &[3, u64::max_value()] {
+            let err = copy_to_dax(
+                &mut File::open(&mem_path).unwrap(),
+                (0, 2 * PAGE_SIZE),
+                &device,
+                PageOffset(pgoff),
+                false,
+                &mut CacheFootprint::start(),
+                &mut |_| Ok(()),
+            )
+            .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(std::fs::read(&dev_path).unwrap().iter().all(|&b| b == 0));
+        std::fs::remove_file(&mem_path).unwrap();
+        std::fs::remove_file(&dev_path).unwrap();
+    }
+}
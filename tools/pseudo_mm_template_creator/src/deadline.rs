@@ -0,0 +1,424 @@
+//! Wall-clock deadlines for batch runs.
+//!
+//! A batch given `--deadline` defers entries that are not expected to finish
+//! before it, based on the throughput of the entries already created, so the
+//! run stops at an entry boundary instead of being killed mid-upload.
+//! `--entry-timeout` bounds a single entry: it is checked between upload
+//! chunks and between regions, and an entry that runs over is abandoned
+//! before its template is written.
+//!
+//! Everything here takes the current time as an argument, so tests can
+//! inject clocks.
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use vmm::pseudo_mm_cancel::CancelToken;
+
+/// Limits applied to a run, from `--deadline` and `--entry-timeout`.
+#[derive(Clone)]
+pub struct RunLimits {
+    pub deadline: Option<SystemTime>,
+    pub entry_timeout: Option<Duration>,
+    /// Stops the run at the next chunk or region when cancelled.
+    pub cancel: CancelToken,
+}
+
+/// Completed entries the throughput estimate is based on.
+const RECENT_ENTRIES: usize = 4;
+
+/// Parses `--deadline`: an RFC 3339 timestamp such as
+/// `2026-10-15T05:00:00Z`, or `+` and a duration relative to `now`, such as
+/// `+90m` (suffixes `s`, `m`, `h`; seconds without one).
+pub fn parse_deadline(spec: &str, now: SystemTime) -> Result<SystemTime, String> {
+    if spec.starts_with('+') {
+        return parse_duration(&spec[1..])
+            .map(|duration| now + duration)
+            .ok_or_else(|| format!("invalid relative deadline '{}'", spec));
+    }
+    parse_rfc3339(spec).ok_or_else(|| {
+        format!(
+            "invalid deadline '{}': expected RFC 3339 (2026-10-15T05:00:00Z) or +DURATION",
+            spec
+        )
+    })
+}
+
+fn parse_duration(spec: &str) -> Option<Duration> {
+    let (digits, unit) = match spec.chars().last()? {
+        's' => (&spec[..spec.len() - 1], 1),
+        'm' => (&spec[..spec.len() - 1], 60),
+        'h' => (&spec[..spec.len() - 1], 3600),
+        _ => (spec, 1),
+    };
+    let value: u64 = digits.parse().ok()?;
+    Some(Duration::from_secs(value.checked_mul(unit)?))
+}
+
+fn parse_rfc3339(spec: &str) -> Option<SystemTime> {
+    let bytes = spec.as_bytes();
+    if bytes.len() < 20 || bytes[4] != b'-' || bytes[7] != b'-' || bytes[13] != b':' {
+        return None;
+    }
+    if !(bytes[10] == b'T' || bytes[10] == b't' || bytes[10] == b' ') || bytes[16] != b':' {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = spec.get(range)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Fractional seconds are accepted and dropped.
+    let mut rest = &spec[19..];
+    if rest.starts_with('.') {
+        let digits = rest[1..].bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        rest = &rest[1 + digits..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let hours: i64 = rest[1..3].parse().ok()?;
+            let minutes: i64 = rest[4..6].parse().ok()?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+        _ => return None,
+    };
+
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// Formats `time` as RFC 3339 in UTC, to the second, such as
+/// `2026-10-15T05:00:00Z`.
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()) as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days between 1970-01-01 and the given date in the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date `days` after 1970-01-01; the inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Estimates entry durations from the throughput of recent entries.
+#[derive(Default)]
+pub struct ThroughputEstimator {
+    recent: VecDeque<(u64, Duration)>,
+}
+
+impl ThroughputEstimator {
+    /// Records that an entry with `bytes` of memory took `elapsed` overall.
+    pub fn record(&mut self, bytes: u64, elapsed: Duration) {
+        if self.recent.len() == RECENT_ENTRIES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((bytes, elapsed));
+    }
+
+    /// Expected duration of an entry with `bytes` of memory, once at least
+    /// one entry has been recorded.
+    pub fn estimate(&self, bytes: u64) -> Option<Duration> {
+        let total_bytes: u64 = self.recent.iter().map(|(bytes, _)| bytes).sum();
+        let total_secs: f64 = self
+            .recent
+            .iter()
+            .map(|(_, elapsed)| elapsed.as_secs_f64())
+            .sum();
+        if total_bytes == 0 || total_secs == 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            bytes as f64 * total_secs / total_bytes as f64,
+        ))
+    }
+}
+
+/// Whether an entry may start at `now` given the run's deadline.
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    Run,
+    /// The entry would not finish in the time left, or the deadline passed.
+    Defer {
+        estimate: Option<Duration>,
+        left: Duration,
+    },
+}
+
+/// Decides whether an entry expected to take `estimate` may start at `now`.
+///
+/// Without an estimate (nothing finished yet) the entry runs as long as the
+/// deadline hasn't passed.
+pub fn admit(
+    now: SystemTime,
+    deadline: Option<SystemTime>,
+    estimate: Option<Duration>,
+) -> Admission {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Admission::Run,
+    };
+    let left = deadline.duration_since(now).unwrap_or_default();
+    match estimate {
+        _ if left == Duration::from_secs(0) => Admission::Defer { estimate, left },
+        Some(needed) if needed > left => Admission::Defer { estimate, left },
+        _ => Admission::Run,
+    }
+}
+
+/// Time budget of one entry, from `--entry-timeout`.
+#[derive(Clone, Copy)]
+pub struct EntryDeadline {
+    start: Instant,
+    timeout: Option<Duration>,
+}
+
+impl EntryDeadline {
+    pub fn new(start: Instant, timeout: Option<Duration>) -> Self {
+        EntryDeadline { start, timeout }
+    }
+
+    /// Time left at `now`, if the entry has a timeout.
+    pub fn remaining_at(&self, now: Instant) -> Option<Duration> {
+        self.timeout.map(|timeout| {
+            let elapsed = now.checked_duration_since(self.start).unwrap_or_default();
+            timeout.checked_sub(elapsed).unwrap_or_default()
+        })
+    }
+
+    /// Time since the entry started.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub fn expired_at(&self, now: Instant) -> bool {
+        self.remaining_at(now) == Some(Duration::from_secs(0))
+    }
+
+    pub fn expired(&self) -> bool {
+        self.expired_at(Instant::now())
+    }
+
+    /// Fails with `TimedOut` once the entry has used up its timeout.
+    pub fn check(&self) -> io::Result<()> {
+        if self.expired() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                EntryTimedOut(self.timeout.unwrap_or_default()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The error `EntryDeadline::check` fails with, told apart from the
+/// `TimedOut` errors of sockets and the module by `is_entry_timeout`.
+#[derive(Debug)]
+pub struct EntryTimedOut(Duration);
+
+impl fmt::Display for EntryTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "entry exceeded --entry-timeout of {}s", self.0.as_secs())
+    }
+}
+
+impl error::Error for EntryTimedOut {}
+
+/// Whether `err` is, or wraps, the error of an entry running over its
+/// `--entry-timeout`.
+pub fn is_entry_timeout(err: &io::Error) -> bool {
+    err.get_ref()
+        .map_or(false, |inner| inner.is::<EntryTimedOut>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_parse_deadline() {
+        let now = at(1_000);
+        assert_eq!(parse_deadline("+90", now), Ok(at(1_090)));
+        assert_eq!(parse_deadline("+30m", now), Ok(at(2_800)));
+        assert_eq!(parse_deadline("+2h", now), Ok(at(8_200)));
+        assert!(parse_deadline("+", now).is_err());
+        assert!(parse_deadline("+5d", now).is_err());
+
+        assert_eq!(parse_deadline("1970-01-01T00:00:00Z", now), Ok(at(0)));
+        assert_eq!(
+            parse_deadline("2026-10-15T05:00:00Z", now),
+            Ok(at(1_792_040_400))
+        );
+        assert_eq!(
+            parse_deadline("2026-10-15T07:00:00.250+02:00", now),
+            Ok(at(1_792_040_400))
+        );
+        assert_eq!(
+            parse_deadline("2024-02-29T00:00:00Z", now),
+            Ok(at(1_709_164_800))
+        );
+        assert!(parse_deadline("2023-02-29T00:00:00Z", now).is_err());
+        assert!(parse_deadline("2026-10-15T05:00:00", now).is_err());
+        assert!(parse_deadline("2026-10-15 25:00:00Z", now).is_err());
+        assert!(parse_deadline("tomorrow", now).is_err());
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(at(0)), "1970-01-01T00:00:00Z");
+        assert_eq!(format_rfc3339(at(1_792_040_400)), "2026-10-15T05:00:00Z");
+        assert_eq!(
+            format_rfc3339(at(1_709_164_800 + 86399)),
+            "2024-02-29T23:59:59Z"
+        );
+        for &secs in &[951_782_400, 1_234_567_890, 4_102_444_799] {
+            let formatted = format_rfc3339(at(secs));
+            assert_eq!(parse_rfc3339(&formatted), Some(at(secs)), "{}", formatted);
+        }
+    }
+
+    #[test]
+    fn test_estimator() {
+        let mut estimator = ThroughputEstimator::default();
+        assert_eq!(estimator.estimate(1 << 30), None);
+
+        estimator.record(1 << 30, Duration::from_secs(10));
+        estimator.record(1 << 30, Duration::from_secs(30));
+        assert_eq!(estimator.estimate(1 << 29), Some(Duration::from_secs(10)));
+
+        // Only the most recent entries count.
+        for _ in 0..RECENT_ENTRIES {
+            estimator.record(1 << 30, Duration::from_secs(4));
+        }
+        assert_eq!(estimator.estimate(1 << 30), Some(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn test_admit() {
+        let deadline = Some(at(100));
+        assert_eq!(admit(at(50), None, None), Admission::Run);
+        assert_eq!(admit(at(50), deadline, None), Admission::Run);
+        assert_eq!(
+            admit(at(50), deadline, Some(Duration::from_secs(50))),
+            Admission::Run
+        );
+        assert_eq!(
+            admit(at(50), deadline, Some(Duration::from_secs(51))),
+            Admission::Defer {
+                estimate: Some(Duration::from_secs(51)),
+                left: Duration::from_secs(50),
+            }
+        );
+        assert_eq!(
+            admit(at(150), deadline, None),
+            Admission::Defer {
+                estimate: None,
+                left: Duration::from_secs(0),
+            }
+        );
+    }
+
+    #[test]
+    fn test_entry_deadline() {
+        let start = Instant::now();
+        let unbounded = EntryDeadline::new(start, None);
+        assert_eq!(
+            unbounded.remaining_at(start + Duration::from_secs(1000)),
+            None
+        );
+        assert!(!unbounded.expired_at(start + Duration::from_secs(1000)));
+
+        let bounded = EntryDeadline::new(start, Some(Duration::from_secs(60)));
+        assert_eq!(
+            bounded.remaining_at(start + Duration::from_secs(20)),
+            Some(Duration::from_secs(40))
+        );
+        assert!(!bounded.expired_at(start + Duration::from_secs(59)));
+        assert!(bounded.expired_at(start + Duration::from_secs(60)));
+        assert!(bounded.check().is_ok());
+
+        let expired = EntryDeadline::new(start, Some(Duration::from_secs(0)));
+        let err = expired.check().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(is_entry_timeout(&err));
+        assert!(!is_entry_timeout(&io::Error::new(
+            io::ErrorKind::TimedOut,
+            "socket read timed out"
+        )));
+    }
+}
@@ -0,0 +1,254 @@
+//! Page-level duplication analysis across the memory files of a batch.
+//!
+//! Every memory file is hashed in a single streaming pass. A frequency map
+//! keyed by page hash records how often each page occurs and in how many
+//! entries; each entry keeps only its list of page hashes (8 bytes per page)
+//! for the per-entry breakdown computed afterwards.
+//!
+//! For every entry the report gives:
+//!
+//! - `unique_bytes`: pages that occur in no other entry,
+//! - `shared_bytes`: pages that occur in at least one other entry,
+//! - `base_coverage_bytes`: bytes of all *other* entries whose pages this
+//!   entry contains, i.e. what a delta template against it would not need to
+//!   upload.
+//!
+//! The entry with the largest coverage is reported as the best base image.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+
+use serde::Serialize;
+use vmm::pseudo_mm_support::PAGE_SIZE;
+
+use crate::page_hash;
+
+#[derive(Default)]
+struct PageStats {
+    occurrences: u64,
+    entries: u32,
+    last_entry: u32,
+}
+
+/// Duplication figures for one batch entry.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EntryDedup {
+    pub label: String,
+    pub mem_file_path: String,
+    pub pages: u64,
+    pub bytes: u64,
+    pub unique_bytes: u64,
+    pub shared_bytes: u64,
+    pub base_coverage_bytes: u64,
+}
+
+/// Duplication report over a whole batch.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DedupReport {
+    pub page_size: u64,
+    pub total_bytes: u64,
+    pub distinct_bytes: u64,
+    pub entries: Vec<EntryDedup>,
+    /// Label of the entry that covers most of the other entries' pages.
+    pub best_base: Option<String>,
+}
+
+/// A batch entry to analyse.
+pub struct DedupInput<R> {
+    pub label: String,
+    pub mem_file_path: String,
+    pub reader: R,
+}
+
+/// Hashes every input and builds the report.
+pub fn build_report<R: Read>(inputs: Vec<DedupInput<R>>) -> io::Result<DedupReport> {
+    let mut stats: HashMap<u64, PageStats> = HashMap::new();
+    let mut entry_hashes = Vec::with_capacity(inputs.len());
+    let mut meta = Vec::with_capacity(inputs.len());
+
+    for (idx, mut input) in inputs.into_iter().enumerate() {
+        let entry = idx as u32;
+        let mut hashes = Vec::new();
+        page_hash::for_each_page_hash(&mut input.reader, |_, hash| {
+            let page = stats.entry(hash).or_default();
+            page.occurrences += 1;
+            if page.entries == 0 || page.last_entry != entry {
+                page.entries += 1;
+                page.last_entry = entry;
+            }
+            hashes.push(hash);
+        })
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", input.mem_file_path, err)))?;
+        entry_hashes.push(hashes);
+        meta.push((input.label, input.mem_file_path));
+    }
+
+    let mut entries = Vec::with_capacity(meta.len());
+    for ((label, mem_file_path), mut hashes) in meta.into_iter().zip(entry_hashes) {
+        let pages = hashes.len() as u64;
+        let mut unique_pages = 0;
+        let mut covered_pages = 0;
+
+        hashes.sort_unstable();
+        let mut start = 0;
+        while start < hashes.len() {
+            let hash = hashes[start];
+            let mut end = start + 1;
+            while end < hashes.len() && hashes[end] == hash {
+                end += 1;
+            }
+            let local = (end - start) as u64;
+            let page = &stats[&hash];
+            if page.entries == 1 {
+                unique_pages += local;
+            }
+            covered_pages += page.occurrences - local;
+            start = end;
+        }
+
+        entries.push(EntryDedup {
+            label,
+            mem_file_path,
+            pages,
+            bytes: pages * PAGE_SIZE,
+            unique_bytes: unique_pages * PAGE_SIZE,
+            shared_bytes: (pages - unique_pages) * PAGE_SIZE,
+            base_coverage_bytes: covered_pages * PAGE_SIZE,
+        });
+    }
+
+    let mut best: Option<&EntryDedup> = None;
+    for entry in &entries {
+        if entry.base_coverage_bytes > 0
+            && best.map_or(true, |b| entry.base_coverage_bytes > b.base_coverage_bytes)
+        {
+            best = Some(entry);
+        }
+    }
+    let best_base = best.map(|entry| entry.label.clone());
+
+    Ok(DedupReport {
+        page_size: PAGE_SIZE,
+        total_bytes: entries.iter().map(|entry| entry.bytes).sum(),
+        distinct_bytes: stats.len() as u64 * PAGE_SIZE,
+        entries,
+        best_base,
+    })
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+impl fmt::Display for DedupReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Batch: {} entries, {} bytes total, {} bytes distinct ({:.1}% duplicated)",
+            self.entries.len(),
+            self.total_bytes,
+            self.distinct_bytes,
+            100.0 - percent(self.distinct_bytes, self.total_bytes)
+        )?;
+        for entry in &self.entries {
+            writeln!(
+                f,
+                "  [{}] {}: {} bytes, unique {} ({:.1}%), shared {} ({:.1}%), covers {} bytes of others",
+                entry.label,
+                entry.mem_file_path,
+                entry.bytes,
+                entry.unique_bytes,
+                percent(entry.unique_bytes, entry.bytes),
+                entry.shared_bytes,
+                percent(entry.shared_bytes, entry.bytes),
+                entry.base_coverage_bytes
+            )?;
+        }
+        match self.best_base {
+            Some(ref label) => write!(f, "Best base image candidate: {}", label),
+            None => write!(f, "Best base image candidate: none (no shared pages)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn pages(fills: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &fill in fills {
+            data.extend(vec![fill; PAGE_SIZE as usize]);
+        }
+        data
+    }
+
+    fn input(label: &str, fills: &[u8]) -> DedupInput<Cursor<Vec<u8>>> {
+        DedupInput {
+            label: label.to_string(),
+            mem_file_path: format!("{}.mem", label),
+            reader: Cursor::new(pages(fills)),
+        }
+    }
+
+    #[test]
+    fn test_report_counts() {
+        let report = build_report(vec![
+            input("a", &[1, 2, 3, 3]),
+            input("b", &[1, 2, 4]),
+            input("c", &[1, 5]),
+        ])
+        .unwrap();
+
+        assert_eq!(report.total_bytes, 9 * PAGE_SIZE);
+        assert_eq!(report.distinct_bytes, 5 * PAGE_SIZE);
+
+        let a = &report.entries[0];
+        assert_eq!(a.pages, 4);
+        // Page 3 repeats within 'a' only, so it is still unique to 'a'.
+        assert_eq!(a.unique_bytes, 2 * PAGE_SIZE);
+        assert_eq!(a.shared_bytes, 2 * PAGE_SIZE);
+        // 'b' pages 1 and 2 plus 'c' page 1.
+        assert_eq!(a.base_coverage_bytes, 3 * PAGE_SIZE);
+
+        let b = &report.entries[1];
+        assert_eq!(b.unique_bytes, PAGE_SIZE);
+        assert_eq!(b.base_coverage_bytes, 3 * PAGE_SIZE);
+
+        let c = &report.entries[2];
+        assert_eq!(c.unique_bytes, PAGE_SIZE);
+        assert_eq!(c.shared_bytes, PAGE_SIZE);
+        assert_eq!(c.base_coverage_bytes, 2 * PAGE_SIZE);
+
+        // Ties go to the earlier entry.
+        assert_eq!(report.best_base.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn test_no_sharing_has_no_base() {
+        let report = build_report(vec![input("a", &[1, 2]), input("b", &[3])]).unwrap();
+        assert_eq!(report.distinct_bytes, report.total_bytes);
+        assert!(report.entries.iter().all(|e| e.shared_bytes == 0));
+        assert_eq!(report.best_base, None);
+        assert!(report.to_string().contains("none (no shared pages)"));
+    }
+
+    #[test]
+    fn test_best_base_prefers_widest_coverage() {
+        let report = build_report(vec![
+            input("small", &[9]),
+            input("base", &[1, 2, 3, 9]),
+            input("x", &[1, 2]),
+            input("y", &[2, 3]),
+        ])
+        .unwrap();
+        assert_eq!(report.best_base.as_deref(), Some("base"));
+        assert_eq!(report.entries[1].base_coverage_bytes, 5 * PAGE_SIZE);
+    }
+}
@@ -0,0 +1,184 @@
+//! Firecracker diff snapshots as input.
+//!
+//! A diff snapshot's memory file is as long as the guest's memory, but only
+//! the pages dirtied since the snapshot it was taken against are written;
+//! the rest are holes. Read like a full memory file, whose holes are zero
+//! pages, it makes a template with all of those pages zeroed. With
+//! `--diff-snapshot` the holes are filled from the base instead:
+//!
+//! - with `--base-mem-file` alone, from the full memory file the diff was
+//!   taken against, at the same offsets. The two are merged as the upload
+//!   reads them, without writing the merged file anywhere, so like a memory
+//!   file on stdin the merged view is streamed once, in order;
+//! - with `--base-template` as well, from that template's image at the same
+//!   guest addresses, and the entry is layered on it (see `layered`): pages
+//!   the diff holds are uploaded if they differ from the base, and every
+//!   other page is shared with the base image.
+//!
+//! Holes are found with `SEEK_DATA`/`SEEK_HOLE`, so the diff must be read
+//! where the filesystem keeps them, in blocks of at most 4 KiB. They can't
+//! tell a diff from a sparse copy of a full memory file, so a memory file
+//! with holes read without `--diff-snapshot` only gets a warning.
+
+use std::cmp::Ordering;
+use std::io::{self, Read};
+
+use vmm::pseudo_mm_support::{PageSize, PAGE_SIZE};
+
+use crate::mem_files::{MemFiles, MemImage, ReadAt};
+
+/// A diff snapshot's memory file with its holes read from a base.
+pub struct DiffView<'a> {
+    diff: MemImage,
+    /// The diff's data, see `MemImage::data_extents`; everything else is
+    /// read from `base`.
+    data: Vec<(u64, u64)>,
+    base: &'a dyn ReadAt,
+    /// Where `Read` continues from.
+    pos: u64,
+}
+
+impl<'a> DiffView<'a> {
+    pub fn new(diff: MemImage, base: &'a dyn ReadAt) -> io::Result<Self> {
+        let data = diff.data_extents()?;
+        Ok(DiffView::with_extents(diff, data, base))
+    }
+
+    /// The view with the diff's data at `data`, whatever its holes are.
+    pub fn with_extents(diff: MemImage, data: Vec<(u64, u64)>, base: &'a dyn ReadAt) -> Self {
+        DiffView {
+            diff,
+            data,
+            base,
+            pos: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.diff.size()
+    }
+
+    /// Pages read from the base, in 4 KiB pages.
+    pub fn base_pages(&self) -> u64 {
+        hole_pages(self.size(), &self.data)
+    }
+}
+
+impl<'a> ReadAt for DiffView<'a> {
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            // The first extent not ending before `offset`.
+            let idx = self
+                .data
+                .binary_search_by(|&(_, end)| {
+                    if end <= offset {
+                        Ordering::Less
+                    } else {
+                        Ordering::Greater
+                    }
+                })
+                .unwrap_or_else(|idx| idx);
+            let next = self.data.get(idx);
+            let (len, in_diff) = match next {
+                Some(&(start, end)) if start <= offset => (end - offset, true),
+                Some(&(start, _)) => (start - offset, false),
+                None => (buf.len() as u64, false),
+            };
+            let len = std::cmp::min(len, buf.len() as u64) as usize;
+            let (head, rest) = buf.split_at_mut(len);
+            if in_diff {
+                self.diff.read_exact_at(head, offset)?;
+            } else {
+                self.base.read_exact_at(head, offset)?;
+            }
+            buf = rest;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Read for DiffView<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len() as u64, self.size() - self.pos) as usize;
+        ReadAt::read_exact_at(self, &mut buf[..len], self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+/// Opens the diff at `mem_files` and the full memory file at `base`, as for
+/// `--mem-file-path`, that it is merged with.
+pub fn open_merged(
+    mem_files: &MemFiles,
+    base: &str,
+    page_size: PageSize,
+) -> io::Result<(MemImage, MemImage)> {
+    let diff = mem_files.open(page_size)?;
+    let base_image = MemFiles::parse(base)?.open(page_size)?;
+    if base_image.size() != diff.size() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "base memory file {} is {} bytes, but the diff snapshot's is {}",
+                base,
+                base_image.size(),
+                diff.size()
+            ),
+        ));
+    }
+    Ok((diff, base_image))
+}
+
+/// Pages of `image` in holes, in 4 KiB pages.
+pub fn holes(image: &MemImage) -> io::Result<u64> {
+    Ok(hole_pages(image.size(), &image.data_extents()?))
+}
+
+fn hole_pages(size: u64, data: &[(u64, u64)]) -> u64 {
+    let data: u64 = data.iter().map(|&(start, end)| end - start).sum();
+    (size - data) / PAGE_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+
+    fn write(name: &str, pages: &[u8]) -> MemImage {
+        let path = test_files::page_file(&format!("diff_{}", name), pages);
+        let image = MemFiles::new(vec![path.to_string_lossy().into_owned()])
+            .open(PageSize::Base)
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        image
+    }
+
+    #[test]
+    fn test_diff_view() {
+        let base = write("base", &[1, 2, 3, 4, 5, 6]);
+        // Pages 1 and 4 were dirtied; the rest of the diff is holes.
+        let diff = write("diff", &[0, 8, 0, 0, 9, 0]);
+        let mut view = DiffView::with_extents(
+            diff,
+            vec![(PAGE_SIZE, 2 * PAGE_SIZE), (4 * PAGE_SIZE, 5 * PAGE_SIZE)],
+            &base,
+        );
+        assert_eq!(view.size(), 6 * PAGE_SIZE);
+        assert_eq!(view.base_pages(), 4);
+
+        let page = |bytes: &[u8], idx: usize| bytes[idx * PAGE_SIZE as usize];
+        let mut merged = Vec::new();
+        view.read_to_end(&mut merged).unwrap();
+        assert_eq!(merged.len() as u64, 6 * PAGE_SIZE);
+        let pages: Vec<u8> = (0..6).map(|idx| page(&merged, idx)).collect();
+        assert_eq!(pages, vec![1, 8, 3, 4, 9, 6]);
+
+        // Reads across the edges of an extent take each part from its side.
+        let mut buf = vec![0u8; 2 * PAGE_SIZE as usize];
+        view.read_exact_at(&mut buf, PAGE_SIZE / 2).unwrap();
+        assert_eq!(buf[0], 1);
+        assert_eq!(buf[PAGE_SIZE as usize], 8);
+        assert_eq!(buf[buf.len() - 1], 3);
+    }
+}
@@ -0,0 +1,93 @@
+//! `--dirty-bitmap`: the pages dirtied since a base template was made.
+//!
+//! Firecracker's dirty tracking already knows which pages of a
+//! re-snapshotted function changed. Given as a file of one bit per 4 KiB
+//! page of the memory file, least significant bit first (the layout of
+//! KVM's bitmaps, written out as bytes), it replaces the comparison with the
+//! base: dirty pages are uploaded without reading the base, and clean pages
+//! are mapped from the base template without reading the memory file. See
+//! `layered::layer_dirty`.
+
+use std::io;
+
+use vmm::pseudo_mm_support::PAGE_SIZE;
+
+/// One bit per 4 KiB page of a memory file, set for dirty pages.
+pub struct DirtyBitmap {
+    bits: Vec<u8>,
+}
+
+impl DirtyBitmap {
+    /// Reads the bitmap at `path` for a memory file of `mem_size` bytes,
+    /// which it must cover exactly, to the byte.
+    pub fn load(path: &str, mem_size: u64) -> io::Result<Self> {
+        let bits = std::fs::read(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path, err)))?;
+        DirtyBitmap::new(bits, mem_size).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("dirty bitmap {}: {}", path, err),
+            )
+        })
+    }
+
+    /// The bitmap of `bits`, which must be valid for `mem_size` bytes.
+    #[cfg(test)]
+    pub fn load_bits(bits: Vec<u8>, mem_size: u64) -> Self {
+        DirtyBitmap::new(bits, mem_size).unwrap()
+    }
+
+    fn new(bits: Vec<u8>, mem_size: u64) -> Result<Self, String> {
+        let pages = mem_size / PAGE_SIZE;
+        let expected = (pages + 7) / 8;
+        if bits.len() as u64 != expected {
+            return Err(format!(
+                "{} bytes, but the memory file's {} pages need {}",
+                bits.len(),
+                pages,
+                expected
+            ));
+        }
+        let bitmap = DirtyBitmap { bits };
+        if let Some(page) = (pages..expected * 8).find(|&page| bitmap.bit(page)) {
+            return Err(format!(
+                "page {} is dirty, past the memory file's {} pages",
+                page, pages
+            ));
+        }
+        Ok(bitmap)
+    }
+
+    fn bit(&self, page: u64) -> bool {
+        self.bits[(page / 8) as usize] & (1 << (page % 8)) != 0
+    }
+
+    /// Whether any of the `len` bytes at `file_offset` of the memory file is
+    /// in a dirty page.
+    pub fn is_dirty(&self, file_offset: u64, len: u64) -> bool {
+        let first = file_offset / PAGE_SIZE;
+        let last = (file_offset + len + PAGE_SIZE - 1) / PAGE_SIZE;
+        (first..last).any(|page| self.bit(page))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_bitmap() {
+        // Pages 0 and 9 of ten.
+        let bitmap = DirtyBitmap::new(vec![0b0000_0001, 0b0000_0010], 10 * PAGE_SIZE).unwrap();
+        assert!(bitmap.is_dirty(0, PAGE_SIZE));
+        assert!(!bitmap.is_dirty(PAGE_SIZE, 8 * PAGE_SIZE));
+        assert!(bitmap.is_dirty(8 * PAGE_SIZE, 2 * PAGE_SIZE));
+
+        let err = DirtyBitmap::new(vec![0; 3], 10 * PAGE_SIZE).err().unwrap();
+        assert_eq!(err, "3 bytes, but the memory file's 10 pages need 2");
+        let err = DirtyBitmap::new(vec![0, 0b0000_0100], 10 * PAGE_SIZE)
+            .err()
+            .unwrap();
+        assert!(err.contains("page 10 is dirty"), "{}", err);
+    }
+}
@@ -0,0 +1,101 @@
+//! Environment variables in batch config paths.
+//!
+//! Batch configs for different environments often differ only in their
+//! directories. `rdma_server`, and each entry's `snapshot_path`,
+//! `mem_file_path`, `output_path` and `rdma_server`, may name environment
+//! variables as `${VAR}`, or `${VAR:-default}` to use `default` when `VAR`
+//! is unset or empty. Expansion happens once, when the config is loaded, and
+//! before `mem_file_path` globs are matched. A `$` not followed by `{` is
+//! kept as is; `--no-env-expand` keeps every value exactly as written.
+
+/// `value` with every `${VAR}` and `${VAR:-default}` replaced, looking
+/// variables up with `lookup`.
+pub fn expand(value: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let body = &rest[start + 2..];
+        let end = body
+            .find('}')
+            .ok_or_else(|| format!("'{}' has an unterminated '${{'", value))?;
+        let (name, default) = match body[..end].find(":-") {
+            Some(split) => (&body[..split], Some(&body[split + 2..end])),
+            None => (&body[..end], None),
+        };
+        if !is_name(name) {
+            return Err(format!("'{}' names an invalid variable '{}'", value, name));
+        }
+        match (lookup(name), default) {
+            (Some(found), None) => out.push_str(&found),
+            (Some(ref found), Some(_)) if !found.is_empty() => out.push_str(found),
+            (_, Some(default)) => out.push_str(default),
+            (None, None) => {
+                return Err(format!(
+                    "environment variable {} is not set and has no default",
+                    name
+                ))
+            }
+        }
+        rest = &body[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Looks variables up in the process environment.
+pub fn from_env(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first == '_' || first.is_ascii_alphabetic() => {
+            chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "SNAP_ROOT" => Some("/srv/snap".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let expand = |value| expand(value, &lookup);
+        assert_eq!(expand("${SNAP_ROOT}/a.snap").unwrap(), "/srv/snap/a.snap");
+        assert_eq!(
+            expand("${SNAP_ROOT}/${SNAP_ROOT}").unwrap(),
+            "/srv/snap//srv/snap"
+        );
+        assert_eq!(expand("/plain/path").unwrap(), "/plain/path");
+        assert_eq!(
+            expand("${OUT_ROOT:-/tmp/out}/a.json").unwrap(),
+            "/tmp/out/a.json"
+        );
+        assert_eq!(expand("${EMPTY:-fallback}").unwrap(), "fallback");
+        assert_eq!(expand("a${EMPTY}b").unwrap(), "ab");
+        assert_eq!(expand("${OUT_ROOT:-}a").unwrap(), "a");
+        assert_eq!(expand("${SNAP_ROOT:-/x}").unwrap(), "/srv/snap");
+        // Only the braced form expands.
+        assert_eq!(expand("/srv/$SNAP_ROOT/$").unwrap(), "/srv/$SNAP_ROOT/$");
+
+        let err = expand("${OUT_ROOT}/a.json").unwrap_err();
+        assert!(err.contains("OUT_ROOT is not set"), "{}", err);
+        assert!(expand("${SNAP_ROOT").unwrap_err().contains("unterminated"));
+        assert!(expand("${1X}")
+            .unwrap_err()
+            .contains("invalid variable '1X'"));
+        assert!(expand("${}").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! Open file descriptor budgeting for batch runs.
+//!
+//! While an entry is being created it holds a few descriptors at the same
+//! time: its output lock, and either every shard of its memory file and a
+//! socket per `--upload-streams` connection during the upload, or the
+//! pseudo_mm device during setup. All of them are closed before the next
+//! entry starts. Running out of descriptors otherwise
+//! surfaces as "Too many open files" from whichever stage happens to open
+//! one next, so the soft limit is raised when possible and batches are
+//! checked against it up front.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+/// Peak descriptors held at once by one entry being created, whose memory
+/// file has `shards` files uploaded over `streams` connections.
+///
+/// The upload outweighs setup's single device descriptor, so only it and
+/// the output lock count.
+pub fn fds_per_entry(shards: u64, streams: u64) -> u64 {
+    1 + shards + streams
+}
+
+/// Soft limit the tool raises itself to, when the hard limit allows.
+const NOFILE_TARGET: u64 = 1 << 16;
+
+/// The process's RLIMIT_NOFILE.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NofileLimit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+pub fn nofile_limit() -> io::Result<NofileLimit> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(NofileLimit {
+        soft: rlim.rlim_cur as u64,
+        hard: rlim.rlim_max as u64,
+    })
+}
+
+/// Raises the soft RLIMIT_NOFILE towards `NOFILE_TARGET`, capped by the hard
+/// limit.
+///
+/// Returns the old and new soft limits if it changed.
+pub fn raise_nofile_limit() -> io::Result<Option<(u64, u64)>> {
+    let limit = nofile_limit()?;
+    let target = std::cmp::min(limit.hard, NOFILE_TARGET);
+    if limit.soft >= target {
+        return Ok(None);
+    }
+    let rlim = libc::rlimit {
+        rlim_cur: target as libc::rlim_t,
+        rlim_max: limit.hard as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some((limit.soft, target)))
+}
+
+/// Number of descriptors the process has open, if /proc is available.
+pub fn open_fds() -> Option<u64> {
+    let entries = std::fs::read_dir("/proc/self/fd").ok()?;
+    // The directory handle used for listing shows up as well.
+    Some((entries.count() as u64).saturating_sub(1))
+}
+
+/// Checks that `concurrent` entries holding `per_entry` descriptors each
+/// can be created at once on top of the `open` descriptors already in use,
+/// returning the estimated peak.
+pub fn check_budget(concurrent: u64, per_entry: u64, open: u64, limit: u64) -> io::Result<u64> {
+    let peak = open + per_entry * concurrent;
+    if peak > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "batch needs about {} open files ({} open now + {} per entry x {} concurrent) \
+                 but RLIMIT_NOFILE is {}; raise it with 'ulimit -n'",
+                peak, open, per_entry, concurrent, limit
+            ),
+        ));
+    }
+    Ok(peak)
+}
+
+/// A file that is opened on first read and closed when dropped.
+///
+/// Lets a caller line up many inputs without holding a descriptor for each.
+pub struct LazyFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl LazyFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        LazyFile {
+            path: path.into(),
+            file: None,
+        }
+    }
+}
+
+impl Read for LazyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            self.file = Some(File::open(&self.path)?);
+        }
+        self.file.as_mut().unwrap().read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+
+    #[test]
+    fn test_check_budget() {
+        let per_entry = fds_per_entry(1, 1);
+        assert_eq!(per_entry, 3);
+        assert_eq!(check_budget(1, per_entry, 10, 1024).unwrap(), 13);
+
+        let err = check_budget(300, per_entry, 10, 256)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("about 910 open files"), "{}", err);
+        assert!(err.contains("RLIMIT_NOFILE is 256"), "{}", err);
+    }
+
+    #[test]
+    fn test_fds_per_entry_scales() {
+        // 4 shards over 8 connections, plus the output lock.
+        assert_eq!(fds_per_entry(4, 8), 13);
+        // What fits with single-file, single-stream entries may not with
+        // sharded, multi-stream ones.
+        assert!(check_budget(16, fds_per_entry(1, 1), 10, 128).is_ok());
+        let err = check_budget(16, fds_per_entry(4, 8), 10, 128)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("about 218 open files"), "{}", err);
+        assert!(err.contains("13 per entry x 16 concurrent"), "{}", err);
+    }
+
+    #[test]
+    fn test_lazy_file_opens_on_read() {
+        let path = test_files::path("lazy");
+        let _ = std::fs::remove_file(&path);
+
+        // Missing files only fail once read.
+        let mut lazy = LazyFile::new(&path);
+        std::fs::write(&path, b"contents").unwrap();
+        let mut read = String::new();
+        lazy.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "contents");
+
+        std::fs::remove_file(&path).unwrap();
+        let mut missing = LazyFile::new(&path);
+        assert_eq!(
+            missing.read(&mut [0u8; 8]).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_raise_never_lowers() {
+        let before = nofile_limit().unwrap();
+        raise_nofile_limit().unwrap();
+        let after = nofile_limit().unwrap();
+        assert!(after.soft >= before.soft);
+        assert_eq!(after.hard, before.hard);
+    }
+}
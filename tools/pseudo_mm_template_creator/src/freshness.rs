@@ -0,0 +1,233 @@
+//! Whether a template still matches the files it was made from.
+//!
+//! Snapshots are regenerated on a schedule while their templates often are
+//! not, and restores then serve old memory. A template made from files
+//! records their size, modification time and a content hash in its
+//! provenance `sources`. `check-freshness` and `--regenerate-stale` compare
+//! those with the files as they are now: by content, or with `--quick` by
+//! size and modification time only, which reads nothing but calls a file
+//! touched without being changed stale.
+//!
+//! The content hash folds the `page_hash` of every page of the file, so a
+//! large memory file is hashed about as fast as it can be read.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::time::UNIX_EPOCH;
+
+use vmm::pseudo_mm_support::{PseudoMmTemplate, SourceFile, SourceFiles};
+
+use crate::mem_files::MemFiles;
+use crate::page_hash;
+
+/// How files are compared with the ones a template records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckMode {
+    /// By size and content hash.
+    Content,
+    /// By size and modification time, see `--quick`.
+    Quick,
+}
+
+/// The outcome of comparing a template's sources with the files now.
+#[derive(Debug, PartialEq)]
+pub enum Freshness {
+    Fresh,
+    /// With what differs.
+    Stale(String),
+}
+
+impl fmt::Display for Freshness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Freshness::Fresh => write!(f, "fresh"),
+            Freshness::Stale(why) => write!(f, "stale: {}", why),
+        }
+    }
+}
+
+/// Records the snapshot at `snapshot_path` and the memory file made of
+/// `mem_files` as they are now, reading all of them.
+pub fn sources(snapshot_path: &str, mem_files: &[String]) -> io::Result<SourceFiles> {
+    Ok(SourceFiles {
+        snapshot: source_file(snapshot_path)?,
+        mem_files: mem_files
+            .iter()
+            .map(|path| source_file(path))
+            .collect::<io::Result<_>>()?,
+    })
+}
+
+fn source_file(path: &str) -> io::Result<SourceFile> {
+    let metadata = fs::metadata(path)?;
+    Ok(SourceFile {
+        path: path.to_string(),
+        size: metadata.len(),
+        modified: modified(&metadata),
+        hash: content_hash(path)?,
+    })
+}
+
+/// Compares `recorded` with the snapshot at `snapshot_path` and the memory
+/// file made of `mem_files`. The paths recorded don't matter, so templates
+/// whose sources moved are still checked.
+pub fn check(
+    recorded: &SourceFiles,
+    snapshot_path: &str,
+    mem_files: &[String],
+    mode: CheckMode,
+) -> io::Result<Freshness> {
+    if recorded.mem_files.len() != mem_files.len() {
+        return Ok(Freshness::Stale(format!(
+            "the memory file has {} shards, the template was made from {}",
+            mem_files.len(),
+            recorded.mem_files.len()
+        )));
+    }
+    let files = std::iter::once((&recorded.snapshot, snapshot_path)).chain(
+        recorded
+            .mem_files
+            .iter()
+            .zip(mem_files.iter().map(String::as_str)),
+    );
+    for (was, path) in files {
+        let metadata = fs::metadata(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("cannot stat {}: {}", path, err)))?;
+        if metadata.len() != was.size {
+            return Ok(Freshness::Stale(format!(
+                "{} is {} bytes, the template was made from {}",
+                path,
+                metadata.len(),
+                was.size
+            )));
+        }
+        let changed = match mode {
+            CheckMode::Quick => modified(&metadata) != was.modified,
+            CheckMode::Content => content_hash(path)? != was.hash,
+        };
+        if changed {
+            return Ok(Freshness::Stale(format!(
+                "{} {} since the template was made",
+                path,
+                match mode {
+                    CheckMode::Quick => "was modified",
+                    CheckMode::Content => "changed",
+                }
+            )));
+        }
+    }
+    Ok(Freshness::Fresh)
+}
+
+/// Compares the sources `template` records with `snapshot_path` and
+/// `mem_files`, failing if it records none.
+pub fn check_template(
+    template: &PseudoMmTemplate,
+    snapshot_path: &str,
+    mem_files: &MemFiles,
+    mode: CheckMode,
+) -> io::Result<Freshness> {
+    if mem_files.is_stdin() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot check freshness against a memory file read from stdin",
+        ));
+    }
+    let sources = template
+        .provenance
+        .as_ref()
+        .and_then(|provenance| provenance.sources.as_ref())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the template records no source files to compare with",
+            )
+        })?;
+    check(sources, snapshot_path, mem_files.paths(), mode)
+}
+
+fn modified(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs())
+}
+
+fn content_hash(path: &str) -> io::Result<String> {
+    let mut file = File::open(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("cannot open {}: {}", path, err)))?;
+    let mut hash = 0u64;
+    page_hash::for_each_page_hash(&mut file, |_, page| {
+        hash = page_hash::page_hash(&(hash ^ page).to_le_bytes());
+    })?;
+    Ok(format!("{:016x}", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+
+    fn scratch(name: &str, contents: &[u8]) -> String {
+        let path = test_files::file(&format!("fresh_{}", name), contents);
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_check() {
+        let snapshot = scratch("snap", b"snapshot");
+        let mem = scratch("mem", &vec![7u8; 3 * 4096]);
+        let mems = vec![mem.clone()];
+        let recorded = sources(&snapshot, &mems).unwrap();
+        assert_eq!(recorded.mem_files[0].size, 3 * 4096);
+        for &mode in &[CheckMode::Content, CheckMode::Quick] {
+            assert_eq!(
+                check(&recorded, &snapshot, &mems, mode).unwrap(),
+                Freshness::Fresh
+            );
+        }
+
+        // One byte changed, same size and, within the second, same mtime.
+        let mut changed = vec![7u8; 3 * 4096];
+        changed[5000] = 8;
+        fs::write(&mem, &changed).unwrap();
+        let stale = check(&recorded, &snapshot, &mems, CheckMode::Content).unwrap();
+        assert_eq!(
+            stale,
+            Freshness::Stale(format!("{} changed since the template was made", mem))
+        );
+
+        fs::write(&mem, vec![7u8; 4096]).unwrap();
+        let stale = check(&recorded, &snapshot, &mems, CheckMode::Quick).unwrap();
+        assert!(stale.to_string().contains("is 4096 bytes"), "{}", stale);
+
+        let stale = check(
+            &recorded,
+            &snapshot,
+            &[mem.clone(), mem.clone()],
+            CheckMode::Quick,
+        );
+        assert!(stale.unwrap().to_string().contains("has 2 shards"));
+
+        fs::remove_file(&mem).unwrap();
+        assert!(check(&recorded, &snapshot, &mems, CheckMode::Quick).is_err());
+        fs::remove_file(&snapshot).unwrap();
+    }
+
+    #[test]
+    fn test_quick_compares_mtime() {
+        let snapshot = scratch("quick_snap", b"snapshot");
+        let mut recorded = sources(&snapshot, &[]).unwrap();
+        recorded.snapshot.modified -= 60;
+        // Same content, so only the quick check notices.
+        assert_eq!(
+            check(&recorded, &snapshot, &[], CheckMode::Content).unwrap(),
+            Freshness::Fresh
+        );
+        let stale = check(&recorded, &snapshot, &[], CheckMode::Quick).unwrap();
+        assert!(stale.to_string().contains("was modified"), "{}", stale);
+        fs::remove_file(&snapshot).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+//! Guard pages around uploaded images.
+//!
+//! An off-by-one in the memory server's pgoff accounting writes the edge
+//! of one image over its neighbour, which only shows up once a guest
+//! restored from the neighbour crashes. With `--guard-pages N`, the N
+//! pages before and after every RDMA image are reserved with it but never
+//! mapped: automatic ranges leave them free, `next_rdma_pgoff` moves past
+//! them, the registry and occupancy files count them, and the template
+//! records the image with its guard pages in `rdma_guarded_range`.
+//!
+//! The guard pages are written with zeros before the image is uploaded.
+
+use std::io;
+
+use vmm::pseudo_mm_addr::PageOffset;
+use vmm::pseudo_mm_support::ImageExtent;
+
+/// Most guard pages written at once.
+pub const CHUNK_PAGES: u64 = 256;
+
+/// The image of `pages` at `pgoff` with `guard` pages on each side.
+pub fn guarded_range(pgoff: PageOffset, pages: u64, guard: u64) -> io::Result<ImageExtent> {
+    let start = pgoff.raw().checked_sub(guard).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "rdma_pgoff {} leaves no room for {} guard pages before it",
+                pgoff, guard
+            ),
+        )
+    })?;
+    guard
+        .checked_mul(2)
+        .and_then(|both| pages.checked_add(both))
+        .filter(|&total| start.checked_add(total).is_some())
+        .map(|total| ImageExtent {
+            pgoff: PageOffset(start),
+            pages: total,
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "rdma_pgoff {} + {} pages and {} guard pages overflows pgoff space",
+                    pgoff, pages, guard
+                ),
+            )
+        })
+}
+
+/// The guard pages of `guarded` around the image of `pages` at `pgoff`,
+/// as `(pgoff, pages)` runs of at most `CHUNK_PAGES`.
+pub fn guard_chunks(guarded: &ImageExtent, pgoff: PageOffset, pages: u64) -> Vec<(u64, u64)> {
+    let image_end = pgoff.raw() + pages;
+    let guarded_end = guarded.pgoff.raw() + guarded.pages;
+    let mut chunks = Vec::new();
+    for &(start, end) in &[(guarded.pgoff.raw(), pgoff.raw()), (image_end, guarded_end)] {
+        let mut at = start;
+        while at < end {
+            let len = std::cmp::min(CHUNK_PAGES, end - at);
+            chunks.push((at, len));
+            at += len;
+        }
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guarded_range() {
+        assert_eq!(
+            guarded_range(PageOffset(16), 100, 16).unwrap(),
+            ImageExtent {
+                pgoff: PageOffset(0),
+                pages: 132,
+            }
+        );
+        let err = guarded_range(PageOffset(8), 100, 16).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "rdma_pgoff 8 leaves no room for 16 guard pages before it"
+        );
+        assert!(guarded_range(PageOffset(u64::MAX - 100), 90, 16).is_err());
+    }
+
+    #[test]
+    fn test_guard_chunks() {
+        let guarded = guarded_range(PageOffset(300), 10, 300).unwrap();
+        assert_eq!(
+            guard_chunks(&guarded, PageOffset(300), 10),
+            vec![(0, 256), (256, 44), (310, 256), (566, 44)]
+        );
+        let unguarded = guarded_range(PageOffset(300), 10, 0).unwrap();
+        assert!(guard_chunks(&unguarded, PageOffset(300), 10).is_empty());
+    }
+}
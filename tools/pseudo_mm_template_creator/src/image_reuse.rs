@@ -0,0 +1,179 @@
+//! Reusing the upload of an identical memory image within a batch.
+//!
+//! Functions snapshotted in the same state often have byte-identical memory
+//! files. Before an entry of a batch reserves its pgoffs, its image is
+//! hashed in one pass over the memory file. If an entry that finished
+//! earlier in the batch uploaded an image with the same hash to the same
+//! server, or the `--registry` records one whose template still maps it,
+//! the entry maps that range instead of uploading its own copy. It still
+//! gets its own pseudo_mm instance and template.
+//!
+//! The hash is SHA-256 over the page size and the image's bytes, so images
+//! made at different page sizes are never shared: a 2 MiB page is only
+//! skipped as zero if all of it is. The same pass finds the zero pages the
+//! upload would have skipped, so the template maps them as demand-zero
+//! like the image it reuses.
+//!
+//! Like `--dedup`, entries running at the same time with `--jobs` don't
+//! reuse each other's images. `--no-reuse` turns this off.
+
+use std::collections::HashMap;
+use std::io;
+
+use vmm::pseudo_mm_support::{PageSize, PAGE_SIZE};
+
+use crate::mem_files::ReadAt;
+use crate::regions::ImageWindow;
+use crate::sha256::Sha256;
+use crate::zero_pages::{self, PageRuns};
+
+/// Bytes read per step; a multiple of every page size.
+const READ_CHUNK: u64 = 4 << 20;
+
+/// What an entry's image hashed to.
+pub struct ImageDigest {
+    /// SHA-256, in hex.
+    pub hash: String,
+    pub bytes: u64,
+    /// Zero pages the upload would skip, relative to the image start.
+    pub zero_pages: PageRuns,
+}
+
+/// Hashes the image made of `windows` of `mem_file`.
+pub fn digest(
+    mem_file: &dyn ReadAt,
+    windows: &[ImageWindow],
+    page_size: PageSize,
+) -> io::Result<ImageDigest> {
+    let mut hasher = Sha256::new();
+    hasher.update(&page_size.bytes().to_le_bytes());
+    let mut zero_pages = PageRuns::default();
+    let mut buf = vec![0u8; READ_CHUNK as usize];
+    let mut bytes = 0;
+    for window in windows {
+        let mut done = 0;
+        while done < window.size {
+            let len = std::cmp::min(READ_CHUNK, window.size - done) as usize;
+            mem_file.read_exact_at(&mut buf[..len], window.file_offset + done)?;
+            hasher.update(&buf[..len]);
+            let first_page = (window.image_offset + done) / PAGE_SIZE;
+            for (at, pages, zero) in
+                zero_pages::split_runs(&buf[..len], first_page, page_size.bytes())
+            {
+                if zero {
+                    zero_pages.push(at, pages);
+                }
+            }
+            done += len as u64;
+        }
+        bytes += window.size;
+    }
+    let hash = hasher
+        .finish()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok(ImageDigest {
+        hash,
+        bytes,
+        zero_pages,
+    })
+}
+
+/// An image already on a server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UploadedImage {
+    pub pgoff: u64,
+    /// The entry, or template in the registry, it was uploaded for.
+    pub owner: String,
+}
+
+/// An entry's image, found already uploaded as `image`.
+pub struct ReusedImage {
+    pub image: UploadedImage,
+    pub digest: ImageDigest,
+}
+
+/// The images uploaded so far in a batch, by server and hash.
+#[derive(Default)]
+pub struct UploadedImages(HashMap<(String, String), UploadedImage>);
+
+impl UploadedImages {
+    pub fn find(&self, target: &str, hash: &str) -> Option<&UploadedImage> {
+        self.0.get(&(target.to_string(), hash.to_string()))
+    }
+
+    /// Records `image`, uploaded to `target`, unless one with its hash was
+    /// recorded first.
+    pub fn add(&mut self, target: &str, hash: &str, image: UploadedImage) {
+        self.0
+            .entry((target.to_string(), hash.to_string()))
+            .or_insert(image);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem_files::MemFiles;
+    use crate::test_files;
+
+    #[test]
+    fn test_digest() {
+        let mut data = vec![0u8; 4 * PAGE_SIZE as usize];
+        data[PAGE_SIZE as usize] = 1;
+        data[3 * PAGE_SIZE as usize] = 2;
+        let path = test_files::file("reuse", &data);
+        let image = MemFiles::new(vec![path.to_string_lossy().into_owned()])
+            .open(PageSize::Base)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let window = |file_offset, size, image_offset| ImageWindow {
+            file_offset,
+            size,
+            image_offset,
+        };
+        let whole = digest(&image, &[window(0, 4 * PAGE_SIZE, 0)], PageSize::Base).unwrap();
+        assert_eq!(whole.hash.len(), 64);
+        assert_eq!(whole.bytes, 4 * PAGE_SIZE);
+        assert_eq!(whole.zero_pages.as_slice(), &[(0, 1), (2, 1)]);
+
+        // The same bytes read in two windows make the same image...
+        let split = digest(
+            &image,
+            &[
+                window(0, 2 * PAGE_SIZE, 0),
+                window(2 * PAGE_SIZE, 2 * PAGE_SIZE, 2 * PAGE_SIZE),
+            ],
+            PageSize::Base,
+        )
+        .unwrap();
+        assert_eq!(split.hash, whole.hash);
+        assert_eq!(split.zero_pages, whole.zero_pages);
+        // ...but not in another order.
+        let swapped = digest(
+            &image,
+            &[
+                window(2 * PAGE_SIZE, 2 * PAGE_SIZE, 0),
+                window(0, 2 * PAGE_SIZE, 2 * PAGE_SIZE),
+            ],
+            PageSize::Base,
+        )
+        .unwrap();
+        assert_ne!(swapped.hash, whole.hash);
+
+        let mut images = UploadedImages::default();
+        let uploaded = |pgoff, owner: &str| UploadedImage {
+            pgoff,
+            owner: owner.to_string(),
+        };
+        images.add("10.0.0.1:9000", &whole.hash, uploaded(100, "fn-a"));
+        images.add("10.0.0.1:9000", &whole.hash, uploaded(200, "fn-b"));
+        assert_eq!(
+            images.find("10.0.0.1:9000", &whole.hash),
+            Some(&uploaded(100, "fn-a"))
+        );
+        assert_eq!(images.find("10.0.0.2:9000", &whole.hash), None);
+    }
+}
@@ -0,0 +1,50 @@
+//! Hexdumps of guest memory attached read-only from a template.
+
+use std::fmt::Write;
+
+/// Bytes shown per hexdump line.
+const LINE_BYTES: usize = 16;
+
+/// Formats `data`, which starts at guest address `base`, as a hexdump.
+///
+/// Each line shows the address, up to 16 bytes in hex and their printable
+/// ASCII characters.
+pub fn hexdump(base: u64, data: &[u8]) -> String {
+    let mut out = String::new();
+    for (idx, line) in data.chunks(LINE_BYTES).enumerate() {
+        let _ = write!(out, "0x{:012x}:", base + (idx * LINE_BYTES) as u64);
+        for byte in line {
+            let _ = write!(out, " {:02x}", byte);
+        }
+        for _ in line.len()..LINE_BYTES {
+            out.push_str("   ");
+        }
+        out.push_str("  |");
+        for &byte in line {
+            out.push(if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            });
+        }
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hexdump() {
+        let mut data = b"pseudo_mm rocks!".to_vec();
+        data.extend_from_slice(&[0, 0x7f, b'A']);
+        assert_eq!(
+            hexdump(0x1000, &data),
+            "0x000000001000: 70 73 65 75 64 6f 5f 6d 6d 20 72 6f 63 6b 73 21  |pseudo_mm rocks!|\n\
+             0x000000001010: 00 7f 41                                         |..A|\n"
+        );
+        assert_eq!(hexdump(0, &[]), "");
+    }
+}
@@ -0,0 +1,245 @@
+//! Registry of the pseudo_mm instances created by this tool.
+//!
+//! The module has no way to enumerate its instances, so every instance the
+//! tool creates is recorded in a registry file, and `list` reads the file
+//! back:
+//!
+//! ```json
+//! {
+//!   "instances": [
+//!     {
+//!       "pseudo_mm_id": 3,
+//!       "regions": 2,
+//!       "pages": 262144,
+//!       "backend": "rdma",
+//!       "template_path": "/srv/templates/fn-a.json",
+//!       "created_at": 1700000000,
+//!       "boot_id": "6c1d1e0e-..."
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! An instance is recorded as soon as it exists and again once its template
+//! is written, so instances of entries abandoned halfway are listed without
+//! a `template_path`. Instances live until reboot; entries carry the boot
+//! they were created in, and ones from earlier boots are dropped.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use vmm::pseudo_mm_support::MemBackend;
+
+use crate::occupancy;
+use crate::output_lock::OutputLock;
+
+/// Registry used unless `--instance-registry` names another; /run is
+/// emptied on reboot, like the module.
+pub const DEFAULT_REGISTRY_PATH: &str = "/run/pseudo_mm/instances.json";
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// How long an update waits for another run to release the registry.
+/// Updates are short, and parallel batch entries all go through it.
+const LOCK_WAIT: Duration = Duration::from_secs(5);
+
+/// A pseudo_mm instance created by the tool.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Instance {
+    pub pseudo_mm_id: i32,
+    pub regions: usize,
+    pub pages: u64,
+    pub backend: MemBackend,
+    /// Template describing the instance, once it is written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template_path: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    #[serde(default)]
+    pub boot_id: String,
+}
+
+impl Instance {
+    /// An instance created now, without a template yet.
+    pub fn new(pseudo_mm_id: i32, regions: usize, pages: u64, backend: MemBackend) -> Self {
+        Instance {
+            pseudo_mm_id,
+            regions,
+            pages,
+            backend,
+            template_path: None,
+            created_at: occupancy::unix_secs(SystemTime::now()),
+            boot_id: boot_id(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct RegistryFile {
+    #[serde(default)]
+    instances: Vec<Instance>,
+}
+
+/// Identifies the current boot; empty when the kernel doesn't expose it,
+/// which makes every entry count as current.
+fn boot_id() -> String {
+    fs::read_to_string(BOOT_ID_PATH)
+        .map(|id| id.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn read(path: &Path) -> io::Result<Vec<Instance>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    serde_json::from_str::<RegistryFile>(&text)
+        .map(|file| file.instances)
+        .map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid registry {}: {}", path.display(), err),
+            )
+        })
+}
+
+/// Applies `change` to the registry at `path` under its lock, dropping
+/// entries from earlier boots.
+fn update<F: FnOnce(&mut Vec<Instance>)>(path: &Path, change: F) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let lock = OutputLock::acquire(path, LOCK_WAIT)?;
+    let mut instances = current_boot(read(path)?, &boot_id());
+    change(&mut instances);
+    let json = serde_json::to_string_pretty(&RegistryFile { instances })?;
+    lock.write(json.as_bytes())
+}
+
+fn current_boot(mut instances: Vec<Instance>, boot_id: &str) -> Vec<Instance> {
+    instances.retain(|instance| instance.boot_id == boot_id);
+    instances
+}
+
+/// Adds `instance`, replacing an earlier record of the same id.
+fn upsert(instances: &mut Vec<Instance>, instance: Instance) {
+    match instances
+        .iter_mut()
+        .find(|other| other.pseudo_mm_id == instance.pseudo_mm_id)
+    {
+        Some(other) => *other = instance,
+        None => instances.push(instance),
+    }
+}
+
+/// Records `instance` in the registry at `path`.
+pub fn record(path: &Path, instance: &Instance) -> io::Result<()> {
+    update(path, |instances| upsert(instances, instance.clone()))
+}
+
+/// The instances of the current boot recorded at `path`, by id.
+pub fn list(path: &Path) -> io::Result<Vec<Instance>> {
+    let mut instances = current_boot(read(path)?, &boot_id());
+    instances.sort_by_key(|instance| instance.pseudo_mm_id);
+    Ok(instances)
+}
+
+/// Renders `instances` as a table; `exists` tells whether a template file
+/// is still there.
+pub fn format_table<F: Fn(&str) -> bool>(instances: &[Instance], exists: F) -> String {
+    let mut out = format!(
+        "{:>6}  {:>7}  {:>10}  {:<7}  {}\n",
+        "ID", "REGIONS", "PAGES", "BACKEND", "TEMPLATE"
+    );
+    for instance in instances {
+        let template = match instance.template_path {
+            Some(ref path) if exists(path) => path.clone(),
+            Some(ref path) => format!("{} (removed)", path),
+            None => "-".to_string(),
+        };
+        out.push_str(&format!(
+            "{:>6}  {:>7}  {:>10}  {:<7}  {}\n",
+            instance.pseudo_mm_id,
+            instance.regions,
+            instance.pages,
+            instance.backend.to_string(),
+            template
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+
+    fn instance(id: i32, boot_id: &str) -> Instance {
+        Instance {
+            pseudo_mm_id: id,
+            regions: 2,
+            pages: 1024,
+            backend: MemBackend::Rdma,
+            template_path: None,
+            created_at: 1_700_000_000,
+            boot_id: boot_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_upsert_and_boots() {
+        let mut instances = current_boot(vec![instance(1, "old"), instance(2, "now")], "now");
+        assert_eq!(instances, vec![instance(2, "now")]);
+
+        // The second record of an instance adds its template.
+        upsert(&mut instances, instance(5, "now"));
+        let mut with_template = instance(5, "now");
+        with_template.template_path = Some("/srv/t.json".to_string());
+        upsert(&mut instances, with_template.clone());
+        assert_eq!(instances, vec![instance(2, "now"), with_template]);
+    }
+
+    #[test]
+    fn test_format_table() {
+        let mut saved = instance(3, "now");
+        saved.template_path = Some("/srv/a.json".to_string());
+        let mut removed = instance(4, "now");
+        removed.template_path = Some("/srv/b.json".to_string());
+        removed.backend = MemBackend::Dax;
+        let table = format_table(&[saved, removed, instance(7, "now")], |path| {
+            path == "/srv/a.json"
+        });
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "    ID  REGIONS       PAGES  BACKEND  TEMPLATE",
+                "     3        2        1024  rdma     /srv/a.json",
+                "     4        2        1024  dax      /srv/b.json (removed)",
+                "     7        2        1024  rdma     -",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_registry_round_trip() {
+        let path = test_files::path("instances").join("instances.json");
+        assert!(list(&path).unwrap().is_empty());
+
+        let mut created = Instance::new(11, 3, 4096, MemBackend::Rdma);
+        record(&path, &created).unwrap();
+        record(&path, &Instance::new(12, 1, 16, MemBackend::Dax)).unwrap();
+        created.template_path = Some("/srv/t.json".to_string());
+        record(&path, &created).unwrap();
+
+        let instances = list(&path).unwrap();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0], created);
+        assert_eq!(instances[1].pseudo_mm_id, 12);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
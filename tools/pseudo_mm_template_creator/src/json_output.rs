@@ -0,0 +1,98 @@
+//! `--output-format json`: one JSON document on stdout and nothing else.
+//!
+//! The tool prints its banners, progress and warnings to stdout. Rather than
+//! route every line, `redirect` points the stdout descriptor at stderr before
+//! anything is printed and keeps a duplicate of the original, which `emit`
+//! writes the run's document to once it is known. A run that fails before
+//! emitting gets `emit_failure` from `main` instead, so a wrapper always
+//! finds exactly one document.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use serde::Serialize;
+
+/// Duplicate of the original stdout while a document is pending; -1 when
+/// stdout was never redirected or the document is out.
+static SAVED_STDOUT: AtomicI32 = AtomicI32::new(-1);
+
+/// Sends everything printed to stdout to stderr from now on.
+pub fn redirect() -> io::Result<()> {
+    io::stdout().flush()?;
+    // Safe because only descriptors are duplicated; nothing is borrowed.
+    let saved = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if saved < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(saved) };
+        return Err(err);
+    }
+    SAVED_STDOUT.store(saved, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Writes `doc` to the original stdout as the run's document. Does nothing
+/// without `redirect`, or once a document is out.
+pub fn emit<T: Serialize>(doc: &T) -> io::Result<()> {
+    let fd = SAVED_STDOUT.swap(-1, Ordering::SeqCst);
+    if fd < 0 {
+        return Ok(());
+    }
+    io::stdout().flush()?;
+    // Safe because `redirect` made `fd` and the swap hands it out once.
+    let mut out = unsafe { File::from_raw_fd(fd) };
+    write_document(&mut out, doc)
+}
+
+/// Reports a run that failed before its document was written.
+pub fn emit_failure(error: &str, kind: &str, exit_code: i32) -> io::Result<()> {
+    emit(&Failure {
+        status: "failed",
+        error,
+        error_kind: kind,
+        exit_code,
+    })
+}
+
+#[derive(Serialize)]
+struct Failure<'a> {
+    status: &'static str,
+    error: &'a str,
+    error_kind: &'a str,
+    exit_code: i32,
+}
+
+fn write_document<T: Serialize>(out: &mut dyn Write, doc: &T) -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut *out, doc)?;
+    writeln!(out)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_document() {
+        let mut out = Vec::new();
+        let doc = Failure {
+            status: "failed",
+            error: "memory file is empty",
+            error_kind: "input",
+            exit_code: 2,
+        };
+        write_document(&mut out, &doc).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.ends_with("}\n"), "{}", text);
+        let parsed: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(parsed["status"], "failed");
+        assert_eq!(parsed["error_kind"], "input");
+        assert_eq!(parsed["exit_code"], 2);
+        // Nothing to write to without `redirect`.
+        emit(&doc).unwrap();
+    }
+}
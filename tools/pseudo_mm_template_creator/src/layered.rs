@@ -0,0 +1,1031 @@
+//! Templates layered on a base template.
+//!
+//! Snapshots of one function taken at different points share most of their
+//! memory. With `--base-template` and `--base-mem-file`, the memory file is
+//! compared with the base's a page at a time, at the same guest addresses,
+//! and only the pages that differ are uploaded, packed in file order into
+//! the entry's own image at `rdma_pgoff`. Every other page keeps its pgoff
+//! in the base image, recorded in the regions' `extents`; zero pages are
+//! demand-zero as usual, whatever the base holds there.
+//!
+//! The base memory file is read at the base image's offsets, so it must be
+//! the image as uploaded: `rdma_image_size` bytes, without padding between
+//! regions. The base image has to stay on the server while templates
+//! layered on it are in use; `registry gc` keeps its range while any is.
+//!
+//! A diff snapshot layered on its base reads the pages it doesn't hold from
+//! the base image too, at the same guest addresses, so they are all shared.
+//! With a dirty bitmap nothing is compared; see `dirty_bitmap`.
+//!
+//! `--refresh-from` layers a new memory file on an old template without
+//! its memory file. The old template's `content_hashes` stand in for it: a
+//! chunk of the new file at the guest addresses of a hashed chunk that
+//! still hashes the same is shared whole, and every page of the other
+//! chunks is uploaded. The hashes are trusted; nothing is read back from
+//! the server. Only templates uploaded from a memory file have hashes, so
+//! a refreshed template can't be refreshed again; refresh from the full
+//! one instead.
+//!
+//! The marker page of a base made with `--marker-gpa` is never shared; the
+//! page holding it is uploaded from the memory file.
+
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use serde::Serialize;
+use vmm::memory_snapshot::GuestMemoryRegionState;
+use vmm::pseudo_mm_addr::PageOffset;
+use vmm::pseudo_mm_support::{
+    self, MemBackend, PageSize, PgoffExtent, PseudoMmTemplate, RegionMetadata, ZeroRange, PAGE_SIZE,
+};
+
+use crate::diff_snapshot::DiffView;
+use crate::dirty_bitmap::DirtyBitmap;
+use crate::mem_files::{MemFiles, ReadAt};
+use crate::region_hash;
+use crate::regions::ImageWindow;
+use crate::zero_pages;
+
+/// Bytes of the memory file compared at a time, a multiple of every page
+/// size.
+const CHUNK: u64 = 4 << 20;
+
+/// Files of the template an entry is layered on.
+#[derive(Clone, Copy)]
+pub struct BaseFiles<'a> {
+    pub template: &'a str,
+    /// The base template's image, as uploaded; `None` compares with the
+    /// template's content hashes instead, for `--refresh-from`.
+    pub mem_file: Option<&'a str>,
+}
+
+impl<'a> BaseFiles<'a> {
+    /// The option that names the base, for messages.
+    pub fn option(&self) -> &'static str {
+        match self.mem_file {
+            Some(_) => "--base-template",
+            None => "--refresh-from",
+        }
+    }
+}
+
+/// Where a run of guest pages is mapped from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    /// Uploaded with the entry, at this byte offset of its image.
+    Overlay(u64),
+    /// Shared with an image already on the server, at this pgoff.
+    Base(u64),
+    Zero,
+}
+
+impl Source {
+    /// Source of the page `bytes` further on.
+    fn advance(self, bytes: u64) -> Source {
+        match self {
+            Source::Overlay(offset) => Source::Overlay(offset + bytes),
+            Source::Base(pgoff) => Source::Base(pgoff + bytes / PAGE_SIZE),
+            Source::Zero => Source::Zero,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Run {
+    gpa: u64,
+    size: u64,
+    source: Source,
+}
+
+/// Page counts of a layered entry, in 4 KiB pages.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LayerStats {
+    pub base_template: String,
+    /// Pages that differ from the base, uploaded as the entry's image.
+    pub overlay_pages: u64,
+    /// Pages mapped from the base image.
+    pub shared_pages: u64,
+    /// Pages a dirty bitmap marks dirty and clean, when there was one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dirty_pages: Option<u64>,
+    /// Mapped from the base without being read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clean_pages: Option<u64>,
+}
+
+/// How an entry's memory divides between its own image and pages shared
+/// with others.
+#[derive(Debug)]
+pub struct Layers {
+    /// Sorted by GPA; every page of the memory file is in one.
+    runs: Vec<Run>,
+    /// Parts of the memory file making up the entry's image.
+    pub windows: Vec<ImageWindow>,
+    /// Pages uploaded as the entry's image, in 4 KiB pages.
+    pub overlay_pages: u64,
+    /// Pages mapped from elsewhere, in 4 KiB pages.
+    pub shared_pages: u64,
+    /// `(dirty, clean)` pages of an entry made from a dirty bitmap.
+    dirty: Option<(u64, u64)>,
+}
+
+impl Layers {
+    fn new(runs: Vec<Run>, windows: Vec<ImageWindow>) -> Self {
+        let mut layers = Layers {
+            runs,
+            windows,
+            overlay_pages: 0,
+            shared_pages: 0,
+            dirty: None,
+        };
+        for run in &layers.runs {
+            match run.source {
+                Source::Overlay(_) => layers.overlay_pages += run.size / PAGE_SIZE,
+                Source::Base(_) => layers.shared_pages += run.size / PAGE_SIZE,
+                Source::Zero => {}
+            }
+        }
+        layers
+    }
+
+    /// Page counts of an entry layered on `base_template`.
+    pub fn stats(&self, base_template: &str) -> LayerStats {
+        LayerStats {
+            base_template: crate::absolute_path(base_template),
+            overlay_pages: self.overlay_pages,
+            shared_pages: self.shared_pages,
+            dirty_pages: self.dirty.map(|dirty| dirty.0),
+            clean_pages: self.dirty.map(|dirty| dirty.1),
+        }
+    }
+}
+
+/// Pages an entry can map from images already on the server instead of
+/// uploading them again.
+pub trait SharedPages {
+    /// Pgoff of a page holding the same bytes as `page`, the entry's page at
+    /// `gpa`, if there is one.
+    fn find(&mut self, gpa: u64, page: &[u8]) -> io::Result<Option<u64>>;
+
+    /// Called for each page that is uploaded instead, read from
+    /// `file_offset` of the memory file into `image_offset` of the entry's
+    /// image.
+    fn uploaded(&mut self, _page: &[u8], _file_offset: u64, _image_offset: u64) {}
+}
+
+/// The pages of a base image, at the same guest addresses.
+struct BasePages<'a> {
+    template: &'a PseudoMmTemplate,
+    mem_file: &'a File,
+    /// See `base_ranges`.
+    ranges: Vec<(u64, u64, u64)>,
+    page: Vec<u8>,
+}
+
+impl<'a> BasePages<'a> {
+    fn new(template: &'a PseudoMmTemplate, mem_file: &'a File, page_size: PageSize) -> Self {
+        BasePages {
+            template,
+            mem_file,
+            ranges: base_ranges(&template.regions, page_size),
+            page: vec![0u8; page_size.bytes() as usize],
+        }
+    }
+}
+
+impl<'a> SharedPages for BasePages<'a> {
+    fn find(&mut self, gpa: u64, page: &[u8]) -> io::Result<Option<u64>> {
+        if holds_marker(self.template, gpa, page.len() as u64) {
+            return Ok(None);
+        }
+        let base = self.template.rdma_base_pgoff.raw();
+        let in_image = base_pgoff(&self.ranges, gpa, page.len() as u64).and_then(|pgoff| {
+            let page = pgoff.checked_sub(base)?;
+            Some((pgoff, page * PAGE_SIZE))
+        });
+        match in_image {
+            Some((pgoff, at)) => {
+                self.mem_file.read_exact_at(&mut self.page, at)?;
+                Ok(if page == &self.page[..] {
+                    Some(pgoff)
+                } else {
+                    None
+                })
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// The pages of a base image in the chunks whose content hash the memory
+/// file still matches, at the same guest addresses.
+struct UnchangedChunks {
+    /// `(gpa, size)` of each chunk that matched, sorted by GPA.
+    chunks: Vec<(u64, u64)>,
+    /// See `base_ranges`.
+    ranges: Vec<(u64, u64, u64)>,
+}
+
+impl UnchangedChunks {
+    /// Hashes `mem_file`, the memory file of an entry with `states`, at the
+    /// guest addresses of the chunks `template` has hashes of.
+    fn new(
+        template: &PseudoMmTemplate,
+        states: &[GuestMemoryRegionState],
+        mem_file: &dyn ReadAt,
+        page_size: PageSize,
+    ) -> io::Result<Self> {
+        let mut chunks = Vec::new();
+        let mut buf = Vec::new();
+        let regions = template
+            .regions
+            .iter()
+            .filter(|region| region.page_size == page_size);
+        for region in regions {
+            let hashes = match region.content_hashes.as_ref() {
+                Some(hashes) => hashes,
+                None => continue,
+            };
+            for (idx, recorded) in hashes.sha256.iter().enumerate() {
+                let offset = idx as u64 * hashes.chunk_size;
+                let size = std::cmp::min(hashes.chunk_size, region.size.saturating_sub(offset));
+                let gpa = region.gpa.raw() + offset;
+                let state = states.iter().find(|state| {
+                    state.base_address <= gpa
+                        && gpa + size <= state.base_address + state.size as u64
+                });
+                let file_offset = match state {
+                    Some(state) if size > 0 => state.offset + (gpa - state.base_address),
+                    _ => continue,
+                };
+                buf.resize(size as usize, 0);
+                mem_file.read_exact_at(&mut buf, file_offset)?;
+                if region_hash::digest(&buf) == *recorded {
+                    chunks.push((gpa, size));
+                }
+            }
+        }
+        chunks.sort_by_key(|chunk| chunk.0);
+        Ok(UnchangedChunks {
+            chunks,
+            ranges: base_ranges(&template.regions, page_size),
+        })
+    }
+}
+
+impl SharedPages for UnchangedChunks {
+    fn find(&mut self, gpa: u64, page: &[u8]) -> io::Result<Option<u64>> {
+        let unit = page.len() as u64;
+        let idx = match self.chunks.binary_search_by_key(&gpa, |chunk| chunk.0) {
+            Ok(idx) => idx,
+            Err(0) => return Ok(None),
+            Err(idx) => idx - 1,
+        };
+        let (start, size) = self.chunks[idx];
+        if gpa + unit > start + size {
+            return Ok(None);
+        }
+        Ok(base_pgoff(&self.ranges, gpa, unit))
+    }
+}
+
+/// The base image's copy of each page of an entry's memory file, found by
+/// guest address; zero where the base maps none.
+struct BaseFill<'a> {
+    states: &'a [GuestMemoryRegionState],
+    /// See `base_ranges`.
+    ranges: Vec<(u64, u64, u64)>,
+    /// Pgoff of the first page of `mem_file`.
+    base_pgoff: u64,
+    mem_file: &'a File,
+}
+
+impl<'a> ReadAt for BaseFill<'a> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        for (idx, page) in buf.chunks_mut(PAGE_SIZE as usize).enumerate() {
+            let at = offset + idx as u64 * PAGE_SIZE;
+            let in_image = self
+                .states
+                .iter()
+                .find(|state| state.offset <= at && at < state.offset + state.size as u64)
+                .and_then(|state| {
+                    let gpa = state.base_address + (at - state.offset);
+                    base_pgoff(&self.ranges, gpa, PAGE_SIZE)?.checked_sub(self.base_pgoff)
+                });
+            match in_image {
+                Some(pages) => self.mem_file.read_exact_at(page, pages * PAGE_SIZE)?,
+                None => page.iter_mut().for_each(|byte| *byte = 0),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares the memory file with the base's, or the base's content hashes
+/// without one, for an entry with `states` mapped with `page_size` pages.
+/// With `diff_snapshot`, the memory file is a diff taken against the base;
+/// see `diff_snapshot`.
+pub fn layer(
+    states: &[GuestMemoryRegionState],
+    mem_files: &MemFiles,
+    base: BaseFiles,
+    page_size: PageSize,
+    diff_snapshot: bool,
+) -> Result<Layers, Box<dyn Error>> {
+    let (template, base_mem) = load_base(base)?;
+    let mem = mem_files.open(page_size)?;
+    let base_mem = match base_mem {
+        Some(base_mem) => base_mem,
+        None if diff_snapshot => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a diff snapshot needs the base's memory file",
+            )))
+        }
+        None => {
+            if template
+                .regions
+                .iter()
+                .all(|region| region.content_hashes.is_none())
+            {
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} records no content hashes to compare with; only templates \
+                         uploaded from a memory file have them",
+                        base.template
+                    ),
+                )));
+            }
+            let mut shared = UnchangedChunks::new(&template, states, &mem, page_size)?;
+            return Ok(diff(states, &mem, page_size, &mut shared)?);
+        }
+    };
+    let mut shared = BasePages::new(&template, &base_mem, page_size);
+    if diff_snapshot {
+        let fill = BaseFill {
+            states,
+            ranges: base_ranges(&template.regions, page_size),
+            base_pgoff: template.rdma_base_pgoff.raw(),
+            mem_file: &base_mem,
+        };
+        let view = DiffView::new(mem, &fill)?;
+        return Ok(diff(states, &view, page_size, &mut shared)?);
+    }
+    Ok(diff(states, &mem, page_size, &mut shared)?)
+}
+
+/// Maps the pages `bitmap` marks clean from the base without reading them,
+/// and uploads the dirty ones, for an entry with `states` mapped with
+/// `page_size` pages. The base must map every clean page, from its image or
+/// as a zero page.
+pub fn layer_dirty(
+    states: &[GuestMemoryRegionState],
+    mem_files: &MemFiles,
+    base: BaseFiles,
+    page_size: PageSize,
+    bitmap: &DirtyBitmap,
+) -> Result<Layers, Box<dyn Error>> {
+    let (template, _) = load_base(base)?;
+    let mem = mem_files.open(page_size)?;
+    dirty_layers(states, &mem, &template, page_size, bitmap).map_err(|err| {
+        Box::new(io::Error::new(
+            err.kind(),
+            format!("base template {}: {}", base.template, err),
+        )) as Box<dyn Error>
+    })
+}
+
+fn dirty_layers(
+    states: &[GuestMemoryRegionState],
+    mem: &dyn ReadAt,
+    template: &PseudoMmTemplate,
+    page_size: PageSize,
+    bitmap: &DirtyBitmap,
+) -> io::Result<Layers> {
+    let ranges = base_ranges(&template.regions, page_size);
+    let unit = page_size.bytes();
+    let mut page = vec![0u8; unit as usize];
+    let mut runs = Vec::new();
+    let mut windows: Vec<ImageWindow> = Vec::new();
+    let mut image_size = 0;
+    let (mut dirty, mut clean) = (0, 0);
+    // GPAs of the clean pages the base doesn't map.
+    let mut uncovered = Vec::new();
+
+    let mut order: Vec<&GuestMemoryRegionState> = states.iter().collect();
+    order.sort_by_key(|state| state.offset);
+    for state in order {
+        let mut offset = 0;
+        while offset < state.size as u64 {
+            let gpa = state.base_address + offset;
+            let file_offset = state.offset + offset;
+            offset += unit;
+            let source = if bitmap.is_dirty(file_offset, unit) || holds_marker(template, gpa, unit)
+            {
+                dirty += unit / PAGE_SIZE;
+                mem.read_exact_at(&mut page, file_offset)?;
+                if zero_pages::is_zero(&page) {
+                    Source::Zero
+                } else {
+                    upload(&mut windows, &mut image_size, file_offset, unit)
+                }
+            } else {
+                clean += unit / PAGE_SIZE;
+                match base_pgoff(&ranges, gpa, unit) {
+                    Some(pgoff) => Source::Base(pgoff),
+                    None if maps(&template.regions, gpa, unit, page_size) => Source::Zero,
+                    None => {
+                        uncovered.push(gpa);
+                        continue;
+                    }
+                }
+            };
+            push_run(&mut runs, gpa, unit, source);
+        }
+    }
+    if let Some(first) = uncovered.first() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "doesn't map {} clean page(s) of the dirty bitmap, the first at gpa 0x{:x}",
+                uncovered.len(),
+                first
+            ),
+        ));
+    }
+    runs.sort_by_key(|run| run.gpa);
+    let mut layers = Layers::new(runs, windows);
+    layers.dirty = Some((dirty, clean));
+    Ok(layers)
+}
+
+/// Whether `size` bytes at `gpa` hold the marker page of `template`, which
+/// the server has in place of the page in its memory file; see
+/// `marker_page`.
+fn holds_marker(template: &PseudoMmTemplate, gpa: u64, size: u64) -> bool {
+    template.marker.as_ref().map_or(false, |marker| {
+        let at = marker.gpa.raw();
+        at < gpa + size && gpa < at + PAGE_SIZE
+    })
+}
+
+/// Loads the template an entry is layered on and opens its image, if given.
+fn load_base(base: BaseFiles) -> Result<(PseudoMmTemplate, Option<File>), Box<dyn Error>> {
+    let invalid = |reason: String| {
+        Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("base template {}: {}", base.template, reason),
+        ))
+    };
+    let template = pseudo_mm_support::load_template_file(Path::new(base.template))?;
+    if template.mem_backend != MemBackend::Rdma {
+        return Err(invalid(format!(
+            "has a {} image; only RDMA images can be shared",
+            template.mem_backend
+        )));
+    }
+    if let Some(parent) = template.base_template.as_ref() {
+        return Err(invalid(format!(
+            "is itself layered on {}; layer on that template instead",
+            parent
+        )));
+    }
+    let path = match base.mem_file {
+        Some(path) => path,
+        None => return Ok((template, None)),
+    };
+    let base_mem = File::open(path)?;
+    let base_size = base_mem.metadata()?.len();
+    if base_size != template.rdma_image_size {
+        return Err(invalid(format!(
+            "image is {} bytes, but {} has {}",
+            template.rdma_image_size, path, base_size
+        )));
+    }
+    Ok((template, Some(base_mem)))
+}
+
+/// `(gpa, size, pgoff)` of the base's image-backed ranges mapped with
+/// `page_size` pages, sorted by GPA.
+fn base_ranges(base: &[RegionMetadata], page_size: PageSize) -> Vec<(u64, u64, u64)> {
+    let mut ranges: Vec<(u64, u64, u64)> = base
+        .iter()
+        .filter(|region| region.page_size == page_size)
+        .flat_map(|region| {
+            let gpa = region.gpa.raw();
+            region
+                .backing_ranges()
+                .into_iter()
+                .map(move |(offset, size, pgoff)| (gpa + offset, size, pgoff.raw()))
+        })
+        .collect();
+    ranges.sort_by_key(|range| range.0);
+    ranges
+}
+
+/// Pgoff of the base's page of `unit` bytes at `gpa`, if `ranges` map one.
+fn base_pgoff(ranges: &[(u64, u64, u64)], gpa: u64, unit: u64) -> Option<u64> {
+    let idx = match ranges.binary_search_by_key(&gpa, |range| range.0) {
+        Ok(idx) => idx,
+        Err(0) => return None,
+        Err(idx) => idx - 1,
+    };
+    let (start, size, pgoff) = ranges[idx];
+    if gpa + unit <= start + size {
+        Some(pgoff + (gpa - start) / PAGE_SIZE)
+    } else {
+        None
+    }
+}
+
+/// Whether a region of `base` mapped with `page_size` pages holds the
+/// `unit` bytes at `gpa`.
+fn maps(base: &[RegionMetadata], gpa: u64, unit: u64, page_size: PageSize) -> bool {
+    base.iter().any(|region| {
+        region.page_size == page_size
+            && region.gpa.raw() <= gpa
+            && gpa + unit <= region.gpa.raw() + region.size
+    })
+}
+
+/// Adds the `unit` bytes at `file_offset` of the memory file to the entry's
+/// image, `image_size` bytes so far.
+fn upload(
+    windows: &mut Vec<ImageWindow>,
+    image_size: &mut u64,
+    file_offset: u64,
+    unit: u64,
+) -> Source {
+    match windows.last_mut() {
+        Some(last) if last.file_offset + last.size == file_offset => last.size += unit,
+        _ => windows.push(ImageWindow {
+            file_offset,
+            size: unit,
+            image_offset: *image_size,
+        }),
+    }
+    *image_size += unit;
+    Source::Overlay(*image_size - unit)
+}
+
+fn push_run(runs: &mut Vec<Run>, gpa: u64, size: u64, source: Source) {
+    if let Some(last) = runs.last_mut() {
+        if last.gpa + last.size == gpa && last.source.advance(last.size) == source {
+            last.size += size;
+            return;
+        }
+    }
+    runs.push(Run { gpa, size, source });
+}
+
+/// Finds where each page of the memory file is mapped from, and the parts
+/// of the file to upload for the pages `shared` has no copy of.
+pub fn diff(
+    states: &[GuestMemoryRegionState],
+    mem_file: &dyn ReadAt,
+    page_size: PageSize,
+    shared: &mut dyn SharedPages,
+) -> io::Result<Layers> {
+    let unit = page_size.bytes();
+    let mut buf = Vec::new();
+    let mut runs = Vec::new();
+    let mut windows: Vec<ImageWindow> = Vec::new();
+    let mut image_size = 0;
+
+    let mut order: Vec<&GuestMemoryRegionState> = states.iter().collect();
+    order.sort_by_key(|state| state.offset);
+    for state in order {
+        let size = state.size as u64;
+        let mut done = 0;
+        while done < size {
+            let len = std::cmp::min(CHUNK, size - done);
+            buf.resize(len as usize, 0);
+            mem_file.read_exact_at(&mut buf, state.offset + done)?;
+            for (idx, page) in buf.chunks(unit as usize).enumerate() {
+                let offset = done + idx as u64 * unit;
+                let gpa = state.base_address + offset;
+                let found = if zero_pages::is_zero(page) {
+                    Some(Source::Zero)
+                } else {
+                    shared.find(gpa, page)?.map(Source::Base)
+                };
+                let source = found.unwrap_or_else(|| {
+                    let file_offset = state.offset + offset;
+                    shared.uploaded(page, file_offset, image_size);
+                    upload(&mut windows, &mut image_size, file_offset, unit)
+                });
+                push_run(&mut runs, gpa, unit, source);
+            }
+            done += len;
+        }
+    }
+    runs.sort_by_key(|run| run.gpa);
+    Ok(Layers::new(runs, windows))
+}
+
+/// Whether a piece of `size` bytes at `pgoff` can be extended by one at
+/// `next`; `None` is zero.
+fn continues(pgoff: Option<u64>, size: u64, next: Option<u64>) -> bool {
+    match (pgoff, next) {
+        (None, None) => true,
+        (Some(pgoff), Some(next)) => pgoff + size / PAGE_SIZE == next,
+        _ => false,
+    }
+}
+
+/// Points `regions` at the pages `layers` found for them, with the entry's
+/// image at `rdma_pgoff`.
+///
+/// `rdma_offset` is set from the first mapped page of each region, and
+/// `extents` cover whatever doesn't follow on from it. The overlay holds no
+/// zero pages, so the upload skips none and `zero_ranges` are exactly the
+/// zero pages found by the diff.
+pub fn apply(regions: &mut [RegionMetadata], layers: &Layers, rdma_pgoff: PageOffset) {
+    for region in regions {
+        let start = region.gpa.raw();
+        let end = start + region.size;
+        // (offset, size, pgoff) of each piece of the region, merged where
+        // pgoffs run on.
+        let mut pieces: Vec<(u64, u64, Option<u64>)> = Vec::new();
+        let overlapping = layers
+            .runs
+            .iter()
+            .filter(|run| run.gpa < end && start < run.gpa + run.size);
+        for run in overlapping {
+            let from = std::cmp::max(run.gpa, start);
+            let to = std::cmp::min(run.gpa + run.size, end);
+            let pgoff = match run.source.advance(from - run.gpa) {
+                Source::Overlay(offset) => Some(rdma_pgoff.raw() + offset / PAGE_SIZE),
+                Source::Base(pgoff) => Some(pgoff),
+                Source::Zero => None,
+            };
+            let offset = from - start;
+            match pieces.last_mut() {
+                Some(last) if last.0 + last.1 == offset && continues(last.2, last.1, pgoff) => {
+                    last.1 += to - from
+                }
+                _ => pieces.push((offset, to - from, pgoff)),
+            }
+        }
+
+        let first = pieces
+            .iter()
+            .filter_map(|&(offset, _, pgoff)| pgoff.map(|pgoff| (offset, pgoff)))
+            .next();
+        let rdma_offset = match first {
+            Some((offset, pgoff)) => pgoff.checked_sub(offset / PAGE_SIZE).unwrap_or(pgoff),
+            None => rdma_pgoff.raw(),
+        };
+        region.rdma_offset = PageOffset(rdma_offset);
+        region.zero_ranges = pieces
+            .iter()
+            .filter(|piece| piece.2.is_none())
+            .map(|&(offset, size, _)| ZeroRange { offset, size })
+            .collect();
+        region.extents = pieces
+            .iter()
+            .filter_map(|&(offset, size, pgoff)| {
+                let pgoff = pgoff?;
+                if pgoff == rdma_offset + offset / PAGE_SIZE {
+                    return None;
+                }
+                Some(PgoffExtent {
+                    offset,
+                    size,
+                    rdma_offset: PageOffset(pgoff),
+                })
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem_files::MemImage;
+    use crate::test_files;
+    use crate::test_templates::{self, region};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use vmm::pseudo_mm_addr::Gpa;
+    use vmm::pseudo_mm_support::{MarkerPage, RegionHashes};
+
+    fn scratch(name: &str, pages: &[u8]) -> (PathBuf, File) {
+        let path = test_files::page_file(&format!("layered_{}", name), pages);
+        let file = File::open(&path).unwrap();
+        (path, file)
+    }
+
+    fn image(path: &Path) -> MemImage {
+        MemFiles::new(vec![path.to_string_lossy().into_owned()])
+            .open(PageSize::Base)
+            .unwrap()
+    }
+
+    fn base_template() -> PseudoMmTemplate {
+        let mut base = region(0, 4, 100);
+        base.zero_ranges = vec![ZeroRange {
+            offset: 3 * PAGE_SIZE,
+            size: PAGE_SIZE,
+        }];
+        PseudoMmTemplate {
+            rdma_base_pgoff: PageOffset(100),
+            rdma_image_size: 4 * PAGE_SIZE,
+            regions: vec![base],
+            ..test_templates::template()
+        }
+    }
+
+    #[test]
+    fn test_diff_and_apply() {
+        // The base holds pages 1, 2 and 3 at GPA 0, then a zero page.
+        let (base_path, base_mem) = scratch("base", &[1, 2, 3, 0]);
+        // Page 1 changed, and a second region has no base at all.
+        let (path, _) = scratch("new", &[1, 9, 3, 0, 5]);
+        let mem = image(&path);
+        let states = vec![
+            GuestMemoryRegionState {
+                base_address: 0,
+                size: 4 * PAGE_SIZE as usize,
+                offset: 0,
+            },
+            GuestMemoryRegionState {
+                base_address: 0x10_0000,
+                size: PAGE_SIZE as usize,
+                offset: 4 * PAGE_SIZE,
+            },
+        ];
+        let template = base_template();
+        let mut shared = BasePages::new(&template, &base_mem, PageSize::Base);
+        let layers = diff(&states, &mem, PageSize::Base, &mut shared).unwrap();
+        assert_eq!(
+            layers.windows,
+            vec![
+                ImageWindow {
+                    file_offset: PAGE_SIZE,
+                    size: PAGE_SIZE,
+                    image_offset: 0,
+                },
+                ImageWindow {
+                    file_offset: 4 * PAGE_SIZE,
+                    size: PAGE_SIZE,
+                    image_offset: PAGE_SIZE,
+                },
+            ]
+        );
+        assert_eq!((layers.overlay_pages, layers.shared_pages), (2, 2));
+
+        let mut regions = vec![region(0, 4, 0), region(0x10_0000, 1, 4)];
+        apply(&mut regions, &layers, PageOffset(500));
+        assert_eq!(regions[0].rdma_offset, PageOffset(100));
+        assert_eq!(
+            regions[0].backing_ranges(),
+            vec![
+                (0, PAGE_SIZE, PageOffset(100)),
+                (PAGE_SIZE, PAGE_SIZE, PageOffset(500)),
+                (2 * PAGE_SIZE, PAGE_SIZE, PageOffset(102)),
+            ]
+        );
+        assert_eq!(regions[0].extents.len(), 1);
+        assert_eq!(
+            regions[0].zero_ranges,
+            vec![ZeroRange {
+                offset: 3 * PAGE_SIZE,
+                size: PAGE_SIZE,
+            }]
+        );
+        assert_eq!(regions[1].rdma_offset, PageOffset(501));
+        assert!(regions[1].extents.is_empty());
+        assert!(pseudo_mm_support::validate_regions(&regions).is_ok());
+
+        fs::remove_file(base_path).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_diff_snapshot_shares_its_holes() {
+        let (base_path, base_mem) = scratch("diff_base", &[1, 2, 3, 0]);
+        // Only page 2 was dirtied, and changed; pages 0, 1 and 3 are holes.
+        let (path, _) = scratch("diff_new", &[0, 0, 7, 0]);
+        let states = vec![GuestMemoryRegionState {
+            base_address: 0,
+            size: 4 * PAGE_SIZE as usize,
+            offset: 0,
+        }];
+        let template = base_template();
+        let fill = BaseFill {
+            states: &states,
+            ranges: base_ranges(&template.regions, PageSize::Base),
+            base_pgoff: template.rdma_base_pgoff.raw(),
+            mem_file: &base_mem,
+        };
+        let view =
+            DiffView::with_extents(image(&path), vec![(2 * PAGE_SIZE, 3 * PAGE_SIZE)], &fill);
+        let mut shared = BasePages::new(&template, &base_mem, PageSize::Base);
+        let layers = diff(&states, &view, PageSize::Base, &mut shared).unwrap();
+        assert_eq!(
+            layers.windows,
+            vec![ImageWindow {
+                file_offset: 2 * PAGE_SIZE,
+                size: PAGE_SIZE,
+                image_offset: 0,
+            }]
+        );
+        assert_eq!((layers.overlay_pages, layers.shared_pages), (1, 2));
+
+        fs::remove_file(base_path).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_dirty_bitmap_maps_clean_pages() {
+        // Clean pages aren't read, so the memory file's bytes there don't
+        // matter; page 1 is dirty.
+        let (path, _) = scratch("dirty_new", &[0, 9, 0, 0, 5]);
+        let mem = image(&path);
+        let mut states = vec![GuestMemoryRegionState {
+            base_address: 0,
+            size: 4 * PAGE_SIZE as usize,
+            offset: 0,
+        }];
+        let template = base_template();
+        let bitmap = DirtyBitmap::load_bits(vec![0b0000_0010], 5 * PAGE_SIZE);
+        let layers = dirty_layers(&states, &mem, &template, PageSize::Base, &bitmap).unwrap();
+        assert_eq!(
+            layers.windows,
+            vec![ImageWindow {
+                file_offset: PAGE_SIZE,
+                size: PAGE_SIZE,
+                image_offset: 0,
+            }]
+        );
+        assert_eq!((layers.overlay_pages, layers.shared_pages), (1, 2));
+        assert_eq!(layers.dirty, Some((1, 3)));
+        let stats = layers.stats("/srv/base.json");
+        assert_eq!((stats.dirty_pages, stats.clean_pages), (Some(1), Some(3)));
+
+        // A clean page in a second region, which the base has no copy of.
+        states.push(GuestMemoryRegionState {
+            base_address: 0x10_0000,
+            size: PAGE_SIZE as usize,
+            offset: 4 * PAGE_SIZE,
+        });
+        let err = dirty_layers(&states, &mem, &template, PageSize::Base, &bitmap).unwrap_err();
+        assert!(
+            err.to_string().contains(
+                "doesn't map 1 clean page(s) of the dirty bitmap, the first at gpa 0x100000"
+            ),
+            "{}",
+            err
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_identical_files_share_everything() {
+        let (base_path, base_mem) = scratch("same_base", &[1, 2, 3, 0]);
+        let (path, _) = scratch("same_new", &[1, 2, 3, 0]);
+        let mem = image(&path);
+        let states = vec![GuestMemoryRegionState {
+            base_address: 0,
+            size: 4 * PAGE_SIZE as usize,
+            offset: 0,
+        }];
+        let template = base_template();
+        let mut shared = BasePages::new(&template, &base_mem, PageSize::Base);
+        let layers = diff(&states, &mem, PageSize::Base, &mut shared).unwrap();
+        assert!(layers.windows.is_empty());
+        assert_eq!(
+            layers.runs,
+            vec![
+                Run {
+                    gpa: 0,
+                    size: 3 * PAGE_SIZE,
+                    source: Source::Base(100),
+                },
+                Run {
+                    gpa: 3 * PAGE_SIZE,
+                    size: PAGE_SIZE,
+                    source: Source::Zero,
+                },
+            ]
+        );
+
+        fs::remove_file(base_path).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_marker_page_is_not_shared() {
+        let (base_path, base_mem) = scratch("marker_base", &[1, 2, 3, 0]);
+        let (path, _) = scratch("marker_new", &[1, 2, 3, 0]);
+        let mem = image(&path);
+        let states = vec![GuestMemoryRegionState {
+            base_address: 0,
+            size: 4 * PAGE_SIZE as usize,
+            offset: 0,
+        }];
+        let mut template = base_template();
+        template.marker = Some(MarkerPage {
+            gpa: Gpa(PAGE_SIZE),
+            image_hash: "ab".repeat(32),
+            created_at: 0,
+            original: "02".repeat(PAGE_SIZE as usize),
+        });
+        let marked = vec![ImageWindow {
+            file_offset: PAGE_SIZE,
+            size: PAGE_SIZE,
+            image_offset: 0,
+        }];
+        let mut shared = BasePages::new(&template, &base_mem, PageSize::Base);
+        let layers = diff(&states, &mem, PageSize::Base, &mut shared).unwrap();
+        assert_eq!(layers.windows, marked);
+        assert_eq!((layers.overlay_pages, layers.shared_pages), (1, 2));
+
+        // Clean in the bitmap, but the base image doesn't hold it either.
+        let bitmap = DirtyBitmap::load_bits(vec![0], 4 * PAGE_SIZE);
+        let layers = dirty_layers(&states, &mem, &template, PageSize::Base, &bitmap).unwrap();
+        assert_eq!(layers.windows, marked);
+
+        fs::remove_file(base_path).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_refresh_shares_unchanged_chunks() {
+        let (old_path, old_mem) = scratch("refresh_old", &[1, 2, 3, 0]);
+        let mut old_image = vec![0u8; 4 * PAGE_SIZE as usize];
+        old_mem.read_exact_at(&mut old_image, 0).unwrap();
+        let mut template = base_template();
+        // Two-page chunks, so the test stays small.
+        template.regions[0].content_hashes = Some(RegionHashes {
+            chunk_size: 2 * PAGE_SIZE,
+            sha256: old_image
+                .chunks(2 * PAGE_SIZE as usize)
+                .map(region_hash::digest)
+                .collect(),
+        });
+        let states = vec![GuestMemoryRegionState {
+            base_address: 0,
+            size: 4 * PAGE_SIZE as usize,
+            offset: 0,
+        }];
+
+        // Page 1, in the first chunk, changed: that chunk is uploaded whole,
+        // the second is shared.
+        for (name, pages, overlay, shared) in &[
+            ("refresh_first", [1, 7, 3, 0], 2, 1),
+            ("refresh_last", [1, 2, 3, 9], 2, 2),
+            ("refresh_same", [1, 2, 3, 0], 0, 3),
+        ] {
+            let (path, _) = scratch(name, pages);
+            let mem = image(&path);
+            let mut chunks =
+                UnchangedChunks::new(&template, &states, &mem, PageSize::Base).unwrap();
+            let layers = diff(&states, &mem, PageSize::Base, &mut chunks).unwrap();
+            assert_eq!(
+                (layers.overlay_pages, layers.shared_pages),
+                (*overlay, *shared),
+                "{}",
+                name
+            );
+
+            // What the server holds: the old image at 100, the refreshed
+            // one's at 500.
+            let new_image = fs::read(&path).unwrap();
+            let mut server = HashMap::new();
+            for (idx, page) in old_image.chunks(PAGE_SIZE as usize).enumerate() {
+                server.insert(100 + idx as u64, page.to_vec());
+            }
+            for window in &layers.windows {
+                for page in 0..window.size / PAGE_SIZE {
+                    let at = (window.file_offset + page * PAGE_SIZE) as usize;
+                    server.insert(
+                        500 + window.image_offset / PAGE_SIZE + page,
+                        new_image[at..at + PAGE_SIZE as usize].to_vec(),
+                    );
+                }
+            }
+            let mut regions = vec![region(0, 4, 0)];
+            apply(&mut regions, &layers, PageOffset(500));
+            let mut restored = vec![0u8; 4 * PAGE_SIZE as usize];
+            for (offset, size, pgoff) in regions[0].backing_ranges() {
+                for page in 0..size / PAGE_SIZE {
+                    let at = (offset + page * PAGE_SIZE) as usize;
+                    restored[at..at + PAGE_SIZE as usize]
+                        .copy_from_slice(&server[&(pgoff.raw() + page)]);
+                }
+            }
+            assert!(restored == new_image, "{} doesn't restore", name);
+            fs::remove_file(path).unwrap();
+        }
+
+        fs::remove_file(old_path).unwrap();
+    }
+}
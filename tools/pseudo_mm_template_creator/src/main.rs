@@ -2,445 +2,2155 @@
 //!
 //! Creates a pseudo_mm template from a Firecracker snapshot.
 
+mod batch;
+mod capacity;
+mod dax;
+mod deadline;
+mod dedup;
+mod diff_snapshot;
+mod dirty_bitmap;
+mod env_expand;
+mod fd_budget;
+mod freshness;
+mod guard_pages;
+mod image_reuse;
+mod inspect;
+mod instance_registry;
+mod json_output;
+mod layered;
+mod marker_page;
+mod mem_files;
+mod mem_reader;
+mod namespace;
+mod occupancy;
+mod output_lock;
+mod page_cache;
+mod page_dedup;
+mod page_hash;
+mod pgoff_alloc;
+mod pgoff_extents;
+mod pgoff_registry;
+mod provenance;
+mod rate_limit;
+mod rebase;
+mod region_hash;
+mod regions;
+mod resume;
+mod run_metrics;
+mod sha256;
+mod single;
+mod snapshot_check;
+mod snapshot_glob;
+mod subcommands;
+mod template_error;
+#[cfg(test)]
+mod test_files;
+#[cfg(test)]
+mod test_templates;
+mod upload;
+mod upload_progress;
+mod zero_pages;
+
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
-use std::net::TcpStream;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::{App, Arg};
-use serde::Deserialize;
+use clap::{App, AppSettings, Arg, ArgMatches};
+use serde::Serialize;
 use serde_json;
-use snapshot::Snapshot;
-use versionize::VersionMap;
-use vmm::memory_snapshot::GuestMemoryState;
-use vmm::persist::MicrovmState;
-use vmm::pseudo_mm_support::{self, PseudoMmTemplate, RegionMetadata, RDMA_MEM};
+use snapshot::{Snapshot, SnapshotVersions};
+use vmm::persist::{self, MicrovmState};
+use vmm::pseudo_mm_addr::{self, Gpa, HvaAddr, PageOffset, ParseAddrError};
+use vmm::pseudo_mm_cancel::{self, CancelToken};
+use vmm::pseudo_mm_support::{
+    self, ImageExtent, MemBackend, PageSize, PseudoMmTemplate, RegionMetadata, RetryPolicy,
+    SnapshotSource, SourceFiles, VmShape, PAGE_SIZE,
+};
+use vmm::version_map::{FC_VERSION_TO_SNAP_VERSION, VERSION_MAP};
 
-const DEFAULT_PSEUDO_MM_BASE: u64 = 0x7000_0000_0000;
-const PAGE_SIZE: u64 = 4096;
+use batch::BatchTemplateEntry;
+use deadline::{EntryDeadline, RunLimits};
+use dirty_bitmap::DirtyBitmap;
+use image_reuse::ReusedImage;
+use instance_registry::Instance;
+use layered::{BaseFiles, LayerStats, Layers};
+use mem_files::{MemFiles, MemImage};
+use namespace::PgoffNamespace;
+use output_lock::OutputLock;
+use page_dedup::{DedupStats, EntryPages, PageStore};
+use pgoff_extents::ExtentPart;
+use region_hash::ChunkDigests;
+use regions::{HvaLayout, ImageWindow, MapBudget, MapCountCheck};
+use resume::ResumedEntry;
+use run_metrics::{EntryRecorder, RunMetrics, SharedMetrics};
+use template_error::TemplateError;
+use upload::{
+    copy_memory_to_dax, open_dax_device, rdma_guard_pages, server_failure, upload_extents_to_rdma,
+    upload_memory_to_rdma, upload_merged_to_rdma, upload_stream_to_rdma, zero_guard_pages,
+    UploadArgs, UploadOptions, UploadStats,
+};
+use upload_progress::{ProgressStyle, UploadProgress};
+use zero_pages::PageRuns;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let matches = App::new("Pseudo_MM Template Creator")
-        .version("1.0")
-        .about("Creates pseudo_mm template from Firecracker snapshot")
-        .arg(
-            Arg::with_name("snapshot")
-                .long("snapshot-path")
-                .value_name("FILE")
-                .required_unless("batch-config")
-                .help("Path to snapshot file"),
-        )
-        .arg(
-            Arg::with_name("mem-file")
-                .long("mem-file-path")
-                .value_name("FILE")
-                .required_unless("batch-config")
-                .help("Path to memory file"),
-        )
-        .arg(
-            Arg::with_name("output")
-                .long("output-path")
-                .value_name("FILE")
-                .required_unless("batch-config")
-                .help("Output template path"),
-        )
-        .arg(
-            Arg::with_name("rdma-server")
-                .long("rdma-server")
-                .value_name("ADDR")
-                .required_unless("batch-config")
-                .help("RDMA control-plane address (host:port)"),
-        )
-        .arg(
-            Arg::with_name("rdma-pgoff")
-                .long("rdma-pgoff")
-                .value_name("PAGES")
-                .required_unless("batch-config")
-                .help("Base RDMA page offset to store this snapshot"),
-        )
-        .arg(
-            Arg::with_name("hva-base")
-                .long("hva-base")
-                .value_name("ADDRESS")
-                .help("Base HVA address (hex, default: 0x700000000000)"),
-        )
-        .arg(
-            Arg::with_name("batch-config")
-                .long("batch-config")
-                .value_name("FILE")
-                .conflicts_with("snapshot")
-                .help("JSON file describing multiple templates to generate"),
-        )
-        .get_matches();
+pub const DEFAULT_PSEUDO_MM_BASE: HvaAddr = HvaAddr(0x7000_0000_0000);
 
-    if let Some(config_path) = matches.value_of("batch-config") {
-        run_batch(config_path)?;
-        return Ok(());
-    }
+/// Bytes read and sent to the RDMA server at a time during uploads, unless
+/// `--upload-chunk-size` says otherwise, and copied at a time to DAX.
+const UPLOAD_CHUNK: usize = 4 << 20;
 
-    let snapshot_path = matches.value_of("snapshot").unwrap();
-    let mem_file_path = matches.value_of("mem-file").unwrap();
-    let output_path = matches.value_of("output").unwrap();
-    let rdma_server = matches.value_of("rdma-server").unwrap();
-    let rdma_pgoff: u64 = matches
-        .value_of("rdma-pgoff")
-        .and_then(|s| s.parse().ok())
-        .expect("rdma-pgoff must be an unsigned integer");
-    let hva_base =
-        parse_hex_address(matches.value_of("hva-base")).unwrap_or(DEFAULT_PSEUDO_MM_BASE);
+/// Set by SIGINT/SIGTERM; template creation watches it through a token.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-    let result = create_template(&TemplateArgs {
-        label: "single",
-        snapshot_path,
-        mem_file_path,
-        output_path,
-        rdma_server,
-        rdma_pgoff,
-        hva_base,
-    })?;
+extern "C" fn on_interrupt(signum: libc::c_int) {
+    const NOTICE: &[u8] =
+        b"\ninterrupted: finishing the current step and cleaning up, signal again to exit now\n";
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // A second signal terminates as usual, in case a stage doesn't return.
+    // write(2) is async-signal-safe, unlike the buffered stderr.
+    unsafe {
+        libc::signal(signum, libc::SIG_DFL);
+        libc::write(
+            libc::STDERR_FILENO,
+            NOTICE.as_ptr() as *const libc::c_void,
+            NOTICE.len(),
+        );
+    }
+}
 
-    println!("\nSummary:");
-    println!("  pseudo_mm_id: {}", result.pseudo_mm_id);
-    println!("  rdma_pgoff : {}", result.rdma_pgoff);
-    println!("  pages      : {}", result.mem_pages);
+/// Turns SIGINT and SIGTERM into cancellation of the returned token, so an
+/// interrupted run stops at the next chunk or region instead of mid-write.
+fn cancel_on_interrupt() -> CancelToken {
+    for &signum in &[libc::SIGINT, libc::SIGTERM] {
+        // Safe because the handler only stores to an atomic and resets the
+        // disposition, both async-signal-safe.
+        unsafe { libc::signal(signum, on_interrupt as *const () as libc::sighandler_t) };
+    }
+    CancelToken::from_static(&INTERRUPTED)
+}
 
-    Ok(())
+pub fn is_cancelled(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<io::Error>()
+        .map_or(false, pseudo_mm_cancel::is_cancelled)
 }
 
-fn run_batch(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Loading batch config from {}", config_path);
-    let file = File::open(config_path)?;
-    let config: BatchConfig = serde_json::from_reader(file)?;
+pub fn is_entry_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<io::Error>()
+        .map_or(false, deadline::is_entry_timeout)
+}
 
-    if config.templates.is_empty() {
-        return Err(Box::new(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "batch config has no templates",
-        )));
+pub fn is_output_exists(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<io::Error>()
+        .map_or(false, output_lock::is_output_exists)
+}
+
+fn main() {
+    if let Err(err) = run() {
+        let (kind, exit_code) = template_error::describe(err.as_ref());
+        eprintln!("error: {} (kind={}, exit_code={})", err, kind, exit_code);
+        if let Err(json_err) = json_output::emit_failure(&err.to_string(), kind, exit_code) {
+            eprintln!("warning: cannot write JSON output: {}", json_err);
+        }
+        std::process::exit(exit_code);
     }
+}
 
-    let default_rdma_server = config.rdma_server.clone();
-    let default_hva_base = parse_optional_hva(config.hva_base.as_deref())?;
-    let mut next_rdma_pgoff = config.default_rdma_pgoff.unwrap_or(0);
-    let mut summaries = Vec::new();
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = app().get_matches();
 
-    println!(
-        "Processing {} templates (starting rdma_pgoff={})",
-        config.templates.len(),
-        next_rdma_pgoff
+    if matches.value_of("output-format") == Some("json") {
+        if let Some(name) = matches.subcommand_name() {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--output-format json does not apply to '{}'", name),
+            )));
+        }
+        json_output::redirect()?;
+    }
+
+    let lock_wait = parse_lock_wait(&matches)?;
+    let instance_registry = PathBuf::from(
+        matches
+            .value_of("instance-registry")
+            .unwrap_or(instance_registry::DEFAULT_REGISTRY_PATH),
     );
+    let pgoff_registry = matches.value_of("registry").map(PathBuf::from);
 
-    for (idx, entry) in config.templates.iter().enumerate() {
-        let label = format!("batch-{}", idx + 1);
-        let rdma_server = entry
-            .rdma_server
-            .as_ref()
-            .or(default_rdma_server.as_ref())
-            .ok_or_else(|| {
+    match fd_budget::raise_nofile_limit() {
+        Ok(Some((old, new))) => println!("Raised open file limit from {} to {}", old, new),
+        Ok(None) => {}
+        Err(err) => println!("warning: cannot raise open file limit: {}", err),
+    }
+
+    if let (name, Some(sub_matches)) = matches.subcommand() {
+        return subcommands::run(
+            name,
+            sub_matches,
+            lock_wait,
+            &instance_registry,
+            pgoff_registry.as_ref().map(PathBuf::as_path),
+        );
+    }
+
+    let options = RunOptions::from_matches(&matches, lock_wait, instance_registry, pgoff_registry)?;
+    let metrics: SharedMetrics = Arc::new(Mutex::new(RunMetrics::new(
+        matches.value_of("metrics-out").map(PathBuf::from),
+        lock_wait,
+    )));
+    let limits = parse_limits(&matches, cancel_on_interrupt())?;
+
+    match matches.value_of("batch-config") {
+        Some(config_path) => batch::run(config_path, &matches, options, &limits, &metrics),
+        None => single::run(&matches, options, &limits, &metrics),
+    }
+}
+
+/// The command line. Each module adds the options it reads; those here apply
+/// to every template a run creates.
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("Pseudo_MM Template Creator")
+        .version("1.0")
+        .about("Creates pseudo_mm template from Firecracker snapshot")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&single::args())
+        .args(&upload::args())
+        .args(&batch::args())
+        .args(&[
+            Arg::with_name("snapshot-data-version")
+                .long("snapshot-data-version")
+                .value_name("VERSION")
+                .help(
+                    "Load snapshots as this data version instead of the one in their \
+                     header",
+                ),
+            Arg::with_name("validate-snapshot")
+                .long("validate-snapshot")
+                .help(
+                    "Check the snapshot's vCPU, device and memory state before making a \
+                     template, reporting every problem found",
+                ),
+            Arg::with_name("force")
+                .long("force")
+                .help("Replace templates that already exist at their output path"),
+            Arg::with_name("pgoff-align")
+                .long("pgoff-align")
+                .value_name("PAGES")
+                .help(
+                    "Start auto-assigned RDMA pgoffs at a multiple of PAGES, and require \
+                     explicit ones to be one; overrides the batch config's pgoff_align",
+                ),
+            Arg::with_name("pgoff-align-strict")
+                .long("pgoff-align-strict")
+                .value_name("BOOL")
+                .possible_values(&["true", "false"])
+                .help("With false, only warn about explicit RDMA pgoffs off the --pgoff-align"),
+            Arg::with_name("pgoff-namespace")
+                .long("pgoff-namespace")
+                .value_name("NAME")
+                .requires("pgoff-namespace-file")
+                .help("Allocate and validate rdma_pgoff within this tenant namespace"),
+            Arg::with_name("pgoff-namespace-file")
+                .long("pgoff-namespace-file")
+                .value_name("FILE")
+                .requires("pgoff-namespace")
+                .help("JSON mapping of namespace name to pgoff window"),
+            Arg::with_name("lock-wait-secs")
+                .long("lock-wait-secs")
+                .value_name("SECONDS")
+                .global(true)
+                .help("How long to wait for another writer to release an output file (default: 0)"),
+            Arg::with_name("instance-registry")
+                .long("instance-registry")
+                .value_name("FILE")
+                .global(true)
+                .help("Registry of created pseudo_mm instances (default: /run/pseudo_mm/instances.json)"),
+            Arg::with_name("registry")
+                .long("registry")
+                .value_name("FILE")
+                .global(true)
+                .help("Record allocated pgoff ranges in FILE and allocate past those of earlier runs"),
+            Arg::with_name("coalesce-regions")
+                .long("coalesce-regions")
+                .help("Merge regions contiguous in GPA, HVA and pgoff into single mappings"),
+            Arg::with_name("no-create-pseudo-mm")
+                .long("no-create-pseudo-mm")
+                .help("Upload the image and write the template without creating a pseudo_mm instance; restore creates it on the host that attaches the template"),
+            Arg::with_name("max-image-pages")
+                .long("max-image-pages")
+                .value_name("PAGES")
+                .help("Fail entries whose image would reach past pgoff PAGES of the RDMA server"),
+            Arg::with_name("guard-pages")
+                .long("guard-pages")
+                .value_name("N")
+                .conflicts_with("pgoff-extents")
+                .help("Reserve N zeroed, unmapped pages before and after each RDMA image"),
+            Arg::with_name("metrics-out")
+                .long("metrics-out")
+                .value_name("PATH")
+                .help("Write Prometheus text-format metrics for the run to PATH"),
+            Arg::with_name("deadline")
+                .long("deadline")
+                .value_name("TIME")
+                .requires("batch-config")
+                .help("Defer batch entries that can't finish by TIME (RFC 3339, or +30m style)"),
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .conflicts_with("metrics-out")
+                .help(
+                    "Plan and validate the pgoff/HVA layout without uploading or creating anything",
+                ),
+            Arg::with_name("plan-output")
+                .long("plan-output")
+                .value_name("FILE")
+                .requires("dry-run")
+                .help("Write the dry run's layout plan to FILE as JSON"),
+            Arg::with_name("output-format")
+                .long("output-format")
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .help(
+                    "With json, print only the result as one JSON document on stdout; \
+                     everything else goes to stderr",
+                ),
+            Arg::with_name("entry-timeout")
+                .long("entry-timeout")
+                .value_name("SECONDS")
+                .help("Abandon an entry that takes longer than SECONDS"),
+        ])
+        .subcommands(subcommands::subcommands())
+}
+
+/// The options of a run creating templates, with or without
+/// `--batch-config`, that apply to each template it creates.
+pub struct RunOptions {
+    pub upload: UploadArgs,
+    pub pgoff_namespace: Option<PgoffNamespace>,
+    pub coalesce_regions: bool,
+    /// See `--no-create-pseudo-mm`.
+    pub create_pseudo_mm: bool,
+    /// See `--force`.
+    pub force: bool,
+    /// See `--validate-snapshot`.
+    pub validate_snapshot: bool,
+    /// See `--snapshot-data-version`.
+    pub snapshot_data_version: Option<u16>,
+    /// See `--pgoff-align`.
+    pub pgoff_align: Option<u64>,
+    /// See `--pgoff-align-strict`.
+    pub pgoff_align_strict: bool,
+    /// See `--max-image-pages`.
+    pub max_image_pages: Option<u64>,
+    /// See `--guard-pages`.
+    pub guard_pages: u64,
+    pub lock_wait: Duration,
+    /// Where created instances are recorded.
+    pub instance_registry: PathBuf,
+    /// See `--registry`.
+    pub pgoff_registry: Option<PathBuf>,
+    /// Plan the layout without uploading or creating anything.
+    pub dry_run: bool,
+    /// Where a dry run writes its layout plan as JSON.
+    pub plan_output: Option<PathBuf>,
+}
+
+impl RunOptions {
+    /// Reads the options of `matches`, with the ones the subcommands take
+    /// too already parsed.
+    fn from_matches(
+        matches: &ArgMatches,
+        lock_wait: Duration,
+        instance_registry: PathBuf,
+        pgoff_registry: Option<PathBuf>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let pgoff_namespace = match matches.value_of("pgoff-namespace") {
+            Some(name) => {
+                let mapping_path = matches.value_of("pgoff-namespace-file").unwrap();
+                let namespace = namespace::load_namespace(mapping_path, name)?;
+                println!(
+                    "Using pgoff namespace '{}' (window [{}, {}))",
+                    namespace.name,
+                    namespace.base_pgoff,
+                    namespace.end_pgoff()
+                );
+                Some(namespace)
+            }
+            None => None,
+        };
+        let upload = UploadArgs::from_matches(matches)?;
+        let pgoff_align = match matches.value_of("pgoff-align") {
+            Some(value) => Some(parse_pgoff_align(value)?),
+            None => None,
+        };
+        let snapshot_data_version = parse_snapshot_data_version(matches)?;
+        let max_image_pages = match matches.value_of("max-image-pages") {
+            Some(value) => Some(value.parse().ok().filter(|&pages| pages > 0).ok_or_else(
+                || {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("--max-image-pages: invalid value '{}'", value),
+                    )
+                },
+            )?),
+            None => None,
+        };
+        let guard_pages = match matches.value_of("guard-pages") {
+            Some(value) => value.parse().map_err(|_| {
                 io::Error::new(
                     io::ErrorKind::InvalidInput,
-                    format!("template {} missing rdma_server", idx + 1),
+                    format!("--guard-pages: invalid value '{}'", value),
                 )
-            })?;
+            })?,
+            None => 0,
+        };
+        Ok(RunOptions {
+            upload,
+            pgoff_namespace,
+            coalesce_regions: matches.is_present("coalesce-regions"),
+            create_pseudo_mm: !matches.is_present("no-create-pseudo-mm"),
+            force: matches.is_present("force"),
+            validate_snapshot: matches.is_present("validate-snapshot"),
+            snapshot_data_version,
+            pgoff_align,
+            pgoff_align_strict: matches.value_of("pgoff-align-strict") != Some("false"),
+            max_image_pages,
+            guard_pages,
+            lock_wait,
+            instance_registry,
+            pgoff_registry,
+            dry_run: matches.is_present("dry-run"),
+            plan_output: matches.value_of("plan-output").map(PathBuf::from),
+        })
+    }
+}
 
-        let hva_base = entry
-            .hva_base
-            .as_deref()
-            .map(parse_hva_strict)
-            .transpose()?;
-        let hva_base = hva_base
-            .or(default_hva_base)
-            .unwrap_or(DEFAULT_PSEUDO_MM_BASE);
-
-        let assigned_pgoff = entry.rdma_pgoff.unwrap_or(next_rdma_pgoff);
-
-        let result = create_template(&TemplateArgs {
-            label: &label,
-            snapshot_path: &entry.snapshot_path,
-            mem_file_path: &entry.mem_file_path,
-            output_path: &entry.output_path,
-            rdma_server,
-            rdma_pgoff: assigned_pgoff,
-            hva_base,
-        })?;
+/// How a batch entry ended.
+pub enum EntryStatus {
+    Created(TemplateResult),
+    /// Layout found by a dry run.
+    Planned(TemplatePlan),
+    Deferred(String),
+    TimedOut(String),
+    /// With the failure's kind, if it has one.
+    Failed(String, Option<TemplateError>),
+    Cancelled(String),
+    /// Created by the run resumed from, or kept as fresh by
+    /// `--regenerate-stale`.
+    Resumed(ResumedEntry),
+}
 
-        let next_candidate = assigned_pgoff + result.mem_pages;
-        if entry.rdma_pgoff.is_none() {
-            next_rdma_pgoff = next_candidate;
-        } else {
-            next_rdma_pgoff = std::cmp::max(next_rdma_pgoff, next_candidate);
-        }
+/// Where an entry's memory image is stored.
+#[derive(Clone, Copy)]
+pub enum ImageTarget<'a> {
+    /// Uploaded to the RDMA memory server at this control-plane address.
+    Rdma { server: &'a str },
+    /// Copied into this DAX device.
+    Dax { device: &'a str },
+}
 
-        summaries.push((label, result));
+impl<'a> ImageTarget<'a> {
+    fn backend(self) -> MemBackend {
+        match self {
+            ImageTarget::Rdma { .. } => MemBackend::Rdma,
+            ImageTarget::Dax { .. } => MemBackend::Dax,
+        }
     }
 
-    println!("\nBatch summary:");
-    for (label, summary) in &summaries {
-        println!(
-            "  [{}] pseudo_mm_id={} rdma_pgoff={} pages={} output={}",
-            label, summary.pseudo_mm_id, summary.rdma_pgoff, summary.mem_pages, summary.output_path
-        );
+    fn dax_device(self) -> Option<&'a str> {
+        match self {
+            ImageTarget::Dax { device } => Some(device),
+            ImageTarget::Rdma { .. } => None,
+        }
     }
 
-    println!("Next available rdma_pgoff: {}", next_rdma_pgoff);
+    /// The RDMA server or DAX device the image goes to.
+    fn name(self) -> &'a str {
+        match self {
+            ImageTarget::Rdma { server } => server,
+            ImageTarget::Dax { device } => device,
+        }
+    }
 
-    Ok(())
+    /// Names the image stored at `pgoff`, for messages.
+    fn describe(self, pgoff: PageOffset) -> String {
+        match self {
+            ImageTarget::Rdma { .. } => format!("image at rdma_pgoff {}", pgoff),
+            ImageTarget::Dax { device } => format!("image at pgoff {} of {}", pgoff, device),
+        }
+    }
 }
 
-struct TemplateArgs<'a> {
+pub struct TemplateArgs<'a> {
     label: &'a str,
     snapshot_path: &'a str,
-    mem_file_path: &'a str,
+    mem_files: &'a MemFiles,
+    /// Size of the memory file given with `--mem-size`, when it is read
+    /// from stdin.
+    stdin_size: Option<u64>,
     output_path: &'a str,
-    rdma_server: &'a str,
-    rdma_pgoff: u64,
-    hva_base: u64,
+    target: ImageTarget<'a>,
+    /// Base page offset of the image on the RDMA server or DAX device.
+    rdma_pgoff: PageOffset,
+    hva_base: HvaAddr,
+    /// Region HVAs other than `hva_base + gpa`.
+    hva_layout: &'a HvaLayout,
+    /// Page size the regions are mapped with; also the alignment the memory
+    /// file, regions, `rdma_pgoff` and `hva_base` must have.
+    page_size: PageSize,
+    /// Template whose image the entry shares unchanged pages with.
+    base: Option<BaseFiles<'a>>,
+    /// The memory file is a diff snapshot; see `diff_snapshot`.
+    diff_snapshot: bool,
+    /// Full memory file a diff snapshot is merged with, when it isn't
+    /// layered on `base`.
+    merge_base: Option<&'a str>,
+    /// See `--dirty-bitmap`; needs `base`.
+    dirty_bitmap: Option<&'a str>,
+    /// See `--marker-gpa`; the image is uploaded whole from the memory file.
+    marker_gpa: Option<Gpa>,
+    /// Pages uploaded earlier in the batch, with `--dedup`.
+    dedup: Option<&'a PageStore>,
+    /// The identical image already at `rdma_pgoff`, which isn't uploaded
+    /// again.
+    reused: Option<&'a ReusedImage>,
+    /// The image is already at `rdma_pgoff`; see `--skip-upload`.
+    skip_upload: bool,
+    /// Pages of the image with `skip_upload`, when the memory file isn't
+    /// read for its size.
+    mem_pages: Option<u64>,
+    /// Data version the snapshot is loaded as, instead of its header's.
+    snapshot_data_version: Option<u16>,
+    /// Run the checks of `snapshot_check` on the snapshot.
+    validate_snapshot: bool,
+    /// Registry the created instance is recorded in.
+    instance_registry: &'a Path,
+    pgoff_namespace: Option<&'a PgoffNamespace>,
+    /// Pages the RDMA server holds, from `--max-image-pages`.
+    pgoff_limit: Option<u64>,
+    /// See `--pgoff-extents`; the image is stored in these rather than at
+    /// `rdma_pgoff`.
+    free_extents: Option<&'a [ImageExtent]>,
+    /// See `--guard-pages`; RDMA images only.
+    guard_pages: u64,
+    /// How RDMA uploads are sent.
+    upload: &'a UploadArgs,
+    progress_style: ProgressStyle,
+    coalesce_regions: bool,
+    /// Whether the instance is created here, or left for restore to create
+    /// from the template.
+    create_pseudo_mm: bool,
+    /// Replace a file already at `output_path`.
+    force: bool,
+    lock_wait: Duration,
+    entry_deadline: EntryDeadline,
+    cancel: &'a CancelToken,
 }
 
-struct TemplateResult {
-    pseudo_mm_id: i32,
-    rdma_pgoff: u64,
+pub struct TemplateResult {
+    /// `None` with `--no-create-pseudo-mm`.
+    pseudo_mm_id: Option<i32>,
+    backend: MemBackend,
+    /// The RDMA server or DAX device the image went to.
+    target: String,
+    rdma_pgoff: PageOffset,
+    hva_base: HvaAddr,
     mem_pages: u64,
     mem_size: u64,
+    /// Time spent uploading or copying the image, retries included.
+    upload_time: Duration,
+    /// Time spent on the whole template, from planning to writing it.
+    total_time: Duration,
+    /// Peak page cache growth during the upload, when /proc is available.
+    cache_peak: Option<u64>,
+    output_path: String,
+    /// The regions of the template, as written.
+    regions: Vec<RegionMetadata>,
+    layered: Option<LayerStats>,
+    dedup: Option<DedupStats>,
+    /// The entry or template whose image was mapped instead of uploading.
+    reused_from: Option<String>,
+}
+
+/// Layout an entry's template is created with.
+///
+/// Computed and validated without touching the memory server or
+/// /dev/pseudo_mm, so a dry run that plans an entry implies the real run
+/// won't fail on its layout.
+#[derive(Serialize)]
+pub struct TemplatePlan {
+    label: String,
     output_path: String,
+    backend: MemBackend,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dax_device: Option<String>,
+    rdma_pgoff: PageOffset,
+    pages: u64,
+    /// Extents the image is split across, when it isn't stored from
+    /// `rdma_pgoff` on; see `pgoff_extents`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    image_extents: Vec<ImageExtent>,
+    /// The image with its guard pages, with `--guard-pages`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    guarded_range: Option<ImageExtent>,
+    /// Bytes of the image: the regions of the memory file, without any
+    /// padding between them.
+    mem_size: u64,
+    hva_base: HvaAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pgoff_namespace: Option<String>,
+    vm_shape: VmShape,
+    source: SnapshotSource,
+    regions: Vec<RegionMetadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    layered: Option<LayerStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dedup: Option<DedupStats>,
+    /// Parts of the memory file making up the image.
+    #[serde(skip)]
+    windows: Vec<ImageWindow>,
+    #[serde(skip)]
+    layers: Option<Layers>,
+    /// Pages the entry uploads, for the batch's `PageStore`.
+    #[serde(skip)]
+    dedup_pages: Option<EntryPages>,
 }
 
-fn create_template(args: &TemplateArgs) -> Result<TemplateResult, Box<dyn std::error::Error>> {
-    println!("\n=== {} :: pseudo_mm template ===", args.label);
+impl TemplatePlan {
+    /// The image's part in each of its extents, whole at `rdma_pgoff` when
+    /// it isn't split.
+    fn extent_parts(&self) -> Vec<ExtentPart> {
+        let whole = [ImageExtent {
+            pgoff: self.rdma_pgoff,
+            pages: self.pages,
+        }];
+        let extents = if self.image_extents.is_empty() {
+            &whole[..]
+        } else {
+            &self.image_extents[..]
+        };
+        pgoff_extents::split(extents, &self.windows)
+    }
+
+    /// Host virtual address range spanned by the regions.
+    fn hva_window(&self) -> (HvaAddr, HvaAddr) {
+        let start = self.regions.iter().map(|region| region.hva.raw()).min();
+        let end = self
+            .regions
+            .iter()
+            .map(|region| region.hva.raw() + region.size)
+            .max();
+        (
+            HvaAddr(start.unwrap_or_else(|| self.hva_base.raw())),
+            HvaAddr(end.unwrap_or_else(|| self.hva_base.raw())),
+        )
+    }
+}
+
+fn print_entry_header(args: &TemplateArgs, kind: &str) {
+    println!("\n=== {} :: {} ===", args.label, kind);
     println!("  snapshot : {}", args.snapshot_path);
-    println!("  memory   : {}", args.mem_file_path);
+    println!("  memory   : {}", args.mem_files);
     println!("  output   : {}", args.output_path);
-    println!("  rdma_srv : {}", args.rdma_server);
-    println!("  rdma_off : {}", args.rdma_pgoff);
-    println!("  hva_base : 0x{:x}", args.hva_base);
-
-    let guest_memory_state = parse_snapshot(args.snapshot_path)?;
-    println!("  regions  : {}", guest_memory_state.regions.len());
+    match args.target {
+        ImageTarget::Rdma { server } => {
+            println!("  rdma_srv : {}", server);
+            match args.free_extents {
+                Some(free) => println!("  rdma_off : in {} free extents", free.len()),
+                None => println!("  rdma_off : {}", args.rdma_pgoff),
+            }
+        }
+        ImageTarget::Dax { device } => {
+            println!("  dax_dev  : {}", device);
+            println!("  dax_off  : {}", args.rdma_pgoff);
+        }
+    }
+    println!("  hva_base : {}", args.hva_base);
+    if let Some(stride) = args.hva_layout.stride {
+        println!("  stride   : 0x{:x}", stride);
+    }
+    for entry in &args.hva_layout.overrides {
+        println!("  hva      : gpa {} -> {}", entry.gpa, entry.hva);
+    }
+    if !args.page_size.is_base() {
+        println!("  page_size: {}", args.page_size);
+    }
+}
 
-    let (mem_size, mem_pages) =
-        upload_memory_to_rdma(args.mem_file_path, args.rdma_server, args.rdma_pgoff)?;
-    println!("  uploaded : {} bytes ({} pages)", mem_size, mem_pages);
+fn print_region(region: &RegionMetadata, backend: MemBackend) {
+    println!(
+        "  -> region GPA={}, size=0x{:x}, HVA={}, {} pgoff={}",
+        region.gpa, region.size, region.hva, backend, region.rdma_offset
+    );
+    if !region.extents.is_empty() {
+        println!("     {} extents elsewhere", region.extents.len());
+    }
+}
 
-    let pseudo_mm_id = pseudo_mm_support::create_pseudo_mm()?;
-    println!("  pseudo_mm: id={}", pseudo_mm_id);
+/// Plans an entry's regions and runs every layout check of a real run.
+fn plan_template(args: &TemplateArgs) -> Result<TemplatePlan, Box<dyn std::error::Error>> {
+    let (microvm_state, versions) = load_snapshot(args.snapshot_path, args.snapshot_data_version)
+        .map_err(|err| TemplateError::SnapshotParse(err.to_string()))?;
+    let source = snapshot_source(args.snapshot_path, &microvm_state, versions)
+        .map_err(|err| TemplateError::SnapshotParse(err.to_string()))?;
+    if args.validate_snapshot {
+        snapshot_check::check(&microvm_state)
+            .map_err(|err| TemplateError::SnapshotParse(err.to_string()))?;
+        println!("  snapshot : validated");
+    }
+    if args.skip_upload {
+        check_skip_upload(args)?;
+    }
+    let vm_shape = persist::vm_shape(&microvm_state);
+    println!("  regions  : {}", microvm_state.memory_state.regions.len());
+    println!("  vm shape : {}", vm_shape);
 
-    let mut regions = Vec::new();
-    for region in &guest_memory_state.regions {
-        let gpa = region.base_address;
-        let size = region.size as u64;
-        let hva = args.hva_base + gpa;
-        if size % PAGE_SIZE != 0 {
+    let mem_size = memory_file_size(args)
+        .and_then(|size| {
+            regions::check_layout(&microvm_state.memory_state.regions, size)?;
+            Ok(size)
+        })
+        .map_err(|err| TemplateError::MemFile(err.to_string()))?;
+    check_page_alignment(args)?;
+    if args.free_extents.is_some() {
+        check_split_image(args)?;
+    }
+    if args.stdin_size.is_some() {
+        check_streamed_upload(args, "a memory file read from stdin")?;
+    } else if let Some(base) = args.merge_base {
+        check_streamed_upload(args, "a diff snapshot merged with its base")?;
+        let (diff, base_image) = diff_snapshot::open_merged(args.mem_files, base, args.page_size)
+            .map_err(|err| TemplateError::MemFile(err.to_string()))?;
+        let view = diff_snapshot::DiffView::new(diff, &base_image)
+            .map_err(|err| TemplateError::MemFile(err.to_string()))?;
+        println!(
+            "  diff     : {} pages read from {}",
+            view.base_pages(),
+            base
+        );
+    } else if !args.diff_snapshot && !args.skip_upload {
+        let holes = open_memory_file(args.mem_files, args.page_size)
+            .and_then(|image| Ok(diff_snapshot::holes(&image)?))
+            .map_err(|err| TemplateError::MemFile(err.to_string()))?;
+        if holes > 0 {
+            println!(
+                "  warning  : memory file has holes over {} pages, mapped as demand-zero; \
+                 pass --diff-snapshot if it is a Firecracker diff snapshot",
+                holes
+            );
+        }
+    }
+    if let (Some(path), None) = (args.dirty_bitmap, args.base) {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("dirty bitmap {} needs a base template", path),
+        )));
+    }
+    let mut dedup_pages = None;
+    let layers = match (args.base, args.target, args.dedup) {
+        (Some(base), ImageTarget::Rdma { .. }, None) => Some(match args.dirty_bitmap {
+            Some(path) => layered::layer_dirty(
+                &microvm_state.memory_state.regions,
+                args.mem_files,
+                base,
+                args.page_size,
+                &DirtyBitmap::load(path, mem_size)
+                    .map_err(|err| TemplateError::MemFile(err.to_string()))?,
+            )?,
+            None => layered::layer(
+                &microvm_state.memory_state.regions,
+                args.mem_files,
+                base,
+                args.page_size,
+                args.diff_snapshot,
+            )?,
+        }),
+        (Some(_), ImageTarget::Rdma { .. }, Some(_)) => {
             return Err(Box::new(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                format!(
-                    "region size 0x{:x} is not page aligned (page size {})",
-                    size, PAGE_SIZE
-                ),
-            )));
+                "--dedup can't be combined with a base template",
+            )))
         }
-        if region.offset % PAGE_SIZE != 0 {
+        (Some(_), ImageTarget::Dax { .. }, _) => {
             return Err(Box::new(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                format!(
-                    "region offset {} is not page aligned (page size {})",
-                    region.offset, PAGE_SIZE
-                ),
-            )));
+                "a base template can only be shared with RDMA images",
+            )))
         }
-        let region_rdma_offset = args.rdma_pgoff + (region.offset / PAGE_SIZE);
-
+        (None, ImageTarget::Rdma { .. }, Some(store)) => {
+            let (layers, pages) = page_dedup::share(
+                &microvm_state.memory_state.regions,
+                args.mem_files,
+                args.page_size,
+                args.rdma_pgoff,
+                store,
+            )
+            .map_err(|err| TemplateError::MemFile(err.to_string()))?;
+            dedup_pages = Some(pages);
+            Some(layers)
+        }
+        (None, _, _) => None,
+    };
+    let layered = match (layers.as_ref(), args.base) {
+        (Some(layers), Some(base)) => Some(layers.stats(base.template)),
+        _ => None,
+    };
+    let dedup = match (layers.as_ref(), dedup_pages.as_ref()) {
+        (Some(layers), Some(_)) => Some(DedupStats {
+            unique_pages: layers.overlay_pages,
+            shared_pages: layers.shared_pages,
+        }),
+        _ => None,
+    };
+    let windows = match layers.as_ref() {
+        Some(layers) => layers.windows.clone(),
+        None => regions::image_windows(&microvm_state.memory_state.regions),
+    };
+    let image_size: u64 = windows.iter().map(|window| window.size).sum();
+    let pages = image_size / PAGE_SIZE;
+    let (rdma_pgoff, image_extents) = match args.free_extents {
+        Some(free) => {
+            let mut extents = pgoff_extents::fit(free, pages, args.page_size.pgoffs())?;
+            let described: Vec<String> = extents
+                .iter()
+                .map(|extent| format!("{}+{}", extent.pgoff, extent.pages))
+                .collect();
+            println!("  extents  : {}", described.join(", "));
+            let first = extents[0].pgoff;
+            if extents.len() == 1 {
+                extents.clear();
+            }
+            (first, extents)
+        }
+        None => (args.rdma_pgoff, Vec::new()),
+    };
+    if let Some(stats) = layered.as_ref() {
+        println!(
+            "  layered  : {} pages differ from {}, {} shared",
+            stats.overlay_pages, stats.base_template, stats.shared_pages
+        );
+        if let (Some(dirty), Some(clean)) = (stats.dirty_pages, stats.clean_pages) {
+            println!("  dirty    : {} pages dirty, {} clean", dirty, clean);
+        }
+    } else if let Some(stats) = dedup.as_ref() {
+        println!(
+            "  dedup    : {} pages to upload, {} shared with pages uploaded before",
+            stats.unique_pages, stats.shared_pages
+        );
+    } else if image_size < mem_size {
         println!(
-            "  -> region GPA=0x{:x}, size=0x{:x}, HVA=0x{:x}, RDMA pgoff={}",
-            gpa, size, hva, region_rdma_offset
+            "  packed   : {} bytes of padding between regions left out",
+            mem_size - image_size
         );
+    }
 
-        pseudo_mm_support::add_memory_map(
-            pseudo_mm_id,
-            hva,
-            hva + size,
-            (libc::PROT_READ | libc::PROT_WRITE) as u64,
-            (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED) as u64,
-            -1,
-            0,
-        )?;
-
-        pseudo_mm_support::setup_page_table(
-            pseudo_mm_id,
-            hva,
-            size,
-            region_rdma_offset,
-            RDMA_MEM,
-            0,
-        )?;
+    let mut planned = regions::plan_regions(
+        &microvm_state.memory_state.regions,
+        args.hva_base,
+        rdma_pgoff,
+        args.page_size,
+    )?;
+    regions::place_hvas(&mut planned, args.hva_base, args.hva_layout, args.page_size)?;
+    if args.coalesce_regions {
+        planned = regions::coalesce(planned);
+        println!("  coalesced: {} mappings", planned.len());
+    }
+    if let Some(layers) = layers.as_ref() {
+        layered::apply(&mut planned, layers, args.rdma_pgoff);
+    }
+    // An instance created here maps the regions next to the creator's own
+    // mappings, which would fail setup with a less telling error.
+    let maps = if !args.create_pseudo_mm {
+        None
+    } else {
+        regions::process_maps().map(Some).unwrap_or_else(|err| {
+            println!(
+                "  warning  : cannot read /proc/self/maps ({}), skipping the mapping check",
+                err
+            );
+            None
+        })
+    };
+    regions::check_hva_ranges(
+        &planned,
+        regions::mmap_min_addr(),
+        pseudo_mm_support::user_addr_limit(),
+        maps.as_deref(),
+    )?;
+    // Attach maps every region; two sharing host addresses can't both be.
+    regions::check_hva_overlap(&planned)?;
+    // Same checks restore applies; catch a bad layout before uploading it.
+    pseudo_mm_support::validate_regions(&planned)?;
+    match MapBudget::probe() {
+        Some(budget) => {
+            if let MapCountCheck::Warn(msg) =
+                regions::check_map_count(planned.len(), &budget, args.coalesce_regions)?
+            {
+                println!("  warning  : {}", msg);
+            }
+        }
+        None => println!("  warning  : cannot read map limits from /proc, skipping check"),
+    }
+
+    // Namespaces partition the memory server; a DAX device is local.
+    let pgoff_namespace = match args.target {
+        ImageTarget::Rdma { .. } => args.pgoff_namespace,
+        ImageTarget::Dax { .. } => None,
+    };
+    let whole = [ImageExtent {
+        pgoff: rdma_pgoff,
+        pages,
+    }];
+    let guarded_range = match rdma_guard_pages(args.target, args.guard_pages) {
+        0 => None,
+        guard => {
+            let guarded = guard_pages::guarded_range(rdma_pgoff, pages, guard)?;
+            println!(
+                "  guard    : {} pages on each side, pgoffs [{}, {})",
+                guard,
+                guarded.pgoff,
+                guarded.pgoff.raw() + guarded.pages
+            );
+            Some(guarded)
+        }
+    };
+    // The guard pages are kept within the same bounds as the image.
+    let stored = match guarded_range.as_ref() {
+        Some(guarded) => std::slice::from_ref(guarded),
+        None if image_extents.is_empty() => &whole[..],
+        None => &image_extents[..],
+    };
+    if let Some(namespace) = pgoff_namespace {
+        // Checked before any bytes are sent: an out-of-window upload would
+        // overwrite another tenant's image.
+        for extent in stored {
+            namespace.check_range(extent.pgoff.raw(), extent.pages)?;
+        }
+        println!("  namespace: {}", namespace.name);
+    }
+    if let (ImageTarget::Rdma { .. }, Some(limit)) = (args.target, args.pgoff_limit) {
+        for extent in stored {
+            capacity::check_range(extent.pgoff.raw(), extent.pages, limit)?;
+        }
+    }
+
+    Ok(TemplatePlan {
+        label: args.label.to_string(),
+        output_path: args.output_path.to_string(),
+        backend: args.target.backend(),
+        dax_device: args.target.dax_device().map(str::to_string),
+        rdma_pgoff,
+        pages,
+        image_extents,
+        guarded_range,
+        mem_size: image_size,
+        hva_base: args.hva_base,
+        pgoff_namespace: pgoff_namespace.map(|ns| ns.name.clone()),
+        vm_shape,
+        source,
+        regions: planned,
+        layered,
+        dedup,
+        windows,
+        layers,
+        dedup_pages,
+    })
+}
 
-        regions.push(RegionMetadata {
+/// Plans an entry for `--dry-run` and prints the layout.
+pub fn dry_run_template(args: &TemplateArgs) -> Result<TemplatePlan, Box<dyn std::error::Error>> {
+    print_entry_header(args, "pseudo_mm template (dry run)");
+    output_lock::check_overwrite(Path::new(args.output_path), args.force)?;
+    let mut plan = plan_template(args)?;
+    pgoff_extents::apply(&mut plan.regions, plan.rdma_pgoff, &plan.image_extents);
+    // Later entries are planned as if this one had been uploaded.
+    if let (Some(store), Some(pages)) = (args.dedup, plan.dedup_pages.take()) {
+        store.add(pages);
+    }
+    for region in &plan.regions {
+        print_region(region, plan.backend);
+    }
+    let (hva_start, hva_end) = plan.hva_window();
+    println!(
+        "  planned  : {}_pgoff [{}, {}), HVA [{}, {})",
+        plan.backend,
+        plan.rdma_pgoff,
+        plan.rdma_pgoff.raw() + plan.pages,
+        hva_start,
+        hva_end
+    );
+    Ok(plan)
+}
+
+/// Layout of a whole dry run, as written by `--plan-output`.
+#[derive(Serialize)]
+pub struct LayoutPlan<'a> {
+    entries: Vec<&'a TemplatePlan>,
+    next_rdma_pgoff: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    next_dax_pgoffs: BTreeMap<&'a str, u64>,
+}
+
+pub fn write_layout_plan(
+    path: &Path,
+    plan: &LayoutPlan,
+    lock_wait: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let json = serde_json::to_string_pretty(plan)?;
+    output_lock::write_locked(path, json.as_bytes(), lock_wait)?;
+    println!("Layout plan written to {}", path.display());
+    Ok(())
+}
+
+/// One entry of a `BatchSummary`, in config order.
+#[derive(Serialize)]
+pub struct EntrySummary<'a> {
+    label: String,
+    snapshot_path: &'a str,
+    output_path: &'a str,
+    /// `ok`, or `failed` with the reason in `error`. Failed entries have no
+    /// template, whether they errored, timed out, were deferred, cancelled
+    /// or never started.
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    /// Kind of a failed entry's error, see `template_error`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pseudo_mm_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<MemBackend>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rdma_pgoff: Option<PageOffset>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hva_base: Option<HvaAddr>,
+    /// End of the entry's pgoff range, exclusive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_pgoff: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pages: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    /// Time spent uploading or copying the image, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload_secs: Option<f64>,
+    /// Time spent creating the template, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    regions: Option<&'a [RegionMetadata]>,
+    /// How much of a layered entry is shared with its base.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    layered: Option<&'a LayerStats>,
+    /// How much of the entry `--dedup` mapped from earlier uploads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dedup: Option<DedupStats>,
+    /// The entry or template whose identical image the entry maps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reused_from: Option<&'a str>,
+    /// With `--regenerate-stale`, for entries that had a template: kept as
+    /// `fresh`, `regenerated`, or left `stale-skipped` by a failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    freshness: Option<&'static str>,
+}
+
+/// Summarizes `entry`; `report` is `None` if it never started.
+pub fn entry_summary<'a>(
+    entry: &'a BatchTemplateEntry,
+    report: &'a Option<(usize, EntryStatus)>,
+) -> EntrySummary<'a> {
+    status_summary(
+        entry.label().to_string(),
+        &entry.snapshot_path,
+        &entry.output_path,
+        report.as_ref().map(|report| &report.1),
+    )
+}
+
+/// Summarizes an entry that ended with `status`, or never started.
+pub fn status_summary<'a>(
+    label: String,
+    snapshot_path: &'a str,
+    output_path: &'a str,
+    status: Option<&'a EntryStatus>,
+) -> EntrySummary<'a> {
+    let mut summary = EntrySummary {
+        label,
+        snapshot_path,
+        output_path,
+        status: "failed",
+        error: None,
+        error_kind: None,
+        pseudo_mm_id: None,
+        backend: None,
+        rdma_pgoff: None,
+        hva_base: None,
+        end_pgoff: None,
+        pages: None,
+        bytes: None,
+        upload_secs: None,
+        total_secs: None,
+        regions: None,
+        layered: None,
+        dedup: None,
+        reused_from: None,
+        freshness: None,
+    };
+    match status {
+        Some(EntryStatus::Created(result)) => {
+            summary.status = "ok";
+            summary.pseudo_mm_id = result.pseudo_mm_id;
+            summary.backend = Some(result.backend);
+            summary.rdma_pgoff = Some(result.rdma_pgoff);
+            summary.hva_base = Some(result.hva_base);
+            summary.end_pgoff = result.rdma_pgoff.raw().checked_add(result.mem_pages);
+            summary.pages = Some(result.mem_pages);
+            summary.bytes = Some(result.mem_size);
+            summary.upload_secs = Some(result.upload_time.as_secs_f64());
+            summary.total_secs = Some(result.total_time.as_secs_f64());
+            summary.regions = Some(&result.regions);
+            summary.layered = result.layered.as_ref();
+            summary.dedup = result.dedup;
+            summary.reused_from = result.reused_from.as_deref();
+        }
+        Some(EntryStatus::Planned(plan)) => {
+            summary.status = "ok";
+            summary.backend = Some(plan.backend);
+            summary.rdma_pgoff = Some(plan.rdma_pgoff);
+            summary.hva_base = Some(plan.hva_base);
+            summary.end_pgoff = plan.rdma_pgoff.raw().checked_add(plan.pages);
+            summary.pages = Some(plan.pages);
+            summary.bytes = Some(plan.mem_size);
+            summary.regions = Some(&plan.regions);
+            summary.layered = plan.layered.as_ref();
+            summary.dedup = plan.dedup;
+        }
+        Some(EntryStatus::Failed(message, failure)) => {
+            summary.error = Some(message);
+            summary.error_kind = Some(
+                failure
+                    .as_ref()
+                    .map_or(template_error::OTHER_KIND, TemplateError::kind),
+            );
+        }
+        Some(EntryStatus::Deferred(message))
+        | Some(EntryStatus::TimedOut(message))
+        | Some(EntryStatus::Cancelled(message)) => summary.error = Some(message),
+        Some(EntryStatus::Resumed(resumed)) => {
+            summary.status = "ok";
+            summary.backend = Some(resumed.backend);
+            summary.rdma_pgoff = Some(resumed.rdma_pgoff);
+            summary.end_pgoff = resumed.rdma_pgoff.raw().checked_add(resumed.pages);
+            summary.pages = Some(resumed.pages);
+        }
+        None => summary.error = Some("skipped: not started"),
+    }
+    summary
+}
+
+pub fn create_template(
+    args: &TemplateArgs,
+    metrics: &EntryRecorder,
+) -> Result<TemplateResult, Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    print_entry_header(args, "pseudo_mm template");
+    args.cancel.check()?;
+
+    // Held from before the upload until the template is written, so a second
+    // run aimed at the same output fails instead of interleaving with us.
+    let output_lock = OutputLock::acquire(Path::new(args.output_path), args.lock_wait)?;
+    output_lock::check_overwrite(Path::new(args.output_path), args.force)?;
+
+    let phase_start = Instant::now();
+    let mut plan = plan_template(args)?;
+    metrics.phase("plan", phase_start.elapsed());
+
+    let rdma_pgoff = plan.rdma_pgoff;
+    let image = args.target.describe(rdma_pgoff);
+    let marker = match args.marker_gpa {
+        Some(gpa) => Some(marker_page::prepare(
             gpa,
-            hva,
-            size,
-            rdma_offset: region_rdma_offset,
+            &plan.regions,
+            rdma_pgoff,
+            &open_memory_file(args.mem_files, args.page_size)?,
+            &plan.windows,
+            args.page_size,
+            occupancy::unix_secs(SystemTime::now()),
+        )?),
+        None => None,
+    };
+    let phase_start = Instant::now();
+    let mut reporter =
+        UploadProgress::new(args.label, plan.mem_size, args.progress_style, phase_start);
+    let mut progress = |bytes| {
+        metrics.upload_progress(bytes, PAGE_SIZE, phase_start.elapsed());
+        reporter.update(bytes);
+        args.cancel.check()?;
+        args.entry_deadline.check()
+    };
+    let options = UploadOptions {
+        drop_cache_behind: args.upload.drop_cache_behind,
+        direct_io: args.upload.direct_io,
+        page_size: args.page_size,
+        retry: args.upload.retry,
+        chunk_size: args.upload.chunk_size,
+        rate_limits: args.upload.rate_limits.clone(),
+        timeouts: args.upload.timeouts,
+        streams: args.upload.streams,
+        deadline: args.entry_deadline,
+        hash_regions: hash_regions(args, &plan),
+        marker: marker.as_ref().map(|(marker, _)| marker.clone()),
+    };
+    let mut on_retry = |attempt, err: &dyn std::error::Error| {
+        metrics.upload_retry();
+        println!(
+            "  warning  : upload attempt {}/{} failed ({}), restarting",
+            attempt, args.upload.retry.attempts, err
+        );
+    };
+    let guard_chunks = plan
+        .guarded_range
+        .as_ref()
+        .map_or_else(Vec::new, |guarded| {
+            guard_pages::guard_chunks(guarded, rdma_pgoff, plan.pages)
         });
+    // Zeroed first, so the image's own upload overrunning them shows too.
+    if let (ImageTarget::Rdma { server }, None, false, false) = (
+        args.target,
+        args.reused,
+        args.skip_upload,
+        guard_chunks.is_empty(),
+    ) {
+        zero_guard_pages(
+            server,
+            &guard_chunks,
+            options
+                .timeouts
+                .bounded_by(options.deadline.remaining_at(Instant::now())),
+        )
+        .map_err(server_failure)?;
     }
+    let upload = match args.reused {
+        // Already on the server; the digest found its zero pages.
+        Some(reused) => Ok(UploadStats {
+            bytes: reused.digest.bytes,
+            pages: reused.digest.bytes / PAGE_SIZE,
+            zero_pages: reused.digest.zero_pages.clone(),
+            throttled: Duration::from_secs(0),
+            cache_peak: None,
+            reads: None,
+            digests: ChunkDigests::new(),
+        }),
+        // Zero pages aren't known without reading the memory file; all of
+        // the image maps from the server.
+        None if args.skip_upload => Ok(UploadStats {
+            bytes: plan.mem_size,
+            pages: plan.mem_size / PAGE_SIZE,
+            zero_pages: PageRuns::default(),
+            throttled: Duration::from_secs(0),
+            cache_peak: None,
+            reads: None,
+            digests: ChunkDigests::new(),
+        }),
+        None => match (args.target, args.stdin_size) {
+            (ImageTarget::Rdma { server }, Some(size)) => upload_stream_to_rdma(
+                &mut io::stdin(),
+                "stdin",
+                size,
+                &plan.windows,
+                server,
+                rdma_pgoff,
+                &options,
+                &mut progress,
+            ),
+            (ImageTarget::Rdma { server }, None) if args.merge_base.is_some() => {
+                upload_merged_to_rdma(args, &plan.windows, server, &options, &mut progress)
+            }
+            (ImageTarget::Rdma { server }, None) if !plan.image_extents.is_empty() => {
+                upload_extents_to_rdma(
+                    args.mem_files,
+                    &plan.extent_parts(),
+                    server,
+                    &options,
+                    &mut progress,
+                    &mut on_retry,
+                )
+            }
+            (ImageTarget::Rdma { server }, None) => upload_memory_to_rdma(
+                args.mem_files,
+                &plan.windows,
+                server,
+                rdma_pgoff,
+                &options,
+                &mut progress,
+                &mut on_retry,
+            ),
+            (ImageTarget::Dax { device }, _) => copy_memory_to_dax(
+                args.mem_files,
+                &plan.windows,
+                device,
+                rdma_pgoff,
+                args.page_size,
+                args.upload.drop_cache_behind,
+                &mut progress,
+            ),
+        },
+    };
+    reporter.finish();
+    let upload = upload.map_err(|err| {
+        if args.cancel.is_cancelled() || args.entry_deadline.expired() {
+            println!(
+                "  abandoned: partial {} is not referenced by any template",
+                image
+            );
+        }
+        err
+    })?;
+    let (mem_size, mem_pages) = (upload.bytes, upload.pages);
+    let upload_time = phase_start.elapsed();
+    metrics.phase("upload", upload_time);
+    if let ImageTarget::Rdma { .. } = args.target {
+        let end = plan
+            .extent_parts()
+            .iter()
+            .map(|part| part.pgoff.raw() + part.pages)
+            .max();
+        metrics.pgoff_reached(end.unwrap_or_else(|| rdma_pgoff.raw()));
+    }
+    match args.reused {
+        Some(reused) => println!(
+            "  reused   : {} bytes ({} pages) uploaded for {}, not uploaded again",
+            mem_size, mem_pages, reused.image.owner
+        ),
+        None if args.skip_upload => println!(
+            "  skipped  : {} bytes ({} pages) taken to be at {}, not uploaded",
+            mem_size, mem_pages, image
+        ),
+        None => println!(
+            "  uploaded : {} bytes ({} pages) in {:.2}s, {:.1} MB/s",
+            mem_size,
+            mem_pages,
+            upload_time.as_secs_f64(),
+            upload_progress::rate(mem_size, upload_time)
+        ),
+    }
+    if let Some(reads) = upload.reads.as_ref() {
+        println!(
+            "  read     : {} bytes {} at {:.1} MB/s",
+            reads.bytes,
+            if reads.direct {
+                "with O_DIRECT"
+            } else {
+                "through the page cache"
+            },
+            upload_progress::rate(reads.bytes, reads.time)
+        );
+    }
+    if upload.throttled > Duration::from_secs(0) {
+        println!(
+            "  throttled: {:.2}s waiting for the upload rate limit",
+            upload.throttled.as_secs_f64()
+        );
+    }
+    // A layered image holds only pages that differ from the base, none of
+    // them zero, whose regions `plan_template` has already laid out.
+    if !upload.zero_pages.as_slice().is_empty() && plan.layers.is_none() {
+        println!(
+            "  zero     : {} pages in {} runs skipped, mapped as demand-zero",
+            upload.zero_pages.pages(),
+            upload.zero_pages.as_slice().len()
+        );
+        regions::assign_zero_pages(&mut plan.regions, rdma_pgoff, upload.zero_pages.as_slice());
+    }
+    if let Some(ranges) = options.hash_regions.as_ref() {
+        let hashes = region_hash::region_hashes(ranges, upload.digests);
+        let hashed = hashes.iter().filter(|hashes| hashes.is_some()).count();
+        for (region, hashes) in plan.regions.iter_mut().zip(hashes) {
+            region.content_hashes = hashes;
+        }
+        println!(
+            "  hashed   : {} of {} regions, SHA-256 per {} MiB",
+            hashed,
+            plan.regions.len(),
+            region_hash::CHUNK_SIZE >> 20
+        );
+    }
+    if let Some((_, recorded)) = marker.as_ref() {
+        println!(
+            "  marker   : page at gpa {} names image {}",
+            recorded.gpa, recorded.image_hash
+        );
+    }
+    // Zero pages are assigned by image page, before the regions move off
+    // contiguous pgoffs.
+    pgoff_extents::apply(&mut plan.regions, rdma_pgoff, &plan.image_extents);
+    let phase_start = Instant::now();
+    let instance = if args.create_pseudo_mm {
+        Some(set_up_instance(args, &plan, &image, metrics)?)
+    } else {
+        args.cancel
+            .check()
+            .and_then(|_| args.entry_deadline.check())
+            .map_err(|err| {
+                println!(
+                    "  abandoned: partial {} is not referenced by any template",
+                    image
+                );
+                err
+            })?;
+        for region in &plan.regions {
+            print_region(region, plan.backend);
+        }
+        println!("  pseudo_mm: not created, restore sets it up from the template");
+        None
+    };
+    let pseudo_mm_id = instance.as_ref().map(|instance| instance.pseudo_mm_id);
+    metrics.phase("setup", phase_start.elapsed());
 
+    let phase_start = Instant::now();
+    let sources = record_sources(args);
+    let required_features = pseudo_mm_support::required_features_for(&plan.regions, plan.backend);
     let template = PseudoMmTemplate {
+        template_version: pseudo_mm_support::template_version_for(&plan.regions, pseudo_mm_id),
         pseudo_mm_id,
         hva_base: args.hva_base,
-        rdma_base_pgoff: args.rdma_pgoff,
+        rdma_base_pgoff: rdma_pgoff,
         rdma_image_size: mem_size,
-        regions,
+        rdma_image_extents: plan.image_extents,
+        rdma_guarded_range: plan.guarded_range,
+        regions: plan.regions,
+        pgoff_namespace: plan.pgoff_namespace,
+        required_features,
+        vm_shape: Some(plan.vm_shape),
+        mem_backend: plan.backend,
+        dax_device: plan.dax_device,
+        base_template: plan
+            .layered
+            .as_ref()
+            .map(|stats| stats.base_template.clone()),
+        source: Some(plan.source),
+        provenance: Some(provenance::current(args.label, sources)),
+        marker: marker.map(|(_, recorded)| recorded),
     };
 
     let json = serde_json::to_string_pretty(&template)?;
-    std::fs::write(args.output_path, &json)?;
+    output_lock.write(json.as_bytes())?;
+    metrics.phase("write", phase_start.elapsed());
     println!("  saved    : {}", args.output_path);
+    if let Some(mut instance) = instance {
+        let saved_path = std::fs::canonicalize(args.output_path)
+            .unwrap_or_else(|_| PathBuf::from(args.output_path));
+        instance.template_path = Some(saved_path.to_string_lossy().into_owned());
+        record_instance(args.instance_registry, &instance);
+    }
+    if let (Some(store), Some(pages)) = (args.dedup, plan.dedup_pages) {
+        store.add(pages);
+    }
 
     Ok(TemplateResult {
         pseudo_mm_id,
-        rdma_pgoff: args.rdma_pgoff,
+        backend: args.target.backend(),
+        target: args.target.name().to_string(),
+        rdma_pgoff,
+        hva_base: args.hva_base,
         mem_pages,
         mem_size,
+        upload_time,
+        total_time: start.elapsed(),
+        cache_peak: upload.cache_peak,
         output_path: args.output_path.to_string(),
+        regions: template.regions,
+        layered: plan.layered,
+        dedup: plan.dedup,
+        reused_from: args.reused.map(|reused| reused.image.owner.clone()),
     })
 }
 
-#[derive(Deserialize)]
-struct BatchConfig {
-    #[serde(default)]
-    rdma_server: Option<String>,
-    #[serde(default)]
-    default_rdma_pgoff: Option<u64>,
-    #[serde(default)]
-    hva_base: Option<String>,
-    #[serde(default)]
-    templates: Vec<BatchTemplateEntry>,
+/// The snapshot and memory file of `args` as they are now, for the
+/// template's provenance; `None` for a memory file read from stdin, or one
+/// that can no longer be read.
+fn record_sources(args: &TemplateArgs) -> Option<SourceFiles> {
+    if args.stdin_size.is_some() {
+        return None;
+    }
+    match freshness::sources(args.snapshot_path, args.mem_files.paths()) {
+        Ok(sources) => Some(sources),
+        Err(err) => {
+            println!("  warning  : cannot record the source files: {}", err);
+            None
+        }
+    }
+}
+
+/// `(image offset, size)` of the regions of `plan` to hash as they are
+/// uploaded: all of them when they are the image itself, streamed from the
+/// memory file to the RDMA server; otherwise none, see `region_hash`.
+fn hash_regions(args: &TemplateArgs, plan: &TemplatePlan) -> Option<Vec<(u64, u64)>> {
+    let streamed = match (args.target, args.reused, args.stdin_size) {
+        (ImageTarget::Rdma { .. }, None, None) => !args.skip_upload && args.merge_base.is_none(),
+        _ => false,
+    };
+    if !streamed || plan.layers.is_some() || !plan.image_extents.is_empty() {
+        return None;
+    }
+    Some(image_ranges(&plan.regions, plan.rdma_pgoff))
 }
 
-#[derive(Deserialize)]
-struct BatchTemplateEntry {
-    snapshot_path: String,
-    mem_file_path: String,
-    output_path: String,
-    #[serde(default)]
-    rdma_pgoff: Option<u64>,
-    #[serde(default)]
-    rdma_server: Option<String>,
-    #[serde(default)]
-    hva_base: Option<String>,
+/// `(image offset, size)` of `regions` in the image at `rdma_pgoff`, taken
+/// from their `rdma_offset` rather than from the snapshot's region order.
+pub fn image_ranges(regions: &[RegionMetadata], rdma_pgoff: PageOffset) -> Vec<(u64, u64)> {
+    regions
+        .iter()
+        .map(|region| {
+            let first_page = region.rdma_offset.raw() - rdma_pgoff.raw();
+            (first_page * PAGE_SIZE, region.size)
+        })
+        .collect()
+}
+
+/// Creates the pseudo_mm instance of `plan`, whose image is `image`, and
+/// sets up its regions, recording the instance in the registry.
+fn set_up_instance(
+    args: &TemplateArgs,
+    plan: &TemplatePlan,
+    image: &str,
+    metrics: &EntryRecorder,
+) -> Result<Instance, Box<dyn std::error::Error>> {
+    let retry = RetryPolicy::default();
+    let pseudo_mm_id = pseudo_mm_support::retry_transient(
+        &retry,
+        pseudo_mm_support::create_pseudo_mm,
+        |attempt, err| {
+            metrics.retry();
+            println!(
+                "  warning  : pseudo_mm create attempt {}/{} failed ({}), retrying",
+                attempt, retry.attempts, err
+            );
+        },
+    )
+    .map_err(|err| TemplateError::PseudoMm(err.to_string()))?;
+    println!("  pseudo_mm: id={}", pseudo_mm_id);
+    // Recorded before setup, so `list` also shows instances whose entry is
+    // abandoned below.
+    let instance = Instance::new(pseudo_mm_id, plan.regions.len(), plan.pages, plan.backend);
+    record_instance(args.instance_registry, &instance);
+
+    // The module has no call to release an instance, so an entry stopped
+    // here names what it leaves behind; the instance stays recorded for
+    // `list`.
+    let check_abandon = || -> io::Result<()> {
+        args.cancel
+            .check()
+            .and_then(|_| args.entry_deadline.check())
+            .map_err(|err| {
+                println!(
+                    "  abandoned: pseudo_mm id={} and the {} have no template",
+                    pseudo_mm_id, image
+                );
+                err
+            })
+    };
+
+    let dax_device = match args.target {
+        ImageTarget::Dax { device } => Some(open_dax_device(device)?),
+        ImageTarget::Rdma { .. } => None,
+    };
+    // Only errors of the module are setup failures; stopping isn't.
+    let mut stopped = false;
+    pseudo_mm_support::setup_regions(
+        pseudo_mm_id,
+        &plan.regions,
+        plan.backend,
+        dax_device.as_ref(),
+        &mut |_, region| {
+            check_abandon().map_err(|err| {
+                stopped = true;
+                err
+            })?;
+            print_region(region, plan.backend);
+            Ok(())
+        },
+    )
+    .map_err(|err| -> Box<dyn std::error::Error> {
+        if stopped {
+            Box::new(err)
+        } else {
+            Box::new(TemplateError::PseudoMm(err.to_string()))
+        }
+    })?;
+    check_abandon()?;
+    Ok(instance)
 }
 
-fn parse_snapshot(path: &str) -> Result<GuestMemoryState, Box<dyn std::error::Error>> {
+/// A template's instance id, or "none" if restore creates it.
+pub fn describe_instance(id: Option<i32>) -> String {
+    id.map_or_else(|| "none".to_string(), |id| id.to_string())
+}
+
+/// Records `instance` in the registry; the instance exists either way, so a
+/// failure is only a warning.
+fn record_instance(registry: &Path, instance: &Instance) {
+    if let Err(err) = instance_registry::record(registry, instance) {
+        println!(
+            "  warning  : cannot record pseudo_mm id={} in {}: {}",
+            instance.pseudo_mm_id,
+            registry.display(),
+            err
+        );
+    }
+}
+
+/// Loads a snapshot's state with the version map restore uses, so
+/// snapshots from older builds load with their missing fields defaulted.
+///
+/// `data_version` overrides the version in the snapshot's header.
+pub fn parse_snapshot(
+    path: &str,
+    data_version: Option<u16>,
+) -> Result<MicrovmState, Box<dyn std::error::Error>> {
+    load_snapshot(path, data_version).map(|(state, _)| state)
+}
+
+/// `parse_snapshot`, also returning the versions in the snapshot's header.
+fn load_snapshot(
+    path: &str,
+    data_version: Option<u16>,
+) -> Result<(MicrovmState, SnapshotVersions), Box<dyn std::error::Error>> {
     let file = File::open(path)?;
     let mut reader = BufReader::new(file);
-    let version_map = VersionMap::new();
-    let microvm_state: MicrovmState = Snapshot::load(&mut reader, version_map).map_err(|err| {
+    let versions = Snapshot::read_versions(&mut reader).map_err(|err| {
         io::Error::new(
             io::ErrorKind::Other,
-            format!("failed to load snapshot: {:?}", err),
+            format!("failed to read snapshot header: {:?}", err),
         )
     })?;
+    let version = data_version.unwrap_or(versions.data_version);
+    let microvm_state: MicrovmState = Snapshot::load_state(&mut reader, &VERSION_MAP, version)
+        .map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "failed to load snapshot ({}): {:?}",
+                    describe_snapshot_versions(versions, data_version),
+                    err
+                ),
+            )
+        })?;
 
-    Ok(microvm_state.memory_state)
+    Ok((microvm_state, versions))
 }
 
-fn parse_hex_address(s: Option<&str>) -> Option<u64> {
-    s.and_then(|s| {
-        let s = s.trim_start_matches("0x");
-        u64::from_str_radix(s, 16).ok()
+/// Describes the snapshot at `path`, whose header has `versions`, for the
+/// template's `source`.
+fn snapshot_source(
+    path: &str,
+    state: &MicrovmState,
+    versions: SnapshotVersions,
+) -> io::Result<SnapshotSource> {
+    let regions = &state.memory_state.regions;
+    Ok(SnapshotSource {
+        snapshot_path: absolute_path(path),
+        snapshot_size: std::fs::metadata(path)?.len(),
+        format_version: versions.format_version,
+        data_version: versions.data_version,
+        firecracker_version: firecracker_release(versions.data_version).map(str::to_string),
+        vcpu_count: state.vcpu_states.len() as u32,
+        guest_memory_size: regions.iter().map(|region| region.size as u64).sum(),
+        region_count: regions.len() as u32,
     })
 }
 
-fn parse_hva_strict(value: &str) -> Result<u64, Box<dyn std::error::Error>> {
-    parse_hex_address(Some(value)).ok_or_else(|| {
-        Box::new(io::Error::new(
+/// Oldest Firecracker release writing snapshot data version `version`.
+fn firecracker_release(version: u16) -> Option<&'static str> {
+    FC_VERSION_TO_SNAP_VERSION
+        .iter()
+        .filter(|&(_, &found)| found == version)
+        .map(|(release, _)| release.as_str())
+        .min()
+}
+
+/// Describes the versions a snapshot was loaded with, for errors.
+fn describe_snapshot_versions(versions: SnapshotVersions, data_version: Option<u16>) -> String {
+    let mut msg = format!(
+        "format version {}, data version {}",
+        versions.format_version, versions.data_version
+    );
+    if let Some(release) = firecracker_release(versions.data_version) {
+        msg.push_str(&format!(" (Firecracker {})", release));
+    }
+    match data_version {
+        Some(version) if version != versions.data_version => {
+            msg.push_str(&format!(", loaded as data version {}", version))
+        }
+        _ => (),
+    }
+    msg.push_str(&format!(
+        "; this build reads data versions up to {}",
+        VERSION_MAP.latest_version()
+    ));
+    msg
+}
+
+/// Reads `--snapshot-data-version`, which must be a version this build knows.
+fn parse_snapshot_data_version(
+    matches: &ArgMatches,
+) -> Result<Option<u16>, Box<dyn std::error::Error>> {
+    let value = match matches.value_of("snapshot-data-version") {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let latest = VERSION_MAP.latest_version();
+    match value.parse::<u16>() {
+        Ok(version) if version >= 1 && version <= latest => Ok(Some(version)),
+        _ => Err(Box::new(io::Error::new(
             io::ErrorKind::InvalidInput,
-            format!("invalid hva_base '{}': expect hex string", value),
-        )) as Box<dyn std::error::Error>
+            format!(
+                "--snapshot-data-version: invalid value '{}': expected a data version \
+                 from 1 to {}",
+                value, latest
+            ),
+        ))),
+    }
+}
+
+/// Parses an optional address or page offset argument, naming the flag on error.
+pub fn parse_arg<T>(
+    matches: &ArgMatches,
+    name: &str,
+) -> Result<Option<T>, Box<dyn std::error::Error>>
+where
+    T: FromStr<Err = ParseAddrError>,
+{
+    matches
+        .value_of(name)
+        .map(|value| {
+            value.parse::<T>().map_err(|err| {
+                Box::new(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--{}: {}", name, err),
+                )) as Box<dyn std::error::Error>
+            })
+        })
+        .transpose()
+}
+
+fn parse_limits(
+    matches: &ArgMatches,
+    cancel: CancelToken,
+) -> Result<RunLimits, Box<dyn std::error::Error>> {
+    let deadline = matches
+        .value_of("deadline")
+        .map(|spec| deadline::parse_deadline(spec, SystemTime::now()))
+        .transpose()
+        .map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("--deadline: {}", err))
+        })?;
+    let entry_timeout = matches
+        .value_of("entry-timeout")
+        .map(|value| {
+            value.parse().map(Duration::from_secs).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--entry-timeout: invalid value '{}'", value),
+                )
+            })
+        })
+        .transpose()?;
+    Ok(RunLimits {
+        deadline,
+        entry_timeout,
+        cancel,
     })
 }
 
-fn parse_optional_hva(value: Option<&str>) -> Result<Option<u64>, Box<dyn std::error::Error>> {
-    match value {
-        None => Ok(None),
-        Some(v) => parse_hex_address(Some(v)).map(Some).ok_or_else(|| {
+/// Parses a byte count with an optional `k`, `m` or `g` suffix.
+pub fn parse_byte_size(value: &str) -> Option<u64> {
+    let lower = value.to_ascii_lowercase();
+    let (digits, shift) = if lower.ends_with('k') {
+        (&lower[..lower.len() - 1], 10)
+    } else if lower.ends_with('m') {
+        (&lower[..lower.len() - 1], 20)
+    } else if lower.ends_with('g') {
+        (&lower[..lower.len() - 1], 30)
+    } else {
+        (&lower[..], 0)
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+}
+
+fn parse_pgoff_align(value: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    pseudo_mm_addr::parse_u64(value)
+        .ok()
+        .filter(|&align| align > 0)
+        .ok_or_else(|| {
             Box::new(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                format!("invalid hva_base '{}': expect hex string", v),
+                format!("--pgoff-align: invalid value '{}'", value),
             )) as Box<dyn std::error::Error>
-        }),
+        })
+}
+
+/// Checks explicit RDMA pgoffs, each with what it is the pgoff of, against
+/// `--pgoff-align`. Misaligned ones fail the run, or with `strict` false
+/// are only warned about.
+pub fn check_pgoff_align(
+    pgoffs: &[(String, u64)],
+    align: u64,
+    strict: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let misaligned: Vec<String> = pgoffs
+        .iter()
+        .filter(|(_, pgoff)| pgoff % align != 0)
+        .map(|(what, pgoff)| {
+            format!(
+                "{}: rdma_pgoff {} is not a multiple of {}",
+                what, pgoff, align
+            )
+        })
+        .collect();
+    if misaligned.is_empty() {
+        return Ok(());
+    }
+    if !strict {
+        for line in &misaligned {
+            println!("warning: {}", line);
+        }
+        return Ok(());
     }
+    Err(Box::new(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "misaligned rdma_pgoff (pass --pgoff-align-strict=false to allow):\n  {}",
+            misaligned.join("\n  ")
+        ),
+    )))
 }
 
-fn upload_memory_to_rdma(
-    mem_file_path: &str,
-    rdma_server: &str,
-    rdma_pgoff: u64,
-) -> Result<(u64, u64), Box<dyn std::error::Error>> {
-    let mut file = File::open(mem_file_path)?;
-    let size = file.seek(SeekFrom::End(0))?;
-    file.seek(SeekFrom::Start(0))?;
+/// `path` made absolute against the working directory, without requiring it
+/// to exist yet.
+pub fn absolute_path(path: &str) -> String {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return path.to_string_lossy().into_owned();
+    }
+    std::env::current_dir()
+        .map(|dir| dir.join(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
 
-    if size % PAGE_SIZE != 0 {
+fn parse_lock_wait(matches: &ArgMatches) -> Result<Duration, Box<dyn std::error::Error>> {
+    match matches.value_of("lock-wait-secs") {
+        Some(value) => {
+            let secs: u64 = value.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--lock-wait-secs: invalid value '{}'", value),
+                )
+            })?;
+            Ok(Duration::from_secs(secs))
+        }
+        None => Ok(Duration::from_secs(0)),
+    }
+}
+
+pub fn check_mem_size(size: u64, page_size: PageSize) -> Result<(), Box<dyn std::error::Error>> {
+    if size % page_size.bytes() != 0 {
         return Err(Box::new(io::Error::new(
             io::ErrorKind::InvalidInput,
             format!(
-                "memory snapshot size must be page aligned ({} bytes)",
-                PAGE_SIZE
+                "memory snapshot size must be page aligned (page size {}, {} bytes)",
+                page_size,
+                page_size.bytes()
             ),
         )));
     }
+    Ok(())
+}
 
-    println!(
-        "Connecting to RDMA server {} and streaming {} bytes...",
-        rdma_server, size
-    );
-    let mut client = RdmaClient::connect(rdma_server)?;
-    client.write_snapshot_from_reader(rdma_pgoff, &mut file, size as u64)?;
-    println!("RDMA upload completed");
+/// Checks that the image's pgoff and the HVA base fall on a page of the
+/// entry's page size, so each page maps one aligned run of the image.
+fn check_page_alignment(args: &TemplateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let misaligned = |what: String| {
+        Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is not page aligned (page size {})",
+                what, args.page_size
+            ),
+        )) as Box<dyn std::error::Error>)
+    };
+    // Extents are aligned as they are picked.
+    if args.free_extents.is_none() && args.rdma_pgoff.raw() % args.page_size.pgoffs() != 0 {
+        return misaligned(format!(
+            "rdma_pgoff {} (a multiple of {} is required)",
+            args.rdma_pgoff,
+            args.page_size.pgoffs()
+        ));
+    }
+    if args.hva_base.raw() % args.page_size.bytes() != 0 {
+        return misaligned(format!("hva_base {}", args.hva_base));
+    }
+    Ok(())
+}
+
+/// Size of an entry's memory file, which must be a multiple of its page
+/// size.
+fn memory_file_size(args: &TemplateArgs) -> Result<u64, Box<dyn std::error::Error>> {
+    let given = match (args.stdin_size, args.mem_pages) {
+        (Some(size), _) => size,
+        (None, Some(pages)) => pages_to_bytes(pages)?,
+        (None, None) => return Ok(open_memory_file(args.mem_files, args.page_size)?.size()),
+    };
+    check_mem_size(given, args.page_size)?;
+    Ok(given)
+}
 
-    Ok((size as u64, (size as u64) / PAGE_SIZE))
+/// Bytes of `pages` pages, failing where they don't fit in a `u64`.
+pub fn pages_to_bytes(pages: u64) -> io::Result<u64> {
+    pages.checked_mul(PAGE_SIZE).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("mem_pages {} overflows the image size", pages),
+        )
+    })
 }
 
-struct RdmaClient {
-    stream: TcpStream,
+/// Rejects the options that would need the image uploaded, or its memory
+/// file read, when `--skip-upload` maps one already on the server.
+fn check_skip_upload(args: &TemplateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let unsupported = if args.stdin_size.is_some() {
+        Some("a memory file read from stdin")
+    } else if let Some(base) = args.base {
+        Some(base.option())
+    } else if args.diff_snapshot {
+        Some("--diff-snapshot")
+    } else if args.dedup.is_some() {
+        Some("--dedup")
+    } else if args.free_extents.is_some() {
+        Some("--pgoff-extents")
+    } else {
+        None
+    };
+    match unsupported {
+        Some(option) => Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "--skip-upload maps the image already at rdma_pgoff and can't be used with {}",
+                option
+            ),
+        ))),
+        None => Ok(()),
+    }
 }
 
-impl RdmaClient {
-    fn connect(addr: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let stream = TcpStream::connect(addr)?;
-        Ok(Self { stream })
+/// Rejects the options that would need `input`, a memory file streamed
+/// once, read again or out of order.
+fn check_streamed_upload(
+    args: &TemplateArgs,
+    input: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let unsupported = if let ImageTarget::Dax { .. } = args.target {
+        Some("--mem-type dax")
+    } else if let Some(base) = args.base {
+        Some(base.option())
+    } else if args.dedup.is_some() {
+        Some("--dedup")
+    } else if args.upload.streams > 1 {
+        Some("--upload-streams")
+    } else if args.upload.retry.attempts > 1 {
+        Some("--upload-retries")
+    } else if args.upload.drop_cache_behind {
+        Some("--drop-cache-behind")
+    } else if args.upload.direct_io {
+        Some("--direct-io")
+    } else {
+        None
+    };
+    match unsupported {
+        Some(option) => Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is streamed once and can't be used with {}",
+                input, option
+            ),
+        ))),
+        None => Ok(()),
     }
+}
 
-    fn write_snapshot_from_reader(
-        &mut self,
-        rdma_pgoff: u64,
-        reader: &mut File,
-        size: u64,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        const CMD_MAP_IMAGE: u32 = 0x1;
-        let mut header = [0u8; 24];
-        header[0..4].copy_from_slice(&CMD_MAP_IMAGE.to_le_bytes());
-        header[8..16].copy_from_slice(&size.to_le_bytes());
-        header[16..24].copy_from_slice(&rdma_pgoff.to_le_bytes());
-        self.stream.write_all(&header)?;
+/// Rejects the options that can't upload an image split across
+/// `--pgoff-extents`.
+fn check_split_image(args: &TemplateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let unsupported = if args.stdin_size.is_some() {
+        Some("a memory file read from stdin")
+    } else if args.merge_base.is_some() {
+        Some("a diff snapshot merged with its base")
+    } else if let Some(base) = args.base {
+        Some(base.option())
+    } else {
+        None
+    };
+    match unsupported {
+        Some(option) => Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--pgoff-extents can't be used with {}", option),
+        ))),
+        None => Ok(()),
+    }
+}
 
-        let copied = io::copy(reader, &mut self.stream)?;
-        if copied != size {
-            return Err(Box::new(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                format!(
-                    "expected to send {} bytes but only wrote {} bytes",
-                    size, copied
-                ),
-            )));
-        }
+/// Opens a memory file, all its shards, whose total size must be a
+/// multiple of `page_size`.
+pub fn open_memory_file(
+    mem_files: &MemFiles,
+    page_size: PageSize,
+) -> Result<MemImage, Box<dyn std::error::Error>> {
+    let image = mem_files.open(page_size)?;
+    check_mem_size(image.size(), page_size)?;
+    Ok(image)
+}
 
-        let mut ack = [0u8; 4];
-        self.stream.read_exact(&mut ack)?;
-        let status = i32::from_le_bytes(ack);
-        if status != 0 {
-            return Err(Box::new(io::Error::new(
-                io::ErrorKind::Other,
-                format!("RDMA server returned error code {}", status),
-            )));
-        }
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_hva_window() {
+        let region = |hva: u64, size: u64| RegionMetadata {
+            hva: HvaAddr(hva),
+            size,
+            ..test_templates::region(0, 0, 0)
+        };
+        let mut plan = TemplatePlan {
+            label: "batch-1".to_string(),
+            output_path: "out.json".to_string(),
+            backend: MemBackend::Rdma,
+            dax_device: None,
+            rdma_pgoff: PageOffset(0),
+            pages: 0,
+            image_extents: Vec::new(),
+            guarded_range: None,
+            mem_size: 0,
+            hva_base: HvaAddr(0x7000_0000_0000),
+            pgoff_namespace: None,
+            vm_shape: VmShape {
+                vcpu_count: 1,
+                mem_size_mib: 128,
+                boot_vcpu_features: None,
+                smt: None,
+            },
+            source: SnapshotSource {
+                snapshot_path: "/srv/snap/vm.snap".to_string(),
+                snapshot_size: 4096,
+                format_version: 1,
+                data_version: 3,
+                firecracker_version: None,
+                vcpu_count: 1,
+                guest_memory_size: 128 << 20,
+                region_count: 1,
+            },
+            regions: Vec::new(),
+            layered: None,
+            dedup: None,
+            windows: Vec::new(),
+            layers: None,
+            dedup_pages: None,
+        };
+        assert_eq!(
+            plan.hva_window(),
+            (HvaAddr(0x7000_0000_0000), HvaAddr(0x7000_0000_0000))
+        );
+
+        plan.regions = vec![
+            region(0x7000_1000_0000, 0x2000),
+            region(0x7000_0000_0000, 0x1000),
+        ];
+        assert_eq!(
+            plan.hva_window(),
+            (HvaAddr(0x7000_0000_0000), HvaAddr(0x7000_1000_2000))
+        );
+    }
+
+    #[test]
+    fn test_huge_page_alignment() {
+        assert!(check_mem_size(4 << 20, PageSize::Huge).is_ok());
+        let err = check_mem_size((2 << 20) + PAGE_SIZE, PageSize::Huge).unwrap_err();
+        assert!(err.to_string().contains("page size 2m"), "{}", err);
+        assert!(check_mem_size(PAGE_SIZE, PageSize::Base).is_ok());
+    }
+
+    #[test]
+    fn test_check_pgoff_align() {
+        let pgoffs = vec![
+            ("fn-a".to_string(), 1024),
+            ("fn-b".to_string(), 1000),
+            ("fn-c".to_string(), 0),
+        ];
+        check_pgoff_align(&pgoffs, 8, true).unwrap();
+        let err = check_pgoff_align(&pgoffs, 512, true)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "misaligned rdma_pgoff (pass --pgoff-align-strict=false to allow):\n  \
+             fn-b: rdma_pgoff 1000 is not a multiple of 512"
+        );
+        check_pgoff_align(&pgoffs, 512, false).unwrap();
+        assert!(parse_pgoff_align("0").is_err());
+        assert_eq!(parse_pgoff_align("0x200").unwrap(), 512);
+    }
+
+    #[test]
+    fn test_describe_snapshot_versions() {
+        let versions = SnapshotVersions {
+            format_version: 1,
+            data_version: 1,
+        };
+        assert_eq!(
+            describe_snapshot_versions(versions, None),
+            format!(
+                "format version 1, data version 1 (Firecracker 0.23.0); this build reads \
+                 data versions up to {}",
+                VERSION_MAP.latest_version()
+            )
+        );
+        let versions = SnapshotVersions {
+            format_version: 1,
+            data_version: 9,
+        };
+        let msg = describe_snapshot_versions(versions, Some(1));
+        assert!(
+            msg.starts_with("format version 1, data version 9, loaded as data version 1;"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn test_parse_snapshot_reports_versions() {
+        let path = test_files::path("snapshot_header");
+        // A data version 1 header followed by a state that isn't a microVM's.
+        let mut file = File::create(&path).unwrap();
+        Snapshot::new(VERSION_MAP.clone(), 1)
+            .save(&mut file, &0u64)
+            .unwrap();
+        drop(file);
+
+        let path_str = path.to_str().unwrap();
+        let err = parse_snapshot(path_str, None).err().unwrap().to_string();
+        assert!(
+            err.contains("format version 1, data version 1 (Firecracker 0.23.0)"),
+            "{}",
+            err
+        );
+        std::fs::remove_file(&path).unwrap();
     }
 }
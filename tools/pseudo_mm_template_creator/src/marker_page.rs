@@ -0,0 +1,243 @@
+//! A page of the image telling the guest which template it came from.
+//!
+//! A guest resumed from the wrong template, or on an image the server mixed
+//! up, runs on without noticing. With `--marker-gpa`, the 4 KiB page at
+//! that guest address, which the guest agent knows to read, is uploaded as
+//! a marker instead of the memory file's bytes:
+//!
+//! | offset | bytes | contents                                          |
+//! |--------|-------|---------------------------------------------------|
+//! | 0      | 8     | `MAGIC`                                           |
+//! | 8      | 64    | hex SHA-256 of the image, as `image_reuse` hashes it |
+//! | 72     | 8     | creation time, seconds since the Unix epoch, LE   |
+//!
+//! and zeros after. The hash is of the memory file's image, before the
+//! marker replaced anything. The template's `marker` records the same
+//! fields and the page's original bytes, so the unmodified image can be
+//! rebuilt from the one on the server.
+//!
+//! Everything made from the upload sees the marker: region content hashes
+//! include it, so `inspect-memory --verify-hashes` checks it too. A
+//! template layered on one with a marker never maps the marker's page from
+//! it, since the base memory file doesn't hold what the server does.
+
+use std::borrow::Cow;
+use std::io;
+
+use vmm::pseudo_mm_addr::{Gpa, PageOffset};
+use vmm::pseudo_mm_support::{MarkerPage, PageSize, RegionMetadata, PAGE_SIZE};
+
+use crate::image_reuse;
+use crate::mem_files::ReadAt;
+use crate::regions::ImageWindow;
+
+/// First bytes of a marker page.
+pub const MAGIC: &[u8; 8] = b"PSMMMRK1";
+
+/// The marker page of an upload, by where it goes in the image.
+#[derive(Clone, Debug)]
+pub struct Marker {
+    /// Byte offset of the page in the image.
+    pub image_offset: u64,
+    pub page: Vec<u8>,
+}
+
+impl Marker {
+    /// `data`, the image from `offset` on, with the marker in place of
+    /// whatever of the page it holds.
+    pub fn overlay<'a>(&self, data: &'a [u8], offset: u64) -> Cow<'a, [u8]> {
+        let end = offset + data.len() as u64;
+        let marker_end = self.image_offset + PAGE_SIZE;
+        if end <= self.image_offset || marker_end <= offset {
+            return Cow::Borrowed(data);
+        }
+        let from = std::cmp::max(offset, self.image_offset);
+        let to = std::cmp::min(end, marker_end);
+        let mut patched = data.to_vec();
+        patched[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+            &self.page[(from - self.image_offset) as usize..(to - self.image_offset) as usize],
+        );
+        Cow::Owned(patched)
+    }
+
+    /// Splits a run of `pages` zero 4 KiB pages from `first_page` into
+    /// `(first page, pages, holds the marker)` parts, the marker's part a
+    /// whole `page` of bytes so it can be sent like any other.
+    pub fn split_zeros(&self, first_page: u64, pages: u64, page: u64) -> Vec<(u64, u64, bool)> {
+        let per_unit = page / PAGE_SIZE;
+        let unit = self.image_offset / PAGE_SIZE / per_unit * per_unit;
+        if unit + per_unit <= first_page || first_page + pages <= unit {
+            return vec![(first_page, pages, false)];
+        }
+        let mut parts = Vec::new();
+        if unit > first_page {
+            parts.push((first_page, unit - first_page, false));
+        }
+        parts.push((unit, per_unit, true));
+        if first_page + pages > unit + per_unit {
+            parts.push((unit + per_unit, first_page + pages - unit - per_unit, false));
+        }
+        parts
+    }
+}
+
+/// Builds the marker for the page at `gpa` of `regions`, laid out in the
+/// image at `rdma_pgoff` made of `windows` of `mem_file`. Reads the image
+/// once to hash it.
+pub fn prepare(
+    gpa: Gpa,
+    regions: &[RegionMetadata],
+    rdma_pgoff: PageOffset,
+    mem_file: &dyn ReadAt,
+    windows: &[ImageWindow],
+    page_size: PageSize,
+    created_at: u64,
+) -> io::Result<(Marker, MarkerPage)> {
+    let gpa = gpa.raw();
+    let image_offset = image_offset(gpa, regions, rdma_pgoff)?;
+    let file_offset = windows
+        .iter()
+        .find(|window| {
+            window.image_offset <= image_offset && image_offset < window.image_offset + window.size
+        })
+        .map(|window| window.file_offset + (image_offset - window.image_offset))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--marker-gpa 0x{:x}: not in the uploaded image", gpa),
+            )
+        })?;
+    let mut original = vec![0u8; PAGE_SIZE as usize];
+    mem_file.read_exact_at(&mut original, file_offset)?;
+    let image_hash = image_reuse::digest(mem_file, windows, page_size)?.hash;
+    let marker = Marker {
+        image_offset,
+        page: page(&image_hash, created_at),
+    };
+    let recorded = MarkerPage {
+        gpa: Gpa(gpa),
+        image_hash,
+        created_at,
+        original: original
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    };
+    Ok((marker, recorded))
+}
+
+/// The marker page holding `image_hash` and `created_at`.
+pub fn page(image_hash: &str, created_at: u64) -> Vec<u8> {
+    let mut page = vec![0u8; PAGE_SIZE as usize];
+    page[..8].copy_from_slice(MAGIC);
+    page[8..8 + image_hash.len()].copy_from_slice(image_hash.as_bytes());
+    page[72..80].copy_from_slice(&created_at.to_le_bytes());
+    page
+}
+
+/// Byte offset in the image at `rdma_pgoff` of the page at `gpa`, which
+/// must be 4 KiB aligned and in one of `regions`.
+fn image_offset(gpa: u64, regions: &[RegionMetadata], rdma_pgoff: PageOffset) -> io::Result<u64> {
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--marker-gpa 0x{:x}: {}", gpa, reason),
+        )
+    };
+    if gpa % PAGE_SIZE != 0 {
+        return Err(invalid("not 4 KiB aligned"));
+    }
+    let region = regions
+        .iter()
+        .find(|region| region.gpa.raw() <= gpa && gpa + PAGE_SIZE <= region.gpa.raw() + region.size)
+        .ok_or_else(|| invalid("not in any memory region"))?;
+    let first_page = region.rdma_offset.raw() - rdma_pgoff.raw();
+    Ok(first_page * PAGE_SIZE + (gpa - region.gpa.raw()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem_files::MemFiles;
+    use crate::test_files;
+    use crate::test_templates::region;
+
+    #[test]
+    fn test_prepare() {
+        let mut data = vec![0u8; 4 * PAGE_SIZE as usize];
+        data[3 * PAGE_SIZE as usize] = 0xaa;
+        let path = test_files::file("marker", &data);
+        let image = MemFiles::new(vec![path.to_string_lossy().into_owned()])
+            .open(PageSize::Base)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Two regions, packed in file order; the second starts at page 102.
+        let regions = vec![region(0, 2, 100), region(0x10_0000, 2, 102)];
+        let windows = vec![ImageWindow {
+            file_offset: 0,
+            size: 4 * PAGE_SIZE,
+            image_offset: 0,
+        }];
+        let (marker, recorded) = prepare(
+            Gpa(0x10_1000),
+            &regions,
+            PageOffset(100),
+            &image,
+            &windows,
+            PageSize::Base,
+            1_700_000_000,
+        )
+        .unwrap();
+        assert_eq!(marker.image_offset, 3 * PAGE_SIZE);
+        assert_eq!(&marker.page[..8], MAGIC);
+        assert_eq!(&marker.page[8..72], recorded.image_hash.as_bytes());
+        assert_eq!(marker.page[72..80], 1_700_000_000u64.to_le_bytes());
+        assert!(recorded.original.starts_with("aa00"));
+        assert_eq!(recorded.original.len(), 2 * PAGE_SIZE as usize);
+
+        for &(gpa, reason) in &[
+            (0x10_1800, "not 4 KiB aligned"),
+            (0x2000, "not in any memory region"),
+        ] {
+            let err = prepare(
+                Gpa(gpa),
+                &regions,
+                PageOffset(100),
+                &image,
+                &windows,
+                PageSize::Base,
+                0,
+            )
+            .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("--marker-gpa 0x{:x}: {}", gpa, reason)
+            );
+        }
+    }
+
+    #[test]
+    fn test_overlay_and_split_zeros() {
+        let marker = Marker {
+            image_offset: 3 * PAGE_SIZE,
+            page: page(&"ab".repeat(32), 7),
+        };
+        let data = vec![1u8; 2 * PAGE_SIZE as usize];
+        assert!(matches!(marker.overlay(&data, 0), Cow::Borrowed(_)));
+        let patched = marker.overlay(&data, 2 * PAGE_SIZE);
+        assert_eq!(&patched[..PAGE_SIZE as usize], &data[..PAGE_SIZE as usize]);
+        assert_eq!(&patched[PAGE_SIZE as usize..], &marker.page[..]);
+
+        assert_eq!(marker.split_zeros(0, 3, PAGE_SIZE), vec![(0, 3, false)]);
+        assert_eq!(
+            marker.split_zeros(1, 6, PAGE_SIZE),
+            vec![(1, 2, false), (3, 1, true), (4, 3, false)]
+        );
+        // With 2 MiB pages the page holding the marker is sent whole.
+        assert_eq!(
+            marker.split_zeros(0, 1024, 2 << 20),
+            vec![(0, 512, true), (512, 512, false)]
+        );
+    }
+}
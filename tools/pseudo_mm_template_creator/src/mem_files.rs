@@ -0,0 +1,698 @@
+//! Memory files split into shards.
+//!
+//! A snapshot pipeline can write guest memory as several sequential files
+//! (`mem.0`, `mem.1`, ...) to spread the disk I/O. `--mem-file-path` and a
+//! batch entry's `mem_file_path` then name the shards, as a comma-separated
+//! list, a glob (`mem.*`, matched in the file name only and ordered with
+//! numbers compared by value, so `mem.10` follows `mem.9`) or, in a batch
+//! config, an array. The shards are read back to back as one memory file:
+//! region offsets, image windows and pgoffs are those of the concatenation.
+//!
+//! Every shard must hold whole pages of the entry's page size, so no page
+//! straddles two files. A single file works exactly as before.
+//!
+//! A batch config's `mem_file_path` is kept as written until `resolve`
+//! expands its environment variables, and only then split and matched.
+//!
+//! `--mem-file-path -` reads the memory file from stdin instead, once and
+//! in order; its size can't be learned up front, so it is given with
+//! `--mem-size`. Such a file can't be opened or sized here; see
+//! `upload_stream_to_rdma` for what a stdin upload supports.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use vmm::pseudo_mm_support::{PageSize, PAGE_SIZE};
+
+use crate::fd_budget::LazyFile;
+use crate::mem_reader;
+use crate::regions::ImageWindow;
+use crate::zero_pages;
+
+/// Names the memory file read from stdin.
+pub const STDIN: &str = "-";
+
+/// The files of one memory image, in image order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemFiles {
+    paths: Vec<String>,
+    /// How `paths` are read once resolved, while they are as written in a
+    /// batch config.
+    unresolved: Option<Written>,
+}
+
+/// Forms of a batch config's `mem_file_path`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Written {
+    /// One value as for `--mem-file-path`.
+    Spec,
+    /// An array of paths, taken literally.
+    List,
+}
+
+impl MemFiles {
+    /// The shards at `paths`, taken literally.
+    pub fn new(paths: Vec<String>) -> Self {
+        MemFiles {
+            paths,
+            unresolved: None,
+        }
+    }
+
+    /// `spec` as a batch config's `mem_file_path`, not resolved yet.
+    #[cfg(test)]
+    pub fn written_spec(spec: &str) -> Self {
+        MemFiles::written(vec![spec.to_string()], Written::Spec)
+    }
+
+    fn written(paths: Vec<String>, form: Written) -> Self {
+        MemFiles {
+            paths,
+            unresolved: Some(form),
+        }
+    }
+
+    /// Finds the shards of a path read from a batch config, after passing
+    /// what was written through `expand`.
+    pub fn resolve(&mut self, expand: &dyn Fn(&str) -> Result<String, String>) -> io::Result<()> {
+        let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidInput, err);
+        let resolved = match self.unresolved {
+            None => return Ok(()),
+            Some(Written::Spec) => MemFiles::parse(&expand(&self.paths[0]).map_err(invalid)?)?,
+            Some(Written::List) => MemFiles::new(
+                self.paths
+                    .iter()
+                    .map(|path| expand(path))
+                    .collect::<Result<_, _>>()
+                    .map_err(invalid)?,
+            ),
+        };
+        if resolved.is_stdin() {
+            // Entries would all read the same stdin.
+            return Err(invalid(
+                "mem_file_path can't be stdin ('-') in a batch".to_string(),
+            ));
+        }
+        *self = resolved;
+        Ok(())
+    }
+
+    /// Parses a `--mem-file-path` value: paths separated by commas, each of
+    /// which may be a glob, or `-` alone for stdin.
+    pub fn parse(spec: &str) -> io::Result<Self> {
+        if spec.trim() == STDIN {
+            return Ok(MemFiles::new(vec![STDIN.to_string()]));
+        }
+        let mut paths = Vec::new();
+        for part in spec.split(',').map(str::trim) {
+            if part == STDIN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("stdin ('-') can't be a shard of '{}'", spec),
+                ));
+            }
+            if part.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("empty memory file path in '{}'", spec),
+                ));
+            }
+            if is_glob(part) {
+                paths.extend(expand_glob(part)?);
+            } else {
+                paths.push(part.to_string());
+            }
+        }
+        Ok(MemFiles::new(paths))
+    }
+
+    /// The shards' paths in image order, once resolved.
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    /// Number of shards, once resolved.
+    pub fn shard_count(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// The first shard that isn't a file, once resolved.
+    pub fn first_missing(&self) -> Option<&str> {
+        self.paths
+            .iter()
+            .find(|path| !Path::new(path).is_file())
+            .map(String::as_str)
+    }
+
+    /// Whether the memory file is read from stdin.
+    pub fn is_stdin(&self) -> bool {
+        self.paths.len() == 1 && self.paths[0] == STDIN
+    }
+
+    /// Total size of the shards, without opening them.
+    pub fn size(&self) -> io::Result<u64> {
+        self.check_not_stdin()?;
+        self.paths.iter().try_fold(0, |total, path| {
+            std::fs::metadata(path)
+                .map(|meta| total + meta.len())
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path, err)))
+        })
+    }
+
+    /// Opens every shard; with more than one, each must be a multiple of
+    /// `page_size`.
+    pub fn open(&self, page_size: PageSize) -> io::Result<MemImage> {
+        self.open_with(page_size, false)
+            .map(|image| image.expect("Only direct opens fall back"))
+    }
+
+    /// Opens every shard like `open`, but with `O_DIRECT`; `None` if the
+    /// filesystem of any shard doesn't support it.
+    pub fn open_direct(&self, page_size: PageSize) -> io::Result<Option<MemImage>> {
+        self.open_with(page_size, true)
+    }
+
+    fn open_with(&self, page_size: PageSize, direct: bool) -> io::Result<Option<MemImage>> {
+        self.check_not_stdin()?;
+        let mut shards = Vec::with_capacity(self.paths.len());
+        let mut start = 0;
+        for path in &self.paths {
+            let mut options = OpenOptions::new();
+            options.read(true);
+            if direct {
+                options.custom_flags(libc::O_DIRECT);
+            }
+            let file = match options.open(path) {
+                Ok(file) => file,
+                Err(ref err) if direct && err.raw_os_error() == Some(libc::EINVAL) => {
+                    return Ok(None)
+                }
+                Err(err) => return Err(io::Error::new(err.kind(), format!("{}: {}", path, err))),
+            };
+            if direct && !mem_reader::reads_direct(&file)? {
+                return Ok(None);
+            }
+            let size = file.metadata()?.len();
+            if self.paths.len() > 1 && size % page_size.bytes() != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "memory file shard {} is {} bytes, not a multiple of the page size {}",
+                        path, size, page_size
+                    ),
+                ));
+            }
+            shards.push(Shard { file, start, size });
+            start += size;
+        }
+        Ok(Some(MemImage { shards, direct }))
+    }
+
+    fn check_not_stdin(&self) -> io::Result<()> {
+        if self.is_stdin() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a memory file read from stdin can only be streamed once, in order",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the shards in order, opening each as it is reached.
+    pub fn lazy_reader(&self) -> Box<dyn Read + Send> {
+        self.paths.iter().fold(
+            Box::new(io::empty()) as Box<dyn Read + Send>,
+            |reader, path| Box::new(reader.chain(LazyFile::new(path))),
+        )
+    }
+}
+
+impl fmt::Display for MemFiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.paths.join(","))
+    }
+}
+
+impl<'de> Deserialize<'de> for MemFiles {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(MemFilesVisitor)
+    }
+}
+
+/// Accepts a string as for `--mem-file-path`, or an array of paths.
+struct MemFilesVisitor;
+
+impl<'de> Visitor<'de> for MemFilesVisitor {
+    type Value = MemFiles;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a memory file path, a comma-separated list or glob, or an array")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<MemFiles, E> {
+        Ok(MemFiles::written(vec![value.to_string()], Written::Spec))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<MemFiles, A::Error> {
+        let mut paths = Vec::new();
+        while let Some(path) = seq.next_element::<String>()? {
+            paths.push(path);
+        }
+        if paths.is_empty() {
+            return Err(de::Error::custom("mem_file_path lists no files"));
+        }
+        Ok(MemFiles::written(paths, Written::List))
+    }
+}
+
+fn expand_glob(pattern: &str) -> io::Result<Vec<String>> {
+    let paths = glob(pattern)?;
+    if paths.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no memory file matches '{}'", pattern),
+        ));
+    }
+    Ok(paths)
+}
+
+/// Files matching `pattern`, whose wildcards (`*` and `?`) must all be in
+/// the file name, in natural order.
+pub fn glob(pattern: &str) -> io::Result<Vec<String>> {
+    let path = Path::new(pattern);
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+    let wild_dir = dir.map_or(false, |dir| is_glob(&dir.to_string_lossy()));
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if !wild_dir => name,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("only the file name of '{}' may contain wildcards", pattern),
+            ))
+        }
+    };
+    let entries = std::fs::read_dir(dir.unwrap_or_else(|| Path::new(".")))
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", pattern, err)))?;
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if let Some(found) = entry.file_name().to_str() {
+            if glob_match(name.as_bytes(), found.as_bytes()) && entry.path().is_file() {
+                names.push(found.to_string());
+            }
+        }
+    }
+    names.sort_by(|a, b| natural_cmp(a, b));
+    Ok(names
+        .into_iter()
+        .map(|name| match dir {
+            Some(dir) => dir.join(name).to_string_lossy().into_owned(),
+            None => name,
+        })
+        .collect())
+}
+
+fn is_glob(path: &str) -> bool {
+    path.contains(&['*', '?'][..])
+}
+
+/// Whether `name` matches `pattern`, where `*` is any run of bytes and `?`
+/// any one byte.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| glob_match(rest, &name[skip..])),
+        Some((&want, rest)) => match name.split_first() {
+            Some((&got, name)) => (want == b'?' || want == got) && glob_match(rest, name),
+            None => false,
+        },
+    }
+}
+
+/// Orders names with their runs of digits compared by value.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x_len, y_len) = (digits(a), digits(b));
+                let (x_num, y_num) = (without_zeros(&a[..x_len]), without_zeros(&b[..y_len]));
+                // Without leading zeros, the longer number is the larger.
+                let order = x_num.len().cmp(&y_num.len()).then_with(|| x_num.cmp(y_num));
+                if order != Ordering::Equal {
+                    return order;
+                }
+                a = &a[x_len..];
+                b = &b[y_len..];
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+fn digits(s: &[u8]) -> usize {
+    s.iter().take_while(|c| c.is_ascii_digit()).count()
+}
+
+fn without_zeros(number: &[u8]) -> &[u8] {
+    &number[number.iter().take_while(|&&c| c == b'0').count()..]
+}
+
+struct Shard {
+    file: File,
+    /// Offset of the shard's first byte in the memory file.
+    start: u64,
+    size: u64,
+}
+
+impl Shard {
+    fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+/// Positional reads of a memory file, or of a view of one.
+pub trait ReadAt {
+    /// Reads `buf.len()` bytes at `offset`.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+}
+
+/// The open shards of a memory file.
+pub struct MemImage {
+    shards: Vec<Shard>,
+    /// Whether the shards were opened with `O_DIRECT`.
+    direct: bool,
+}
+
+impl MemImage {
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    /// The same shards, opened again with the same flags.
+    pub fn try_clone(&self) -> io::Result<MemImage> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            shards.push(Shard {
+                file: shard.file.try_clone()?,
+                start: shard.start,
+                size: shard.size,
+            });
+        }
+        Ok(MemImage {
+            shards,
+            direct: self.direct,
+        })
+    }
+
+    /// Size of the memory file, all shards together.
+    pub fn size(&self) -> u64 {
+        self.shards.last().map_or(0, Shard::end)
+    }
+
+    /// `windows`, in order, cut where one shard ends and the next starts,
+    /// and grouped into runs in the same shard, by shard index. File
+    /// offsets are within the shard; image offsets are unchanged.
+    pub fn split(&self, windows: &[ImageWindow]) -> Vec<(usize, Vec<ImageWindow>)> {
+        let mut groups: Vec<(usize, Vec<ImageWindow>)> = Vec::new();
+        for window in windows {
+            let end = window.file_offset + window.size;
+            for (idx, shard) in self.shards.iter().enumerate() {
+                let from = std::cmp::max(window.file_offset, shard.start);
+                let to = std::cmp::min(end, shard.end());
+                if from >= to {
+                    continue;
+                }
+                let piece = ImageWindow {
+                    file_offset: from - shard.start,
+                    size: to - from,
+                    image_offset: window.image_offset + (from - window.file_offset),
+                };
+                match groups.last_mut() {
+                    Some((last, pieces)) if *last == idx => pieces.push(piece),
+                    _ => groups.push((idx, vec![piece])),
+                }
+            }
+        }
+        groups
+    }
+
+    pub fn shard(&mut self, idx: usize) -> &mut File {
+        &mut self.shards[idx].file
+    }
+
+    /// Byte ranges `[start, end)` of the memory file, aligned to 4 KiB
+    /// pages, that may hold data; see `zero_pages::data_extents`.
+    pub fn data_extents(&self) -> io::Result<Vec<(u64, u64)>> {
+        let mut extents: Vec<(u64, u64)> = Vec::new();
+        for shard in &self.shards {
+            for (start, end) in zero_pages::data_extents(&shard.file, shard.size, PAGE_SIZE)? {
+                let (start, end) = (shard.start + start, shard.start + end);
+                match extents.last_mut() {
+                    Some(last) if last.1 == start => last.1 = end,
+                    _ => extents.push((start, end)),
+                }
+            }
+        }
+        Ok(extents)
+    }
+
+    /// Reads `buf.len()` bytes at `offset` in the memory file, from as many
+    /// shards as they span.
+    pub fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        for shard in &self.shards {
+            if buf.is_empty() {
+                break;
+            }
+            if offset >= shard.end() {
+                continue;
+            }
+            let len = std::cmp::min(buf.len() as u64, shard.end() - offset) as usize;
+            let (head, rest) = buf.split_at_mut(len);
+            shard.file.read_exact_at(head, offset - shard.start)?;
+            buf = rest;
+            offset += len as u64;
+        }
+        if !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "memory file ends before offset 0x{:x}",
+                    offset + buf.len() as u64
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ReadAt for MemImage {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        MemImage::read_exact_at(self, buf, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+    use std::path::PathBuf;
+
+    /// Writes shards of `pages[i]` pages, each byte its offset in the
+    /// whole file modulo 251, into a new directory.
+    fn shards(name: &str, pages: &[u64]) -> (PathBuf, Vec<String>) {
+        let dir = test_files::dir(&format!("shards_{}", name));
+        let mut offset = 0u64;
+        let mut paths = Vec::new();
+        for (idx, &count) in pages.iter().enumerate() {
+            let len = count * PAGE_SIZE;
+            let contents: Vec<u8> = (offset..offset + len).map(|at| (at % 251) as u8).collect();
+            let path = dir.join(format!("mem.{}", idx));
+            std::fs::write(&path, contents).unwrap();
+            paths.push(path.to_string_lossy().into_owned());
+            offset += len;
+        }
+        (dir, paths)
+    }
+
+    #[test]
+    fn test_parse_mem_files() {
+        let (dir, paths) = shards("parse", &[1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(MemFiles::parse("a, b").unwrap().paths, &["a", "b"]);
+        assert!(MemFiles::parse("a,,b").is_err());
+        assert!(MemFiles::parse(" - ").unwrap().is_stdin());
+        assert!(MemFiles::parse("a,-").is_err());
+        assert!(!MemFiles::parse("a").unwrap().is_stdin());
+        let err = MemFiles::parse("-").unwrap().size().unwrap_err();
+        assert!(err.to_string().contains("stdin"), "{}", err);
+
+        // mem.10 sorts after mem.9.
+        let glob = format!("{}/mem.*", dir.display());
+        assert_eq!(MemFiles::parse(&glob).unwrap().paths, paths.as_slice());
+        let glob = format!("{}/mem.?", dir.display());
+        assert_eq!(MemFiles::parse(&glob).unwrap().paths, &paths[..10]);
+
+        let none = format!("{}/other.*", dir.display());
+        let err = MemFiles::parse(&none).unwrap_err().to_string();
+        assert!(err.contains("no memory file matches"), "{}", err);
+        let nested = format!("{}/*/mem.0", dir.display());
+        assert!(MemFiles::parse(&nested).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_written_paths() {
+        let (dir, paths) = shards("resolve", &[1, 1]);
+        let expand = |value: &str| Ok(value.replace("${ROOT}", &dir.to_string_lossy()));
+
+        // Globs are matched after expansion.
+        let mut spec = MemFiles::written(vec!["${ROOT}/mem.*".to_string()], Written::Spec);
+        spec.resolve(&expand).unwrap();
+        assert_eq!(spec, MemFiles::new(paths));
+
+        // Array entries are expanded but taken literally.
+        let mut list = MemFiles::written(
+            vec!["${ROOT}/mem.*".to_string(), "b".to_string()],
+            Written::List,
+        );
+        list.resolve(&expand).unwrap();
+        assert_eq!(
+            list.paths,
+            &[format!("{}/mem.*", dir.display()), "b".to_string()]
+        );
+
+        let mut stdin = MemFiles::written(vec!["-".to_string()], Written::Spec);
+        let err = stdin.resolve(&expand).unwrap_err().to_string();
+        assert!(err.contains("can't be stdin"), "{}", err);
+        let mut unset = MemFiles::written(vec!["${X}".to_string()], Written::Spec);
+        let err = unset.resolve(&|_| Err("X is not set".to_string()));
+        assert!(err.unwrap_err().to_string().contains("X is not set"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec!["mem.10", "mem.9", "mem.01", "mem.1a", "mem.x"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["mem.01", "mem.1a", "mem.9", "mem.10", "mem.x"]);
+        assert!(glob_match(b"mem.*", b"mem."));
+        assert!(!glob_match(b"mem.?", b"mem."));
+    }
+
+    #[test]
+    fn test_open_checks_shard_alignment() {
+        let (dir, mut paths) = shards("align", &[2, 1]);
+        let files = MemFiles::new(paths.clone());
+        assert_eq!(files.size().unwrap(), 3 * PAGE_SIZE);
+        assert_eq!(files.open(PageSize::Base).unwrap().size(), 3 * PAGE_SIZE);
+
+        let short = dir.join("short");
+        std::fs::write(&short, vec![1u8; 100]).unwrap();
+        paths.insert(1, short.to_string_lossy().into_owned());
+        let err = MemFiles::new(paths).open(PageSize::Base).err().unwrap();
+        assert!(err.to_string().contains("is 100 bytes"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_split_at_shard_boundaries() {
+        let page = PAGE_SIZE;
+        let (dir, paths) = shards("split", &[2, 1, 3]);
+        let image = MemFiles::new(paths).open(PageSize::Base).unwrap();
+        let windows = [
+            // Ends exactly where shard 0 ends.
+            ImageWindow {
+                file_offset: 0,
+                size: 2 * page,
+                image_offset: 0,
+            },
+            // Starts where shard 1 starts and runs one page into shard 2.
+            ImageWindow {
+                file_offset: 2 * page,
+                size: 2 * page,
+                image_offset: 2 * page,
+            },
+            ImageWindow {
+                file_offset: 5 * page,
+                size: page,
+                image_offset: 4 * page,
+            },
+        ];
+        assert_eq!(
+            image.split(&windows),
+            vec![
+                (
+                    0,
+                    vec![ImageWindow {
+                        file_offset: 0,
+                        size: 2 * page,
+                        image_offset: 0,
+                    }]
+                ),
+                (
+                    1,
+                    vec![ImageWindow {
+                        file_offset: 0,
+                        size: page,
+                        image_offset: 2 * page,
+                    }]
+                ),
+                (
+                    2,
+                    vec![
+                        ImageWindow {
+                            file_offset: 0,
+                            size: page,
+                            image_offset: 3 * page,
+                        },
+                        ImageWindow {
+                            file_offset: 2 * page,
+                            size: page,
+                            image_offset: 4 * page,
+                        },
+                    ]
+                ),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_across_shards() {
+        let page = PAGE_SIZE;
+        let (dir, paths) = shards("read", &[1, 1, 2]);
+        let files = MemFiles::new(paths);
+        let image = files.open(PageSize::Base).unwrap();
+        let expected = |from: u64, len: u64| -> Vec<u8> {
+            (from..from + len).map(|at| (at % 251) as u8).collect()
+        };
+
+        // The last byte of shard 0 through the first of shard 2.
+        let mut buf = vec![0u8; page as usize + 2];
+        image.read_exact_at(&mut buf, page - 1).unwrap();
+        assert_eq!(buf, expected(page - 1, page + 2));
+
+        let mut tail = vec![0u8; 2];
+        let err = image.read_exact_at(&mut tail, 4 * page - 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut all = Vec::new();
+        files.lazy_reader().read_to_end(&mut all).unwrap();
+        assert_eq!(all, expected(0, 4 * page));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
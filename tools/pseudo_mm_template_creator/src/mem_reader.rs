@@ -0,0 +1,485 @@
+//! Reading the memory file for an RDMA upload.
+//!
+//! An upload is planned as a list of steps over its windows: runs of pages
+//! the filesystem reports as holes are skipped, and the data in between is
+//! read `--upload-chunk-size` bytes at a time. By default the chunks are read
+//! through the page cache, one after the other with the sends.
+//!
+//! With `--direct-io` the memory file is opened with `O_DIRECT` instead, so a
+//! huge file neither goes through nor evicts the page cache. Reads then need
+//! buffers, offsets and lengths aligned to `DIRECT_ALIGN`, which whole pages
+//! are. A thread reads the next chunk into one of two buffers while the other
+//! is being sent. If the filesystem of any shard rejects `O_DIRECT`, the
+//! file is read through the page cache as usual.
+
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::FileExt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::mem_files::MemImage;
+use crate::regions::ImageWindow;
+use crate::{page_cache, zero_pages, PAGE_SIZE};
+
+/// Alignment of `O_DIRECT` buffers, offsets and lengths.
+pub const DIRECT_ALIGN: usize = 4096;
+
+/// Chunks the prefetch thread reads ahead into.
+const PIPELINE_BUFFERS: usize = 2;
+
+/// A chunk of one shard of the memory file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkRead {
+    pub shard: usize,
+    /// Offset within the shard.
+    pub offset: u64,
+    pub len: usize,
+}
+
+/// One step of an upload. `done` is the bytes of the windows handled before
+/// the step, whether sent or skipped.
+#[derive(Debug, PartialEq)]
+pub enum UploadStep {
+    /// Pages of a hole, zero without being read.
+    Skip {
+        first_page: u64,
+        pages: u64,
+        done: u64,
+    },
+    /// A chunk holding the image from 4 KiB page `first_page` on.
+    Send {
+        read: ChunkRead,
+        first_page: u64,
+        done: u64,
+    },
+}
+
+/// Plans the upload of `windows` of `image` in `page`-aligned chunks of at
+/// most `chunk_size` bytes.
+///
+/// Moves the file positions of the shards.
+pub fn upload_steps(
+    image: &mut MemImage,
+    windows: &[ImageWindow],
+    page: u64,
+    chunk_size: usize,
+) -> io::Result<Vec<UploadStep>> {
+    let mut steps = Vec::new();
+    // Bytes of the windows before the current one.
+    let mut before = 0;
+    for (shard, windows) in image.split(windows) {
+        let reader = image.shard(shard);
+        for window in &windows {
+            let (first, size) = (window.file_offset, window.file_offset + window.size);
+            // Page of the image holding file offset `offset`.
+            let image_page = |offset: u64| (window.image_offset + offset - first) / PAGE_SIZE;
+            let handled = |offset: u64| before + offset - first;
+            // Offset in the shard up to which bytes are handled.
+            let mut done = first;
+            let extents = zero_pages::data_extents(reader, size, page)?
+                .into_iter()
+                .map(|(start, end)| (std::cmp::max(start, first), end))
+                .filter(|&(start, end)| start < end);
+            for (start, end) in extents {
+                if start > done {
+                    steps.push(UploadStep::Skip {
+                        first_page: image_page(done),
+                        pages: (start - done) / PAGE_SIZE,
+                        done: handled(done),
+                    });
+                    done = start;
+                }
+                while done < end {
+                    let len = std::cmp::min(chunk_size as u64, end - done);
+                    steps.push(UploadStep::Send {
+                        read: ChunkRead {
+                            shard,
+                            offset: done,
+                            len: len as usize,
+                        },
+                        first_page: image_page(done),
+                        done: handled(done),
+                    });
+                    done += len;
+                }
+            }
+            if done < size {
+                steps.push(UploadStep::Skip {
+                    first_page: image_page(done),
+                    pages: (size - done) / PAGE_SIZE,
+                    done: handled(done),
+                });
+            }
+            before += window.size;
+        }
+    }
+    Ok(steps)
+}
+
+/// How the memory file was read, and how fast.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReadStats {
+    /// Whether it was read with `O_DIRECT`.
+    pub direct: bool,
+    pub bytes: u64,
+    /// Time spent in reads, not waiting for the sends.
+    pub time: Duration,
+}
+
+impl ReadStats {
+    /// Stats of reads running side by side, over connections of their own.
+    pub fn merge(self, other: ReadStats) -> ReadStats {
+        ReadStats {
+            direct: self.direct && other.direct,
+            bytes: self.bytes + other.bytes,
+            time: std::cmp::max(self.time, other.time),
+        }
+    }
+
+    /// Stats of reads running one after another.
+    pub fn followed_by(self, other: ReadStats) -> ReadStats {
+        ReadStats {
+            direct: self.direct && other.direct,
+            bytes: self.bytes + other.bytes,
+            time: self.time + other.time,
+        }
+    }
+
+    fn add(&mut self, bytes: usize, time: Duration) {
+        self.bytes += bytes as u64;
+        self.time += time;
+    }
+}
+
+/// Reads the chunks of an upload, in plan order.
+pub enum ChunkReader<'a> {
+    /// Through the page cache, as the sender asks for them.
+    Buffered {
+        image: &'a mut MemImage,
+        buf: Vec<u8>,
+        stats: ReadStats,
+    },
+    /// With `O_DIRECT`, a chunk ahead of the sender.
+    Direct(Prefetcher),
+}
+
+impl<'a> ChunkReader<'a> {
+    /// Reads the `Send` steps of `steps` from `image`, with `O_DIRECT` if it
+    /// was opened that way.
+    pub fn new(
+        image: &'a mut MemImage,
+        steps: &[UploadStep],
+        chunk_size: usize,
+    ) -> io::Result<Self> {
+        if image.is_direct() {
+            let reads = steps
+                .iter()
+                .filter_map(|step| match *step {
+                    UploadStep::Send { read, .. } => Some(read),
+                    UploadStep::Skip { .. } => None,
+                })
+                .collect();
+            return Ok(ChunkReader::Direct(Prefetcher::start(
+                image.try_clone()?,
+                reads,
+                chunk_size,
+            )));
+        }
+        Ok(ChunkReader::Buffered {
+            image,
+            buf: vec![0u8; chunk_size],
+            stats: ReadStats::default(),
+        })
+    }
+
+    /// The bytes of `read`, which must be the next read of the plan.
+    pub fn read(&mut self, read: &ChunkRead) -> io::Result<&[u8]> {
+        match self {
+            ChunkReader::Buffered { image, buf, stats } => {
+                let start = Instant::now();
+                let reader = image.shard(read.shard);
+                reader.seek(SeekFrom::Start(read.offset))?;
+                let filled = zero_pages::read_full(reader, &mut buf[..read.len])?;
+                if filled != read.len {
+                    return Err(short_read(read, filled));
+                }
+                stats.add(read.len, start.elapsed());
+                Ok(&buf[..read.len])
+            }
+            ChunkReader::Direct(prefetcher) => prefetcher.next(),
+        }
+    }
+
+    /// Drops `read`, once sent, from the page cache. Direct reads never
+    /// filled it.
+    pub fn drop_behind(&mut self, read: &ChunkRead) -> io::Result<()> {
+        match self {
+            ChunkReader::Buffered { image, .. } => {
+                page_cache::drop_range(image.shard(read.shard), read.offset, read.len as u64)
+            }
+            ChunkReader::Direct(_) => Ok(()),
+        }
+    }
+
+    pub fn stats(&self) -> ReadStats {
+        match self {
+            ChunkReader::Buffered { stats, .. } => *stats,
+            ChunkReader::Direct(prefetcher) => prefetcher.stats,
+        }
+    }
+}
+
+fn short_read(read: &ChunkRead, filled: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!(
+            "expected to read {} bytes at offset 0x{:x} but the memory file ended after {} bytes",
+            read.len,
+            read.offset,
+            read.offset + filled as u64
+        ),
+    )
+}
+
+/// A chunk read by the prefetch thread: its buffer, length and read time.
+type Filled = io::Result<(AlignedBuf, usize, Duration)>;
+
+/// Reads chunks on a thread of its own, into buffers handed back once the
+/// chunk before has been sent.
+pub struct Prefetcher {
+    filled: mpsc::Receiver<Filled>,
+    /// Dropped to stop the thread.
+    free: Option<mpsc::Sender<AlignedBuf>>,
+    /// The chunk last returned by `next`, and its length.
+    current: Option<(AlignedBuf, usize)>,
+    thread: Option<thread::JoinHandle<()>>,
+    stats: ReadStats,
+}
+
+impl Prefetcher {
+    /// Starts reading `reads` of `image`, in order, into buffers of
+    /// `chunk_size` bytes.
+    pub fn start(mut image: MemImage, reads: Vec<ChunkRead>, chunk_size: usize) -> Self {
+        let (free, buffers) = mpsc::channel();
+        for _ in 0..PIPELINE_BUFFERS {
+            let _ = free.send(AlignedBuf::new(chunk_size));
+        }
+        // Never full: only as many chunks as buffers are ever in flight.
+        let (filled_tx, filled) = mpsc::sync_channel(PIPELINE_BUFFERS);
+        let thread = thread::spawn(move || {
+            for read in reads {
+                let mut buf = match buffers.recv() {
+                    Ok(buf) => buf,
+                    Err(_) => return,
+                };
+                let start = Instant::now();
+                let result = read_exact_at(image.shard(read.shard), &mut buf[..read.len], &read)
+                    .map(|_| (buf, read.len, start.elapsed()));
+                let failed = result.is_err();
+                if filled_tx.send(result).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Prefetcher {
+            filled,
+            free: Some(free),
+            current: None,
+            thread: Some(thread),
+            stats: ReadStats {
+                direct: true,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Waits for the next chunk, handing the buffer of the one before back
+    /// to the thread.
+    pub fn next(&mut self) -> io::Result<&[u8]> {
+        if let (Some((buf, _)), Some(free)) = (self.current.take(), self.free.as_ref()) {
+            let _ = free.send(buf);
+        }
+        let (buf, len, time) = self.filled.recv().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "the memory file reader stopped before the upload ended",
+            )
+        })??;
+        self.stats.add(len, time);
+        let (buf, len) = self.current.get_or_insert((buf, len));
+        Ok(&buf[..*len])
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        // Without free buffers, the thread stops after its current read.
+        self.free.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn read_exact_at(file: &File, buf: &mut [u8], read: &ChunkRead) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read_at(&mut buf[filled..], read.offset + filled as u64) {
+            Ok(0) => return Err(short_read(read, filled)),
+            Ok(bytes) => filled += bytes,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Whether `file`, opened with `O_DIRECT`, can really be read that way:
+/// some filesystems accept the flag and only fail the reads.
+pub fn reads_direct(file: &File) -> io::Result<bool> {
+    let mut buf = AlignedBuf::new(DIRECT_ALIGN);
+    match file.read_at(&mut buf, 0) {
+        Ok(_) => Ok(true),
+        Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// A zeroed heap buffer aligned to `DIRECT_ALIGN`.
+pub struct AlignedBuf {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// Safe because the buffer is owned and only reached through `&self` or
+// `&mut self`.
+unsafe impl Send for AlignedBuf {}
+
+impl AlignedBuf {
+    pub fn new(len: usize) -> Self {
+        let layout = Self::layout(len);
+        // Safe because the layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(std::cmp::max(len, 1), DIRECT_ALIGN).expect("Invalid buffer layout")
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safe because `ptr` holds `len` initialized bytes.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safe because `ptr` holds `len` initialized bytes, borrowed once.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // Safe because `ptr` was allocated with this layout.
+        unsafe { alloc::dealloc(self.ptr, Self::layout(self.len)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem_files::MemFiles;
+    use crate::test_files;
+    use vmm::pseudo_mm_support::PageSize;
+
+    fn mem_file(name: &str, pages: u64) -> String {
+        let contents: Vec<u8> = (0..pages * PAGE_SIZE).map(|at| (at % 251) as u8).collect();
+        let path = test_files::file(&format!("reader_{}", name), &contents);
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_aligned_buf() {
+        let mut buf = AlignedBuf::new(3 * DIRECT_ALIGN);
+        assert_eq!(buf.as_ptr() as usize % DIRECT_ALIGN, 0);
+        assert!(buf.iter().all(|&b| b == 0));
+        buf[DIRECT_ALIGN] = 7;
+        assert_eq!(buf.len(), 3 * DIRECT_ALIGN);
+        assert_eq!(buf[DIRECT_ALIGN], 7);
+    }
+
+    #[test]
+    fn test_upload_steps_in_chunks() {
+        let path = mem_file("steps", 5);
+        let mut image = MemFiles::new(vec![path.clone()])
+            .open(PageSize::Base)
+            .unwrap();
+        let windows = [ImageWindow {
+            file_offset: PAGE_SIZE,
+            size: 4 * PAGE_SIZE,
+            image_offset: 0,
+        }];
+        let steps = upload_steps(&mut image, &windows, PAGE_SIZE, 3 * DIRECT_ALIGN).unwrap();
+        let send = |offset: u64, len: u64, done: u64| UploadStep::Send {
+            read: ChunkRead {
+                shard: 0,
+                offset,
+                len: len as usize,
+            },
+            first_page: done / PAGE_SIZE,
+            done,
+        };
+        // A file without holes is read whole, in chunks.
+        assert_eq!(
+            steps,
+            vec![
+                send(PAGE_SIZE, 3 * PAGE_SIZE, 0),
+                send(4 * PAGE_SIZE, PAGE_SIZE, 3 * PAGE_SIZE),
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_prefetcher_reads_in_order() {
+        let path = mem_file("prefetch", 6);
+        let contents = std::fs::read(&path).unwrap();
+        let image = MemFiles::new(vec![path.clone()])
+            .open(PageSize::Base)
+            .unwrap();
+        let read = |page: u64, pages: u64| ChunkRead {
+            shard: 0,
+            offset: page * PAGE_SIZE,
+            len: (pages * PAGE_SIZE) as usize,
+        };
+        let reads = vec![read(4, 2), read(0, 1), read(1, 3), read(5, 2)];
+        let mut prefetcher = Prefetcher::start(image, reads.clone(), 3 * PAGE_SIZE as usize);
+        for read in &reads[..3] {
+            let start = read.offset as usize;
+            assert_eq!(
+                prefetcher.next().unwrap(),
+                &contents[start..start + read.len]
+            );
+        }
+        // Past the end of the file.
+        let err = prefetcher.next().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(prefetcher.stats.bytes, 6 * PAGE_SIZE);
+        assert!(prefetcher.stats.direct);
+        drop(prefetcher);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+//! Per-tenant pgoff namespaces.
+//!
+//! A shared memory server partitions its page space into windows, one per
+//! tenant. The mapping file is a JSON object keyed by namespace name:
+//!
+//! ```json
+//! {
+//!   "tenant-a": { "base_pgoff": 0, "pages": 1048576 },
+//!   "tenant-b": { "base_pgoff": 1048576, "pages": 524288 }
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+
+use serde::Deserialize;
+
+/// One namespace window as described in the mapping file.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NamespaceWindow {
+    /// First page offset owned by the namespace.
+    pub base_pgoff: u64,
+    /// Number of pages owned by the namespace.
+    pub pages: u64,
+}
+
+/// A resolved namespace the creator allocates pgoffs from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PgoffNamespace {
+    pub name: String,
+    pub base_pgoff: u64,
+    pub pages: u64,
+}
+
+impl PgoffNamespace {
+    /// First page offset past the end of the namespace window.
+    pub fn end_pgoff(&self) -> u64 {
+        // Overflow is rejected when the mapping is loaded.
+        self.base_pgoff + self.pages
+    }
+
+    /// Pages left in the window starting at `pgoff`.
+    pub fn remaining_from(&self, pgoff: u64) -> u64 {
+        if pgoff >= self.end_pgoff() {
+            0
+        } else {
+            self.end_pgoff() - std::cmp::max(pgoff, self.base_pgoff)
+        }
+    }
+
+    /// Checks that `[pgoff, pgoff + pages)` lies entirely inside the window.
+    pub fn check_range(&self, pgoff: u64, pages: u64) -> io::Result<()> {
+        if pgoff < self.base_pgoff {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "rdma_pgoff {} is below namespace '{}' window [{}, {})",
+                    pgoff,
+                    self.name,
+                    self.base_pgoff,
+                    self.end_pgoff()
+                ),
+            ));
+        }
+        let fits = pgoff
+            .checked_add(pages)
+            .map_or(false, |end| end <= self.end_pgoff());
+        if !fits {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "rdma_pgoff range [{}, +{}) exceeds namespace '{}' window [{}, {}): {} pages remaining",
+                    pgoff,
+                    pages,
+                    self.name,
+                    self.base_pgoff,
+                    self.end_pgoff(),
+                    self.remaining_from(pgoff)
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Loads the mapping file at `path` and resolves the namespace `name`.
+pub fn load_namespace(
+    path: &str,
+    name: &str,
+) -> Result<PgoffNamespace, Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    let mapping: HashMap<String, NamespaceWindow> = serde_json::from_reader(file)?;
+    let namespace = resolve_namespace(&mapping, name)?;
+    Ok(namespace)
+}
+
+/// Validates the whole mapping and resolves the namespace `name` from it.
+///
+/// Windows of different namespaces must not overlap: the mapping is shared by
+/// every creator host, so a bad entry would let one tenant allocate pages
+/// owned by another.
+pub fn resolve_namespace(
+    mapping: &HashMap<String, NamespaceWindow>,
+    name: &str,
+) -> io::Result<PgoffNamespace> {
+    let mut windows: Vec<(&String, &NamespaceWindow)> = mapping.iter().collect();
+    windows.sort_by_key(|(_, window)| window.base_pgoff);
+
+    for (ns_name, window) in &windows {
+        if window.pages == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("namespace '{}' has an empty window", ns_name),
+            ));
+        }
+        if window.base_pgoff.checked_add(window.pages).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("namespace '{}' window overflows the pgoff space", ns_name),
+            ));
+        }
+    }
+    for pair in windows.windows(2) {
+        let (prev_name, prev) = pair[0];
+        let (next_name, next) = pair[1];
+        if prev.base_pgoff + prev.pages > next.base_pgoff {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "namespace windows '{}' [{}, {}) and '{}' [{}, {}) overlap",
+                    prev_name,
+                    prev.base_pgoff,
+                    prev.base_pgoff + prev.pages,
+                    next_name,
+                    next.base_pgoff,
+                    next.base_pgoff + next.pages
+                ),
+            ));
+        }
+    }
+
+    let window = mapping.get(name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown pgoff namespace '{}'", name),
+        )
+    })?;
+    Ok(PgoffNamespace {
+        name: name.to_string(),
+        base_pgoff: window.base_pgoff,
+        pages: window.pages,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(base_pgoff: u64, pages: u64) -> NamespaceWindow {
+        NamespaceWindow { base_pgoff, pages }
+    }
+
+    fn namespace(base_pgoff: u64, pages: u64) -> PgoffNamespace {
+        PgoffNamespace {
+            name: "tenant".to_string(),
+            base_pgoff,
+            pages,
+        }
+    }
+
+    #[test]
+    fn test_check_range_inside_window() {
+        let ns = namespace(100, 50);
+        assert!(ns.check_range(100, 50).is_ok());
+        assert!(ns.check_range(120, 30).is_ok());
+        assert!(ns.check_range(149, 1).is_ok());
+        assert!(ns.check_range(150, 0).is_ok());
+    }
+
+    #[test]
+    fn test_check_range_outside_window() {
+        let ns = namespace(100, 50);
+        // Below the window.
+        assert!(ns.check_range(99, 1).is_err());
+        assert!(ns.check_range(0, 10).is_err());
+        // Straddles the end.
+        assert!(ns.check_range(149, 2).is_err());
+        assert!(ns.check_range(100, 51).is_err());
+        // Past the end.
+        assert!(ns.check_range(150, 1).is_err());
+        // Wrapping arithmetic must not sneak back into the window.
+        assert!(ns.check_range(120, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_exceeding_reports_remaining() {
+        let ns = namespace(100, 50);
+        let err = ns.check_range(130, 40).unwrap_err().to_string();
+        assert!(err.contains("tenant"), "{}", err);
+        assert!(err.contains("20 pages remaining"), "{}", err);
+
+        assert_eq!(ns.remaining_from(0), 50);
+        assert_eq!(ns.remaining_from(100), 50);
+        assert_eq!(ns.remaining_from(149), 1);
+        assert_eq!(ns.remaining_from(150), 0);
+        assert_eq!(ns.remaining_from(u64::MAX), 0);
+    }
+
+    #[test]
+    fn test_sequential_allocation_stays_in_window() {
+        // Mirrors run_batch: auto-assigned entries start at the window base
+        // and advance by their page counts until the window is exhausted.
+        let ns = namespace(1000, 100);
+        let mut next = ns.base_pgoff;
+        for pages in &[40, 40, 20] {
+            assert!(ns.check_range(next, *pages).is_ok());
+            next += pages;
+        }
+        assert_eq!(next, ns.end_pgoff());
+        assert!(ns.check_range(next, 1).is_err());
+    }
+
+    #[test]
+    fn test_resolve_namespace() {
+        let mut mapping = HashMap::new();
+        mapping.insert("a".to_string(), window(0, 100));
+        mapping.insert("b".to_string(), window(100, 100));
+
+        let ns = resolve_namespace(&mapping, "b").unwrap();
+        assert_eq!(ns.name, "b");
+        assert_eq!(ns.base_pgoff, 100);
+        assert_eq!(ns.end_pgoff(), 200);
+
+        let err = resolve_namespace(&mapping, "c").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_resolve_rejects_bad_mappings() {
+        let mut overlapping = HashMap::new();
+        overlapping.insert("a".to_string(), window(0, 101));
+        overlapping.insert("b".to_string(), window(100, 100));
+        let err = resolve_namespace(&overlapping, "a")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("overlap"), "{}", err);
+
+        let mut empty = HashMap::new();
+        empty.insert("a".to_string(), window(0, 0));
+        assert!(resolve_namespace(&empty, "a").is_err());
+
+        let mut overflowing = HashMap::new();
+        overflowing.insert("a".to_string(), window(u64::MAX, 2));
+        assert!(resolve_namespace(&overflowing, "a").is_err());
+    }
+
+    #[test]
+    fn test_mapping_file_format() {
+        let json = r#"{
+            "tenant-a": { "base_pgoff": 0, "pages": 1048576 },
+            "tenant-b": { "base_pgoff": 1048576, "pages": 524288 }
+        }"#;
+        let mapping: HashMap<String, NamespaceWindow> = serde_json::from_str(json).unwrap();
+        let ns = resolve_namespace(&mapping, "tenant-b").unwrap();
+        assert_eq!(ns.base_pgoff, 1048576);
+        assert_eq!(ns.pages, 524288);
+    }
+}
@@ -0,0 +1,307 @@
+//! Server occupancy state for offline pgoff planning.
+//!
+//! An occupancy file is a consolidated view of which RDMA page ranges are
+//! taken, gathered from the templates found on a creator host:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "generated_at": 1700000000,
+//!   "ranges": [
+//!     {
+//!       "start_pgoff": 0,
+//!       "pages": 262144,
+//!       "owner": "/srv/templates/fn-a.json",
+//!       "label": "fn-a",
+//!       "created_at": 1699990000
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `generated_at` and `created_at` are seconds since the Unix epoch; the
+//! latter is the template file's modification time. Ranges are sorted by
+//! `start_pgoff`. Files with a `version` newer than `OCCUPANCY_VERSION` are
+//! rejected.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use vmm::pseudo_mm_support::{self, MemBackend, PseudoMmTemplate};
+
+/// Current occupancy file format version.
+pub const OCCUPANCY_VERSION: u32 = 1;
+
+/// Consolidated occupancy of one memory server.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Occupancy {
+    pub version: u32,
+    pub generated_at: u64,
+    pub ranges: Vec<OccupiedRange>,
+}
+
+/// A page range owned by one template.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OccupiedRange {
+    pub start_pgoff: u64,
+    pub pages: u64,
+    /// Path of the template that owns the range.
+    pub owner: String,
+    pub label: String,
+    pub created_at: u64,
+}
+
+impl OccupiedRange {
+    /// Builds the ranges covered by `template`'s uploaded image, one for
+    /// each extent it is split across, or one for the image and its guard
+    /// pages.
+    pub fn from_template(
+        template: &PseudoMmTemplate,
+        owner: &str,
+        label: &str,
+        created_at: u64,
+    ) -> Vec<Self> {
+        let extents = match template.rdma_guarded_range {
+            Some(guarded) => vec![guarded],
+            None => template.image_extents(),
+        };
+        extents
+            .into_iter()
+            .map(|extent| OccupiedRange {
+                start_pgoff: extent.pgoff.raw(),
+                pages: extent.pages,
+                owner: owner.to_string(),
+                label: label.to_string(),
+                created_at,
+            })
+            .collect()
+    }
+
+    pub fn end_pgoff(&self) -> u64 {
+        self.start_pgoff.saturating_add(self.pages)
+    }
+
+    pub fn overlaps(&self, other: &OccupiedRange) -> bool {
+        self.pages > 0
+            && other.pages > 0
+            && self.start_pgoff < other.end_pgoff()
+            && other.start_pgoff < self.end_pgoff()
+    }
+}
+
+impl Occupancy {
+    /// Returns the recorded ranges that intersect `proposed`.
+    ///
+    /// A range owned by the same template path as `proposed` is the template's
+    /// own earlier entry and is not a conflict.
+    pub fn conflicts_with(&self, proposed: &OccupiedRange) -> Vec<&OccupiedRange> {
+        self.ranges
+            .iter()
+            .filter(|range| range.owner != proposed.owner && range.overlaps(proposed))
+            .collect()
+    }
+
+    /// Returns the overlapping pairs among the recorded ranges, sorted by
+    /// start as `export_from_dir` leaves them: each range that overlaps an
+    /// earlier one, with the earlier range reaching furthest past it.
+    pub fn overlapping(&self) -> Vec<(&OccupiedRange, &OccupiedRange)> {
+        let mut pairs = Vec::new();
+        let mut furthest: Option<&OccupiedRange> = None;
+        for range in self.ranges.iter().filter(|range| range.pages > 0) {
+            if let Some(earlier) = furthest {
+                if earlier.overlaps(range) {
+                    pairs.push((earlier, range));
+                }
+            }
+            if furthest.map_or(true, |earlier| range.end_pgoff() > earlier.end_pgoff()) {
+                furthest = Some(range);
+            }
+        }
+        pairs
+    }
+}
+
+/// Collects the ranges of every template found under `dir`, recursively.
+///
+/// Files that are not JSON templates are skipped with a note.
+pub fn export_from_dir(dir: &Path) -> Result<Occupancy, Box<dyn std::error::Error>> {
+    let mut ranges = Vec::new();
+    collect_ranges(dir, &mut ranges)?;
+    ranges.sort_by(|a, b| {
+        a.start_pgoff
+            .cmp(&b.start_pgoff)
+            .then_with(|| a.owner.cmp(&b.owner))
+    });
+
+    Ok(Occupancy {
+        version: OCCUPANCY_VERSION,
+        generated_at: unix_secs(SystemTime::now()),
+        ranges,
+    })
+}
+
+fn collect_ranges(
+    dir: &Path,
+    ranges: &mut Vec<OccupiedRange>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.path());
+
+    for entry in entries {
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_ranges(&path, ranges)?;
+            continue;
+        }
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+
+        let template = match pseudo_mm_support::load_template_file(&path) {
+            Ok(template) => template,
+            Err(err) => {
+                println!("  skipping {}: {}", path.display(), err);
+                continue;
+            }
+        };
+
+        if template.mem_backend != MemBackend::Rdma {
+            // Its pages are on a local device, not the memory server.
+            println!(
+                "  skipping {}: {} template",
+                path.display(),
+                template.mem_backend
+            );
+            continue;
+        }
+
+        let created_at = metadata.modified().map(unix_secs).unwrap_or(0);
+        ranges.extend(OccupiedRange::from_template(
+            &template,
+            &owner_for(&path),
+            &label_for(&path),
+            created_at,
+        ));
+    }
+    Ok(())
+}
+
+/// Loads an occupancy file, refusing versions this build doesn't understand.
+pub fn load(path: &str) -> Result<Occupancy, Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    let occupancy: Occupancy = serde_json::from_reader(file)?;
+    if occupancy.version > OCCUPANCY_VERSION {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "occupancy file version {} is newer than supported version {}",
+                occupancy.version, OCCUPANCY_VERSION
+            ),
+        )));
+    }
+    Ok(occupancy)
+}
+
+/// Canonical owner string for a template path, so the same file is recognised
+/// whether it was named relatively or absolutely.
+pub fn owner_for(path: &Path) -> String {
+    std::fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Derives a human label from a template path (its file stem).
+pub fn label_for(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string_lossy().into_owned())
+}
+
+/// Seconds since the Unix epoch, or 0 for earlier times.
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start_pgoff: u64, pages: u64, owner: &str) -> OccupiedRange {
+        OccupiedRange {
+            start_pgoff,
+            pages,
+            owner: owner.to_string(),
+            label: owner.to_string(),
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let a = range(100, 50, "a");
+        assert!(a.overlaps(&range(100, 50, "b")));
+        assert!(a.overlaps(&range(149, 1, "b")));
+        assert!(a.overlaps(&range(0, 101, "b")));
+        assert!(a.overlaps(&range(120, 1, "b")));
+        // Touching ranges are adjacent, not overlapping.
+        assert!(!a.overlaps(&range(150, 10, "b")));
+        assert!(!a.overlaps(&range(0, 100, "b")));
+        // Empty ranges occupy nothing.
+        assert!(!a.overlaps(&range(120, 0, "b")));
+    }
+
+    #[test]
+    fn test_conflicts_with() {
+        let occupancy = Occupancy {
+            version: OCCUPANCY_VERSION,
+            generated_at: 0,
+            ranges: vec![
+                range(0, 100, "a"),
+                range(100, 100, "b"),
+                range(300, 10, "c"),
+            ],
+        };
+
+        let conflicts = occupancy.conflicts_with(&range(50, 100, "new"));
+        let owners: Vec<&str> = conflicts.iter().map(|r| r.owner.as_str()).collect();
+        assert_eq!(owners, vec!["a", "b"]);
+
+        assert!(occupancy.conflicts_with(&range(200, 100, "new")).is_empty());
+        // A template re-checked against its own recorded range is fine.
+        assert!(occupancy.conflicts_with(&range(300, 10, "c")).is_empty());
+    }
+
+    #[test]
+    fn test_overlapping() {
+        // `a` overlaps `c` past `b`, which lies inside it.
+        let occupancy = Occupancy {
+            version: OCCUPANCY_VERSION,
+            generated_at: 0,
+            ranges: vec![
+                range(0, 100, "a"),
+                range(10, 10, "b"),
+                range(50, 10, "c"),
+                range(100, 10, "d"),
+            ],
+        };
+        let owners: Vec<(&str, &str)> = occupancy
+            .overlapping()
+            .into_iter()
+            .map(|(earlier, later)| (earlier.owner.as_str(), later.owner.as_str()))
+            .collect();
+        assert_eq!(owners, vec![("a", "b"), ("a", "c")]);
+    }
+
+    #[test]
+    fn test_label_for() {
+        assert_eq!(label_for(Path::new("/srv/t/fn-a.json")), "fn-a");
+        assert_eq!(label_for(Path::new("fn-b")), "fn-b");
+    }
+}
@@ -0,0 +1,277 @@
+//! Advisory locking and atomic replacement of output files.
+//!
+//! A writer takes an exclusive `flock` on a sidecar `<path>.lock` file and
+//! records its pid there, so a contending writer can report who holds the
+//! output. Contents are written to a temporary file in the same directory and
+//! renamed over the target, so readers never observe a partial file. The file
+//! and then the directory are synced, so the rename survives a crash.
+
+use std::error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Delay between lock attempts while waiting.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Exclusive hold on an output path, released on drop.
+pub struct OutputLock {
+    path: PathBuf,
+    // Keeps the flock alive; closing the file releases it.
+    lock_file: File,
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+fn try_flock(file: &File) -> io::Result<bool> {
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// Reads the holder's pid from the lock file.
+///
+/// The holder writes its pid right after taking the lock, so an empty file is
+/// re-read briefly before giving up.
+fn read_holder(lock_file: &mut File) -> io::Result<String> {
+    for _ in 0..10 {
+        let mut holder = String::new();
+        lock_file.seek(SeekFrom::Start(0))?;
+        lock_file.read_to_string(&mut holder)?;
+        let holder = holder.trim();
+        if !holder.is_empty() {
+            return Ok(holder.to_string());
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    Ok("unknown".to_string())
+}
+
+impl OutputLock {
+    /// Locks `path` for writing, waiting up to `wait` for a current holder.
+    ///
+    /// Fails with "output locked by pid N" if the lock is still held when
+    /// `wait` runs out.
+    pub fn acquire(path: &Path, wait: Duration) -> io::Result<Self> {
+        let lock_path = lock_path(path);
+        let mut lock_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&lock_path)?;
+
+        let deadline = Instant::now() + wait;
+        while !try_flock(&lock_file)? {
+            if Instant::now() >= deadline {
+                let holder = read_holder(&mut lock_file)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("output {} locked by pid {}", path.display(), holder),
+                ));
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+
+        lock_file.set_len(0)?;
+        lock_file.seek(SeekFrom::Start(0))?;
+        write!(lock_file, "{}", std::process::id())?;
+        lock_file.sync_all()?;
+
+        Ok(OutputLock {
+            path: path.to_path_buf(),
+            lock_file,
+        })
+    }
+
+    /// Atomically replaces the locked path with `contents`.
+    pub fn write(&self, contents: &[u8]) -> io::Result<()> {
+        let mut tmp_name = self.path.as_os_str().to_owned();
+        tmp_name.push(format!(".tmp.{}", std::process::id()));
+        let tmp_path = PathBuf::from(tmp_name);
+
+        let result = File::create(&tmp_path)
+            .and_then(|mut tmp| {
+                tmp.write_all(contents)?;
+                tmp.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp_path, &self.path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        result.and_then(|_| sync_parent(&self.path))
+    }
+}
+
+/// Syncs the directory holding `path`, making a rename into it durable.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Error for an output that exists and is kept, see `check_overwrite`.
+#[derive(Debug)]
+pub struct OutputExists(PathBuf);
+
+impl fmt::Display for OutputExists {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "output {} already exists; pass --force to overwrite it",
+            self.0.display()
+        )
+    }
+}
+
+impl error::Error for OutputExists {}
+
+/// Fails with an `OutputExists` error if something is at `path`, unless
+/// `force`.
+///
+/// A template may still be referenced by a VM, so replacing one has to be
+/// asked for. Check while holding the path's `OutputLock`, so no other
+/// writer creates it in between.
+pub fn check_overwrite(path: &Path, force: bool) -> io::Result<()> {
+    if force || std::fs::symlink_metadata(path).is_err() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        OutputExists(path.to_path_buf()),
+    ))
+}
+
+/// Whether `err` is, or wraps, an `OutputExists` error.
+pub fn is_output_exists(err: &io::Error) -> bool {
+    err.get_ref()
+        .map_or(false, |inner| inner.is::<OutputExists>())
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        // Clear the pid so a stale lock file doesn't name a finished writer.
+        let _ = self.lock_file.set_len(0);
+    }
+}
+
+/// Locks `path`, writes `contents` atomically and releases the lock.
+pub fn write_locked(path: &Path, contents: &[u8], wait: Duration) -> io::Result<()> {
+    OutputLock::acquire(path, wait)?.write(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+    use std::sync::{Arc, Barrier};
+
+    #[test]
+    fn test_write_replaces_atomically() {
+        let dir = test_files::dir("output_lock_write");
+        let path = dir.join("template.json");
+        std::fs::write(&path, b"old contents that are longer").unwrap();
+
+        write_locked(&path, b"new", Duration::from_secs(0)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+
+        // Only the target and its lock file remain.
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["template.json", "template.json.lock"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_overwrite() {
+        let dir = test_files::dir("output_lock_overwrite");
+        let path = dir.join("template.json");
+        check_overwrite(&path, false).unwrap();
+
+        std::fs::write(&path, b"referenced").unwrap();
+        let err = check_overwrite(&path, false).unwrap_err();
+        assert!(is_output_exists(&err));
+        assert!(err.to_string().contains("pass --force"), "{}", err);
+        check_overwrite(&path, true).unwrap();
+        assert!(!is_output_exists(&io::Error::from(
+            io::ErrorKind::AlreadyExists
+        )));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_competing_writers() {
+        let dir = test_files::dir("output_lock_compete");
+        let path = dir.join("template.json");
+        let writers = 4;
+        let barrier = Arc::new(Barrier::new(writers));
+
+        let handles: Vec<_> = (0..writers)
+            .map(|idx| {
+                let path = path.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let result = OutputLock::acquire(&path, Duration::from_secs(0));
+                    if let Ok(ref lock) = result {
+                        lock.write(format!("writer {}", idx).as_bytes()).unwrap();
+                    }
+                    // Hold the winner's lock until every writer has tried.
+                    barrier.wait();
+                    result.map(|_| idx).map_err(|err| err.to_string())
+                })
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        let winners: Vec<usize> = results.iter().filter_map(|r| r.clone().ok()).collect();
+        assert_eq!(winners.len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("writer {}", winners[0])
+        );
+
+        let expected = format!("locked by pid {}", std::process::id());
+        for err in results.iter().filter_map(|r| r.clone().err()) {
+            assert!(err.contains(&expected), "{}", err);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_release() {
+        let dir = test_files::dir("output_lock_wait");
+        let path = dir.join("template.json");
+
+        let lock = OutputLock::acquire(&path, Duration::from_secs(0)).unwrap();
+        let waiter = {
+            let path = path.clone();
+            thread::spawn(move || write_locked(&path, b"second", Duration::from_secs(10)))
+        };
+        thread::sleep(Duration::from_millis(100));
+        lock.write(b"first").unwrap();
+        drop(lock);
+
+        waiter.join().unwrap().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
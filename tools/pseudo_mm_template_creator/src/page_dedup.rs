@@ -0,0 +1,380 @@
+//! Page deduplication across the entries of a batch.
+//!
+//! Snapshots in one batch often run on the same runtime and hold many of
+//! the same pages, yet each entry uploads its whole image. With `--dedup`,
+//! every populated page of an RDMA entry is hashed and looked up among the
+//! pages uploaded so far in the run, its own included; a page already
+//! uploaded is mapped from that pgoff through the regions' `extents`
+//! instead. Only the remaining pages are uploaded, packed in file order into
+//! the entry's image at `rdma_pgoff`, as for a layered entry.
+//!
+//! `--dedup-hash fast`, the default, keys pages by a 64-bit hash and
+//! compares a match byte for byte with the page it was uploaded from, so a
+//! collision only costs a read. `sha256` trusts the digest and keeps no
+//! memory files open.
+//!
+//! An entry's pages are offered to later entries once its template is
+//! written, so no template maps a page whose upload failed; entries running
+//! at the same time with `--jobs` don't share with each other. Entries still
+//! reserve a range for their whole memory file. The images a template maps
+//! pages from must stay on the server while it is in use; `registry gc`
+//! keeps their ranges.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use vmm::memory_snapshot::GuestMemoryRegionState;
+use vmm::pseudo_mm_addr::PageOffset;
+use vmm::pseudo_mm_support::{PageSize, PAGE_SIZE};
+
+use crate::layered::{self, Layers, SharedPages};
+use crate::mem_files::{MemFiles, MemImage};
+use crate::page_hash;
+use crate::sha256::sha256;
+
+/// How pages are told apart, see `--dedup-hash`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupHash {
+    Fast,
+    Sha256,
+}
+
+impl FromStr for DedupHash {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "fast" => Ok(DedupHash::Fast),
+            "sha256" => Ok(DedupHash::Sha256),
+            _ => Err(format!(
+                "invalid dedup hash '{}': expected fast or sha256",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DedupHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DedupHash::Fast => "fast",
+            DedupHash::Sha256 => "sha256",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum PageKey {
+    Fast(u64),
+    Sha256([u8; 32]),
+}
+
+impl DedupHash {
+    fn key(self, page: &[u8]) -> PageKey {
+        match self {
+            DedupHash::Fast => PageKey::Fast(page_hash::page_hash(page)),
+            DedupHash::Sha256 => PageKey::Sha256(sha256(page)),
+        }
+    }
+}
+
+/// Where a page was uploaded from and to.
+#[derive(Debug, Clone, Copy)]
+struct UploadedPage {
+    pgoff: u64,
+    file_offset: u64,
+}
+
+/// Pages uploaded so far in a batch.
+pub struct PageStore {
+    hash: DedupHash,
+    inner: Mutex<Pages>,
+}
+
+struct Pages {
+    /// Keyed by page size and contents, with the index of the memory file
+    /// in `images` the page was read from.
+    pages: HashMap<(u64, PageKey), (usize, UploadedPage)>,
+    /// Memory files of the entries added, to compare `Fast` matches with.
+    images: Vec<MemImage>,
+}
+
+impl PageStore {
+    pub fn new(hash: DedupHash) -> Self {
+        PageStore {
+            hash,
+            inner: Mutex::new(Pages {
+                pages: HashMap::new(),
+                images: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn hash(&self) -> DedupHash {
+        self.hash
+    }
+
+    /// Offers the pages `entry` uploaded to the entries planned after it.
+    pub fn add(&self, entry: EntryPages) {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let image = inner.images.len();
+        if let Some(mem) = entry.image {
+            inner.images.push(mem);
+        }
+        for (key, page) in entry.pages {
+            inner.pages.entry(key).or_insert((image, page));
+        }
+    }
+}
+
+/// Pages an entry uploads, for `PageStore::add` once its template is
+/// written.
+pub struct EntryPages {
+    /// The entry's memory file, kept for `Fast` comparisons.
+    image: Option<MemImage>,
+    pages: HashMap<(u64, PageKey), UploadedPage>,
+}
+
+/// Page counts of a deduplicated entry, in 4 KiB pages.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct DedupStats {
+    /// Pages uploaded as the entry's image.
+    pub unique_pages: u64,
+    /// Pages mapped from earlier uploads of the same contents.
+    pub shared_pages: u64,
+}
+
+/// Dedup totals of a batch's entries with a template.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DedupSummary {
+    pub hash: DedupHash,
+    /// Populated pages of the entries, every one mapped from some image.
+    pub pages: u64,
+    pub unique_pages: u64,
+    pub shared_pages: u64,
+    /// `pages` per page uploaded.
+    pub ratio: f64,
+}
+
+impl DedupSummary {
+    pub fn new<'a, I>(hash: DedupHash, entries: I) -> Self
+    where
+        I: IntoIterator<Item = &'a DedupStats>,
+    {
+        let (unique_pages, shared_pages) =
+            entries.into_iter().fold((0, 0), |(unique, shared), stats| {
+                (unique + stats.unique_pages, shared + stats.shared_pages)
+            });
+        let pages = unique_pages + shared_pages;
+        DedupSummary {
+            hash,
+            pages,
+            unique_pages,
+            shared_pages,
+            ratio: if unique_pages > 0 {
+                pages as f64 / unique_pages as f64
+            } else {
+                1.0
+            },
+        }
+    }
+}
+
+/// Looks an entry's pages up in the store, then among those it uploads
+/// itself.
+struct Lookup<'a> {
+    store: &'a PageStore,
+    mem: &'a MemImage,
+    unit: u64,
+    rdma_pgoff: u64,
+    own: HashMap<(u64, PageKey), UploadedPage>,
+    /// Key of the page last passed to `find`.
+    last: Option<PageKey>,
+    buf: Vec<u8>,
+}
+
+/// Whether `page` is what `image` holds at `file_offset`, for a match by
+/// `hash`.
+fn same_page(
+    hash: DedupHash,
+    image: &MemImage,
+    file_offset: u64,
+    page: &[u8],
+    buf: &mut Vec<u8>,
+) -> io::Result<bool> {
+    match hash {
+        DedupHash::Sha256 => Ok(true),
+        DedupHash::Fast => {
+            buf.resize(page.len(), 0);
+            image.read_exact_at(buf, file_offset)?;
+            Ok(&buf[..] == page)
+        }
+    }
+}
+
+impl<'a> SharedPages for Lookup<'a> {
+    fn find(&mut self, _gpa: u64, page: &[u8]) -> io::Result<Option<u64>> {
+        let hash = self.store.hash;
+        let key = hash.key(page);
+        self.last = Some(key);
+        if let Some(own) = self.own.get(&(self.unit, key)).copied() {
+            let same = same_page(hash, self.mem, own.file_offset, page, &mut self.buf)?;
+            return Ok(if same { Some(own.pgoff) } else { None });
+        }
+        let inner = self.store.inner.lock().expect("Poisoned lock");
+        match inner.pages.get(&(self.unit, key)) {
+            Some(&(image, uploaded)) => {
+                let same = match inner.images.get(image) {
+                    Some(mem) => same_page(hash, mem, uploaded.file_offset, page, &mut self.buf)?,
+                    None => hash == DedupHash::Sha256,
+                };
+                Ok(if same { Some(uploaded.pgoff) } else { None })
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn uploaded(&mut self, page: &[u8], file_offset: u64, image_offset: u64) {
+        let hash = self.store.hash;
+        let key = self.last.take().unwrap_or_else(|| hash.key(page));
+        let pgoff = self.rdma_pgoff + image_offset / PAGE_SIZE;
+        self.own
+            .entry((self.unit, key))
+            .or_insert(UploadedPage { pgoff, file_offset });
+    }
+}
+
+/// Finds the pages of an entry with `states`, whose image goes to
+/// `rdma_pgoff`, that `store` or the entry itself already has.
+pub fn share(
+    states: &[GuestMemoryRegionState],
+    mem_files: &MemFiles,
+    page_size: PageSize,
+    rdma_pgoff: PageOffset,
+    store: &PageStore,
+) -> io::Result<(Layers, EntryPages)> {
+    let mem = mem_files.open(page_size)?;
+    let mut lookup = Lookup {
+        store,
+        mem: &mem,
+        unit: page_size.bytes(),
+        rdma_pgoff: rdma_pgoff.raw(),
+        own: HashMap::new(),
+        last: None,
+        buf: Vec::new(),
+    };
+    let layers = layered::diff(states, &mem, page_size, &mut lookup)?;
+    let pages = lookup.own;
+    let image = match store.hash {
+        DedupHash::Fast => Some(mem),
+        DedupHash::Sha256 => None,
+    };
+    Ok((layers, EntryPages { image, pages }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+    use crate::test_templates;
+    use std::fs;
+    use std::path::Path;
+    use vmm::pseudo_mm_support::RegionMetadata;
+
+    fn states(pages: u64) -> Vec<GuestMemoryRegionState> {
+        vec![GuestMemoryRegionState {
+            base_address: 0,
+            size: (pages * PAGE_SIZE) as usize,
+            offset: 0,
+        }]
+    }
+
+    fn region(pages: u64, rdma_offset: u64) -> RegionMetadata {
+        test_templates::region(0, pages, rdma_offset)
+    }
+
+    fn share_file(
+        path: &Path,
+        pages: u64,
+        rdma_pgoff: u64,
+        store: &PageStore,
+    ) -> (Layers, EntryPages) {
+        let files = MemFiles::new(vec![path.to_string_lossy().into_owned()]);
+        share(
+            &states(pages),
+            &files,
+            PageSize::Base,
+            PageOffset(rdma_pgoff),
+            store,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_share_across_entries() {
+        for &hash in &[DedupHash::Fast, DedupHash::Sha256] {
+            let store = PageStore::new(hash);
+            // Page 2 repeats page 0 of the same entry.
+            let first = test_files::page_file("dedup_first", &[1, 2, 1, 0]);
+            let (layers, pages) = share_file(&first, 4, 100, &store);
+            assert_eq!((layers.overlay_pages, layers.shared_pages), (2, 1));
+            let mut regions = vec![region(4, 100)];
+            layered::apply(&mut regions, &layers, PageOffset(100));
+            assert_eq!(
+                regions[0].backing_ranges(),
+                vec![
+                    (0, 2 * PAGE_SIZE, PageOffset(100)),
+                    (2 * PAGE_SIZE, PAGE_SIZE, PageOffset(100)),
+                ]
+            );
+
+            // Not shared until the first entry's template is written.
+            let second = test_files::page_file("dedup_second", &[2, 3]);
+            let (layers, _) = share_file(&second, 2, 200, &store);
+            assert_eq!((layers.overlay_pages, layers.shared_pages), (2, 0));
+
+            store.add(pages);
+            let (layers, _) = share_file(&second, 2, 200, &store);
+            assert_eq!((layers.overlay_pages, layers.shared_pages), (1, 1));
+            assert_eq!(layers.windows.len(), 1);
+            assert_eq!(layers.windows[0].file_offset, PAGE_SIZE);
+            let mut regions = vec![region(2, 200)];
+            layered::apply(&mut regions, &layers, PageOffset(200));
+            assert_eq!(
+                regions[0].backing_ranges(),
+                vec![
+                    (0, PAGE_SIZE, PageOffset(101)),
+                    (PAGE_SIZE, PAGE_SIZE, PageOffset(200)),
+                ]
+            );
+
+            fs::remove_file(first).unwrap();
+            fs::remove_file(second).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_dedup_summary() {
+        let entries = vec![
+            DedupStats {
+                unique_pages: 100,
+                shared_pages: 0,
+            },
+            DedupStats {
+                unique_pages: 25,
+                shared_pages: 75,
+            },
+        ];
+        let summary = DedupSummary::new(DedupHash::Fast, &entries);
+        assert_eq!((summary.pages, summary.unique_pages), (200, 125));
+        assert!((summary.ratio - 1.6).abs() < 1e-9);
+        assert_eq!(DedupSummary::new(DedupHash::Fast, &[]).ratio, 1.0);
+        assert_eq!("sha256".parse::<DedupHash>(), Ok(DedupHash::Sha256));
+        assert!("md5".parse::<DedupHash>().is_err());
+    }
+}
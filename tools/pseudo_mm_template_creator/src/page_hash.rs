@@ -0,0 +1,164 @@
+//! Streaming per-page content hashing of memory files.
+//!
+//! Memory files are read once, front to back, in large chunks; every
+//! `PAGE_SIZE` page is reduced to a 64-bit content hash. A trailing partial
+//! page is hashed as if zero-padded, mirroring how it is mapped.
+
+use std::io::{self, Read};
+
+use vmm::pseudo_mm_support::PAGE_SIZE;
+
+/// Bytes read from the file per `read` call.
+const READ_CHUNK: usize = 1 << 20;
+
+const MIX: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Hashes the contents of one page.
+///
+/// Not cryptographic; collisions between distinct pages are possible but
+/// rare enough for duplication estimates.
+pub fn page_hash(page: &[u8]) -> u64 {
+    let mut hash = MIX ^ page.len() as u64;
+    let mut words = page.chunks_exact(8);
+    for word in &mut words {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(word);
+        hash = (hash ^ u64::from_le_bytes(bytes))
+            .wrapping_mul(MIX)
+            .rotate_left(31);
+    }
+    for &byte in words.remainder() {
+        hash = (hash ^ u64::from(byte)).wrapping_mul(MIX).rotate_left(31);
+    }
+    // splitmix64 finalizer so nearby pages spread across the hash space.
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Reads `reader` to the end, calling `f(page_index, hash)` for every page.
+///
+/// Returns the number of pages seen.
+pub fn for_each_page_hash<R, F>(reader: &mut R, mut f: F) -> io::Result<u64>
+where
+    R: Read,
+    F: FnMut(u64, u64),
+{
+    let page_size = PAGE_SIZE as usize;
+    let mut buf = vec![0u8; READ_CHUNK];
+    let mut filled = 0;
+    let mut page_index = 0;
+
+    loop {
+        let read = match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        filled += read;
+
+        let whole = filled - filled % page_size;
+        for page in buf[..whole].chunks(page_size) {
+            f(page_index, page_hash(page));
+            page_index += 1;
+        }
+        buf.copy_within(whole..filled, 0);
+        filled -= whole;
+    }
+
+    if filled > 0 {
+        for byte in &mut buf[filled..page_size] {
+            *byte = 0;
+        }
+        f(page_index, page_hash(&buf[..page_size]));
+        page_index += 1;
+    }
+    Ok(page_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Reader that returns at most `step` bytes per call, to exercise pages
+    /// split across reads.
+    struct Trickle {
+        data: Vec<u8>,
+        pos: usize,
+        step: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = std::cmp::min(
+                std::cmp::min(self.step, buf.len()),
+                self.data.len() - self.pos,
+            );
+            buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    fn page(fill: u8) -> Vec<u8> {
+        vec![fill; PAGE_SIZE as usize]
+    }
+
+    #[test]
+    fn test_page_hash_distinguishes_content() {
+        assert_eq!(page_hash(&page(0)), page_hash(&page(0)));
+        assert_ne!(page_hash(&page(0)), page_hash(&page(1)));
+
+        let mut flipped = page(0);
+        flipped[PAGE_SIZE as usize - 1] = 1;
+        assert_ne!(page_hash(&page(0)), page_hash(&flipped));
+    }
+
+    #[test]
+    fn test_for_each_page_hash() {
+        let mut data = page(7);
+        data.extend(page(0));
+        data.extend(page(7));
+
+        let mut hashes = Vec::new();
+        let pages = for_each_page_hash(&mut Cursor::new(&data), |idx, hash| {
+            hashes.push((idx, hash))
+        })
+        .unwrap();
+        assert_eq!(pages, 3);
+        assert_eq!(hashes[0], (0, page_hash(&page(7))));
+        assert_eq!(hashes[1], (1, page_hash(&page(0))));
+        assert_eq!(hashes[2], (2, page_hash(&page(7))));
+
+        let mut trickled = Vec::new();
+        let mut reader = Trickle {
+            data,
+            pos: 0,
+            step: 1000,
+        };
+        for_each_page_hash(&mut reader, |idx, hash| trickled.push((idx, hash))).unwrap();
+        assert_eq!(trickled, hashes);
+    }
+
+    #[test]
+    fn test_partial_page_is_zero_padded() {
+        let mut data = page(3);
+        data.extend(vec![0u8; 100]);
+
+        let mut hashes = Vec::new();
+        let pages =
+            for_each_page_hash(&mut Cursor::new(&data), |_, hash| hashes.push(hash)).unwrap();
+        assert_eq!(pages, 2);
+        assert_eq!(hashes[1], page_hash(&page(0)));
+
+        let empty: &[u8] = &[];
+        assert_eq!(
+            for_each_page_hash(&mut Cursor::new(empty), |_, _| ()).unwrap(),
+            0
+        );
+    }
+}
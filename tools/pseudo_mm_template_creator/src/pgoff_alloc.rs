@@ -0,0 +1,407 @@
+//! Page offset allocation for batch entries.
+//!
+//! Entries without an explicit `rdma_pgoff` are placed right after the
+//! previous entry on the same backend. The range is reserved from the memory
+//! file's size before the upload starts, so entries running in parallel
+//! never write to overlapping ranges. An entry that then fails leaves its
+//! range unused rather than letting a later entry reuse it.
+//!
+//! Explicit pgoffs are taken as given, so they can land on another entry's
+//! range; `overlapping_pairs` finds those before anything is uploaded.
+//!
+//! `--pgoff-align` rounds automatic RDMA ranges, and the next free pgoff
+//! reported after a batch, up to a multiple of its value, for servers that
+//! back their image space with large pages.
+//!
+//! `--guard-pages` keeps pages free before and after every automatic RDMA
+//! range, see `guard_pages`; an explicit pgoff must leave room for them
+//! before it.
+
+use std::collections::HashMap;
+use std::io;
+
+/// Next free pgoff on the RDMA server and on each DAX device.
+pub struct PgoffAllocator {
+    next_rdma: u64,
+    /// Multiple every automatic RDMA range starts at.
+    rdma_align: u64,
+    /// Pages left free on each side of every RDMA range.
+    rdma_guard: u64,
+    /// DAX devices allocate independently, each starting at page 0.
+    next_dax: HashMap<String, u64>,
+}
+
+impl PgoffAllocator {
+    /// Allocates RDMA ranges from `rdma_base`.
+    pub fn new(rdma_base: u64) -> Self {
+        PgoffAllocator::with_rdma_align(rdma_base, 1)
+    }
+
+    /// Allocates RDMA ranges from `rdma_base`, starting each automatic one
+    /// at a multiple of `rdma_align`.
+    pub fn with_rdma_align(rdma_base: u64, rdma_align: u64) -> Self {
+        PgoffAllocator {
+            next_rdma: rdma_base,
+            rdma_align,
+            rdma_guard: 0,
+            next_dax: HashMap::new(),
+        }
+    }
+
+    /// Leaves `pages` free before and after every RDMA range.
+    pub fn with_rdma_guard(mut self, pages: u64) -> Self {
+        self.rdma_guard = pages;
+        self
+    }
+
+    /// Reserves `pages` on `dax_device`, or on the RDMA server when `None`,
+    /// returning the first pgoff of the range.
+    ///
+    /// An `explicit` pgoff is used as is; later automatic ranges start past
+    /// it if it lies beyond the current position. Automatic ranges start at
+    /// a multiple of `align`, and of the RDMA alignment on the RDMA server,
+    /// skipping the pgoffs before it. Fails if the range would end past the
+    /// last pgoff, counting the guard pages around RDMA ranges.
+    pub fn reserve(
+        &mut self,
+        dax_device: Option<&str>,
+        explicit: Option<u64>,
+        pages: u64,
+        align: u64,
+    ) -> io::Result<u64> {
+        let (next, align, guard) = match dax_device {
+            Some(device) => (
+                self.next_dax.entry(device.to_string()).or_insert(0),
+                align,
+                0,
+            ),
+            None => (
+                &mut self.next_rdma,
+                lcm(align, self.rdma_align),
+                self.rdma_guard,
+            ),
+        };
+        match explicit {
+            Some(start) if start < guard => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "rdma_pgoff {} leaves no room for {} guard pages before it",
+                        start, guard
+                    ),
+                ))
+            }
+            _ => {}
+        }
+        let start = match explicit {
+            Some(start) => Some(start),
+            None => next
+                .checked_add(guard)
+                .and_then(|first| checked_round_up(first, align)),
+        };
+        let end = start
+            .and_then(|start| start.checked_add(pages))
+            .and_then(|end| end.checked_add(guard));
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                *next = std::cmp::max(*next, end);
+                Ok(start)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                match explicit {
+                    Some(start) => format!(
+                        "rdma_pgoff {} + {} pages overflows pgoff space",
+                        start, pages
+                    ),
+                    None => format!(
+                        "next free pgoff {} + {} pages overflows pgoff space",
+                        *next, pages
+                    ),
+                },
+            )),
+        }
+    }
+
+    /// Moves the position on `dax_device`, or on the RDMA server when
+    /// `None`, to at least `floor`, so automatic ranges start past it.
+    pub fn raise(&mut self, dax_device: Option<&str>, floor: u64) {
+        let next = match dax_device {
+            Some(device) => self.next_dax.entry(device.to_string()).or_insert(0),
+            None => &mut self.next_rdma,
+        };
+        *next = std::cmp::max(*next, floor);
+    }
+
+    /// First RDMA pgoff past every reserved range that an automatic range
+    /// can start at.
+    pub fn next_rdma(&self) -> u64 {
+        round_up(self.next_rdma, self.rdma_align)
+    }
+
+    /// First pgoff past every reserved range of each DAX device, by device.
+    pub fn next_dax(&self) -> Vec<(&str, u64)> {
+        let mut devices: Vec<_> = self
+            .next_dax
+            .iter()
+            .map(|(device, next)| (device.as_str(), *next))
+            .collect();
+        devices.sort();
+        devices
+    }
+}
+
+fn round_up(pgoff: u64, align: u64) -> u64 {
+    checked_round_up(pgoff, align).unwrap_or(u64::MAX)
+}
+
+fn checked_round_up(pgoff: u64, align: u64) -> Option<u64> {
+    pgoff
+        .checked_add(align - 1)
+        .map(|pgoff| pgoff / align * align)
+}
+
+fn lcm(a: u64, b: u64) -> u64 {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        let rem = x % y;
+        x = y;
+        y = rem;
+    }
+    a / x * b
+}
+
+/// A batch entry's pgoff range on one RDMA server or DAX device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlannedRange<'a> {
+    pub entry: usize,
+    /// Server or device the range is on; ranges on different ones can't
+    /// overlap.
+    pub target: &'a str,
+    pub start: u64,
+    pub pages: u64,
+}
+
+impl<'a> PlannedRange<'a> {
+    pub fn end(&self) -> u64 {
+        self.start.saturating_add(self.pages)
+    }
+
+    fn overlaps(&self, other: &PlannedRange) -> bool {
+        self.target == other.target
+            && self.pages > 0
+            && other.pages > 0
+            && self.start < other.end()
+            && other.start < self.end()
+    }
+}
+
+/// Every pair of `ranges` that share pgoffs on the same target, in entry
+/// order.
+pub fn overlapping_pairs<'r, 'a>(
+    ranges: &'r [PlannedRange<'a>],
+) -> Vec<(&'r PlannedRange<'a>, &'r PlannedRange<'a>)> {
+    let mut pairs = Vec::new();
+    for (idx, range) in ranges.iter().enumerate() {
+        for other in &ranges[idx + 1..] {
+            if range.overlaps(other) {
+                pairs.push((range, other));
+            }
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn test_reserve() {
+        let mut allocator = PgoffAllocator::new(100);
+        assert_eq!(allocator.reserve(None, None, 10, 1).unwrap(), 100);
+        assert_eq!(allocator.reserve(None, None, 5, 1).unwrap(), 110);
+        // Explicit ranges past the position push it forward...
+        assert_eq!(allocator.reserve(None, Some(200), 10, 1).unwrap(), 200);
+        assert_eq!(allocator.reserve(None, None, 1, 1).unwrap(), 210);
+        // ...and ones behind it leave it alone.
+        assert_eq!(allocator.reserve(None, Some(0), 10, 1).unwrap(), 0);
+        assert_eq!(allocator.next_rdma(), 211);
+        // 2 MiB entries start on a multiple of 512 pgoffs.
+        assert_eq!(allocator.reserve(None, None, 1024, 512).unwrap(), 512);
+        assert_eq!(allocator.reserve(None, None, 1, 1).unwrap(), 1536);
+        assert_eq!(allocator.next_rdma(), 1537);
+
+        assert_eq!(
+            allocator.reserve(Some("/dev/dax1.0"), None, 4, 1).unwrap(),
+            0
+        );
+        assert_eq!(
+            allocator.reserve(Some("/dev/dax0.0"), None, 4, 1).unwrap(),
+            0
+        );
+        assert_eq!(
+            allocator.reserve(Some("/dev/dax0.0"), None, 4, 1).unwrap(),
+            4
+        );
+        assert_eq!(
+            allocator.next_dax(),
+            vec![("/dev/dax0.0", 8), ("/dev/dax1.0", 4)]
+        );
+        assert_eq!(allocator.next_rdma(), 1537);
+
+        allocator.raise(None, 1000);
+        assert_eq!(allocator.next_rdma(), 1537);
+        allocator.raise(None, 4000);
+        assert_eq!(allocator.reserve(None, None, 1, 1).unwrap(), 4000);
+        allocator.raise(Some("/dev/dax1.0"), 100);
+        assert_eq!(
+            allocator.reserve(Some("/dev/dax1.0"), None, 1, 1).unwrap(),
+            100
+        );
+    }
+
+    #[test]
+    fn test_rdma_align() {
+        let mut allocator = PgoffAllocator::with_rdma_align(100, 512);
+        assert_eq!(allocator.next_rdma(), 512);
+        assert_eq!(allocator.reserve(None, None, 10, 1).unwrap(), 512);
+        assert_eq!(allocator.next_rdma(), 1024);
+        // Explicit pgoffs are taken as given.
+        assert_eq!(allocator.reserve(None, Some(1030), 10, 1).unwrap(), 1030);
+        assert_eq!(allocator.reserve(None, None, 10, 1).unwrap(), 1536);
+        // Combined with the entry's own page alignment.
+        let mut allocator = PgoffAllocator::with_rdma_align(0, 3);
+        assert_eq!(allocator.reserve(None, Some(1), 1, 1).unwrap(), 1);
+        assert_eq!(allocator.reserve(None, None, 1, 2).unwrap(), 6);
+        // DAX devices aren't aligned.
+        assert_eq!(
+            allocator.reserve(Some("/dev/dax0.0"), None, 3, 1).unwrap(),
+            0
+        );
+        assert_eq!(
+            allocator.reserve(Some("/dev/dax0.0"), None, 3, 1).unwrap(),
+            3
+        );
+        assert_eq!(lcm(512, 1), 512);
+        assert_eq!(lcm(4, 6), 12);
+    }
+
+    #[test]
+    fn test_rdma_guard() {
+        let mut allocator = PgoffAllocator::with_rdma_align(0, 4).with_rdma_guard(2);
+        // Aligned past its guard pages, [2, 4), and followed by [8, 10).
+        assert_eq!(allocator.reserve(None, None, 4, 1).unwrap(), 4);
+        assert_eq!(allocator.next_rdma(), 12);
+        assert_eq!(allocator.reserve(None, None, 4, 1).unwrap(), 12);
+        assert_eq!(allocator.reserve(None, Some(100), 1, 1).unwrap(), 100);
+        assert_eq!(allocator.next_rdma(), 104);
+        let err = allocator
+            .reserve(None, Some(1), 1, 1)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "rdma_pgoff 1 leaves no room for 2 guard pages before it"
+        );
+        assert!(allocator.reserve(None, Some(u64::MAX - 2), 1, 1).is_err());
+        // DAX devices aren't guarded.
+        assert_eq!(
+            allocator.reserve(Some("/dev/dax0.0"), None, 4, 1).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_reserve_overflow() {
+        let mut allocator = PgoffAllocator::new(0);
+        let err = allocator
+            .reserve(None, Some(18_446_744_073_709_551_000), 1024, 1)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "rdma_pgoff 18446744073709551000 + 1024 pages overflows pgoff space"
+        );
+        // Nothing was reserved.
+        assert_eq!(allocator.reserve(None, None, 1, 1).unwrap(), 0);
+
+        assert_eq!(
+            allocator.reserve(None, Some(u64::MAX - 10), 10, 1).unwrap(),
+            u64::MAX - 10
+        );
+        let err = allocator.reserve(None, None, 1, 1).unwrap_err().to_string();
+        assert!(
+            err.starts_with("next free pgoff 18446744073709551615"),
+            "{}",
+            err
+        );
+        let mut aligned = PgoffAllocator::with_rdma_align(u64::MAX - 10, 512);
+        assert!(aligned.reserve(None, None, 1, 1).is_err());
+    }
+
+    #[test]
+    fn test_overlapping_pairs() {
+        let range = |entry, target, start, pages| PlannedRange {
+            entry,
+            target,
+            start,
+            pages,
+        };
+        let ranges = [
+            range(0, "10.0.0.1:9000", 0, 100),
+            range(1, "10.0.0.1:9000", 100, 50),
+            // Explicit, behind the allocator: on top of entries 0 and 1.
+            range(2, "10.0.0.1:9000", 90, 20),
+            // Same pgoffs on another server or device are fine.
+            range(3, "10.0.0.2:9000", 0, 100),
+            range(4, "/dev/dax0.0", 0, 100),
+            range(5, "10.0.0.1:9000", 150, 0),
+        ];
+        let pairs: Vec<(usize, usize)> = overlapping_pairs(&ranges)
+            .into_iter()
+            .map(|(a, b)| (a.entry, b.entry))
+            .collect();
+        assert_eq!(pairs, vec![(0, 2), (1, 2)]);
+        assert!(overlapping_pairs(&ranges[..2]).is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_reservations_are_disjoint() {
+        let allocator = Arc::new(Mutex::new(PgoffAllocator::new(0)));
+        let workers: Vec<_> = (0..8u64)
+            .map(|worker| {
+                let allocator = allocator.clone();
+                thread::spawn(move || {
+                    (0..50)
+                        .map(|_| {
+                            let pages = worker + 1;
+                            let start = allocator
+                                .lock()
+                                .unwrap()
+                                .reserve(None, None, pages, 1)
+                                .unwrap();
+                            (start, start + pages)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut ranges: Vec<(u64, u64)> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect();
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            assert!(
+                pair[0].1 <= pair[1].0,
+                "{:?} overlaps {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+        assert_eq!(ranges.last().unwrap().1, 50 * (1..=8).sum::<u64>());
+    }
+}
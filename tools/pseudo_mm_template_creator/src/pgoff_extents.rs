@@ -0,0 +1,313 @@
+//! Splitting an image across free pgoff extents.
+//!
+//! After many templates are created and deleted, the memory server's free
+//! pgoffs are scattered: no run is long enough for a large image although
+//! enough pages are free in total. With `--pgoff-extents`, the image goes in
+//! the free extents given: whole in the first one it fits in, or else
+//! filling them in order.
+//!
+//! The image keeps its layout; only where its pages are stored changes.
+//! Each extent's part of it is uploaded as an image of its own, and the
+//! regions' `extents` map each part from its pgoffs, so restore sets up
+//! the page tables once per extent. The template records the chosen
+//! extents in `rdma_image_extents`.
+
+use std::io;
+
+use vmm::pseudo_mm_addr::{self, PageOffset};
+use vmm::pseudo_mm_support::{ImageExtent, PgoffExtent, RegionMetadata, PAGE_SIZE};
+
+use crate::regions::{self, ImageWindow};
+
+/// Parses `PGOFF+PAGES[,PGOFF+PAGES...]`, decimal or 0x-prefixed hex.
+pub fn parse(list: &str) -> Result<Vec<ImageExtent>, String> {
+    list.split(',')
+        .map(|extent| {
+            let plus = extent
+                .find('+')
+                .ok_or_else(|| format!("invalid extent '{}': expected PGOFF+PAGES", extent))?;
+            let number = |value: &str| {
+                pseudo_mm_addr::parse_u64(value)
+                    .map_err(|err| format!("invalid extent '{}': {}", extent, err))
+            };
+            let pgoff = number(&extent[..plus])?;
+            let pages = number(&extent[plus + 1..])?;
+            if pgoff.checked_add(pages).is_none() {
+                return Err(format!(
+                    "invalid extent '{}': overflows pgoff space",
+                    extent
+                ));
+            }
+            Ok(ImageExtent {
+                pgoff: PageOffset(pgoff),
+                pages,
+            })
+        })
+        .collect()
+}
+
+/// Pages of `extent` usable by pages of `align` pgoffs, from the first
+/// aligned pgoff in it.
+fn usable(extent: &ImageExtent, align: u64) -> (u64, u64) {
+    let end = extent.pgoff.raw().saturating_add(extent.pages);
+    let start = match extent.pgoff.raw().checked_add(align - 1) {
+        Some(last) => last / align * align,
+        None => return (end, 0),
+    };
+    if start >= end {
+        return (start, 0);
+    }
+    (start, (end - start) / align * align)
+}
+
+/// The extents of `free` an image of `pages` pages is stored in, in image
+/// order, each starting and ending on a multiple of `align` pgoffs: the
+/// first extent that holds the whole image, or else as many as it takes in
+/// the order given.
+pub fn fit(free: &[ImageExtent], pages: u64, align: u64) -> io::Result<Vec<ImageExtent>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let mut sorted = free.to_vec();
+    sorted.sort_by_key(|extent| extent.pgoff);
+    for pair in sorted.windows(2) {
+        if pair[0].pgoff.raw().saturating_add(pair[0].pages) > pair[1].pgoff.raw() {
+            return Err(invalid(format!(
+                "free pgoff extents {}+{} and {}+{} overlap",
+                pair[0].pgoff, pair[0].pages, pair[1].pgoff, pair[1].pages
+            )));
+        }
+    }
+
+    let usable: Vec<(u64, u64)> = free.iter().map(|extent| usable(extent, align)).collect();
+    if let Some(&(start, _)) = usable.iter().find(|&&(_, len)| len >= pages) {
+        return Ok(vec![ImageExtent {
+            pgoff: PageOffset(start),
+            pages,
+        }]);
+    }
+    let mut extents = Vec::new();
+    let mut left = pages;
+    for (start, len) in usable {
+        if left == 0 {
+            break;
+        }
+        if len == 0 {
+            continue;
+        }
+        let take = std::cmp::min(len, left);
+        extents.push(ImageExtent {
+            pgoff: PageOffset(start),
+            pages: take,
+        });
+        left -= take;
+    }
+    if left > 0 {
+        let total: u64 = extents.iter().map(|extent| extent.pages).sum();
+        return Err(invalid(format!(
+            "free pgoff extents hold {} usable pages, the image needs {}",
+            total, pages
+        )));
+    }
+    Ok(extents)
+}
+
+/// One extent's part of an image.
+#[derive(Debug, PartialEq)]
+pub struct ExtentPart {
+    pub pgoff: PageOffset,
+    /// Image page the part starts at.
+    pub first_page: u64,
+    pub pages: u64,
+    /// The windows stored in the extent, with image offsets from its start.
+    pub windows: Vec<ImageWindow>,
+}
+
+/// Splits the image of `windows` over `extents`.
+pub fn split(extents: &[ImageExtent], windows: &[ImageWindow]) -> Vec<ExtentPart> {
+    let mut first_page = 0;
+    extents
+        .iter()
+        .map(|extent| {
+            let start = first_page * PAGE_SIZE;
+            let windows =
+                regions::windows_within(windows, (start, start + extent.pages * PAGE_SIZE))
+                    .into_iter()
+                    .map(|window| ImageWindow {
+                        image_offset: window.image_offset - start,
+                        ..window
+                    })
+                    .collect();
+            let part = ExtentPart {
+                pgoff: extent.pgoff,
+                first_page,
+                pages: extent.pages,
+                windows,
+            };
+            first_page += extent.pages;
+            part
+        })
+        .collect()
+}
+
+/// Moves `regions`, planned for an image stored contiguously from
+/// `rdma_pgoff`, to the pgoffs of `extents`: each region's `rdma_offset`
+/// becomes that of its first page, and `extents` map the rest of it where
+/// it crosses into another extent.
+pub fn apply(regions: &mut [RegionMetadata], rdma_pgoff: PageOffset, extents: &[ImageExtent]) {
+    for region in regions {
+        let first = region.rdma_offset.raw() - rdma_pgoff.raw();
+        let end = first + region.size / PAGE_SIZE;
+        // (offset, size, pgoff) of each piece of the region.
+        let mut pieces: Vec<(u64, u64, u64)> = Vec::new();
+        let mut extent_start = 0;
+        for extent in extents {
+            let extent_end = extent_start + extent.pages;
+            let from = std::cmp::max(first, extent_start);
+            let to = std::cmp::min(end, extent_end);
+            if from < to {
+                let pgoff = extent.pgoff.raw() + (from - extent_start);
+                let offset = (from - first) * PAGE_SIZE;
+                match pieces.last_mut() {
+                    // Extents that happen to be adjacent.
+                    Some(last) if last.2 + last.1 / PAGE_SIZE == pgoff => {
+                        last.1 += (to - from) * PAGE_SIZE
+                    }
+                    _ => pieces.push((offset, (to - from) * PAGE_SIZE, pgoff)),
+                }
+            }
+            extent_start = extent_end;
+        }
+        let rdma_offset = match pieces.first() {
+            Some(&(_, _, pgoff)) => pgoff,
+            None => continue,
+        };
+        region.rdma_offset = PageOffset(rdma_offset);
+        region.extents = pieces[1..]
+            .iter()
+            .map(|&(offset, size, pgoff)| PgoffExtent {
+                offset,
+                size,
+                rdma_offset: PageOffset(pgoff),
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_templates::region;
+
+    fn extent(pgoff: u64, pages: u64) -> ImageExtent {
+        ImageExtent {
+            pgoff: PageOffset(pgoff),
+            pages,
+        }
+    }
+
+    #[test]
+    fn test_parse_extents() {
+        assert_eq!(
+            parse("100+50,0x1000+0x10").unwrap(),
+            vec![extent(100, 50), extent(0x1000, 16)]
+        );
+        assert!(parse("100").unwrap_err().contains("expected PGOFF+PAGES"));
+        assert!(parse("100+x").is_err());
+        assert!(parse("0xffffffffffffffff+2")
+            .unwrap_err()
+            .contains("overflows"));
+    }
+
+    #[test]
+    fn test_fit_extents() {
+        let free = [extent(0, 10), extent(100, 40), extent(200, 100)];
+        // Whole in the first extent it fits in.
+        assert_eq!(fit(&free, 40, 1).unwrap(), vec![extent(100, 40)]);
+        // Otherwise spread over them in order.
+        assert_eq!(
+            fit(&free, 120, 1).unwrap(),
+            vec![extent(0, 10), extent(100, 40), extent(200, 70)]
+        );
+        let err = fit(&free, 200, 1).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "free pgoff extents hold 150 usable pages, the image needs 200"
+        );
+        // Huge pages only use aligned whole pages of each extent.
+        let free = [extent(1, 1024), extent(2048, 512)];
+        assert_eq!(
+            fit(&free, 1024, 512).unwrap(),
+            vec![extent(512, 512), extent(2048, 512)]
+        );
+        let err = fit(&[extent(0, 10), extent(5, 10)], 5, 1)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("overlap"), "{}", err);
+    }
+
+    #[test]
+    fn test_split_and_apply() {
+        let extents = [extent(100, 3), extent(500, 5)];
+        let windows = [
+            ImageWindow {
+                file_offset: 0,
+                size: 2 * PAGE_SIZE,
+                image_offset: 0,
+            },
+            ImageWindow {
+                file_offset: 4 * PAGE_SIZE,
+                size: 6 * PAGE_SIZE,
+                image_offset: 2 * PAGE_SIZE,
+            },
+        ];
+        let parts = split(&extents, &windows);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            parts[0].windows,
+            vec![
+                windows[0],
+                ImageWindow {
+                    file_offset: 4 * PAGE_SIZE,
+                    size: PAGE_SIZE,
+                    image_offset: 2 * PAGE_SIZE,
+                },
+            ]
+        );
+        assert_eq!(
+            parts[1].windows,
+            vec![ImageWindow {
+                file_offset: 5 * PAGE_SIZE,
+                size: 5 * PAGE_SIZE,
+                image_offset: 0,
+            }]
+        );
+        assert_eq!(parts[1].first_page, 3);
+
+        // Planned contiguously from pgoff 7.
+        let mut regions = vec![region(0, 2, 7), region(0x100000, 6, 9)];
+        apply(&mut regions, PageOffset(7), &extents);
+        assert_eq!(regions[0].rdma_offset, PageOffset(100));
+        assert!(regions[0].extents.is_empty());
+        assert_eq!(regions[1].rdma_offset, PageOffset(102));
+        assert_eq!(
+            regions[1].extents,
+            vec![PgoffExtent {
+                offset: PAGE_SIZE,
+                size: 5 * PAGE_SIZE,
+                rdma_offset: PageOffset(500),
+            }]
+        );
+        assert_eq!(
+            regions[1].backing_ranges(),
+            vec![
+                (0, PAGE_SIZE, PageOffset(102)),
+                (PAGE_SIZE, 5 * PAGE_SIZE, PageOffset(500)),
+            ]
+        );
+
+        // Adjacent extents map as one.
+        let mut regions = vec![region(0, 8, 0)];
+        apply(&mut regions, PageOffset(0), &[extent(10, 3), extent(13, 5)]);
+        assert_eq!(regions[0].rdma_offset, PageOffset(10));
+        assert!(regions[0].extents.is_empty());
+    }
+}
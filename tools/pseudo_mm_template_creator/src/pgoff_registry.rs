@@ -0,0 +1,555 @@
+//! Persistent registry of allocated pgoff ranges.
+//!
+//! A batch only knows the ranges of its own run, so separate invocations
+//! against the same memory server would hand out the same pgoffs. With
+//! `--registry FILE`, every range is recorded when it is reserved, before its
+//! upload starts, and automatic pgoffs start past everything recorded on the
+//! same server or device:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "ranges": [
+//!     {
+//!       "target": "10.0.0.1:9000",
+//!       "start_pgoff": 0,
+//!       "pages": 262144,
+//!       "template_path": "/srv/templates/fn-a.json",
+//!       "allocated_at": 1700000000
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `allocated_at` is seconds since the Unix epoch. The file is only changed
+//! under an flock on `<FILE>.lock` and replaced by rename, so concurrent runs
+//! never lose each other's ranges. A range stays recorded when its entry
+//! fails; `registry gc` drops ranges whose template is gone or no longer
+//! uses them, unless a template layered on that one, or deduplicated
+//! against it, still maps its pages.
+//!
+//! Ranges of batch entries also record their image's `image_hash` (see
+//! `image_reuse`). A later entry with the same image maps that range
+//! instead of uploading, and is recorded with a range of its own at the
+//! same pgoffs, so the pages stay recorded as long as either template
+//! uses them.
+//!
+//! With `--guard-pages`, a range covers the image's guard pages too, so
+//! later runs keep clear of them.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use vmm::pseudo_mm_support::{PseudoMmTemplate, PAGE_SIZE};
+
+use crate::output_lock::OutputLock;
+use crate::pgoff_alloc::PgoffAllocator;
+
+/// Current registry file format version.
+pub const REGISTRY_VERSION: u32 = 1;
+
+/// How long to wait for another run to release the registry. Updates are
+/// a quick read-modify-write, so contention never lasts long.
+const LOCK_WAIT: Duration = Duration::from_secs(10);
+
+/// A pgoff range reserved for one template.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegisteredRange {
+    /// RDMA server or DAX device the range is on.
+    pub target: String,
+    pub start_pgoff: u64,
+    pub pages: u64,
+    /// Template the range's image belongs to, once written.
+    pub template_path: String,
+    pub allocated_at: u64,
+    /// SHA-256 of the image, for batch entries that can share it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_hash: Option<String>,
+}
+
+impl RegisteredRange {
+    pub fn end_pgoff(&self) -> u64 {
+        self.start_pgoff.saturating_add(self.pages)
+    }
+
+    fn overlaps(&self, target: &str, start: u64, pages: u64) -> bool {
+        self.target == target
+            && self.pages > 0
+            && pages > 0
+            && self.start_pgoff < start.saturating_add(pages)
+            && start < self.end_pgoff()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct RegistryFile {
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    ranges: Vec<RegisteredRange>,
+}
+
+/// A range to reserve for one template.
+pub struct Reservation<'a> {
+    pub target: &'a str,
+    /// The device when `target` is a DAX device, for the allocator.
+    pub dax_device: Option<&'a str>,
+    pub explicit: Option<u64>,
+    pub pages: u64,
+    pub align: u64,
+    pub template_path: &'a str,
+    pub now: u64,
+    pub image_hash: Option<&'a str>,
+    /// Pages recorded on each side of the image, see `guard_pages`; the
+    /// allocator must be set to leave as many free.
+    pub guard_pages: u64,
+}
+
+impl<'a> Reservation<'a> {
+    /// The range recorded for an image at `start`, guard pages included.
+    fn recorded(&self, start: u64) -> RegisteredRange {
+        RegisteredRange {
+            target: self.target.to_string(),
+            start_pgoff: start.saturating_sub(self.guard_pages),
+            pages: self.pages.saturating_add(2 * self.guard_pages),
+            template_path: self.template_path.to_string(),
+            allocated_at: self.now,
+            image_hash: self.image_hash.map(str::to_string),
+        }
+    }
+}
+
+/// The registry, locked until dropped.
+pub struct PgoffRegistry {
+    lock: OutputLock,
+    path: PathBuf,
+    ranges: Vec<RegisteredRange>,
+}
+
+impl PgoffRegistry {
+    /// Locks and reads the registry at `path`; a missing file is empty.
+    pub fn lock(path: &Path) -> io::Result<Self> {
+        let lock = OutputLock::acquire(path, LOCK_WAIT)?;
+        let file = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str::<RegistryFile>(&text).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid pgoff registry {}: {}", path.display(), err),
+                )
+            })?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => RegistryFile::default(),
+            Err(err) => return Err(err),
+        };
+        if file.version > REGISTRY_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "pgoff registry {} has version {}, newer than the supported version {}",
+                    path.display(),
+                    file.version,
+                    REGISTRY_VERSION
+                ),
+            ));
+        }
+        Ok(PgoffRegistry {
+            lock,
+            path: path.to_path_buf(),
+            ranges: file.ranges,
+        })
+    }
+
+    pub fn ranges(&self) -> &[RegisteredRange] {
+        &self.ranges
+    }
+
+    /// First pgoff past every range recorded on `target`.
+    pub fn next_free(&self, target: &str) -> u64 {
+        self.ranges
+            .iter()
+            .filter(|range| range.target == target)
+            .map(RegisteredRange::end_pgoff)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Reserves `request`'s range with `allocator`, automatic ranges past
+    /// everything recorded on its target, and records it.
+    ///
+    /// An explicit range may only overlap ranges of the same template, which
+    /// it replaces.
+    pub fn reserve(
+        &mut self,
+        allocator: &mut PgoffAllocator,
+        request: &Reservation,
+    ) -> io::Result<u64> {
+        match request.explicit {
+            Some(start) => {
+                let guarded = request.recorded(start);
+                let (start, pages) = (guarded.start_pgoff, guarded.pages);
+                let conflict = self.ranges.iter().find(|range| {
+                    range.template_path != request.template_path
+                        && range.overlaps(request.target, start, pages)
+                });
+                if let Some(range) = conflict {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "pgoff range [{}, {}) on {} overlaps [{}, {}) of {} in registry {} \
+                             (run 'registry gc' if that template is gone)",
+                            start,
+                            guarded.end_pgoff(),
+                            request.target,
+                            range.start_pgoff,
+                            range.end_pgoff(),
+                            range.template_path,
+                            self.path.display()
+                        ),
+                    ));
+                }
+                self.ranges
+                    .retain(|range| !range.overlaps(request.target, start, pages));
+            }
+            None => allocator.raise(request.dax_device, self.next_free(request.target)),
+        }
+        let start = allocator.reserve(
+            request.dax_device,
+            request.explicit,
+            request.pages,
+            request.align,
+        )?;
+        self.ranges.push(request.recorded(start));
+        Ok(start)
+    }
+
+    /// A range on `target` holding an image with `image_hash` that `load`
+    /// finds its template still using.
+    pub fn find_image<F>(&self, target: &str, image_hash: &str, load: F) -> Option<&RegisteredRange>
+    where
+        F: Fn(&Path) -> io::Result<PseudoMmTemplate>,
+    {
+        self.ranges.iter().find(|range| {
+            range.target == target
+                && range.image_hash.as_deref() == Some(image_hash)
+                && load(Path::new(&range.template_path))
+                    .map_or(false, |template| uses_range(&template, range))
+        })
+    }
+
+    /// Records `request`'s range at `start`, where an identical image
+    /// already is, without reserving it.
+    pub fn share(&mut self, request: &Reservation, start: u64) {
+        self.ranges.push(request.recorded(start));
+    }
+
+    /// Drops ranges no template uses any more, returning them.
+    ///
+    /// A range is dropped when `load` finds no template at its path, or one
+    /// whose image is elsewhere. Ranges younger than `min_age` seconds are
+    /// kept regardless, since their upload may still be running, and so are
+    /// ranges whose template fails to load for other reasons, is the base
+    /// of a template whose range is kept, or holds pages such a template's
+    /// extents map on the same target.
+    pub fn gc<F>(&mut self, now: u64, min_age: u64, load: F) -> Vec<RegisteredRange>
+    where
+        F: Fn(&Path) -> io::Result<PseudoMmTemplate>,
+    {
+        let mut bases: Vec<String> = Vec::new();
+        // (target, pgoff, pages) of every extent of the kept templates.
+        let mut mapped: Vec<(String, u64, u64)> = Vec::new();
+        for range in &self.ranges {
+            let template = match load(Path::new(&range.template_path)) {
+                Ok(template) if uses_range(&template, range) => template,
+                _ => continue,
+            };
+            bases.extend(template.base_template);
+            for region in &template.regions {
+                for extent in &region.extents {
+                    mapped.push((
+                        range.target.clone(),
+                        extent.rdma_offset.raw(),
+                        extent.size / PAGE_SIZE,
+                    ));
+                }
+            }
+        }
+        let (kept, dropped) = self.ranges.drain(..).partition(|range| {
+            if now.saturating_sub(range.allocated_at) < min_age
+                || bases.contains(&range.template_path)
+                || mapped
+                    .iter()
+                    .any(|(target, pgoff, pages)| range.overlaps(target, *pgoff, *pages))
+            {
+                return true;
+            }
+            match load(Path::new(&range.template_path)) {
+                Ok(template) => uses_range(&template, range),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => false,
+                Err(_) => true,
+            }
+        });
+        self.ranges = kept;
+        dropped
+    }
+
+    /// Writes the registry back, keeping the lock.
+    pub fn save(&self) -> io::Result<()> {
+        let file = RegistryFile {
+            version: REGISTRY_VERSION,
+            ranges: self.ranges.clone(),
+        };
+        let json = serde_json::to_string_pretty(&file)?;
+        self.lock.write(json.as_bytes())
+    }
+}
+
+/// Whether `template`'s image occupies `range`. Ranges are reserved for
+/// the whole memory file, so an image that leaves out padding between
+/// regions takes fewer pages than its range.
+fn uses_range(template: &PseudoMmTemplate, range: &RegisteredRange) -> bool {
+    match template.rdma_guarded_range {
+        Some(guarded) => guarded.pgoff.raw() == range.start_pgoff && guarded.pages <= range.pages,
+        None => {
+            template.rdma_base_pgoff.raw() == range.start_pgoff
+                && (template.rdma_image_size + PAGE_SIZE - 1) / PAGE_SIZE <= range.pages
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+    use crate::test_templates;
+    use vmm::pseudo_mm_addr::PageOffset;
+    use vmm::pseudo_mm_support::{ImageExtent, PgoffExtent, RegionMetadata};
+
+    fn request<'a>(explicit: Option<u64>, pages: u64, template_path: &'a str) -> Reservation<'a> {
+        Reservation {
+            target: "10.0.0.1:9000",
+            dax_device: None,
+            explicit,
+            pages,
+            align: 1,
+            template_path,
+            now: 1_700_000_000,
+            image_hash: None,
+            guard_pages: 0,
+        }
+    }
+
+    fn template(base: u64, pages: u64) -> PseudoMmTemplate {
+        PseudoMmTemplate {
+            rdma_base_pgoff: PageOffset(base),
+            rdma_image_size: pages * PAGE_SIZE,
+            ..test_templates::template()
+        }
+    }
+
+    #[test]
+    fn test_reserve_past_recorded_ranges() {
+        let dir = test_files::dir("registry_reserve");
+        let path = dir.join("pgoffs.json");
+
+        // An earlier run on the same server.
+        let mut registry = PgoffRegistry::lock(&path).unwrap();
+        let mut allocator = PgoffAllocator::new(0);
+        assert_eq!(
+            registry
+                .reserve(&mut allocator, &request(None, 100, "/srv/a.json"))
+                .unwrap(),
+            0
+        );
+        registry.save().unwrap();
+        drop(registry);
+
+        let mut registry = PgoffRegistry::lock(&path).unwrap();
+        let mut allocator = PgoffAllocator::new(0);
+        assert_eq!(registry.next_free("10.0.0.1:9000"), 100);
+        assert_eq!(registry.next_free("/dev/dax0.0"), 0);
+        assert_eq!(
+            registry
+                .reserve(&mut allocator, &request(None, 10, "/srv/b.json"))
+                .unwrap(),
+            100
+        );
+        // Explicit ranges may not land on another template's...
+        let err = registry
+            .reserve(&mut allocator, &request(Some(50), 10, "/srv/c.json"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("[50, 60) on 10.0.0.1:9000 overlaps [0, 100) of /srv/a.json"),
+            "{}",
+            err
+        );
+        // ...but may replace their own.
+        registry
+            .reserve(&mut allocator, &request(Some(0), 50, "/srv/a.json"))
+            .unwrap();
+        let ranges: Vec<(u64, u64)> = registry
+            .ranges()
+            .iter()
+            .map(|range| (range.start_pgoff, range.pages))
+            .collect();
+        assert_eq!(ranges, vec![(100, 10), (0, 50)]);
+        // Nor past the last pgoff.
+        let err = registry
+            .reserve(
+                &mut allocator,
+                &request(Some(18_446_744_073_709_551_000), 1024, "/srv/d.json"),
+            )
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("overflows pgoff space"), "{}", err);
+        assert_eq!(registry.ranges().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reserve_with_guard_pages() {
+        let dir = test_files::dir("registry_guard");
+        let mut registry = PgoffRegistry::lock(&dir.join("pgoffs.json")).unwrap();
+        let mut allocator = PgoffAllocator::new(0).with_rdma_guard(4);
+        let guarded = |explicit, pages, template_path: &'static str| Reservation {
+            guard_pages: 4,
+            ..request(explicit, pages, template_path)
+        };
+        assert_eq!(
+            registry
+                .reserve(&mut allocator, &guarded(None, 10, "/srv/a.json"))
+                .unwrap(),
+            4
+        );
+        assert_eq!(registry.next_free("10.0.0.1:9000"), 18);
+        // An explicit image may not land on another's guard pages...
+        let err = registry
+            .reserve(&mut allocator, &request(Some(16), 10, "/srv/b.json"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("[16, 26) on 10.0.0.1:9000 overlaps [0, 18) of /srv/a.json"),
+            "{}",
+            err
+        );
+        // ...nor put its own on another image.
+        let err = registry
+            .reserve(&mut allocator, &guarded(Some(20), 10, "/srv/b.json"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("[16, 34)"), "{}", err);
+
+        // A template recording its guarded range still uses the range.
+        let load = |_: &Path| {
+            let mut template = template(4, 10);
+            template.rdma_guarded_range = Some(ImageExtent {
+                pgoff: PageOffset(0),
+                pages: 18,
+            });
+            Ok(template)
+        };
+        assert!(registry.gc(u64::MAX, 0, load).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_gc() {
+        let dir = test_files::dir("registry_gc");
+        let mut registry = PgoffRegistry::lock(&dir.join("pgoffs.json")).unwrap();
+        let range = |start, pages, template_path: &str, allocated_at| RegisteredRange {
+            target: "10.0.0.1:9000".to_string(),
+            start_pgoff: start,
+            pages,
+            template_path: template_path.to_string(),
+            allocated_at,
+            image_hash: None,
+        };
+        registry.ranges = vec![
+            range(0, 100, "/srv/live.json", 0),
+            range(100, 100, "/srv/gone.json", 0),
+            // Just reserved; the upload may still be running.
+            range(200, 100, "/srv/new.json", 990),
+            // The template was rebased elsewhere.
+            range(300, 100, "/srv/moved.json", 0),
+            range(400, 100, "/srv/corrupt.json", 0),
+            // Gone, but a live template still shares its pages.
+            range(500, 100, "/srv/base.json", 0),
+            range(600, 10, "/srv/layered.json", 0),
+            // Gone, but a deduplicated template maps one of its pages.
+            range(700, 100, "/srv/first.json", 0),
+            range(800, 10, "/srv/deduped.json", 0),
+        ];
+        let dropped = registry.gc(1000, 60, |path| match path.to_str().unwrap() {
+            "/srv/live.json" => Ok(template(0, 100)),
+            "/srv/moved.json" => Ok(template(5000, 100)),
+            "/srv/corrupt.json" => Err(io::Error::new(io::ErrorKind::InvalidData, "bad")),
+            "/srv/layered.json" => {
+                let mut layered = template(600, 10);
+                layered.base_template = Some("/srv/base.json".to_string());
+                Ok(layered)
+            }
+            "/srv/deduped.json" => {
+                let mut deduped = template(800, 10);
+                deduped.regions = vec![RegionMetadata {
+                    extents: vec![PgoffExtent {
+                        offset: 10 * PAGE_SIZE,
+                        size: PAGE_SIZE,
+                        rdma_offset: PageOffset(750),
+                    }],
+                    ..test_templates::region(0, 11, 800)
+                }];
+                Ok(deduped)
+            }
+            _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+        });
+        let starts = |ranges: &[RegisteredRange]| -> Vec<u64> {
+            ranges.iter().map(|range| range.start_pgoff).collect()
+        };
+        assert_eq!(starts(&dropped), vec![100, 300]);
+        assert_eq!(
+            starts(registry.ranges()),
+            vec![0, 200, 400, 500, 600, 700, 800]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_image() {
+        let dir = test_files::dir("registry_image");
+        let mut registry = PgoffRegistry::lock(&dir.join("pgoffs.json")).unwrap();
+        let mut first = request(None, 100, "/srv/a.json");
+        first.image_hash = Some("ab12");
+        registry
+            .reserve(&mut PgoffAllocator::new(0), &first)
+            .unwrap();
+        let load = |path: &Path| match path.to_str().unwrap() {
+            "/srv/a.json" => Ok(template(0, 100)),
+            _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+        };
+        let found = registry.find_image("10.0.0.1:9000", "ab12", load);
+        assert_eq!(found.map(|range| range.start_pgoff), Some(0));
+        assert!(registry.find_image("10.0.0.1:9000", "cd34", load).is_none());
+        assert!(registry.find_image("10.0.0.2:9000", "ab12", load).is_none());
+        // Not once its template maps another range.
+        assert!(registry
+            .find_image("10.0.0.1:9000", "ab12", |_| Ok(template(500, 100)))
+            .is_none());
+
+        // The image's pages stay recorded while the template sharing them
+        // lives on.
+        let mut second = request(None, 100, "/srv/b.json");
+        second.image_hash = Some("ab12");
+        registry.share(&second, 0);
+        let dropped = registry.gc(2_000_000_000, 60, |path| match path.to_str().unwrap() {
+            "/srv/b.json" => Ok(template(0, 100)),
+            _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+        });
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].template_path, "/srv/a.json");
+        assert_eq!(registry.ranges()[0].template_path, "/srv/b.json");
+        assert_eq!(registry.ranges()[0].start_pgoff, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
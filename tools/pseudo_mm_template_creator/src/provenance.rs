@@ -0,0 +1,57 @@
+//! The `provenance` recorded in every template.
+//!
+//! Templates outlive the runs that made them, so each one says when it was
+//! made, on which host, by which build of this tool and for which entry,
+//! and from which files as they were then (see `freshness`).
+
+use std::ffi::CStr;
+use std::time::SystemTime;
+
+use vmm::pseudo_mm_support::{Provenance, SourceFiles};
+
+use crate::deadline;
+
+/// Name and version of this tool, as recorded in templates.
+pub fn tool() -> String {
+    format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// Provenance of a template made now for the entry labelled `label` from
+/// `sources`.
+pub fn current(label: &str, sources: Option<SourceFiles>) -> Provenance {
+    Provenance {
+        created_at: deadline::format_rfc3339(SystemTime::now()),
+        hostname: hostname().unwrap_or_else(|| "unknown".to_string()),
+        tool: tool(),
+        label: label.to_string(),
+        command_line: std::env::args().collect(),
+        sources,
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0 as libc::c_char; 256];
+    // Safe because the length leaves room for the terminating NUL, which
+    // is written below in case the name was truncated.
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len() - 1) } != 0 {
+        return None;
+    }
+    buf[buf.len() - 1] = 0;
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Some(name.to_string_lossy().into_owned()).filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current() {
+        let provenance = current("fn-a", None);
+        assert_eq!(provenance.label, "fn-a");
+        assert!(provenance.tool.starts_with("pseudo_mm_template_creator "));
+        assert!(provenance.created_at.ends_with('Z'));
+        assert!(!provenance.hostname.is_empty());
+        assert!(!provenance.command_line.is_empty());
+    }
+}
@@ -0,0 +1,229 @@
+//! Upload bandwidth limits.
+//!
+//! `--max-upload-rate` caps each connection of an RDMA upload, and
+//! `--max-batch-upload-rate` caps the sum over all of a batch's
+//! connections. Both are token buckets charged with the bytes of each
+//! image before it is sent; zero pages cost nothing since they are never
+//! sent. A bucket may go into debt by one image, and whoever takes from it
+//! next waits for the debt to be paid off, so jobs sharing the batch bucket
+//! are slowed down together rather than one at a time.
+//!
+//! Buckets take the current time as an argument, so tests can inject
+//! clocks.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest single sleep while waiting for tokens, so cancellation and
+/// `--entry-timeout` are still noticed at low rates.
+const WAIT_SLICE: Duration = Duration::from_millis(100);
+
+/// A bandwidth in bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate(pub u64);
+
+impl FromStr for Rate {
+    type Err = String;
+
+    /// Parses a rate such as `200MiB/s`, `1.5g` or `500MB/s`: `k`, `m`, `g`
+    /// and their `KiB`-style spellings are binary, `KB`, `MB` and `GB`
+    /// decimal. The `/s` is optional; a bare number is bytes per second.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let lower = spec.trim().to_ascii_lowercase();
+        let lower = if lower.ends_with("/s") {
+            &lower[..lower.len() - 2]
+        } else {
+            &lower[..]
+        };
+        let split = lower
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(lower.len());
+        let (number, unit) = lower.split_at(split);
+        let scale: f64 = match unit {
+            "" | "b" => 1.0,
+            "k" | "kib" => 1024.0,
+            "m" | "mib" => 1024.0 * 1024.0,
+            "g" | "gib" => 1024.0 * 1024.0 * 1024.0,
+            "kb" => 1e3,
+            "mb" => 1e6,
+            "gb" => 1e9,
+            _ => return Err(format!("invalid rate '{}': unknown unit '{}'", spec, unit)),
+        };
+        let bytes = number
+            .parse::<f64>()
+            .ok()
+            .map(|value| value * scale)
+            .filter(|&bytes| bytes >= 1.0 && bytes < 1e18)
+            .ok_or_else(|| format!("invalid rate '{}': expected e.g. 200MiB/s", spec))?;
+        Ok(Rate(bytes as u64))
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1} MiB/s", self.0 as f64 / (1024.0 * 1024.0))
+    }
+}
+
+/// Bytes that may be sent at `rate`, refilled continuously.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    /// Tokens a bucket left idle fills up to: a tenth of a second's worth.
+    capacity: f64,
+    /// Negative while in debt.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(rate: Rate, now: Instant) -> Self {
+        let rate = rate.0 as f64;
+        TokenBucket {
+            rate,
+            capacity: rate / 10.0,
+            tokens: rate / 10.0,
+            refilled: now,
+        }
+    }
+
+    /// Takes `bytes` tokens, returning how long to wait before sending them.
+    pub fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        if now > self.refilled {
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+            self.refilled = now;
+        }
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Limits from `--max-upload-rate` and `--max-batch-upload-rate`.
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    pub per_upload: Option<Rate>,
+    /// Shared by every upload of the batch.
+    pub aggregate: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl RateLimits {
+    pub fn new(per_upload: Option<Rate>, aggregate: Option<Rate>) -> Self {
+        RateLimits {
+            per_upload,
+            aggregate: aggregate
+                .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate, Instant::now())))),
+        }
+    }
+}
+
+/// Paces one upload by `RateLimits`.
+pub struct Throttle {
+    own: Option<TokenBucket>,
+    aggregate: Option<Arc<Mutex<TokenBucket>>>,
+    waited: Duration,
+}
+
+impl Throttle {
+    pub fn new(limits: &RateLimits) -> Self {
+        Throttle {
+            own: limits
+                .per_upload
+                .map(|rate| TokenBucket::new(rate, Instant::now())),
+            aggregate: limits.aggregate.clone(),
+            waited: Duration::from_secs(0),
+        }
+    }
+
+    /// Waits until `bytes` may be sent, calling `check` between sleeps and
+    /// giving up with its error.
+    pub fn wait(
+        &mut self,
+        bytes: u64,
+        check: &mut dyn FnMut() -> io::Result<()>,
+    ) -> io::Result<()> {
+        let now = Instant::now();
+        let mut wait = self
+            .own
+            .as_mut()
+            .map_or(Duration::from_secs(0), |bucket| bucket.take(bytes, now));
+        if let Some(aggregate) = self.aggregate.as_ref() {
+            let shared = aggregate.lock().expect("Poisoned lock").take(bytes, now);
+            wait = std::cmp::max(wait, shared);
+        }
+        let until = now + wait;
+        loop {
+            let now = Instant::now();
+            if now >= until {
+                break;
+            }
+            check()?;
+            thread::sleep(std::cmp::min(until - now, WAIT_SLICE));
+        }
+        self.waited += wait;
+        Ok(())
+    }
+
+    /// Total time spent waiting so far.
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!("200MiB/s".parse(), Ok(Rate(200 << 20)));
+        assert_eq!("200m".parse(), Ok(Rate(200 << 20)));
+        assert_eq!("1.5G".parse(), Ok(Rate(3 << 29)));
+        assert_eq!("500MB/s".parse(), Ok(Rate(500_000_000)));
+        assert_eq!("64kib".parse(), Ok(Rate(64 << 10)));
+        assert_eq!("4096".parse(), Ok(Rate(4096)));
+        for bad in &["", "0", "fast", "10mbit/s", "-5m", "0.1"] {
+            assert!(bad.parse::<Rate>().is_err(), "{}", bad);
+        }
+        assert_eq!(Rate(200 << 20).to_string(), "200.0 MiB/s");
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        // 1000 bytes/s; full with 100 bytes.
+        let mut bucket = TokenBucket::new(Rate(1000), start);
+        assert_eq!(bucket.take(100, start), Duration::from_secs(0));
+        // 500 bytes in debt: half a second.
+        assert_eq!(bucket.take(500, start), Duration::from_millis(500));
+        // Whoever comes next waits for the debt too.
+        assert_eq!(bucket.take(100, ms(200)), Duration::from_millis(400));
+        // Paid off and refilled, but never past a tenth of a second's worth.
+        assert_eq!(bucket.take(100, ms(10_000)), Duration::from_secs(0));
+        assert_eq!(bucket.take(100, ms(10_000)), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_throttle_gives_up_when_checked() {
+        let limits = RateLimits::new(Some(Rate(1000)), None);
+        let mut throttle = Throttle::new(&limits);
+        // 100 bytes are free; 10 more seconds' worth are not.
+        throttle.wait(100, &mut || Ok(())).unwrap();
+        let err = throttle
+            .wait(10_000, &mut || {
+                Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"))
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    }
+}
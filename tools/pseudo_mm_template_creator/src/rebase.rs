@@ -0,0 +1,581 @@
+//! Rewriting template pgoffs after an image migration.
+//!
+//! Moving a memory server's contents to a new box places the images at
+//! different base page offsets. Rebasing shifts a template's
+//! `rdma_base_pgoff`, every region's `rdma_offset` and extents, and the
+//! extents an image split across pgoff extents is stored in, by the same
+//! delta, so the template points at the image's new location.
+//!
+//! The batch form reads a mapping file produced by the migration tooling,
+//! listing the page ranges that moved:
+//!
+//! ```json
+//! [
+//!   { "old_base_pgoff": 0, "pages": 1048576, "new_base_pgoff": 4194304 },
+//!   { "old_base_pgoff": 1048576, "pages": 524288, "new_base_pgoff": 0 }
+//! ]
+//! ```
+//!
+//! Each template is rebased by the move its image lies in.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use vmm::pseudo_mm_addr::PageOffset;
+use vmm::pseudo_mm_support::{self, ImageExtent, PseudoMmTemplate, PAGE_SIZE};
+
+use crate::output_lock;
+
+/// One moved page range from the migration mapping file.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PgoffMove {
+    pub old_base_pgoff: u64,
+    pub pages: u64,
+    pub new_base_pgoff: u64,
+}
+
+impl PgoffMove {
+    /// Delta applied to pgoffs inside the moved range.
+    pub fn delta(&self) -> i128 {
+        delta_between(self.old_base_pgoff, self.new_base_pgoff)
+    }
+
+    /// Whether `[start, start + pages)` lies entirely inside the old range.
+    fn contains(&self, start: u64, pages: u64) -> bool {
+        start >= self.old_base_pgoff
+            && start
+                .checked_add(pages)
+                .map_or(false, |end| end <= self.old_base_pgoff + self.pages)
+    }
+}
+
+/// Loads a migration mapping file, rejecting overlapping or overflowing moves.
+pub fn load_moves(path: &str) -> Result<Vec<PgoffMove>, Box<dyn std::error::Error>> {
+    let mut moves: Vec<PgoffMove> = serde_json::from_reader(File::open(path)?)?;
+    moves.sort_by_key(|mv| mv.old_base_pgoff);
+    for mv in &moves {
+        if mv.old_base_pgoff.checked_add(mv.pages).is_none()
+            || mv.new_base_pgoff.checked_add(mv.pages).is_none()
+        {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "move of {} pages from {} to {} overflows the pgoff space",
+                    mv.pages, mv.old_base_pgoff, mv.new_base_pgoff
+                ),
+            )));
+        }
+    }
+    for pair in moves.windows(2) {
+        if pair[0].old_base_pgoff + pair[0].pages > pair[1].old_base_pgoff {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "moves from {} and {} overlap",
+                    pair[0].old_base_pgoff, pair[1].old_base_pgoff
+                ),
+            )));
+        }
+    }
+    Ok(moves)
+}
+
+/// Finds the move covering `template`'s whole image, every extent of it.
+pub fn move_for<'a>(moves: &'a [PgoffMove], template: &PseudoMmTemplate) -> Option<&'a PgoffMove> {
+    let extents = template.image_extents();
+    moves.iter().find(|mv| {
+        extents
+            .iter()
+            .all(|extent| mv.contains(extent.pgoff.raw(), extent.pages))
+    })
+}
+
+/// Delta that moves pgoff `from` to `to`. Pgoffs span all of `u64`, so
+/// deltas are `i128`: any two pgoffs are apart by one.
+fn delta_between(from: u64, to: u64) -> i128 {
+    i128::from(to) - i128::from(from)
+}
+
+/// Parses a signed pgoff delta such as `-256` or `+0x100`.
+pub fn parse_delta(value: &str) -> io::Result<i128> {
+    let invalid = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--pgoff-delta: {}", reason),
+        )
+    };
+    let (negative, magnitude) = match value.chars().next() {
+        Some('-') => (true, &value[1..]),
+        Some('+') => (false, &value[1..]),
+        _ => (false, value),
+    };
+    let magnitude: PageOffset = magnitude
+        .parse()
+        .map_err(|err| invalid(format!("{}", err)))?;
+    let magnitude = i128::from(magnitude.raw());
+    Ok(if negative { -magnitude } else { magnitude })
+}
+
+fn image_pages(template: &PseudoMmTemplate) -> u64 {
+    (template.rdma_image_size + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+fn shift(pgoff: PageOffset, delta: i128, what: &str, reserved: u64) -> io::Result<PageOffset> {
+    // Deltas come from two `u64`s at most, so the sum can't overflow.
+    let shifted = u64::try_from(i128::from(pgoff.raw()) + delta).ok();
+    match shifted {
+        Some(shifted) if shifted >= reserved => Ok(PageOffset(shifted)),
+        Some(shifted) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} {} would move to {}, inside the reserved pgoffs [0, {})",
+                what, pgoff, shifted, reserved
+            ),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} {} cannot be shifted by {} without leaving the pgoff space",
+                what, pgoff, delta
+            ),
+        )),
+    }
+}
+
+/// Shifts every pgoff in `template` by `delta`.
+///
+/// Fails without modifying anything if a pgoff would underflow, overflow or
+/// land below `reserved`, or if the rebased regions fail validation.
+/// Templates layered on a base template are refused: their extents point
+/// into the base's image, which moves with its own template. So are
+/// templates whose extents map pages of other images, as `--dedup` makes;
+/// extents within the template's own image move with it.
+pub fn rebase(
+    mut template: PseudoMmTemplate,
+    delta: i128,
+    reserved: u64,
+) -> io::Result<PseudoMmTemplate> {
+    if let Some(base) = template.base_template.as_ref() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "template is layered on {}; recreate it once the base is rebased",
+                base
+            ),
+        ));
+    }
+    let base = shift(template.rdma_base_pgoff, delta, "rdma_base_pgoff", reserved)?;
+    // The image must still fit in the pgoff space at its new base.
+    if base.raw().checked_add(image_pages(&template)).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "image at rdma_base_pgoff {} overflows the pgoff space",
+                base
+            ),
+        ));
+    }
+    let mut image_extents = Vec::with_capacity(template.rdma_image_extents.len());
+    for (idx, extent) in template.rdma_image_extents.iter().enumerate() {
+        let what = format!("image extent {}", idx);
+        let pgoff = shift(extent.pgoff, delta, &what, reserved)?;
+        if pgoff.raw().checked_add(extent.pages).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} at pgoff {} overflows the pgoff space", what, pgoff),
+            ));
+        }
+        image_extents.push(ImageExtent {
+            pgoff,
+            pages: extent.pages,
+        });
+    }
+
+    let guarded = match template.rdma_guarded_range {
+        Some(mut guarded) => {
+            guarded.pgoff = shift(guarded.pgoff, delta, "rdma_guarded_range", reserved)?;
+            Some(guarded)
+        }
+        None => None,
+    };
+
+    let image = template.image_extents();
+    let in_image = |start: u64, pages: u64| {
+        image.iter().any(|extent| {
+            start >= extent.pgoff.raw()
+                && start.saturating_add(pages) <= extent.pgoff.raw().saturating_add(extent.pages)
+        })
+    };
+    let mut offsets = Vec::with_capacity(template.regions.len());
+    let mut extent_offsets = Vec::new();
+    for (idx, region) in template.regions.iter().enumerate() {
+        let what = format!("region {} rdma_offset", idx);
+        offsets.push(shift(region.rdma_offset, delta, &what, reserved)?);
+        for extent in &region.extents {
+            if !in_image(extent.rdma_offset.raw(), extent.size / PAGE_SIZE) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "region {} maps pgoff {} outside the template's image; recreate it \
+                         once the image holding it is rebased",
+                        idx, extent.rdma_offset
+                    ),
+                ));
+            }
+            let what = format!("region {} extent", idx);
+            extent_offsets.push(shift(extent.rdma_offset, delta, &what, reserved)?);
+        }
+    }
+
+    // Older templates come out in the current layout, with the base and
+    // size `parse_template` filled in.
+    template.template_version =
+        pseudo_mm_support::template_version_for(&template.regions, template.pseudo_mm_id);
+    template.rdma_base_pgoff = base;
+    template.rdma_image_extents = image_extents;
+    template.rdma_guarded_range = guarded;
+    let mut extent_offsets = extent_offsets.into_iter();
+    for (region, offset) in template.regions.iter_mut().zip(offsets) {
+        region.rdma_offset = offset;
+        for (extent, offset) in region.extents.iter_mut().zip(&mut extent_offsets) {
+            extent.rdma_offset = offset;
+        }
+    }
+    pseudo_mm_support::validate_regions(&template.regions)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+    Ok(template)
+}
+
+/// How a single template's pgoffs are shifted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shift {
+    /// Shift by a fixed number of pages.
+    Delta(i128),
+    /// Shift so that `rdma_base_pgoff` becomes this value.
+    NewBase(PageOffset),
+}
+
+/// Reads and rebases one template file.
+pub fn rebase_file(
+    input: &Path,
+    shift: Shift,
+    reserved: u64,
+) -> Result<PseudoMmTemplate, Box<dyn std::error::Error>> {
+    let template = pseudo_mm_support::load_template_file(input)?;
+    let delta = match shift {
+        Shift::Delta(delta) => delta,
+        Shift::NewBase(new_base) => delta_between(template.rdma_base_pgoff.raw(), new_base.raw()),
+    };
+    Ok(rebase(template, delta, reserved)?)
+}
+
+/// Rebases every template under `template_dir` into the same relative path
+/// under `output_dir`, using the move each image lies in.
+///
+/// All templates are rebased before any is written, so a template without a
+/// matching move or with an invalid result leaves the output untouched.
+pub fn rebase_dir(
+    template_dir: &Path,
+    output_dir: &Path,
+    moves: &[PgoffMove],
+    reserved: u64,
+    lock_wait: Duration,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut paths = Vec::new();
+    collect_templates(template_dir, &mut paths)?;
+
+    let mut rebased = Vec::with_capacity(paths.len());
+    let mut failures = Vec::new();
+    for path in paths {
+        let template = match pseudo_mm_support::load_template_file(&path) {
+            Ok(template) => template,
+            Err(err) => {
+                println!("  skipping {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        let result = match move_for(moves, &template) {
+            Some(mv) => rebase(template, mv.delta(), reserved).map_err(|err| err.to_string()),
+            None => Err(format!(
+                "no move covers rdma_base_pgoff {}",
+                template.rdma_base_pgoff
+            )),
+        };
+        match result {
+            Ok(template) => rebased.push((path, template)),
+            Err(err) => failures.push(format!("{}: {}", path.display(), err)),
+        }
+    }
+
+    if !failures.is_empty() {
+        for failure in &failures {
+            println!("  error: {}", failure);
+        }
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} templates could not be rebased", failures.len()),
+        )));
+    }
+
+    for (path, template) in &rebased {
+        // Paths come from walking template_dir, so the prefix is always there.
+        let output = output_dir.join(path.strip_prefix(template_dir)?);
+        if let Some(parent) = output.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let json = serde_json::to_string_pretty(template)?;
+        output_lock::write_locked(&output, json.as_bytes(), lock_wait)?;
+        println!(
+            "  {} -> {} (rdma_base_pgoff={})",
+            path.display(),
+            output.display(),
+            template.rdma_base_pgoff
+        );
+    }
+    Ok(rebased.len())
+}
+
+fn collect_templates(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let path = entry.path();
+        if entry.metadata()?.is_dir() {
+            collect_templates(&path, paths)?;
+        } else if path.extension().and_then(|ext| ext.to_str()) == Some("json") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_templates::{self, region};
+    use vmm::pseudo_mm_addr::HvaAddr;
+    use vmm::pseudo_mm_support::{MemBackend, PgoffExtent, VmShape};
+
+    fn template() -> PseudoMmTemplate {
+        PseudoMmTemplate {
+            template_version: 1,
+            pseudo_mm_id: Some(3),
+            rdma_base_pgoff: PageOffset(1000),
+            rdma_image_size: 6 * PAGE_SIZE,
+            regions: vec![region(0, 4, 1000), region(0x10_0000, 2, 1004)],
+            pgoff_namespace: Some("tenant-a".to_string()),
+            required_features: vec!["dax".to_string()],
+            vm_shape: Some(VmShape {
+                vcpu_count: 2,
+                mem_size_mib: 512,
+                boot_vcpu_features: Some(0x1234),
+                smt: Some(true),
+            }),
+            mem_backend: MemBackend::Dax,
+            dax_device: Some("/dev/dax0.0".to_string()),
+            ..test_templates::template()
+        }
+    }
+
+    #[test]
+    fn test_rebase_shifts_every_pgoff() {
+        let rebased = rebase(template(), 500, 0).unwrap();
+        assert_eq!(rebased.rdma_base_pgoff, PageOffset(1500));
+        assert_eq!(rebased.regions[0].rdma_offset, PageOffset(1500));
+        assert_eq!(rebased.regions[1].rdma_offset, PageOffset(1504));
+        // Nothing but pgoffs changes.
+        assert_eq!(rebased.regions[1].hva, HvaAddr(0x7000_0010_0000));
+        assert_eq!(rebased.rdma_image_size, 6 * PAGE_SIZE);
+        assert_eq!(rebased.pgoff_namespace.as_deref(), Some("tenant-a"));
+    }
+
+    #[test]
+    fn test_rebase_shifts_guarded_range() {
+        let mut guarded = template();
+        guarded.rdma_guarded_range = Some(ImageExtent {
+            pgoff: PageOffset(998),
+            pages: 10,
+        });
+        let rebased = rebase(guarded, 500, 0).unwrap();
+        assert_eq!(
+            rebased.rdma_guarded_range,
+            Some(ImageExtent {
+                pgoff: PageOffset(1498),
+                pages: 10,
+            })
+        );
+    }
+
+    #[test]
+    fn test_rebase_round_trip() {
+        let original = serde_json::to_string_pretty(&template()).unwrap();
+        for &delta in &[1i128, 4096, 1 << 40] {
+            let there = rebase(template(), delta, 0).unwrap();
+            let back = rebase(there, -delta, 0).unwrap();
+            assert_eq!(serde_json::to_string_pretty(&back).unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn test_rebase_refuses_bad_deltas() {
+        let err = rebase(template(), -1001, 0).unwrap_err().to_string();
+        assert!(err.contains("rdma_base_pgoff 1000"), "{}", err);
+
+        let err = rebase(template(), -900, 128).unwrap_err().to_string();
+        assert!(err.contains("reserved pgoffs [0, 128)"), "{}", err);
+
+        let mut high = template();
+        high.rdma_base_pgoff = PageOffset(u64::max_value() - 10);
+        let err = rebase(high, 20, 0).unwrap_err().to_string();
+        assert!(err.contains("pgoff space"), "{}", err);
+
+        // Regions are checked too, not just the base.
+        let mut skewed = template();
+        skewed.regions[0].rdma_offset = PageOffset(10);
+        let err = rebase(skewed, -500, 0).unwrap_err().to_string();
+        assert!(err.contains("region 0 rdma_offset"), "{}", err);
+
+        let mut layered = template();
+        layered.base_template = Some("/srv/base.json".to_string());
+        let err = rebase(layered, 500, 0).unwrap_err().to_string();
+        assert!(err.contains("layered on /srv/base.json"), "{}", err);
+
+        let mut deduped = template();
+        deduped.regions[1].extents = vec![PgoffExtent {
+            offset: PAGE_SIZE,
+            size: PAGE_SIZE,
+            rdma_offset: PageOffset(200),
+        }];
+        let err = rebase(deduped, 500, 0).unwrap_err().to_string();
+        assert!(err.contains("region 1 maps pgoff 200 outside"), "{}", err);
+    }
+
+    #[test]
+    fn test_rebase_shifts_extents_within_the_image() {
+        let mut deduped = template();
+        // The second region's last page repeats the first page of the image.
+        deduped.regions[1].extents = vec![PgoffExtent {
+            offset: PAGE_SIZE,
+            size: PAGE_SIZE,
+            rdma_offset: PageOffset(1000),
+        }];
+        let rebased = rebase(deduped, 500, 0).unwrap();
+        assert_eq!(rebased.regions[1].extents[0].rdma_offset, PageOffset(1500));
+        assert_eq!(rebased.template_version, 2);
+    }
+
+    /// `template()` with its image split across pgoffs 1000 and 3000, the
+    /// second region in the second extent and crossing back into the first.
+    fn split_template() -> PseudoMmTemplate {
+        let mut split = template();
+        split.rdma_image_extents = vec![
+            ImageExtent {
+                pgoff: PageOffset(1000),
+                pages: 5,
+            },
+            ImageExtent {
+                pgoff: PageOffset(3000),
+                pages: 1,
+            },
+        ];
+        split.regions[1].rdma_offset = PageOffset(1004);
+        split.regions[1].extents = vec![PgoffExtent {
+            offset: PAGE_SIZE,
+            size: PAGE_SIZE,
+            rdma_offset: PageOffset(3000),
+        }];
+        split
+    }
+
+    #[test]
+    fn test_rebase_shifts_image_extents() {
+        let rebased = rebase(split_template(), 500, 0).unwrap();
+        assert_eq!(
+            rebased.rdma_image_extents,
+            vec![
+                ImageExtent {
+                    pgoff: PageOffset(1500),
+                    pages: 5,
+                },
+                ImageExtent {
+                    pgoff: PageOffset(3500),
+                    pages: 1,
+                },
+            ]
+        );
+        assert_eq!(rebased.regions[1].rdma_offset, PageOffset(1504));
+        assert_eq!(rebased.regions[1].extents[0].rdma_offset, PageOffset(3500));
+
+        // A move has to cover every extent.
+        let mv = |old_base_pgoff, pages| PgoffMove {
+            old_base_pgoff,
+            pages,
+            new_base_pgoff: 10_000,
+        };
+        assert!(move_for(&[mv(1000, 10)], &split_template()).is_none());
+        assert!(move_for(&[mv(1000, 2001)], &split_template()).is_some());
+    }
+
+    #[test]
+    fn test_parse_delta() {
+        assert_eq!(parse_delta("256").unwrap(), 256);
+        assert_eq!(parse_delta("+0x100").unwrap(), 256);
+        assert_eq!(parse_delta("-0x100").unwrap(), -256);
+        assert!(parse_delta("-").is_err());
+        assert_eq!(
+            parse_delta("-0xffffffffffffffff").unwrap(),
+            -i128::from(u64::max_value())
+        );
+        assert!(parse_delta("0x10000000000000000").is_err());
+    }
+
+    #[test]
+    fn test_rebase_high_pgoffs() {
+        // Deltas between pgoffs past 2^63 don't fit an i64.
+        let mv = PgoffMove {
+            old_base_pgoff: 1 << 63,
+            pages: 1 << 20,
+            new_base_pgoff: 0,
+        };
+        assert_eq!(mv.delta(), -(1i128 << 63));
+        let mut high = template();
+        high.rdma_base_pgoff = PageOffset(1 << 63);
+        high.regions[0].rdma_offset = PageOffset(1 << 63);
+        high.regions[1].rdma_offset = PageOffset((1 << 63) + 4);
+        let rebased = rebase(high, mv.delta(), 0).unwrap();
+        assert_eq!(rebased.rdma_base_pgoff, PageOffset(0));
+        assert_eq!(rebased.regions[1].rdma_offset, PageOffset(4));
+
+        let err = rebase(template(), i128::from(u64::max_value()), 0)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("without leaving the pgoff space"), "{}", err);
+    }
+
+    #[test]
+    fn test_move_for() {
+        let moves = vec![
+            PgoffMove {
+                old_base_pgoff: 0,
+                pages: 1000,
+                new_base_pgoff: 5000,
+            },
+            PgoffMove {
+                old_base_pgoff: 1000,
+                pages: 10,
+                new_base_pgoff: 0,
+            },
+        ];
+        let mv = move_for(&moves, &template()).unwrap();
+        assert_eq!(mv.delta(), -1000);
+
+        // An image straddling two moves has no single delta.
+        let mut large = template();
+        large.rdma_base_pgoff = PageOffset(995);
+        assert!(move_for(&moves, &large).is_none());
+    }
+}
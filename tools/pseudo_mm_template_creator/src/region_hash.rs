@@ -0,0 +1,430 @@
+//! Content hashes of the regions of a template.
+//!
+//! A template copied around or a server that loses pages leaves guests
+//! restored from it running on other memory than was snapshotted, which
+//! nothing notices. An upload from a memory file records the SHA-256 of
+//! each region's bytes in its `content_hashes`, one digest per
+//! `CHUNK_SIZE` bytes from the region's start, so they can be checked a
+//! chunk at a time. The bytes are hashed as they are streamed, after a
+//! chunk's images are handed to the socket and before their acks are
+//! waited for, so hashing overlaps the server's work and the file isn't
+//! read again; holes skipped without being read hash as zeros. Parallel
+//! streams split the image at chunk boundaries, so each hashes its own
+//! chunks.
+//!
+//! A region only gets hashes when all of its chunks were hashed, which
+//! leaves out layered and deduplicated templates, whose regions are partly
+//! other images, and images split across pgoff extents, read from stdin,
+//! merged from a diff snapshot, copied to DAX devices or not uploaded.
+//!
+//! `inspect-memory --verify-hashes` attaches a template read-only and
+//! re-hashes a random sample of its chunks, or all of them.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use vmm::pseudo_mm_support::{RegionHashes, RegionMetadata, PAGE_SIZE};
+
+use crate::sha256::Sha256;
+
+/// Bytes of a region hashed together.
+pub const CHUNK_SIZE: u64 = 2 << 20;
+
+/// Zeros hashed at a time for holes.
+static ZEROS: [u8; 64 << 10] = [0; 64 << 10];
+
+/// Hex SHA-256 of `data`.
+pub fn digest(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hex(&hasher.finish())
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Digests of the chunks an upload hashed, by `(region, chunk)`.
+pub type ChunkDigests = BTreeMap<(usize, u64), String>;
+
+/// A chunk hashed up to `at`.
+struct Partial {
+    /// Image offset the next byte must come from.
+    at: u64,
+    hasher: Sha256,
+}
+
+/// Hashes the chunks of regions from the image bytes handed to it.
+///
+/// Bytes of a chunk must come in order, but chunks may be interleaved;
+/// a chunk whose bytes skip or go back is dropped.
+pub struct ImageHasher {
+    /// `(image offset, size)` of each region.
+    ranges: Vec<(u64, u64)>,
+    partial: BTreeMap<(usize, u64), Partial>,
+    done: ChunkDigests,
+    /// Chunks seen out of order, never hashed.
+    broken: Vec<(usize, u64)>,
+    /// Digest of a whole chunk of zeros.
+    zero_chunk: Option<String>,
+}
+
+impl ImageHasher {
+    /// Hashes regions at the `(image offset, size)` of `ranges`.
+    pub fn new(ranges: &[(u64, u64)]) -> Self {
+        ImageHasher {
+            ranges: ranges.to_vec(),
+            partial: BTreeMap::new(),
+            done: BTreeMap::new(),
+            broken: Vec::new(),
+            zero_chunk: None,
+        }
+    }
+
+    /// Hashes `data`, the image from `offset` on.
+    pub fn update(&mut self, offset: u64, data: &[u8]) {
+        self.feed(offset, data.len() as u64, Some(data));
+    }
+
+    /// Hashes `len` zero bytes of the image from `offset` on.
+    pub fn zeros(&mut self, offset: u64, len: u64) {
+        self.feed(offset, len, None);
+    }
+
+    fn feed(&mut self, start: u64, len: u64, data: Option<&[u8]>) {
+        let end = start + len;
+        let mut offset = start;
+        while offset < end {
+            let found = self
+                .ranges
+                .iter()
+                .enumerate()
+                .find(|&(_, &(first, size))| offset >= first && offset < first + size);
+            let (region, first, size) = match found {
+                Some((region, &(first, size))) => (region, first, size),
+                None => {
+                    // Between regions: on to the next one.
+                    offset = self
+                        .ranges
+                        .iter()
+                        .map(|&(first, _)| first)
+                        .filter(|&first| first > offset)
+                        .min()
+                        .map_or(end, |first| std::cmp::min(first, end));
+                    continue;
+                }
+            };
+            let index = (offset - first) / CHUNK_SIZE;
+            let chunk_start = first + index * CHUNK_SIZE;
+            let chunk_end = std::cmp::min(chunk_start + CHUNK_SIZE, first + size);
+            let stop = std::cmp::min(chunk_end, end);
+            let key = (region, index);
+            let continues = self
+                .partial
+                .get(&key)
+                .map_or(false, |partial| partial.at == offset);
+            if !continues {
+                if self.partial.remove(&key).is_some()
+                    || offset != chunk_start
+                    || self.done.contains_key(&key)
+                {
+                    self.done.remove(&key);
+                    self.broken.push(key);
+                    offset = stop;
+                    continue;
+                }
+                if data.is_none() && stop == chunk_end && chunk_end - chunk_start == CHUNK_SIZE {
+                    let zero_chunk = self
+                        .zero_chunk
+                        .get_or_insert_with(|| digest(&vec![0u8; CHUNK_SIZE as usize]))
+                        .clone();
+                    self.done.insert(key, zero_chunk);
+                    offset = stop;
+                    continue;
+                }
+                self.partial.insert(
+                    key,
+                    Partial {
+                        at: offset,
+                        hasher: Sha256::new(),
+                    },
+                );
+            }
+            let partial = self.partial.get_mut(&key).expect("Chunk started above");
+            match data {
+                Some(data) => {
+                    let bytes = &data[(offset - start) as usize..(stop - start) as usize];
+                    partial.hasher.update(bytes);
+                }
+                None => {
+                    let mut left = stop - offset;
+                    while left > 0 {
+                        let len = std::cmp::min(left, ZEROS.len() as u64);
+                        partial.hasher.update(&ZEROS[..len as usize]);
+                        left -= len;
+                    }
+                }
+            }
+            partial.at = stop;
+            if stop == chunk_end {
+                let partial = self.partial.remove(&key).expect("Chunk started above");
+                self.done.insert(key, hex(&partial.hasher.finish()));
+            }
+            offset = stop;
+        }
+    }
+
+    /// The digests of the chunks hashed whole.
+    pub fn finish(self) -> ChunkDigests {
+        let mut done = self.done;
+        for key in &self.broken {
+            done.remove(key);
+        }
+        done
+    }
+}
+
+/// The hashes of each region at the `(image offset, size)` of `ranges`
+/// all of whose chunks are in `digests`, in region order.
+pub fn region_hashes(
+    ranges: &[(u64, u64)],
+    mut digests: ChunkDigests,
+) -> Vec<Option<RegionHashes>> {
+    ranges
+        .iter()
+        .enumerate()
+        .map(|(region, &(_, size))| {
+            let chunks = (size + CHUNK_SIZE - 1) / CHUNK_SIZE;
+            let sha256: Option<Vec<String>> = (0..chunks)
+                .map(|index| digests.remove(&(region, index)))
+                .collect();
+            sha256.map(|sha256| RegionHashes {
+                chunk_size: CHUNK_SIZE,
+                sha256,
+            })
+        })
+        .collect()
+}
+
+/// Moves the boundaries between the contiguous `slices` of an image down
+/// to the chunk boundaries of the regions at `ranges`, so each chunk is
+/// uploaded, and hashed, by one stream. Slices left empty are dropped.
+pub fn align_slices(slices: &[(u64, u64)], ranges: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let (start, end) = match (slices.first(), slices.last()) {
+        (Some(first), Some(last)) => (first.0, last.1),
+        _ => return Vec::new(),
+    };
+    let mut aligned = Vec::new();
+    let mut from = start;
+    for &(cut, _) in &slices[1..] {
+        let cut = match ranges
+            .iter()
+            .find(|&&(first, size)| cut > first && cut < first + size)
+        {
+            Some(&(first, _)) => first + (cut - first) / CHUNK_SIZE * CHUNK_SIZE,
+            None => cut,
+        };
+        if cut > from {
+            aligned.push((from, cut));
+            from = cut;
+        }
+    }
+    aligned.push((from, end));
+    aligned
+}
+
+/// How many chunks `--verify-hashes` re-hashes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashSample {
+    /// Random chunks, until they hold this many pages.
+    Pages(u64),
+    All,
+}
+
+impl FromStr for HashSample {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "all" => Ok(HashSample::All),
+            _ => match value.parse() {
+                Ok(pages) if pages > 0 => Ok(HashSample::Pages(pages)),
+                _ => Err(format!(
+                    "invalid --verify-hashes '{}': expected a number of pages or all",
+                    value
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for HashSample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashSample::Pages(pages) => write!(f, "{} pages", pages),
+            HashSample::All => f.write_str("all"),
+        }
+    }
+}
+
+/// `(region, chunk)` of the hashed chunks of `regions` to re-hash, sorted.
+///
+/// Samples are drawn with xorshift64 from `seed`, so a failure can be
+/// reproduced with the same seed.
+pub fn chunks_to_verify(
+    regions: &[RegionMetadata],
+    sample: HashSample,
+    seed: u64,
+) -> Vec<(usize, u64)> {
+    // `(region, chunk, pages)` of every hashed chunk.
+    let mut chunks: Vec<(usize, u64, u64)> = Vec::new();
+    for (idx, region) in regions.iter().enumerate() {
+        if let Some(hashes) = region.content_hashes.as_ref() {
+            for chunk in 0..hashes.sha256.len() as u64 {
+                let offset = chunk * hashes.chunk_size;
+                let len = std::cmp::min(hashes.chunk_size, region.size.saturating_sub(offset));
+                chunks.push((idx, chunk, len / PAGE_SIZE));
+            }
+        }
+    }
+    let budget = match sample {
+        HashSample::All => return chunks.iter().map(|&(idx, chunk, _)| (idx, chunk)).collect(),
+        HashSample::Pages(pages) => pages,
+    };
+
+    let mut rng = XorShift64::new(seed);
+    let mut picked = Vec::new();
+    let mut pages = 0;
+    while pages < budget && !chunks.is_empty() {
+        let at = rng.next_u64() % chunks.len() as u64;
+        let (idx, chunk, len) = chunks.swap_remove(at as usize);
+        picked.push((idx, chunk));
+        pages += len;
+    }
+    picked.sort();
+    picked
+}
+
+/// xorshift64, so that a seed picks the same sample from run to run without
+/// a dependency on a random number crate. Tests use it for generated inputs.
+#[derive(Clone, Debug)]
+pub(crate) struct XorShift64(u64);
+
+impl XorShift64 {
+    /// Starts from `seed`, made odd since xorshift never leaves zero.
+    pub(crate) fn new(seed: u64) -> Self {
+        XorShift64(seed | 1)
+    }
+
+    /// The next number of the sequence.
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_templates;
+
+    fn image(len: u64) -> Vec<u8> {
+        (0..len).map(|at| (at / PAGE_SIZE % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_hash_regions() {
+        // A region of two and a half chunks, a page of padding, and a region
+        // of half a chunk that is a hole of the file.
+        let first = CHUNK_SIZE * 5 / 2;
+        let ranges = [(0, first), (first + PAGE_SIZE, CHUNK_SIZE / 2)];
+        let data = image(first + PAGE_SIZE);
+        let mut hasher = ImageHasher::new(&ranges);
+        // Out of chunk order, each chunk's bytes in order.
+        hasher.update(CHUNK_SIZE, &data[CHUNK_SIZE as usize..]);
+        hasher.update(0, &data[..CHUNK_SIZE as usize]);
+        hasher.zeros(first + PAGE_SIZE, CHUNK_SIZE / 2);
+        let hashes = region_hashes(&ranges, hasher.finish());
+
+        let expected = |from: u64, to: u64| digest(&data[from as usize..to as usize]);
+        assert_eq!(
+            hashes[0],
+            Some(RegionHashes {
+                chunk_size: CHUNK_SIZE,
+                sha256: vec![
+                    expected(0, CHUNK_SIZE),
+                    expected(CHUNK_SIZE, 2 * CHUNK_SIZE),
+                    expected(2 * CHUNK_SIZE, first),
+                ],
+            })
+        );
+        let zeros = vec![0u8; (CHUNK_SIZE / 2) as usize];
+        assert_eq!(hashes[1].as_ref().unwrap().sha256, vec![digest(&zeros)]);
+    }
+
+    #[test]
+    fn test_hash_incomplete_regions() {
+        let ranges = [(0, 2 * CHUNK_SIZE), (2 * CHUNK_SIZE, CHUNK_SIZE)];
+        let data = image(3 * CHUNK_SIZE);
+        let mut hasher = ImageHasher::new(&ranges);
+        // The first region's second chunk starts mid-way; the second region
+        // is sent twice.
+        hasher.update(0, &data[..CHUNK_SIZE as usize]);
+        hasher.update(CHUNK_SIZE + PAGE_SIZE, &data[..PAGE_SIZE as usize]);
+        hasher.update(2 * CHUNK_SIZE, &data[..CHUNK_SIZE as usize]);
+        hasher.update(2 * CHUNK_SIZE, &data[..CHUNK_SIZE as usize]);
+        let digests = hasher.finish();
+        assert_eq!(digests.keys().collect::<Vec<_>>(), vec![&(0, 0)]);
+        assert_eq!(region_hashes(&ranges, digests), vec![None, None]);
+    }
+
+    #[test]
+    fn test_align_slices() {
+        let ranges = [(0, 5 * CHUNK_SIZE), (5 * CHUNK_SIZE, 2 * CHUNK_SIZE)];
+        let third = 7 * CHUNK_SIZE / 3;
+        let slices = [(0, third), (third, 2 * third), (2 * third, 7 * CHUNK_SIZE)];
+        assert_eq!(
+            align_slices(&slices, &ranges),
+            vec![
+                (0, 2 * CHUNK_SIZE),
+                (2 * CHUNK_SIZE, 4 * CHUNK_SIZE),
+                (4 * CHUNK_SIZE, 7 * CHUNK_SIZE),
+            ]
+        );
+        // Slices within one chunk collapse into one.
+        let slices = [(0, PAGE_SIZE), (PAGE_SIZE, 2 * PAGE_SIZE)];
+        assert_eq!(align_slices(&slices, &ranges), vec![(0, 2 * PAGE_SIZE)]);
+    }
+
+    #[test]
+    fn test_chunks_to_verify() {
+        let region = |chunks: Option<usize>| RegionMetadata {
+            size: 3 * CHUNK_SIZE,
+            content_hashes: chunks.map(|chunks| RegionHashes {
+                chunk_size: CHUNK_SIZE,
+                sha256: vec![String::new(); chunks],
+            }),
+            ..test_templates::region(0, 0, 0)
+        };
+        let regions = [region(Some(3)), region(None), region(Some(3))];
+        assert_eq!(
+            chunks_to_verify(&regions, HashSample::All, 1),
+            vec![(0, 0), (0, 1), (0, 2), (2, 0), (2, 1), (2, 2)]
+        );
+        let picked = chunks_to_verify(&regions, HashSample::Pages(600), 7);
+        assert_eq!(picked.len(), 2);
+        assert!(picked.iter().all(|&(idx, chunk)| idx != 1 && chunk < 3));
+        assert_eq!(chunks_to_verify(&regions, HashSample::Pages(1), 7).len(), 1);
+    }
+
+    #[test]
+    fn test_parse_hash_sample() {
+        assert_eq!("all".parse(), Ok(HashSample::All));
+        assert_eq!("256".parse(), Ok(HashSample::Pages(256)));
+        assert!("0".parse::<HashSample>().is_err());
+        assert!("some".parse::<HashSample>().is_err());
+    }
+}
@@ -0,0 +1,1099 @@
+//! Region planning for pseudo_mm templates.
+//!
+//! Every planned region becomes one mapping (VMA) in the pseudo_mm instance
+//! and, after attach, in the restored process. Fragmented snapshots can need
+//! more mappings than `vm.max_map_count` leaves room for, which otherwise
+//! fails partway through setup with ENOMEM. Regions are therefore planned and
+//! counted before anything is uploaded, and adjacent regions can optionally be
+//! coalesced to reduce the count.
+
+use std::io;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use vmm::memory_snapshot::GuestMemoryRegionState;
+use vmm::pseudo_mm_addr::{Gpa, HvaAddr, PageOffset};
+use vmm::pseudo_mm_support::{PageSize, RegionMetadata, ZeroRange, PAGE_SIZE};
+
+/// Fraction of the available mappings above which a warning is printed.
+const MAP_COUNT_WARN_PERCENT: usize = 50;
+
+/// A run of the memory file that lands on consecutive pages of the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageWindow {
+    /// Where the run starts in the memory file.
+    pub file_offset: u64,
+    pub size: u64,
+    /// Where the run starts in the image, whose first page is at the
+    /// template's `rdma_pgoff`.
+    pub image_offset: u64,
+}
+
+impl ImageWindow {
+    pub fn image_end(&self) -> u64 {
+        self.image_offset + self.size
+    }
+}
+
+/// Offsets in the image of the regions of `states`, in `states` order.
+///
+/// Regions are packed back to back in file order, leaving out any padding
+/// between them in the memory file, so the image takes no more pages on the
+/// server than the guest has memory. A file without padding keeps every
+/// region at its file offset.
+pub fn image_offsets(states: &[GuestMemoryRegionState]) -> Vec<u64> {
+    let mut by_offset: Vec<usize> = (0..states.len()).collect();
+    by_offset.sort_by_key(|&idx| states[idx].offset);
+    let mut offsets = vec![0; states.len()];
+    let mut packed = 0;
+    for idx in by_offset {
+        offsets[idx] = packed;
+        packed += states[idx].size as u64;
+    }
+    offsets
+}
+
+/// The runs of the memory file making up the image of `states`, in image
+/// order. Regions adjacent in the file share a run.
+pub fn image_windows(states: &[GuestMemoryRegionState]) -> Vec<ImageWindow> {
+    let offsets = image_offsets(states);
+    let mut windows: Vec<ImageWindow> = states
+        .iter()
+        .zip(offsets)
+        .filter(|(state, _)| state.size > 0)
+        .map(|(state, image_offset)| ImageWindow {
+            file_offset: state.offset,
+            size: state.size as u64,
+            image_offset,
+        })
+        .collect();
+    windows.sort_by_key(|window| window.image_offset);
+    let mut merged: Vec<ImageWindow> = Vec::with_capacity(windows.len());
+    for window in windows {
+        if let Some(last) = merged.last_mut() {
+            if last.file_offset + last.size == window.file_offset {
+                last.size += window.size;
+                continue;
+            }
+        }
+        merged.push(window);
+    }
+    merged
+}
+
+/// The parts of `windows` covering image bytes `[start, end)`.
+pub fn windows_within(windows: &[ImageWindow], (start, end): (u64, u64)) -> Vec<ImageWindow> {
+    windows
+        .iter()
+        .filter_map(|window| {
+            let from = std::cmp::max(window.image_offset, start);
+            let to = std::cmp::min(window.image_end(), end);
+            if from >= to {
+                return None;
+            }
+            Some(ImageWindow {
+                file_offset: window.file_offset + (from - window.image_offset),
+                size: to - from,
+                image_offset: from,
+            })
+        })
+        .collect()
+}
+
+/// Computes the pseudo_mm regions for a snapshot's guest memory layout,
+/// mapped with `page_size` pages. Region pgoffs follow `image_offsets`.
+///
+/// Fails if a region would end past the end of HVA or pgoff space, rather
+/// than wrapping around to address 0.
+pub fn plan_regions(
+    states: &[GuestMemoryRegionState],
+    hva_base: HvaAddr,
+    rdma_pgoff: PageOffset,
+    page_size: PageSize,
+) -> io::Result<Vec<RegionMetadata>> {
+    let page = page_size.bytes();
+    let image_offsets = image_offsets(states);
+    let mut regions = Vec::with_capacity(states.len());
+    for (state, image_offset) in states.iter().zip(image_offsets) {
+        let size = state.size as u64;
+        if size % page != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "region size 0x{:x} is not page aligned (page size {})",
+                    size, page_size
+                ),
+            ));
+        }
+        if state.offset % page != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "region offset {} is not page aligned (page size {})",
+                    state.offset, page_size
+                ),
+            ));
+        }
+        if state.base_address % page != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "region gpa 0x{:x} is not page aligned (page size {})",
+                    state.base_address, page_size
+                ),
+            ));
+        }
+
+        let gpa = Gpa(state.base_address);
+        let hva = match hva_base.raw().checked_add(gpa.raw()) {
+            Some(hva) if hva.checked_add(size).is_some() => hva,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "region at gpa {} overflows HVA space: hva_base {} + gpa {} + size 0x{:x}",
+                        gpa, hva_base, gpa, size
+                    ),
+                ))
+            }
+        };
+        let pgoff = match rdma_pgoff.raw().checked_add(image_offset / PAGE_SIZE) {
+            Some(pgoff) if pgoff.checked_add(size / PAGE_SIZE).is_some() => pgoff,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "region at gpa {} overflows pgoff space: rdma_pgoff {} + {} + {} pages",
+                        gpa,
+                        rdma_pgoff,
+                        image_offset / PAGE_SIZE,
+                        size / PAGE_SIZE
+                    ),
+                ))
+            }
+        };
+        regions.push(RegionMetadata {
+            gpa,
+            hva: HvaAddr(hva),
+            size,
+            rdma_offset: PageOffset(pgoff),
+            page_size,
+            zero_ranges: Vec::new(),
+            extents: Vec::new(),
+            content_hashes: None,
+        });
+    }
+    Ok(regions)
+}
+
+/// An explicit HVA for the region at `gpa`, given as `GPA=HVA` on the
+/// command line.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct RegionHva {
+    pub gpa: Gpa,
+    pub hva: HvaAddr,
+}
+
+impl FromStr for RegionHva {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(gpa), Some(hva)) => Ok(RegionHva {
+                gpa: gpa.parse().map_err(|err| format!("gpa: {}", err))?,
+                hva: hva.parse().map_err(|err| format!("hva: {}", err))?,
+            }),
+            _ => Err(format!("invalid value '{}': expected GPA=HVA", s)),
+        }
+    }
+}
+
+/// Where regions go in the restored process when `hva_base + gpa` won't do,
+/// e.g. for templates attached into the same process, which all have a
+/// region at GPA 0.
+#[derive(Clone, Debug, Default)]
+pub struct HvaLayout {
+    /// Places region `i`, in snapshot order, at `hva_base + i * stride`.
+    pub stride: Option<u64>,
+    /// Explicit HVAs, applied after the stride.
+    pub overrides: Vec<RegionHva>,
+}
+
+/// Moves planned `regions` to the HVAs `layout` asks for.
+///
+/// Every HVA must be aligned to `page_size`, and every override must name
+/// the GPA a region starts at. Overlaps are left to `check_hva_overlap`.
+pub fn place_hvas(
+    regions: &mut [RegionMetadata],
+    hva_base: HvaAddr,
+    layout: &HvaLayout,
+    page_size: PageSize,
+) -> io::Result<()> {
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    let page = page_size.bytes();
+
+    if let Some(stride) = layout.stride {
+        if stride == 0 || stride % page != 0 {
+            return invalid(format!(
+                "region stride 0x{:x} is not a non-zero multiple of the page size ({})",
+                stride, page_size
+            ));
+        }
+        for (idx, region) in regions.iter_mut().enumerate() {
+            let hva = (idx as u64)
+                .checked_mul(stride)
+                .and_then(|offset| hva_base.raw().checked_add(offset));
+            region.hva = match hva {
+                Some(hva) => HvaAddr(hva),
+                None => {
+                    return invalid(format!(
+                        "region {} does not fit in HVA space with stride 0x{:x}",
+                        idx, stride
+                    ))
+                }
+            };
+        }
+    }
+
+    for (idx, entry) in layout.overrides.iter().enumerate() {
+        if layout.overrides[..idx]
+            .iter()
+            .any(|other| other.gpa == entry.gpa)
+        {
+            return invalid(format!("gpa {} has more than one HVA override", entry.gpa));
+        }
+        if entry.hva.raw() % page != 0 {
+            return invalid(format!(
+                "HVA override {} for gpa {} is not aligned to the page size ({})",
+                entry.hva, entry.gpa, page_size
+            ));
+        }
+        match regions.iter_mut().find(|region| region.gpa == entry.gpa) {
+            Some(region) => region.hva = entry.hva,
+            None => {
+                return invalid(format!(
+                    "HVA override names gpa {}, but no region starts there",
+                    entry.gpa
+                ))
+            }
+        }
+    }
+
+    for region in regions.iter() {
+        if region.hva.raw().checked_add(region.size).is_none() {
+            return invalid(format!(
+                "region at gpa {} overflows HVA space: HVA {} + size 0x{:x}",
+                region.gpa, region.hva, region.size
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Fails if two regions share host virtual addresses, which attach can't map.
+pub fn check_hva_overlap(regions: &[RegionMetadata]) -> io::Result<()> {
+    let mut by_hva: Vec<(u64, u64, usize)> = regions
+        .iter()
+        .enumerate()
+        .map(|(idx, region)| {
+            let start = region.hva.raw();
+            (start, start.saturating_add(region.size), idx)
+        })
+        .collect();
+    by_hva.sort();
+    for pair in by_hva.windows(2) {
+        let ((prev_start, prev_end, prev), (start, end, idx)) = (pair[0], pair[1]);
+        if start < prev_end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "regions {} and {} overlap in HVA space: [0x{:x}, 0x{:x}) and [0x{:x}, 0x{:x})",
+                    prev, idx, prev_start, prev_end, start, end
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A mapping of the current process, as listed in `/proc/self/maps`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessMapping {
+    pub start: u64,
+    pub end: u64,
+    /// Path or pseudo-path such as `[heap]`; "anonymous" if it has none.
+    pub name: String,
+}
+
+/// Parses the contents of a `/proc/<pid>/maps` file, skipping lines it
+/// can't read.
+pub fn parse_maps(maps: &str) -> Vec<ProcessMapping> {
+    maps.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let range = fields.next()?;
+            let dash = range.find('-')?;
+            let start = u64::from_str_radix(&range[..dash], 16).ok()?;
+            let end = u64::from_str_radix(&range[dash + 1..], 16).ok()?;
+            // perms, offset, dev and inode come before the path.
+            let name = fields.nth(4).unwrap_or("anonymous").to_string();
+            Some(ProcessMapping { start, end, name })
+        })
+        .collect()
+}
+
+/// Reads the mappings of the current process.
+pub fn process_maps() -> io::Result<Vec<ProcessMapping>> {
+    std::fs::read_to_string("/proc/self/maps").map(|maps| parse_maps(&maps))
+}
+
+/// Lowest address a process may map, from `vm.mmap_min_addr`.
+pub fn mmap_min_addr() -> u64 {
+    std::fs::read_to_string("/proc/sys/vm/mmap_min_addr")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(PAGE_SIZE)
+}
+
+/// Checks that every region's `[hva, hva + size)` is page aligned and lies
+/// in user space, `[min_addr, limit)`, and with `maps` that it is clear of
+/// the process's own mappings, which an instance created here would collide
+/// with.
+///
+/// Fails listing every offending region, naming the mapping it hits.
+pub fn check_hva_ranges(
+    regions: &[RegionMetadata],
+    min_addr: u64,
+    limit: u64,
+    maps: Option<&[ProcessMapping]>,
+) -> io::Result<()> {
+    let mut problems = Vec::new();
+    for (idx, region) in regions.iter().enumerate() {
+        let start = region.hva.raw();
+        let end = start.saturating_add(region.size);
+        let range = format!("region {}: hva [0x{:x}, 0x{:x})", idx, start, end);
+        if start % PAGE_SIZE != 0 {
+            problems.push(format!("{} is not page aligned", range));
+        }
+        if start < min_addr || end > limit {
+            problems.push(format!(
+                "{} is outside the {}-bit user address space [0x{:x}, 0x{:x})",
+                range,
+                64 - (limit - 1).leading_zeros(),
+                min_addr,
+                limit
+            ));
+            continue;
+        }
+        let hit = maps
+            .unwrap_or(&[])
+            .iter()
+            .find(|mapping| mapping.start < end && start < mapping.end);
+        if let Some(mapping) = hit {
+            problems.push(format!(
+                "{} overlaps {} [0x{:x}, 0x{:x}) of this process",
+                range, mapping.name, mapping.start, mapping.end
+            ));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unusable hva ranges:\n  {}", problems.join("\n  ")),
+    ))
+}
+
+/// Checks that a memory file of `mem_size` bytes matches the snapshot's
+/// region layout: every region lies within the file, no two regions share
+/// file bytes, and the file ends where the last region does.
+///
+/// A mismatched snapshot and memory file would otherwise upload fine and
+/// restore into a broken guest.
+pub fn check_layout(states: &[GuestMemoryRegionState], mem_size: u64) -> io::Result<()> {
+    let mismatch = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("memory file does not match the snapshot: {}", msg),
+        ))
+    };
+
+    let mut by_offset = Vec::with_capacity(states.len());
+    for (idx, state) in states.iter().enumerate() {
+        let end = match state.offset.checked_add(state.size as u64) {
+            Some(end) if end <= mem_size => end,
+            _ => {
+                return mismatch(format!(
+                    "region {} (gpa 0x{:x}) needs bytes {}..{} but the file is {} bytes",
+                    idx,
+                    state.base_address,
+                    state.offset,
+                    u128::from(state.offset) + state.size as u128,
+                    mem_size
+                ))
+            }
+        };
+        by_offset.push((state.offset, end, idx));
+    }
+    by_offset.sort();
+    for pair in by_offset.windows(2) {
+        let ((_, prev_end, prev), (start, _, idx)) = (pair[0], pair[1]);
+        if start < prev_end {
+            return mismatch(format!(
+                "regions {} and {} overlap at file bytes {}..{}",
+                prev, idx, start, prev_end
+            ));
+        }
+    }
+
+    let (expected, last) = by_offset
+        .iter()
+        .map(|&(_, end, idx)| (end, Some(idx)))
+        .max()
+        .unwrap_or((0, None));
+    if mem_size != expected {
+        let after = match last {
+            Some(idx) => format!(" (region {} ends the layout)", idx),
+            None => String::new(),
+        };
+        return mismatch(format!(
+            "expected {} bytes but the file is {} bytes{}",
+            expected, mem_size, after
+        ));
+    }
+    Ok(())
+}
+
+/// Merges regions that are contiguous in GPA, HVA and RDMA page offset.
+///
+/// Merging only joins a region onto the one ending exactly where it starts in
+/// all three spaces, so every page keeps its GPA, HVA and pgoff.
+pub fn coalesce(mut regions: Vec<RegionMetadata>) -> Vec<RegionMetadata> {
+    regions.sort_by_key(|region| region.gpa);
+    let mut merged: Vec<RegionMetadata> = Vec::with_capacity(regions.len());
+    for region in regions {
+        if let Some(last) = merged.last_mut() {
+            let contiguous = last.page_size == region.page_size
+                && last.gpa.raw() + last.size == region.gpa.raw()
+                && last.hva.raw() + last.size == region.hva.raw()
+                && last.rdma_offset.raw() + last.size / PAGE_SIZE == region.rdma_offset.raw();
+            if contiguous {
+                for zero in region.zero_ranges {
+                    let offset = last.size + zero.offset;
+                    match last.zero_ranges.last_mut() {
+                        Some(prev) if prev.offset + prev.size == offset => prev.size += zero.size,
+                        _ => last.zero_ranges.push(ZeroRange {
+                            offset,
+                            size: zero.size,
+                        }),
+                    }
+                }
+                last.size += region.size;
+                continue;
+            }
+        }
+        merged.push(region);
+    }
+    merged
+}
+
+/// Records the image's zero pages in the regions covering them.
+///
+/// `zero_pages` are sorted page runs `(page, pages)` relative to the start of
+/// the image, which is at `rdma_pgoff`.
+pub fn assign_zero_pages(
+    regions: &mut [RegionMetadata],
+    rdma_pgoff: PageOffset,
+    zero_pages: &[(u64, u64)],
+) {
+    for region in regions {
+        let first = region.rdma_offset.raw() - rdma_pgoff.raw();
+        let end = first + region.size / PAGE_SIZE;
+        region.zero_ranges = zero_pages
+            .iter()
+            .filter_map(|&(page, pages)| {
+                let start = std::cmp::max(page, first);
+                let stop = std::cmp::min(page + pages, end);
+                if start >= stop {
+                    return None;
+                }
+                Some(ZeroRange {
+                    offset: (start - first) * PAGE_SIZE,
+                    size: (stop - start) * PAGE_SIZE,
+                })
+            })
+            .collect();
+    }
+}
+
+/// How many more mappings the current process may create.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MapBudget {
+    pub max_map_count: usize,
+    pub current: usize,
+}
+
+impl MapBudget {
+    /// Reads `vm.max_map_count` and the process's current mappings from /proc.
+    pub fn probe() -> Option<Self> {
+        let max_map_count = std::fs::read_to_string("/proc/sys/vm/max_map_count")
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        let current = std::fs::read_to_string("/proc/self/maps")
+            .ok()?
+            .lines()
+            .count();
+        Some(MapBudget {
+            max_map_count,
+            current,
+        })
+    }
+
+    pub fn available(&self) -> usize {
+        self.max_map_count.saturating_sub(self.current)
+    }
+}
+
+/// Outcome of checking a planned mapping count against the budget.
+#[derive(Debug, PartialEq)]
+pub enum MapCountCheck {
+    Ok,
+    /// Fits, but uses a large share of the remaining mappings.
+    Warn(String),
+}
+
+/// Checks `planned` mappings against `budget`, failing if they cannot fit.
+pub fn check_map_count(
+    planned: usize,
+    budget: &MapBudget,
+    coalesced: bool,
+) -> io::Result<MapCountCheck> {
+    let available = budget.available();
+    let guidance = if coalesced {
+        "raise vm.max_map_count"
+    } else {
+        "retry with --coalesce-regions or raise vm.max_map_count"
+    };
+
+    if planned > available {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "template needs {} mappings but only {} are available \
+                 (vm.max_map_count={}, {} in use); {}",
+                planned, available, budget.max_map_count, budget.current, guidance
+            ),
+        ));
+    }
+    if planned * 100 > available * MAP_COUNT_WARN_PERCENT {
+        return Ok(MapCountCheck::Warn(format!(
+            "template needs {} of {} available mappings; {}",
+            planned, available, guidance
+        )));
+    }
+    Ok(MapCountCheck::Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region_hash::XorShift64;
+    use std::collections::BTreeMap;
+
+    fn state(base_address: u64, pages: u64, offset_pages: u64) -> GuestMemoryRegionState {
+        GuestMemoryRegionState {
+            base_address,
+            size: (pages * PAGE_SIZE) as usize,
+            offset: offset_pages * PAGE_SIZE,
+        }
+    }
+
+    /// Page-level view of a region list: GPA page -> (HVA page, pgoff).
+    fn flatten(regions: &[RegionMetadata]) -> BTreeMap<u64, (u64, u64)> {
+        let mut pages = BTreeMap::new();
+        for region in regions {
+            for page in 0..region.size / PAGE_SIZE {
+                let previous = pages.insert(
+                    region.gpa.raw() / PAGE_SIZE + page,
+                    (
+                        region.hva.raw() / PAGE_SIZE + page,
+                        region.rdma_offset.raw() + page,
+                    ),
+                );
+                assert!(previous.is_none(), "regions overlap");
+            }
+        }
+        pages
+    }
+
+    #[test]
+    fn test_plan_regions() {
+        let hva_base = HvaAddr(0x7000_0000_0000);
+        let regions = plan_regions(
+            &[state(0, 4, 0), state(0x10_0000, 2, 4)],
+            hva_base,
+            PageOffset(100),
+            PageSize::Base,
+        )
+        .unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[1].gpa, Gpa(0x10_0000));
+        assert_eq!(regions[1].hva, HvaAddr(0x7000_0010_0000));
+        assert_eq!(regions[1].rdma_offset, PageOffset(104));
+
+        let unaligned = GuestMemoryRegionState {
+            base_address: 0,
+            size: 100,
+            offset: 0,
+        };
+        assert!(plan_regions(&[unaligned], hva_base, PageOffset(0), PageSize::Base).is_err());
+
+        // Near the top of either space, regions fail instead of wrapping.
+        let layout = [state(0, 4, 0), state(0x10_0000, 4, 4)];
+        let err = plan_regions(&layout, hva_base, PageOffset(u64::MAX - 6), PageSize::Base)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "gpa 0x100000 overflows pgoff space: rdma_pgoff 18446744073709551609 + 4 + 4"
+            ),
+            "{}",
+            err
+        );
+        plan_regions(&layout, hva_base, PageOffset(u64::MAX - 8), PageSize::Base).unwrap();
+        let err = plan_regions(
+            &layout,
+            HvaAddr(u64::MAX - 0xfffff),
+            PageOffset(0),
+            PageSize::Base,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("region at gpa 0x100000 overflows HVA space"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_plan_huge_regions() {
+        let hva_base = HvaAddr(0x7000_0000_0000);
+        let regions = plan_regions(
+            &[state(0, 1024, 0), state(0x4000_0000, 512, 1024)],
+            hva_base,
+            PageOffset(512),
+            PageSize::Huge,
+        )
+        .unwrap();
+        assert_eq!(regions[1].rdma_offset, PageOffset(1536));
+        assert!(regions
+            .iter()
+            .all(|region| region.page_size == PageSize::Huge));
+
+        // Fine with 4k pages, not with 2m ones.
+        let err = plan_regions(
+            &[state(0, 1024, 4)],
+            hva_base,
+            PageOffset(0),
+            PageSize::Huge,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("region offset 16384"), "{}", err);
+        assert!(err.contains("page size 2m"), "{}", err);
+        let err = plan_regions(
+            &[state(0x1000, 512, 0)],
+            hva_base,
+            PageOffset(0),
+            PageSize::Huge,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("gpa 0x1000"), "{}", err);
+    }
+
+    #[test]
+    fn test_check_layout() {
+        let layout = [state(0, 4, 0), state(0x10_0000, 2, 4)];
+        assert!(check_layout(&layout, 6 * PAGE_SIZE).is_ok());
+
+        let err = |states: &[GuestMemoryRegionState], size| {
+            let err = check_layout(states, size).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            err.to_string()
+        };
+        // Truncated file.
+        let msg = err(&layout, 5 * PAGE_SIZE);
+        assert!(
+            msg.contains(
+                "region 1 (gpa 0x100000) needs bytes 16384..24576 but the file is 20480 bytes"
+            ),
+            "{}",
+            msg
+        );
+        // File from a bigger VM.
+        let msg = err(&layout, 8 * PAGE_SIZE);
+        assert!(
+            msg.contains("expected 24576 bytes but the file is 32768 bytes (region 1 ends"),
+            "{}",
+            msg
+        );
+        // Regions sharing file bytes, listed out of order.
+        let msg = err(&[state(0x10_0000, 2, 3), state(0, 4, 0)], 5 * PAGE_SIZE);
+        assert!(
+            msg.contains("regions 1 and 0 overlap at file bytes 12288..16384"),
+            "{}",
+            msg
+        );
+        let overflow = GuestMemoryRegionState {
+            base_address: 0,
+            size: PAGE_SIZE as usize,
+            offset: u64::max_value(),
+        };
+        assert!(err(&[overflow], PAGE_SIZE).contains("region 0"));
+        assert!(check_layout(&[], 0).is_ok());
+        assert!(err(&[], PAGE_SIZE).contains("expected 0 bytes"));
+    }
+
+    #[test]
+    fn test_coalesce_merges_only_contiguous() {
+        let hva_base = HvaAddr(0x7000_0000_0000);
+        let regions = plan_regions(
+            &[
+                // Contiguous in GPA and file offset: merged.
+                state(0, 4, 0),
+                state(4 * PAGE_SIZE, 4, 4),
+                // GPA gap: kept separate.
+                state(16 * PAGE_SIZE, 2, 8),
+                // Contiguous GPA, but before the previous region in the
+                // image: kept separate.
+                state(18 * PAGE_SIZE, 2, 1024),
+                state(20 * PAGE_SIZE, 2, 20),
+            ],
+            hva_base,
+            PageOffset(0),
+            PageSize::Base,
+        )
+        .unwrap();
+
+        let merged = coalesce(regions.clone());
+        assert_eq!(merged.len(), 4);
+        assert_eq!(merged[0].size, 8 * PAGE_SIZE);
+        assert_eq!(flatten(&merged), flatten(&regions));
+    }
+
+    #[test]
+    fn test_plan_regions_packs_file_holes() {
+        // Padding after the first region and between the other two, which
+        // are listed out of file order.
+        let states = [
+            state(0, 4, 0),
+            state(0x20_0000, 2, 16),
+            state(0x10_0000, 4, 8),
+        ];
+        assert!(check_layout(&states, 18 * PAGE_SIZE).is_ok());
+        assert_eq!(
+            image_offsets(&states),
+            vec![0, 8 * PAGE_SIZE, 4 * PAGE_SIZE]
+        );
+        let regions = plan_regions(
+            &states,
+            HvaAddr(0x7000_0000_0000),
+            PageOffset(100),
+            PageSize::Base,
+        )
+        .unwrap();
+        let pgoffs: Vec<u64> = regions
+            .iter()
+            .map(|region| region.rdma_offset.raw())
+            .collect();
+        assert_eq!(pgoffs, vec![100, 108, 104]);
+
+        let window = |file_page: u64, pages: u64, image_page: u64| ImageWindow {
+            file_offset: file_page * PAGE_SIZE,
+            size: pages * PAGE_SIZE,
+            image_offset: image_page * PAGE_SIZE,
+        };
+        let windows = image_windows(&states);
+        assert_eq!(
+            windows,
+            vec![window(0, 4, 0), window(8, 4, 4), window(16, 2, 8)]
+        );
+        assert_eq!(
+            windows_within(&windows, (3 * PAGE_SIZE, 9 * PAGE_SIZE)),
+            vec![window(3, 1, 3), window(8, 4, 4), window(16, 1, 8)]
+        );
+        // A file without padding is a single run at its own offsets.
+        assert_eq!(
+            image_windows(&[state(0x10_0000, 2, 4), state(0, 4, 0)]),
+            vec![window(0, 6, 0)]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_is_lossless() {
+        let mut rng = XorShift64::new(0x2545_f491_4f6c_dd1d);
+        let mut next = move || rng.next_u64();
+
+        for _ in 0..200 {
+            let mut states = Vec::new();
+            let mut gpa_page = 0;
+            let mut offset_page = 0;
+            for _ in 0..(next() % 16 + 1) {
+                let pages = next() % 8 + 1;
+                states.push(state(gpa_page * PAGE_SIZE, pages, offset_page));
+                // Sometimes leave a gap in GPA or in the file layout.
+                gpa_page += pages + if next() % 3 == 0 { next() % 4 } else { 0 };
+                offset_page += pages + if next() % 3 == 0 { next() % 4 } else { 0 };
+            }
+            // The snapshot's region order must not matter.
+            let len = states.len();
+            for i in 0..len {
+                states.swap(i, (next() % len as u64) as usize);
+            }
+
+            let regions = plan_regions(
+                &states,
+                HvaAddr(0x7000_0000_0000),
+                PageOffset(7),
+                PageSize::Base,
+            )
+            .unwrap();
+            let merged = coalesce(regions.clone());
+            assert!(merged.len() <= regions.len());
+            assert_eq!(flatten(&merged), flatten(&regions));
+            // Nothing mergeable is left.
+            assert_eq!(coalesce(merged.clone()).len(), merged.len());
+        }
+    }
+
+    #[test]
+    fn test_assign_zero_pages() {
+        let mut regions = plan_regions(
+            // The file gap is left out, so the second region starts at page
+            // 4 of the image.
+            &[
+                state(0, 4, 0),
+                state(0x10_0000, 4, 6),
+                state(0x20_0000, 2, 10),
+            ],
+            HvaAddr(0x7000_0000_0000),
+            PageOffset(100),
+            PageSize::Base,
+        )
+        .unwrap();
+        // Runs spanning region boundaries.
+        assign_zero_pages(&mut regions, PageOffset(100), &[(0, 1), (3, 2), (7, 3)]);
+        let zero = |offset: u64, pages: u64| ZeroRange {
+            offset: offset * PAGE_SIZE,
+            size: pages * PAGE_SIZE,
+        };
+        assert_eq!(regions[0].zero_ranges, vec![zero(0, 1), zero(3, 1)]);
+        assert_eq!(regions[1].zero_ranges, vec![zero(0, 1), zero(3, 1)]);
+        // Entirely zero.
+        assert_eq!(regions[2].zero_ranges, vec![zero(0, 2)]);
+        assert!(regions[2].populated_ranges().is_empty());
+
+        // Coalescing keeps every zero page where it was.
+        let mut regions = plan_regions(
+            &[state(0, 4, 0), state(4 * PAGE_SIZE, 4, 4)],
+            HvaAddr(0),
+            PageOffset(0),
+            PageSize::Base,
+        )
+        .unwrap();
+        assign_zero_pages(&mut regions, PageOffset(0), &[(2, 4), (7, 1)]);
+        let merged = coalesce(regions);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].zero_ranges, vec![zero(2, 4), zero(7, 1)]);
+    }
+
+    #[test]
+    fn test_place_hvas() {
+        let hva_base = HvaAddr(0x7000_0000_0000);
+        let plan = || {
+            plan_regions(
+                &[state(0, 4, 0), state(0x10_0000, 4, 4)],
+                hva_base,
+                PageOffset(0),
+                PageSize::Base,
+            )
+            .unwrap()
+        };
+        let layout = |stride, overrides: &[(u64, u64)]| HvaLayout {
+            stride,
+            overrides: overrides
+                .iter()
+                .map(|&(gpa, hva)| RegionHva {
+                    gpa: Gpa(gpa),
+                    hva: HvaAddr(hva),
+                })
+                .collect(),
+        };
+
+        let mut regions = plan();
+        place_hvas(
+            &mut regions,
+            hva_base,
+            &layout(Some(1 << 30), &[]),
+            PageSize::Base,
+        )
+        .unwrap();
+        assert_eq!(regions[0].hva, hva_base);
+        assert_eq!(regions[1].hva, HvaAddr(0x7000_4000_0000));
+        // GPAs and pgoffs don't move.
+        assert_eq!(regions[1].gpa, Gpa(0x10_0000));
+        assert_eq!(regions[1].rdma_offset, PageOffset(4));
+
+        // Overrides win over the stride.
+        let mut regions = plan();
+        place_hvas(
+            &mut regions,
+            hva_base,
+            &layout(Some(1 << 30), &[(0x10_0000, 0x7100_0000_0000)]),
+            PageSize::Base,
+        )
+        .unwrap();
+        assert_eq!(regions[0].hva, hva_base);
+        assert_eq!(regions[1].hva, HvaAddr(0x7100_0000_0000));
+        check_hva_overlap(&regions).unwrap();
+
+        let err = |layout: HvaLayout, page_size| {
+            place_hvas(&mut plan(), hva_base, &layout, page_size)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(err(layout(Some(0), &[]), PageSize::Base).contains("stride 0x0"));
+        assert!(err(layout(Some(0x1000), &[]), PageSize::Huge).contains("(2m)"));
+        let msg = err(layout(None, &[(0x1000, 0x7100_0000_0000)]), PageSize::Base);
+        assert!(msg.contains("no region starts there"), "{}", msg);
+        let msg = err(layout(None, &[(0, 0x7100_0000_0800)]), PageSize::Base);
+        assert!(msg.contains("not aligned"), "{}", msg);
+        let msg = err(
+            layout(None, &[(0, 0x7100_0000_0000), (0, 0)]),
+            PageSize::Base,
+        );
+        assert!(msg.contains("more than one"), "{}", msg);
+        let msg = err(layout(None, &[(0, u64::MAX - 0xfff)]), PageSize::Base);
+        assert!(msg.contains("gpa 0x0 overflows HVA space"), "{}", msg);
+    }
+
+    #[test]
+    fn test_check_hva_overlap() {
+        let mut regions = plan_regions(
+            &[state(0, 4, 0), state(0x10_0000, 4, 4)],
+            HvaAddr(0x7000_0000_0000),
+            PageOffset(0),
+            PageSize::Base,
+        )
+        .unwrap();
+        check_hva_overlap(&regions).unwrap();
+
+        // A stride smaller than a region puts the next one inside it.
+        regions[1].hva = HvaAddr(0x7000_0000_2000);
+        let err = check_hva_overlap(&regions).unwrap_err().to_string();
+        assert!(
+            err.contains("regions 0 and 1 overlap in HVA space"),
+            "{}",
+            err
+        );
+        assert!(err.contains("[0x700000002000, 0x700000006000)"), "{}", err);
+
+        // Ending exactly where the next region starts is fine.
+        regions[1].hva = HvaAddr(0x7000_0000_4000);
+        check_hva_overlap(&regions).unwrap();
+    }
+
+    #[test]
+    fn test_check_hva_ranges() {
+        let maps = parse_maps(
+            "55d0c0000000-55d0c0021000 rw-p 00000000 00:00 0                          [heap]\n\
+             7000000a0000-7000000b0000 rw-p 00000000 00:00 0 \n\
+             7f3a00000000-7f3a00200000 r-xp 00000000 fd:01 1234                       /usr/lib/libc.so.6\n\
+             not a mapping\n",
+        );
+        assert_eq!(maps.len(), 3);
+        assert_eq!(maps[0].name, "[heap]");
+        assert_eq!(maps[1].name, "anonymous");
+        assert_eq!(
+            (maps[2].start, maps[2].end),
+            (0x7f3a_0000_0000, 0x7f3a_0020_0000)
+        );
+
+        let mut regions = plan_regions(
+            &[
+                state(0, 4, 0),
+                state(0x10_0000, 4, 4),
+                state(0x20_0000, 4, 8),
+            ],
+            HvaAddr(0x7000_0000_0000),
+            PageOffset(0),
+            PageSize::Base,
+        )
+        .unwrap();
+        check_hva_ranges(&regions, 0x1_0000, 1 << 47, Some(&maps)).unwrap();
+
+        regions[0].hva = HvaAddr(0xffff_8000_0000_0000);
+        regions[1].hva = HvaAddr(0x7000_000a_8000);
+        regions[2].hva = HvaAddr(0x7000_0020_0010);
+        let err = check_hva_ranges(&regions, 0x1_0000, 1 << 47, Some(&maps))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "region 0: hva [0xffff800000000000, 0xffff800000004000) is outside the 47-bit \
+                 user address space [0x10000, 0x800000000000)"
+            ),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains(
+                "region 1: hva [0x7000000a8000, 0x7000000ac000) overlaps anonymous \
+                 [0x7000000a0000, 0x7000000b0000) of this process"
+            ),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("region 2: hva [0x700000200010, 0x700000204010) is not page aligned"),
+            "{}",
+            err
+        );
+        // The process's mappings only matter when they're given.
+        regions[0].hva = HvaAddr(0x7000_0000_0000);
+        regions[2].hva = HvaAddr(0x7000_0020_0000);
+        check_hva_ranges(&regions, 0x1_0000, 1 << 47, None).unwrap();
+        // 5-level paging hosts have room above 47 bits.
+        regions[0].hva = HvaAddr(0x8000_0000_0000);
+        check_hva_ranges(&regions, 0x1_0000, 1 << 56, None).unwrap();
+    }
+
+    #[test]
+    fn test_check_map_count() {
+        let budget = MapBudget {
+            max_map_count: 1000,
+            current: 200,
+        };
+        assert_eq!(budget.available(), 800);
+        assert_eq!(
+            check_map_count(10, &budget, false).unwrap(),
+            MapCountCheck::Ok
+        );
+
+        match check_map_count(500, &budget, false).unwrap() {
+            MapCountCheck::Warn(msg) => assert!(msg.contains("--coalesce-regions"), "{}", msg),
+            other => panic!("expected a warning, got {:?}", other),
+        }
+
+        let err = check_map_count(801, &budget, true).unwrap_err().to_string();
+        assert!(err.contains("801 mappings"), "{}", err);
+        assert!(err.contains("vm.max_map_count=1000"), "{}", err);
+        assert!(!err.contains("--coalesce-regions"), "{}", err);
+    }
+}
@@ -0,0 +1,172 @@
+//! Resuming a batch from the summary of an earlier run.
+//!
+//! `--resume-from` reads the `--summary-output` of an earlier run of the
+//! same batch. Entries it records as `ok`, matched by label and output
+//! path, are not run again: their images are on the server and their
+//! templates written. Their ranges are reserved as recorded before those
+//! of the other entries, and automatic ranges start past the earlier run's
+//! `next_rdma_pgoff`, so a range an entry failed part way through uploading
+//! is never reused. With `--resume-verify` an entry is only skipped if its
+//! template still loads.
+//!
+//! The new summary carries the skipped entries over as recorded, so a run
+//! can be resumed from in turn.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+use vmm::pseudo_mm_addr::PageOffset;
+use vmm::pseudo_mm_support::MemBackend;
+
+/// A `--summary-output`, as far as resuming needs it.
+#[derive(Deserialize)]
+pub struct PreviousSummary {
+    /// Kept as written, to carry over into the new summary.
+    entries: Vec<serde_json::Value>,
+    #[serde(default)]
+    next_rdma_pgoff: u64,
+    #[serde(default)]
+    next_dax_pgoffs: BTreeMap<String, u64>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// The fields of a summary entry that say what it left on the server.
+#[derive(Deserialize)]
+struct RecordedEntry {
+    label: String,
+    output_path: String,
+    status: String,
+    backend: Option<MemBackend>,
+    rdma_pgoff: Option<PageOffset>,
+    pages: Option<u64>,
+}
+
+/// An entry the earlier run created.
+#[derive(Debug, PartialEq)]
+pub struct ResumedEntry {
+    pub backend: MemBackend,
+    pub rdma_pgoff: PageOffset,
+    pub pages: u64,
+    pub output_path: String,
+    /// The entry as the earlier summary recorded it.
+    pub summary: serde_json::Value,
+}
+
+/// What a batch takes over from the run it resumes.
+pub struct Resume {
+    /// By config entry; `None` for those to run.
+    pub entries: Vec<Option<ResumedEntry>>,
+    pub next_rdma_pgoff: u64,
+    pub next_dax_pgoffs: BTreeMap<String, u64>,
+}
+
+/// Loads the summary at `path`, refusing that of a dry run, which created
+/// nothing.
+pub fn load(path: &Path) -> Result<PreviousSummary, Box<dyn std::error::Error>> {
+    let context = |err: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err),
+        )
+    };
+    let json = std::fs::read_to_string(path).map_err(|err| context(&err))?;
+    let summary: PreviousSummary = serde_json::from_str(&json).map_err(|err| context(&err))?;
+    if summary.dry_run {
+        return Err(Box::new(context(
+            &"is the summary of a dry run, which created nothing",
+        )));
+    }
+    Ok(summary)
+}
+
+/// Resumes `entries`, by label and output path, from those of `summary`
+/// the earlier run created.
+pub fn resume(summary: PreviousSummary, entries: &[(&str, &str)]) -> io::Result<Resume> {
+    let mut done = BTreeMap::new();
+    for value in summary.entries {
+        let recorded: RecordedEntry = serde_json::from_value(value.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        if recorded.status != "ok" {
+            continue;
+        }
+        let missing = |field| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("entry {} is ok but records no {}", recorded.label, field),
+            )
+        };
+        let entry = ResumedEntry {
+            backend: recorded.backend.ok_or_else(|| missing("backend"))?,
+            rdma_pgoff: recorded.rdma_pgoff.ok_or_else(|| missing("rdma_pgoff"))?,
+            pages: recorded.pages.ok_or_else(|| missing("pages"))?,
+            output_path: recorded.output_path.clone(),
+            summary: value,
+        };
+        done.insert((recorded.label, recorded.output_path), entry);
+    }
+    Ok(Resume {
+        entries: entries
+            .iter()
+            .map(|&(label, output_path)| done.remove(&(label.to_string(), output_path.to_string())))
+            .collect(),
+        next_rdma_pgoff: summary.next_rdma_pgoff,
+        next_dax_pgoffs: summary.next_dax_pgoffs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume() {
+        let summary: PreviousSummary = serde_json::from_str(
+            r#"{
+                "entries": [
+                    {"label": "fn-a", "snapshot_path": "a.snap", "output_path": "a.json",
+                     "status": "ok", "pseudo_mm_id": 3, "backend": "rdma",
+                     "rdma_pgoff": 0, "end_pgoff": 32, "pages": 32},
+                    {"label": "fn-b", "snapshot_path": "b.snap", "output_path": "b.json",
+                     "status": "failed", "error": "failed: refused"},
+                    {"label": "fn-c", "snapshot_path": "c.snap", "output_path": "c.json",
+                     "status": "ok", "backend": "dax", "rdma_pgoff": 8, "pages": 4}
+                ],
+                "next_rdma_pgoff": 64
+            }"#,
+        )
+        .unwrap();
+        assert!(!summary.dry_run);
+
+        let batch = resume(
+            summary,
+            &[
+                ("fn-a", "a.json"),
+                ("fn-b", "b.json"),
+                // Written somewhere else this time.
+                ("fn-c", "c2.json"),
+                ("fn-d", "d.json"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(batch.next_rdma_pgoff, 64);
+        let resumed = batch.entries;
+        assert_eq!(resumed.len(), 4);
+        let a = resumed[0].as_ref().unwrap();
+        assert_eq!(
+            (a.backend, a.rdma_pgoff, a.pages),
+            (MemBackend::Rdma, PageOffset(0), 32)
+        );
+        assert_eq!(a.summary["pseudo_mm_id"], 3);
+        assert!(resumed[1..].iter().all(Option::is_none));
+
+        let summary: PreviousSummary = serde_json::from_str(
+            r#"{"entries": [{"label": "fn-a", "output_path": "a.json", "status": "ok"}]}"#,
+        )
+        .unwrap();
+        let err = resume(summary, &[("fn-a", "a.json")]).err().unwrap();
+        assert_eq!(err.to_string(), "entry fn-a is ok but records no backend");
+    }
+}
@@ -0,0 +1,472 @@
+//! Prometheus text-format metrics for a creator run.
+//!
+//! With `--metrics-out` the run's counters are written for a node exporter
+//! textfile collector at the end of the run, and every `FLUSH_INTERVAL`
+//! during uploads so long batches show progress. Files are replaced through
+//! `output_lock`, so a scrape never sees a partial file; the lock and
+//! temporary files don't end in `.prom` and are ignored by the collector.
+//!
+//! Metric names are part of the tool's interface; dashboards key off them.
+//! All per-entry series carry an `entry` label with the entry's label
+//! (`single`, or `batch-N` for the Nth batch entry). Names:
+//!
+//! - `pseudo_mm_creator_uploaded_bytes_total{entry}` (counter)
+//! - `pseudo_mm_creator_uploaded_pages_total{entry}` (counter)
+//! - `pseudo_mm_creator_upload_seconds_total{entry}` (counter)
+//! - `pseudo_mm_creator_retries_total{entry}` (counter): pseudo_mm create
+//!   retries after transient busy errors
+//! - `pseudo_mm_creator_upload_retries_total{entry}` (counter): RDMA uploads
+//!   restarted after a dropped connection or a retryable server status
+//! - `pseudo_mm_creator_entries_total{result}` (counter), `result` being
+//!   `succeeded`, `failed`, `cancelled`, `skipped`, `deferred` or
+//!   `timed_out`
+//! - `pseudo_mm_creator_pgoff_high_water` (gauge): first rdma_pgoff past
+//!   every image uploaded so far
+//! - `pseudo_mm_creator_phase_duration_seconds{entry,phase}` (summary),
+//!   `phase` being one of `PHASES`
+
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::output_lock;
+
+/// How often metrics are rewritten while an upload is running.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Phases timed for every entry, in the order they run.
+pub const PHASES: [&str; 4] = ["plan", "upload", "setup", "write"];
+
+/// How an entry of the run ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryOutcome {
+    Succeeded,
+    Failed,
+    /// Stopped through the run's cancel token (SIGINT/SIGTERM).
+    Cancelled,
+    /// Not attempted because an earlier entry failed.
+    Skipped,
+    /// Not started because it wouldn't finish before the run's deadline.
+    Deferred,
+    /// Abandoned after running past its entry timeout.
+    TimedOut,
+}
+
+/// Name, help text and per-entry value of a counter.
+type EntryCounter = (&'static str, &'static str, fn(&EntryMetrics) -> String);
+
+#[derive(Default)]
+struct EntryMetrics {
+    label: String,
+    bytes: u64,
+    pages: u64,
+    upload_secs: f64,
+    retries: u64,
+    upload_retries: u64,
+    phases: Vec<(&'static str, f64)>,
+}
+
+/// Handle to one entry's metrics, from `RunMetrics::start_entry`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntryId(usize);
+
+/// Metrics collected over one run, optionally mirrored to a file.
+pub struct RunMetrics {
+    out: Option<PathBuf>,
+    lock_wait: Duration,
+    last_flush: Instant,
+    entries: Vec<EntryMetrics>,
+    succeeded: u64,
+    failed: u64,
+    cancelled: u64,
+    skipped: u64,
+    deferred: u64,
+    timed_out: u64,
+    pgoff_high_water: u64,
+}
+
+impl RunMetrics {
+    /// Collects metrics, writing them to `out` when given.
+    pub fn new(out: Option<PathBuf>, lock_wait: Duration) -> Self {
+        RunMetrics {
+            out,
+            lock_wait,
+            last_flush: Instant::now(),
+            entries: Vec::new(),
+            succeeded: 0,
+            failed: 0,
+            cancelled: 0,
+            skipped: 0,
+            deferred: 0,
+            timed_out: 0,
+            pgoff_high_water: 0,
+        }
+    }
+
+    /// Starts collecting for the entry labelled `label`.
+    pub fn start_entry(&mut self, label: &str) -> EntryId {
+        self.entries.push(EntryMetrics {
+            label: label.to_string(),
+            ..Default::default()
+        });
+        EntryId(self.entries.len() - 1)
+    }
+
+    /// Records how long `phase` took for `entry`.
+    pub fn phase(&mut self, entry: EntryId, phase: &'static str, elapsed: Duration) {
+        debug_assert!(PHASES.contains(&phase), "undocumented phase {}", phase);
+        self.entries[entry.0]
+            .phases
+            .push((phase, elapsed.as_secs_f64()));
+    }
+
+    /// Records upload progress of `entry`, rewriting the file if the last
+    /// write is older than `FLUSH_INTERVAL`.
+    pub fn upload_progress(
+        &mut self,
+        entry: EntryId,
+        bytes: u64,
+        page_size: u64,
+        elapsed: Duration,
+    ) {
+        let entry = &mut self.entries[entry.0];
+        entry.bytes = bytes;
+        entry.pages = bytes / page_size;
+        entry.upload_secs = elapsed.as_secs_f64();
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            // Progress updates are best effort; the final write reports errors.
+            if let Err(err) = self.flush() {
+                println!("warning: cannot write metrics: {}", err);
+            }
+        }
+    }
+
+    /// Counts a pseudo_mm create retry for `entry`.
+    pub fn retry(&mut self, entry: EntryId) {
+        self.entries[entry.0].retries += 1;
+    }
+
+    /// Counts a restarted RDMA upload for `entry`.
+    pub fn upload_retry(&mut self, entry: EntryId) {
+        self.entries[entry.0].upload_retries += 1;
+    }
+
+    /// Raises the pgoff high-water mark to `pgoff` if it is higher.
+    pub fn pgoff_reached(&mut self, pgoff: u64) {
+        self.pgoff_high_water = std::cmp::max(self.pgoff_high_water, pgoff);
+    }
+
+    /// Counts an entry's outcome.
+    pub fn finish_entry(&mut self, outcome: EntryOutcome) {
+        match outcome {
+            EntryOutcome::Succeeded => self.succeeded += 1,
+            EntryOutcome::Failed => self.failed += 1,
+            EntryOutcome::Cancelled => self.cancelled += 1,
+            EntryOutcome::Skipped => self.skipped += 1,
+            EntryOutcome::Deferred => self.deferred += 1,
+            EntryOutcome::TimedOut => self.timed_out += 1,
+        }
+    }
+
+    /// Writes the metrics file, if one was requested.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        match self.out {
+            Some(ref path) => {
+                output_lock::write_locked(path, self.render().as_bytes(), self.lock_wait)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let per_entry: [EntryCounter; 5] = [
+            (
+                "pseudo_mm_creator_uploaded_bytes_total",
+                "Bytes of guest memory uploaded to the RDMA server.",
+                |entry| entry.bytes.to_string(),
+            ),
+            (
+                "pseudo_mm_creator_uploaded_pages_total",
+                "Pages of guest memory uploaded to the RDMA server.",
+                |entry| entry.pages.to_string(),
+            ),
+            (
+                "pseudo_mm_creator_upload_seconds_total",
+                "Time spent streaming guest memory to the RDMA server.",
+                |entry| entry.upload_secs.to_string(),
+            ),
+            (
+                "pseudo_mm_creator_retries_total",
+                "pseudo_mm create retries after transient busy errors.",
+                |entry| entry.retries.to_string(),
+            ),
+            (
+                "pseudo_mm_creator_upload_retries_total",
+                "RDMA uploads restarted after a retryable failure.",
+                |entry| entry.upload_retries.to_string(),
+            ),
+        ];
+        for (name, help, value) in per_entry.iter() {
+            header(&mut out, name, help, "counter");
+            for entry in &self.entries {
+                let _ = writeln!(
+                    out,
+                    "{}{{entry=\"{}\"}} {}",
+                    name,
+                    escape(&entry.label),
+                    value(entry)
+                );
+            }
+        }
+
+        let name = "pseudo_mm_creator_entries_total";
+        header(&mut out, name, "Entries of the run by outcome.", "counter");
+        for (result, count) in &[
+            ("succeeded", self.succeeded),
+            ("failed", self.failed),
+            ("cancelled", self.cancelled),
+            ("skipped", self.skipped),
+            ("deferred", self.deferred),
+            ("timed_out", self.timed_out),
+        ] {
+            let _ = writeln!(out, "{}{{result=\"{}\"}} {}", name, result, count);
+        }
+
+        let name = "pseudo_mm_creator_pgoff_high_water";
+        header(
+            &mut out,
+            name,
+            "First rdma_pgoff past every image uploaded so far.",
+            "gauge",
+        );
+        let _ = writeln!(out, "{} {}", name, self.pgoff_high_water);
+
+        let name = "pseudo_mm_creator_phase_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "Time spent in each phase of an entry.",
+            "summary",
+        );
+        for entry in &self.entries {
+            for (phase, secs) in &entry.phases {
+                let labels = format!("entry=\"{}\",phase=\"{}\"", escape(&entry.label), phase);
+                let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, secs);
+                let _ = writeln!(out, "{}_count{{{}}} 1", name, labels);
+            }
+        }
+        out
+    }
+}
+
+/// Run metrics shared between the workers of a batch.
+pub type SharedMetrics = Arc<Mutex<RunMetrics>>;
+
+/// Records one entry into shared run metrics.
+pub struct EntryRecorder {
+    run: SharedMetrics,
+    entry: EntryId,
+}
+
+impl EntryRecorder {
+    /// Starts collecting for the entry labelled `label`.
+    pub fn start(run: &SharedMetrics, label: &str) -> Self {
+        let entry = run.lock().expect("Poisoned lock").start_entry(label);
+        EntryRecorder {
+            run: run.clone(),
+            entry,
+        }
+    }
+
+    pub fn phase(&self, phase: &'static str, elapsed: Duration) {
+        self.run
+            .lock()
+            .expect("Poisoned lock")
+            .phase(self.entry, phase, elapsed);
+    }
+
+    pub fn upload_progress(&self, bytes: u64, page_size: u64, elapsed: Duration) {
+        self.run
+            .lock()
+            .expect("Poisoned lock")
+            .upload_progress(self.entry, bytes, page_size, elapsed);
+    }
+
+    pub fn retry(&self) {
+        self.run.lock().expect("Poisoned lock").retry(self.entry);
+    }
+
+    pub fn upload_retry(&self) {
+        self.run
+            .lock()
+            .expect("Poisoned lock")
+            .upload_retry(self.entry);
+    }
+
+    pub fn pgoff_reached(&self, pgoff: u64) {
+        self.run.lock().expect("Poisoned lock").pgoff_reached(pgoff);
+    }
+
+    /// Counts the entry's outcome.
+    pub fn finish(self, outcome: EntryOutcome) {
+        self.run
+            .lock()
+            .expect("Poisoned lock")
+            .finish_entry(outcome);
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value as the text format requires.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+
+    fn sample_run() -> RunMetrics {
+        let mut metrics = RunMetrics::new(None, Duration::from_secs(0));
+        let first = metrics.start_entry("batch-1");
+        let second = metrics.start_entry("batch-2");
+        // Entries of a parallel batch interleave.
+        metrics.phase(first, "plan", Duration::from_millis(5));
+        metrics.upload_progress(second, 4096, 4096, Duration::from_millis(10));
+        metrics.upload_progress(first, 8 * 4096, 4096, Duration::from_millis(1500));
+        metrics.phase(first, "upload", Duration::from_millis(1500));
+        metrics.retry(first);
+        metrics.upload_retry(second);
+        metrics.upload_retry(second);
+        metrics.pgoff_reached(108);
+        metrics.finish_entry(EntryOutcome::Succeeded);
+        metrics.finish_entry(EntryOutcome::Failed);
+        metrics.finish_entry(EntryOutcome::Skipped);
+        metrics.finish_entry(EntryOutcome::Deferred);
+        metrics
+    }
+
+    /// Value of the sample of `metric` labelled `label` in `text`.
+    fn value(text: &str, metric: &str, label: (&str, &str)) -> Option<f64> {
+        let prefix = format!("{}{{{}=\"{}\"", metric, label.0, label.1);
+        text.lines()
+            .find(|line| line.starts_with(&prefix))
+            .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+    }
+
+    #[test]
+    fn test_render_parses() {
+        let text = sample_run().render();
+        // Every sample is `name{labels} value` or `name value`, under a
+        // `# TYPE` of its family.
+        let mut family = None;
+        for line in text.lines() {
+            if line.starts_with("# TYPE ") {
+                family = line.split(' ').nth(2).map(str::to_string);
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let (series, value) = line.split_at(line.rfind(' ').unwrap());
+            assert!(value[1..].parse::<f64>().is_ok(), "{}", line);
+            let name = series.split('{').next().unwrap();
+            assert!(
+                name.starts_with(family.as_deref().unwrap()),
+                "{} outside its family",
+                line
+            );
+            assert_eq!(series.contains('{'), series.ends_with('}'), "{}", line);
+        }
+
+        let entry = ("entry", "batch-1");
+        assert_eq!(
+            value(&text, "pseudo_mm_creator_uploaded_bytes_total", entry),
+            Some(32768.0)
+        );
+        assert_eq!(
+            value(&text, "pseudo_mm_creator_uploaded_pages_total", entry),
+            Some(8.0)
+        );
+        assert_eq!(
+            value(&text, "pseudo_mm_creator_upload_seconds_total", entry),
+            Some(1.5)
+        );
+        assert_eq!(
+            value(&text, "pseudo_mm_creator_retries_total", entry),
+            Some(1.0)
+        );
+        assert_eq!(
+            value(
+                &text,
+                "pseudo_mm_creator_upload_retries_total",
+                ("entry", "batch-2")
+            ),
+            Some(2.0)
+        );
+        assert_eq!(
+            value(
+                &text,
+                "pseudo_mm_creator_uploaded_pages_total",
+                ("entry", "batch-2")
+            ),
+            Some(1.0)
+        );
+        for (result, count) in &[
+            ("succeeded", 1.0),
+            ("failed", 1.0),
+            ("cancelled", 0.0),
+            ("skipped", 1.0),
+            ("deferred", 1.0),
+            ("timed_out", 0.0),
+        ] {
+            assert_eq!(
+                value(
+                    &text,
+                    "pseudo_mm_creator_entries_total",
+                    ("result", *result)
+                ),
+                Some(*count)
+            );
+        }
+    }
+
+    #[test]
+    fn test_render_format() {
+        let text = sample_run().render();
+        assert!(text.contains(
+            "# TYPE pseudo_mm_creator_entries_total counter\n\
+             pseudo_mm_creator_entries_total{result=\"succeeded\"} 1\n"
+        ));
+        assert!(text.contains("pseudo_mm_creator_pgoff_high_water 108\n"));
+        assert!(text.contains(
+            "pseudo_mm_creator_phase_duration_seconds_sum{entry=\"batch-1\",phase=\"upload\"} 1.5\n\
+             pseudo_mm_creator_phase_duration_seconds_count{entry=\"batch-1\",phase=\"upload\"} 1\n"
+        ));
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_flush_writes_file() {
+        let path = test_files::path("metrics").with_extension("prom");
+        let mut metrics = sample_run();
+        metrics.out = Some(path.clone());
+        metrics.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), metrics.render());
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(path.with_extension("prom.lock"));
+    }
+}
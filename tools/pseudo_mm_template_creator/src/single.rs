@@ -0,0 +1,482 @@
+//! A run without `--batch-config`: one template from `--snapshot-path` and
+//! `--mem-file-path`.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+use clap::{Arg, ArgMatches};
+use vmm::pseudo_mm_addr::{self, PageOffset};
+use vmm::pseudo_mm_support::{PageSize, PAGE_SIZE};
+
+use crate::deadline::{EntryDeadline, RunLimits};
+use crate::json_output;
+use crate::layered::BaseFiles;
+use crate::mem_files::MemFiles;
+use crate::occupancy;
+use crate::output_lock;
+use crate::pgoff_alloc::PgoffAllocator;
+use crate::pgoff_extents;
+use crate::pgoff_registry::{PgoffRegistry, Reservation};
+use crate::regions::{HvaLayout, RegionHva};
+use crate::run_metrics::{EntryOutcome, EntryRecorder, SharedMetrics};
+use crate::upload::rdma_guard_pages;
+use crate::upload_progress::{self, ProgressStyle};
+use crate::{
+    absolute_path, check_pgoff_align, create_template, describe_instance, dry_run_template,
+    is_cancelled, is_entry_timeout, pages_to_bytes, parse_arg, parse_byte_size, status_summary,
+    write_layout_plan, EntryStatus, EntrySummary, ImageTarget, LayoutPlan, RunOptions,
+    TemplateArgs, TemplateResult, DEFAULT_PSEUDO_MM_BASE,
+};
+
+/// The options of the template a run creates without `--batch-config`.
+pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("snapshot")
+            .long("snapshot-path")
+            .value_name("FILE")
+            .required_unless("batch-config")
+            .help("Path to snapshot file"),
+        Arg::with_name("mem-file")
+            .long("mem-file-path")
+            .value_name("FILE")
+            .required_unless("batch-config")
+            .help("Path to memory file, or its shards in order as a comma-separated list or a glob such as mem.*, or - for stdin"),
+        Arg::with_name("mem-size")
+            .long("mem-size")
+            .value_name("BYTES")
+            .help("Size of a memory file read from stdin, with an optional k/m/g suffix"),
+        Arg::with_name("output")
+            .long("output-path")
+            .value_name("FILE")
+            .required_unless("batch-config")
+            .help("Output template path"),
+        Arg::with_name("rdma-server")
+            .long("rdma-server")
+            .value_name("ADDR")
+            .required_unless_one(&["batch-config", "dax-device"])
+            .help("RDMA control-plane address (host:port, or unix:PATH for a local socket)"),
+        Arg::with_name("rdma-pgoff")
+            .long("rdma-pgoff")
+            .value_name("PAGES")
+            .required_unless_one(&["batch-config", "pgoff-extents"])
+            .help(
+                "Base page offset on the RDMA server or DAX device to store this snapshot \
+                 (decimal or 0x-prefixed hex)",
+            ),
+        Arg::with_name("pgoff-extents")
+            .long("pgoff-extents")
+            .value_name("LIST")
+            .conflicts_with_all(&["batch-config", "rdma-pgoff", "base-template", "registry"])
+            .help(
+                "Store the RDMA image in these free pgoff extents, PGOFF+PAGES[,...], split \
+                 over several if no one holds it",
+            ),
+        Arg::with_name("skip-upload")
+            .long("skip-upload")
+            .conflicts_with_all(&["batch-config", "pgoff-extents"])
+            .help(
+                "Write the template for the image already at --rdma-pgoff instead of \
+                 uploading it",
+            ),
+        Arg::with_name("mem-pages")
+            .long("mem-pages")
+            .value_name("PAGES")
+            .requires("skip-upload")
+            .help("Pages of the image with --skip-upload, instead of the memory file's size"),
+        Arg::with_name("mem-type")
+            .long("mem-type")
+            .value_name("TYPE")
+            .possible_values(&["rdma", "dax"])
+            .conflicts_with("batch-config")
+            .help("Backend the memory image is stored in (default: rdma)"),
+        Arg::with_name("dax-device")
+            .long("dax-device")
+            .value_name("PATH")
+            .conflicts_with("batch-config")
+            .help("DAX device the memory image is copied into (with --mem-type dax)"),
+        Arg::with_name("page-size")
+            .long("page-size")
+            .value_name("SIZE")
+            .possible_values(&["4k", "2m"])
+            .conflicts_with("batch-config")
+            .help("Page size the regions are mapped with (default: 4k)"),
+        Arg::with_name("hva-base")
+            .long("hva-base")
+            .value_name("ADDRESS")
+            .help("Base HVA address (decimal or 0x-prefixed hex, default: 0x700000000000)"),
+        Arg::with_name("region-stride")
+            .long("region-stride")
+            .value_name("BYTES")
+            .conflicts_with("batch-config")
+            .help("Place region i at hva_base + i * BYTES instead of hva_base + gpa"),
+        Arg::with_name("region-hva")
+            .long("region-hva")
+            .value_name("GPA=HVA")
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with("batch-config")
+            .help("Map the region starting at GPA at HVA (repeatable)"),
+        Arg::with_name("base-template")
+            .long("base-template")
+            .value_name("FILE")
+            .requires("base-mem-file")
+            .conflicts_with_all(&["batch-config", "dax-device"])
+            .help("Upload only the pages that differ from this template's image and share the rest"),
+        Arg::with_name("base-mem-file")
+            .long("base-mem-file")
+            .value_name("FILE")
+            .help(
+                "Image of --base-template, to compare the memory file with; with \
+                 --diff-snapshot alone, the full memory file the diff was taken against",
+            ),
+        Arg::with_name("refresh-from")
+            .long("refresh-from")
+            .value_name("TEMPLATE")
+            .conflicts_with_all(&[
+                "batch-config",
+                "dax-device",
+                "base-template",
+                "diff-snapshot",
+            ])
+            .help(
+                "Upload only the chunks whose content hash differs from those TEMPLATE \
+                 recorded and share the rest of its image",
+            ),
+        Arg::with_name("dirty-bitmap")
+            .long("dirty-bitmap")
+            .value_name("FILE")
+            .requires("base-template")
+            .conflicts_with("diff-snapshot")
+            .help(
+                "Bitmap of the memory file's dirty 4 KiB pages; upload only those and \
+                 map the clean ones from --base-template",
+            ),
+        Arg::with_name("marker-gpa")
+            .long("marker-gpa")
+            .value_name("ADDR")
+            .conflicts_with_all(&[
+                "batch-config",
+                "dax-device",
+                "base-template",
+                "refresh-from",
+                "diff-snapshot",
+                "skip-upload",
+                "pgoff-extents",
+                "mem-size",
+            ])
+            .help(
+                "Upload a marker naming the image in place of the 4 KiB page at guest \
+                 address ADDR, for the guest to check",
+            ),
+        Arg::with_name("diff-snapshot")
+            .long("diff-snapshot")
+            .requires("base-mem-file")
+            .conflicts_with("batch-config")
+            .help(
+                "The memory file is a Firecracker diff snapshot; read the pages it \
+                 doesn't hold from the base",
+            ),
+    ]
+}
+
+/// Creates the template `matches` describes, with the rest of the run's
+/// options in `run`.
+pub fn run(
+    matches: &ArgMatches,
+    run: RunOptions,
+    limits: &RunLimits,
+    metrics: &SharedMetrics,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let snapshot_path = matches.value_of("snapshot").unwrap();
+    let mem_files = MemFiles::parse(matches.value_of("mem-file").unwrap())?;
+    let stdin_size = parse_mem_size(matches, &mem_files)?;
+    let output_path = matches.value_of("output").unwrap();
+    let target = match matches.value_of("mem-type") {
+        Some("dax") => ImageTarget::Dax {
+            device: matches.value_of("dax-device").ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--mem-type dax requires --dax-device",
+                )
+            })?,
+        },
+        _ => ImageTarget::Rdma {
+            server: matches.value_of("rdma-server").ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "--rdma-server is required")
+            })?,
+        },
+    };
+    let free_extents = match (matches.value_of("pgoff-extents"), target) {
+        (None, _) => None,
+        (Some(_), ImageTarget::Dax { .. }) => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--pgoff-extents only splits RDMA images",
+            )))
+        }
+        (Some(list), ImageTarget::Rdma { .. }) => {
+            Some(pgoff_extents::parse(list).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--pgoff-extents: {}", err),
+                )
+            })?)
+        }
+    };
+    // With --pgoff-extents, planning picks the image's pgoffs.
+    let rdma_pgoff: PageOffset = parse_arg(matches, "rdma-pgoff")?.unwrap_or_default();
+    if let (Some(align), ImageTarget::Rdma { .. }, None) = (run.pgoff_align, target, &free_extents)
+    {
+        let pgoffs = [("--rdma-pgoff".to_string(), rdma_pgoff.raw())];
+        check_pgoff_align(&pgoffs, align, run.pgoff_align_strict)?;
+    }
+    let hva_base = parse_arg(matches, "hva-base")?.unwrap_or(DEFAULT_PSEUDO_MM_BASE);
+    let page_size: PageSize = matches.value_of("page-size").unwrap_or("4k").parse()?;
+    // Refused before anything is uploaded, rather than when the instance is set up.
+    page_size.check_supported().map_err(|reason| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--page-size {}", reason),
+        )
+    })?;
+    let hva_layout = parse_hva_layout(matches)?;
+    let diff_snapshot = matches.is_present("diff-snapshot");
+    let skip_upload = matches.is_present("skip-upload");
+    let mem_pages = match matches.value_of("mem-pages") {
+        Some(value) => Some(value.parse::<u64>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--mem-pages: invalid value '{}'", value),
+            )
+        })?),
+        None => None,
+    };
+    let merge_base = match (diff_snapshot, matches.is_present("base-template")) {
+        (true, false) => matches.value_of("base-mem-file"),
+        (false, false) if matches.is_present("base-mem-file") => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--base-mem-file requires --base-template or --diff-snapshot",
+            )))
+        }
+        _ => None,
+    };
+
+    if let Some(path) = run.pgoff_registry.as_ref() {
+        output_lock::check_overwrite(Path::new(output_path), run.force)?;
+        let mut registry = PgoffRegistry::lock(path)?;
+        let mem_size = match (stdin_size, mem_pages) {
+            (Some(size), _) => size,
+            (None, Some(pages)) => pages_to_bytes(pages)?,
+            (None, None) => mem_files.size()?,
+        };
+        let template_path = absolute_path(output_path);
+        let guard_pages = rdma_guard_pages(target, run.guard_pages);
+        registry.reserve(
+            &mut PgoffAllocator::new(0).with_rdma_guard(guard_pages),
+            &Reservation {
+                target: target.name(),
+                dax_device: target.dax_device(),
+                explicit: Some(rdma_pgoff.raw()),
+                pages: (mem_size + PAGE_SIZE - 1) / PAGE_SIZE,
+                align: page_size.pgoffs(),
+                template_path: &template_path,
+                now: occupancy::unix_secs(SystemTime::now()),
+                image_hash: None,
+                guard_pages,
+            },
+        )?;
+        if !run.dry_run {
+            registry.save()?;
+        }
+    }
+
+    let entry_deadline = EntryDeadline::new(Instant::now(), limits.entry_timeout);
+    let args = TemplateArgs {
+        label: "single",
+        snapshot_path,
+        mem_files: &mem_files,
+        stdin_size,
+        output_path,
+        target,
+        rdma_pgoff,
+        hva_base,
+        hva_layout: &hva_layout,
+        page_size,
+        base: match matches.value_of("base-template") {
+            Some(template) => Some(BaseFiles {
+                template,
+                mem_file: matches.value_of("base-mem-file"),
+            }),
+            None => matches.value_of("refresh-from").map(|template| BaseFiles {
+                template,
+                mem_file: None,
+            }),
+        },
+        diff_snapshot,
+        merge_base,
+        dirty_bitmap: matches.value_of("dirty-bitmap"),
+        marker_gpa: parse_arg(matches, "marker-gpa")?,
+        dedup: None,
+        reused: None,
+        skip_upload,
+        mem_pages,
+        snapshot_data_version: run.snapshot_data_version,
+        validate_snapshot: run.validate_snapshot,
+        instance_registry: &run.instance_registry,
+        pgoff_namespace: run.pgoff_namespace.as_ref(),
+        pgoff_limit: run.max_image_pages,
+        free_extents: free_extents.as_deref(),
+        guard_pages: run.guard_pages,
+        upload: &run.upload,
+        progress_style: ProgressStyle::detect(false),
+        coalesce_regions: run.coalesce_regions,
+        create_pseudo_mm: run.create_pseudo_mm,
+        force: run.force,
+        lock_wait: run.lock_wait,
+        entry_deadline,
+        cancel: &limits.cancel,
+    };
+
+    if run.dry_run {
+        let plan = dry_run_template(&args)?;
+        let next_pgoff = plan.rdma_pgoff.raw() + plan.pages;
+        println!("\nNext available {}_pgoff: {}", plan.backend, next_pgoff);
+        if let Some(path) = run.plan_output.as_ref() {
+            let mut layout = LayoutPlan {
+                entries: vec![&plan],
+                next_rdma_pgoff: next_pgoff,
+                next_dax_pgoffs: BTreeMap::new(),
+            };
+            if let Some(device) = plan.dax_device.as_ref() {
+                layout.next_rdma_pgoff = 0;
+                layout.next_dax_pgoffs.insert(device, next_pgoff);
+            }
+            write_layout_plan(path, &layout, run.lock_wait)?;
+        }
+        let planned = EntryStatus::Planned(plan);
+        json_output::emit(&single_summary(&args, &planned))?;
+        return Ok(());
+    }
+
+    let recorder = EntryRecorder::start(metrics, "single");
+    let result = create_template(&args, &recorder);
+    recorder.finish(match result {
+        Ok(_) => EntryOutcome::Succeeded,
+        Err(ref err) if is_cancelled(err.as_ref()) => EntryOutcome::Cancelled,
+        Err(ref err) if is_entry_timeout(err.as_ref()) => EntryOutcome::TimedOut,
+        Err(_) => EntryOutcome::Failed,
+    });
+    metrics.lock().expect("Poisoned lock").flush()?;
+    let result = result?;
+
+    print_summary(&args, &result);
+    json_output::emit(&single_summary(&args, &EntryStatus::Created(result)))?;
+
+    Ok(())
+}
+
+/// Prints what `create_template` reported for the template of `args`.
+fn print_summary(args: &TemplateArgs, result: &TemplateResult) {
+    println!("\nSummary:");
+    println!("  pseudo_mm_id: {}", describe_instance(result.pseudo_mm_id));
+    println!("  backend    : {}", result.backend);
+    println!("  rdma_pgoff : {}", result.rdma_pgoff);
+    println!("  hva_base   : {}", result.hva_base);
+    println!("  pages      : {}", result.mem_pages);
+    println!(
+        "  upload     : {:.2}s, {:.1} MB/s",
+        result.upload_time.as_secs_f64(),
+        upload_progress::rate(result.mem_size, result.upload_time)
+    );
+    if let Some(peak) = result.cache_peak {
+        println!("  cache peak : +{} bytes", peak);
+    }
+    if let Some(layered) = result.layered.as_ref() {
+        println!(
+            "  overlay    : {} pages, {} shared with {}",
+            layered.overlay_pages, layered.shared_pages, layered.base_template
+        );
+        if let (Some(dirty), Some(clean)) = (layered.dirty_pages, layered.clean_pages) {
+            println!("  dirty      : {} pages, {} clean", dirty, clean);
+        }
+    }
+    if let (Some(layered), Some(None)) =
+        (result.layered.as_ref(), args.base.map(|base| base.mem_file))
+    {
+        // At this upload's rate, what the shared pages would have taken.
+        let secs = result.upload_time.as_secs_f64();
+        let saved = if result.mem_size > 0 && secs > 0.0 {
+            format!(
+                ", ~{:.2}s saved",
+                (layered.shared_pages * PAGE_SIZE) as f64 * secs / result.mem_size as f64
+            )
+        } else {
+            String::new()
+        };
+        println!(
+            "  refresh    : {} pages reused, {} uploaded{}",
+            layered.shared_pages, layered.overlay_pages, saved
+        );
+    }
+}
+
+/// Summarizes the template of a run without `--batch-config`, as printed
+/// by `--output-format json`.
+fn single_summary<'a>(args: &'a TemplateArgs, status: &'a EntryStatus) -> EntrySummary<'a> {
+    status_summary(
+        args.label.to_string(),
+        args.snapshot_path,
+        args.output_path,
+        Some(status),
+    )
+}
+
+/// Reads `--region-stride` and `--region-hva`.
+fn parse_hva_layout(matches: &ArgMatches) -> Result<HvaLayout, Box<dyn std::error::Error>> {
+    let invalid = |name: &str, err: String| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("--{}: {}", name, err))
+    };
+    let stride = match matches.value_of("region-stride") {
+        Some(value) => Some(
+            pseudo_mm_addr::parse_u64(value)
+                .map_err(|err| invalid("region-stride", err.to_string()))?,
+        ),
+        None => None,
+    };
+    let mut overrides = Vec::new();
+    for value in matches.values_of("region-hva").into_iter().flatten() {
+        overrides.push(
+            value
+                .parse::<RegionHva>()
+                .map_err(|err| invalid("region-hva", err))?,
+        );
+    }
+    Ok(HvaLayout { stride, overrides })
+}
+
+/// `--mem-size`, which a memory file read from stdin requires and any
+/// other has no use for.
+fn parse_mem_size(
+    matches: &ArgMatches,
+    mem_files: &MemFiles,
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let invalid = |message: String| -> Box<dyn std::error::Error> {
+        Box::new(io::Error::new(io::ErrorKind::InvalidInput, message))
+    };
+    match (matches.value_of("mem-size"), mem_files.is_stdin()) {
+        (Some(value), true) => parse_byte_size(value)
+            .filter(|&size| size > 0)
+            .map(Some)
+            .ok_or_else(|| invalid(format!("--mem-size: invalid value '{}'", value))),
+        (None, true) => Err(invalid(
+            "--mem-file-path - reads stdin, whose size must be given with --mem-size".to_string(),
+        )),
+        (Some(_), false) => Err(invalid(
+            "--mem-size is only for a memory file read from stdin (--mem-file-path -)".to_string(),
+        )),
+        (None, false) => Ok(None),
+    }
+}
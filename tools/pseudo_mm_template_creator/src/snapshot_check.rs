@@ -0,0 +1,228 @@
+//! `--validate-snapshot`: sanity checks on the whole microVM state.
+//!
+//! Making a template only needs the snapshot's memory regions, so a snapshot
+//! whose vCPU or device sections are damaged still produces a template that
+//! then fails at restore. These checks look at the rest of the state too and
+//! report every problem found, so one run tells CI all that is wrong.
+
+use std::io;
+
+use vmm::memory_snapshot::GuestMemoryRegionState;
+use vmm::persist::MicrovmState;
+
+const MIB: u64 = 1 << 20;
+
+/// Where a device of the snapshot is registered on the MMIO bus.
+struct DeviceSlot<'a> {
+    kind: &'static str,
+    id: &'a str,
+    addr: u64,
+    len: u64,
+    irqs: &'a [u32],
+}
+
+/// Fails with every problem found in `state`, one per line.
+pub fn check(state: &MicrovmState) -> io::Result<()> {
+    let problems = problems(state);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "snapshot failed validation with {} problem(s):\n  - {}",
+            problems.len(),
+            problems.join("\n  - ")
+        ),
+    ))
+}
+
+fn problems(state: &MicrovmState) -> Vec<String> {
+    let mut problems = Vec::new();
+    if state.vcpu_states.is_empty() {
+        problems.push("the snapshot has no vCPU states".to_string());
+    }
+    problems.extend(region_problems(
+        &state.memory_state.regions,
+        state.vm_info.mem_size_mib,
+    ));
+
+    let devices = &state.device_states;
+    let mut slots = Vec::new();
+    for block in devices.block_devices.iter() {
+        slots.push(DeviceSlot {
+            kind: "block",
+            id: &block.device_id,
+            addr: block.mmio_slot.addr,
+            len: block.mmio_slot.len,
+            irqs: &block.mmio_slot.irqs,
+        });
+    }
+    for net in devices.net_devices.iter() {
+        slots.push(DeviceSlot {
+            kind: "net",
+            id: &net.device_id,
+            addr: net.mmio_slot.addr,
+            len: net.mmio_slot.len,
+            irqs: &net.mmio_slot.irqs,
+        });
+    }
+    if let Some(vsock) = devices.vsock_device.as_ref() {
+        slots.push(DeviceSlot {
+            kind: "vsock",
+            id: &vsock.device_id,
+            addr: vsock.mmio_slot.addr,
+            len: vsock.mmio_slot.len,
+            irqs: &vsock.mmio_slot.irqs,
+        });
+    }
+    problems.extend(device_problems(&slots));
+    problems
+}
+
+/// Regions must be non-empty, must not overlap in guest memory and must add
+/// up to the VM's memory size.
+fn region_problems(regions: &[GuestMemoryRegionState], mem_size_mib: u64) -> Vec<String> {
+    let mut problems = Vec::new();
+    if regions.is_empty() {
+        problems.push("the snapshot has no memory regions".to_string());
+        return problems;
+    }
+
+    let mut by_gpa = Vec::with_capacity(regions.len());
+    let mut total = 0u128;
+    for (idx, region) in regions.iter().enumerate() {
+        if region.size == 0 {
+            problems.push(format!(
+                "memory region {} (gpa 0x{:x}) is empty",
+                idx, region.base_address
+            ));
+            continue;
+        }
+        let end = u128::from(region.base_address) + region.size as u128;
+        by_gpa.push((u128::from(region.base_address), end, idx));
+        total += region.size as u128;
+    }
+    by_gpa.sort();
+    for pair in by_gpa.windows(2) {
+        let ((_, prev_end, prev), (start, _, idx)) = (pair[0], pair[1]);
+        if start < prev_end {
+            problems.push(format!(
+                "memory regions {} and {} overlap at gpa 0x{:x}..0x{:x}",
+                prev, idx, start, prev_end
+            ));
+        }
+    }
+
+    let expected = u128::from(mem_size_mib) * u128::from(MIB);
+    if problems.is_empty() && total != expected {
+        problems.push(format!(
+            "memory regions hold {} bytes but the VM has {} MiB",
+            total, mem_size_mib
+        ));
+    }
+    problems
+}
+
+/// Device ids must be unique per kind, and no two devices may share MMIO
+/// addresses or interrupt lines.
+fn device_problems(slots: &[DeviceSlot]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (idx, slot) in slots.iter().enumerate() {
+        let name = format!("{} device '{}'", slot.kind, slot.id);
+        if slot.id.is_empty() {
+            problems.push(format!("a {} device has no id", slot.kind));
+        }
+        if slot.len == 0 {
+            problems.push(format!("{} has an empty MMIO range", name));
+        }
+        if slot.irqs.is_empty() {
+            problems.push(format!("{} has no interrupt line", name));
+        }
+        for other in &slots[..idx] {
+            if other.kind == slot.kind && other.id == slot.id {
+                problems.push(format!("{} appears more than once", name));
+            }
+            let overlaps = other.len > 0
+                && slot.len > 0
+                && u128::from(slot.addr) < u128::from(other.addr) + u128::from(other.len)
+                && u128::from(other.addr) < u128::from(slot.addr) + u128::from(slot.len);
+            if overlaps {
+                problems.push(format!(
+                    "{} at MMIO 0x{:x} overlaps {} device '{}' at 0x{:x}",
+                    name, slot.addr, other.kind, other.id, other.addr
+                ));
+            }
+            if let Some(irq) = slot.irqs.iter().find(|irq| other.irqs.contains(irq)) {
+                problems.push(format!(
+                    "{} shares irq {} with {} device '{}'",
+                    name, irq, other.kind, other.id
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(base_address: u64, size: usize) -> GuestMemoryRegionState {
+        GuestMemoryRegionState {
+            base_address,
+            size,
+            offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_region_problems() {
+        let layout = [region(0, 1 << 20), region(2 << 20, 1 << 20)];
+        assert!(region_problems(&layout, 2).is_empty());
+
+        assert_eq!(region_problems(&layout, 4).len(), 1);
+        assert!(region_problems(&[], 2)[0].contains("no memory regions"));
+
+        // Every problem is reported, not just the first.
+        let broken = [
+            region(0, 2 << 20),
+            region(1 << 20, 1 << 20),
+            region(8 << 20, 0),
+        ];
+        let problems = region_problems(&broken, 3);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("region 2 (gpa 0x800000) is empty"));
+        assert!(problems[1].contains("regions 0 and 1 overlap"));
+    }
+
+    #[test]
+    fn test_device_problems() {
+        let slot = |kind, id, addr, irqs| DeviceSlot {
+            kind,
+            id,
+            addr,
+            len: 0x1000,
+            irqs,
+        };
+        let devices = [
+            slot("block", "rootfs", 0xd000_0000, &[5][..]),
+            slot("net", "eth0", 0xd000_1000, &[6][..]),
+            slot("vsock", "vsock", 0xd000_2000, &[7][..]),
+        ];
+        assert!(device_problems(&devices).is_empty());
+
+        let devices = [
+            slot("block", "rootfs", 0xd000_0000, &[5][..]),
+            slot("block", "rootfs", 0xd000_0800, &[5][..]),
+            slot("net", "", 0xd000_2000, &[][..]),
+        ];
+        let problems = device_problems(&devices);
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems[0].contains("appears more than once"));
+        assert!(problems[1].contains("overlaps block device 'rootfs'"));
+        assert!(problems[2].contains("shares irq 5"));
+        assert!(problems[3].contains("a net device has no id"));
+        assert!(problems[4].contains("no interrupt line"));
+    }
+}
@@ -0,0 +1,126 @@
+//! Batch entries for every snapshot matching a glob.
+//!
+//! Snapshots of many functions made the same way need batch entries that
+//! differ only in their paths. An entry with `snapshot_glob` instead of
+//! `snapshot_path` stands for one entry per matching snapshot, with
+//! wildcards in the file name only, as for a `mem_file_path` glob. Its
+//! `mem_file_path`, `output_path` and `label` are templates for each match:
+//! `{stem}` is the snapshot's file name without its extension, `{name}` its
+//! file name and `{dir}` its directory, so `"{dir}/{stem}.mem"` is the memory
+//! file next to it. `${` is left for `env_expand`.
+//!
+//! The entries replace the stanza when the config is loaded, in natural
+//! order of the snapshot names, so pgoffs are allocated the same way on
+//! every run. A match without a memory file fails the load.
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+
+use crate::mem_files;
+
+/// Snapshots matching `pattern`, in natural order; at least one.
+pub fn matching(pattern: &str) -> io::Result<Vec<String>> {
+    let paths = mem_files::glob(pattern)?;
+    if paths.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no snapshot matches '{}'", pattern),
+        ));
+    }
+    Ok(paths)
+}
+
+/// `template` with its placeholders replaced by the parts of `snapshot`.
+pub fn fill(template: &str, snapshot: &str) -> Result<String, String> {
+    let snapshot = Path::new(snapshot);
+    let part = |name: &str| {
+        let part = match name {
+            "stem" => snapshot.file_stem(),
+            "name" => snapshot.file_name(),
+            "dir" => match snapshot.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => Some(dir.as_os_str()),
+                _ => Some(OsStr::new(".")),
+            },
+            _ => {
+                return Err(format!(
+                    "'{}' has an unknown placeholder '{{{}}}'",
+                    template, name
+                ))
+            }
+        };
+        Ok(part.map_or_else(String::new, |part| part.to_string_lossy().into_owned()))
+    };
+
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..=start]);
+            rest = &rest[start + 1..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let body = &rest[start + 1..];
+        let end = body
+            .find('}')
+            .ok_or_else(|| format!("'{}' has an unterminated '{{'", template))?;
+        out.push_str(&part(&body[..end])?);
+        rest = &body[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+
+    #[test]
+    fn test_fill() {
+        let snapshot = "/srv/snap/fn-a.snapshot";
+        assert_eq!(
+            fill("{dir}/{stem}.mem", snapshot).unwrap(),
+            "/srv/snap/fn-a.mem"
+        );
+        assert_eq!(
+            fill("/out/{name}.json", snapshot).unwrap(),
+            "/out/fn-a.snapshot.json"
+        );
+        assert_eq!(fill("{dir}/{stem}.mem", "fn-b.snap").unwrap(), "./fn-b.mem");
+        assert_eq!(
+            fill("/plain/out.json", snapshot).unwrap(),
+            "/plain/out.json"
+        );
+        // Left for env_expand.
+        assert_eq!(
+            fill("${OUT:-/out}/{stem}.json", snapshot).unwrap(),
+            "${OUT:-/out}/fn-a.json"
+        );
+
+        let err = fill("{dir}/{base}.mem", snapshot).unwrap_err();
+        assert!(err.contains("unknown placeholder '{base}'"), "{}", err);
+        assert!(fill("{stem", snapshot)
+            .unwrap_err()
+            .contains("unterminated"));
+    }
+
+    #[test]
+    fn test_matching() {
+        let dir = test_files::dir("snapshot_glob");
+        for name in &["fn-10.snap", "fn-9.snap", "fn-1.snap", "fn-1.mem"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let found = matching(&format!("{}/fn-*.snap", dir.display())).unwrap();
+        let names: Vec<_> = found
+            .iter()
+            .map(|path| Path::new(path).file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["fn-1.snap", "fn-9.snap", "fn-10.snap"]);
+        let err = matching(&format!("{}/*.snapshot", dir.display())).unwrap_err();
+        assert!(err.to_string().contains("no snapshot matches"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,617 @@
+//! The subcommands other than creating templates: `occupancy`,
+//! `dedup-report`, `rebase`, `list`, `registry`, `inspect-memory` and
+//! `check-freshness`.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use vmm::pseudo_mm_addr::{self, Gpa, PageOffset};
+use vmm::pseudo_mm_restore::{self, ReadOnlyGuestMemory, RestoreOptions};
+use vmm::pseudo_mm_support::{self, PAGE_SIZE};
+
+use crate::batch::{self, load_batch_config};
+use crate::dedup;
+use crate::freshness::{self, CheckMode, Freshness};
+use crate::inspect;
+use crate::instance_registry;
+use crate::mem_files::MemFiles;
+use crate::occupancy::{self, OccupiedRange};
+use crate::output_lock;
+use crate::parse_arg;
+use crate::pgoff_registry::PgoffRegistry;
+use crate::rebase;
+use crate::region_hash::{self, HashSample};
+
+/// The subcommands, for the top-level command.
+pub fn subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+    vec![
+        SubCommand::with_name("occupancy")
+            .about("Export or check RDMA server occupancy state")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("export")
+                    .about("Build an occupancy file from the templates in a directory")
+                    .arg(
+                        Arg::with_name("template-dir")
+                            .long("template-dir")
+                            .value_name("DIR")
+                            .required(true)
+                            .help("Directory searched recursively for template JSON files"),
+                    )
+                    .arg(
+                        Arg::with_name("output")
+                            .long("output-path")
+                            .value_name("FILE")
+                            .required(true)
+                            .help("Output occupancy file path"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("check")
+                    .about("Check a proposed template's pgoff range against an occupancy file")
+                    .arg(
+                        Arg::with_name("template")
+                            .long("template")
+                            .value_name("FILE")
+                            .required(true)
+                            .help("Template to check"),
+                    )
+                    .arg(
+                        Arg::with_name("occupancy")
+                            .long("occupancy")
+                            .value_name("FILE")
+                            .required(true)
+                            .help("Occupancy file produced by 'occupancy export'"),
+                    ),
+            ),
+        SubCommand::with_name("dedup-report")
+            .about("Report page duplication across the memory files of a batch")
+            .arg(
+                Arg::with_name("batch-config")
+                    .long("batch-config")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Batch config whose entries' memory files are analysed"),
+            )
+    .arg(batch::no_env_expand_arg())
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["text", "json"])
+                    .default_value("text")
+                    .help("Report output format"),
+            ),
+        SubCommand::with_name("rebase")
+            .about("Rewrite template pgoffs after images moved on the memory server")
+            .arg(
+                Arg::with_name("template")
+                    .long("template")
+                    .value_name("FILE")
+                    .required_unless("template-dir")
+                    .conflicts_with("template-dir")
+                    .requires("output")
+                    .help("Template to rebase"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .long("output-path")
+                    .value_name("FILE")
+                    .help("Output path for the rebased template"),
+            )
+            .arg(
+                Arg::with_name("pgoff-delta")
+                    .long("pgoff-delta")
+                    .value_name("PAGES")
+                    .allow_hyphen_values(true)
+                    .conflicts_with("new-base")
+                    .help("Signed number of pages to shift every pgoff by"),
+            )
+            .arg(
+                Arg::with_name("new-base")
+                    .long("new-base")
+                    .value_name("PAGES")
+                    .help("New rdma_base_pgoff; the delta is derived from the current one"),
+            )
+            .arg(
+                Arg::with_name("template-dir")
+                    .long("template-dir")
+                    .value_name("DIR")
+                    .requires_all(&["output-dir", "mapping"])
+                    .conflicts_with_all(&["pgoff-delta", "new-base"])
+                    .help("Rebase every template under this directory"),
+            )
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("DIR")
+                    .help("Directory the rebased templates are written to"),
+            )
+            .arg(
+                Arg::with_name("mapping")
+                    .long("mapping")
+                    .value_name("FILE")
+                    .help("Migration mapping of moved pgoff ranges"),
+            )
+            .arg(
+                Arg::with_name("reserved-pgoffs")
+                    .long("reserved-pgoffs")
+                    .value_name("PAGES")
+                    .help("Refuse to rebase anything below this pgoff (default: 0)"),
+            ),
+        SubCommand::with_name("list")
+            .about("List the pseudo_mm instances created by this tool since boot")
+            .arg(
+                Arg::with_name("json")
+                    .long("json")
+                    .help("Print the instances as JSON"),
+            ),
+        SubCommand::with_name("registry")
+            .about("Maintain the pgoff registry given with --registry")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("gc")
+                    .about("Drop ranges whose template is gone or no longer uses them")
+                    .arg(
+                        Arg::with_name("min-age-secs")
+                            .long("min-age-secs")
+                            .value_name("SECONDS")
+                            .default_value("3600")
+                            .help("Keep ranges reserved more recently, whose upload may still be running"),
+                    ),
+            ),
+        SubCommand::with_name("inspect-memory")
+            .about(
+                "Attach a template read-only and hexdump a guest memory range or check its \
+                 content hashes",
+            )
+            .arg(
+                Arg::with_name("template")
+                    .long("template")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Template to attach"),
+            )
+            .arg(
+                Arg::with_name("gpa")
+                    .long("gpa")
+                    .value_name("ADDRESS")
+                    .required_unless("verify-hashes")
+                    .help("Guest physical address to start at (decimal or 0x-prefixed hex)"),
+            )
+            .arg(
+                Arg::with_name("len")
+                    .long("len")
+                    .value_name("BYTES")
+                    .default_value("256")
+                    .help("Number of bytes to dump"),
+            )
+            .arg(
+                Arg::with_name("verify-hashes")
+                    .long("verify-hashes")
+                    .value_name("PAGES|all")
+                    .conflicts_with("gpa")
+                    .help(
+                        "Re-hash random chunks of the regions, at least PAGES pages of \
+                         them, or all of them, and compare with the content hashes \
+                         the template recorded",
+                    ),
+            ),
+        SubCommand::with_name("check-freshness")
+            .about("Check whether a template was made from a snapshot and memory file as they are now")
+            .arg(
+                Arg::with_name("template")
+                    .long("template")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Template to check"),
+            )
+            .arg(
+                Arg::with_name("snapshot")
+                    .long("snapshot-path")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Snapshot the template should have been made from"),
+            )
+            .arg(
+                Arg::with_name("mem-file")
+                    .long("mem-file-path")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Memory file the template should have been made from, or its shards"),
+            )
+            .arg(
+                Arg::with_name("quick")
+                    .long("quick")
+                    .help("Compare sizes and modification times only, without reading the files"),
+            ),
+    ]
+}
+
+/// Runs subcommand `name`. The subcommands that need them also take the
+/// top-level `--lock-wait-secs`, `--instance-registry` and `--registry`.
+pub fn run(
+    name: &str,
+    matches: &ArgMatches,
+    lock_wait: Duration,
+    instance_registry: &Path,
+    pgoff_registry: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match name {
+        "occupancy" => run_occupancy(matches, lock_wait),
+        "dedup-report" => run_dedup_report(matches),
+        "inspect-memory" => run_inspect_memory(matches),
+        "rebase" => run_rebase(matches, lock_wait),
+        "check-freshness" => run_check_freshness(matches),
+        "list" => run_list(matches, instance_registry),
+        "registry" => run_registry(matches, pgoff_registry),
+        _ => unreachable!("no subcommand named {}", name),
+    }
+}
+
+fn run_occupancy(
+    matches: &ArgMatches,
+    lock_wait: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match matches.subcommand() {
+        ("export", Some(args)) => {
+            let template_dir = args.value_of("template-dir").unwrap();
+            let output_path = args.value_of("output").unwrap();
+            println!("Collecting templates under {}", template_dir);
+            let occupancy = occupancy::export_from_dir(Path::new(template_dir))?;
+
+            for (earlier, later) in occupancy.overlapping() {
+                println!(
+                    "  warning: '{}' [{}, {}) overlaps '{}' [{}, {})",
+                    earlier.label,
+                    earlier.start_pgoff,
+                    earlier.end_pgoff(),
+                    later.label,
+                    later.start_pgoff,
+                    later.end_pgoff()
+                );
+            }
+
+            let json = serde_json::to_string_pretty(&occupancy)?;
+            output_lock::write_locked(Path::new(output_path), json.as_bytes(), lock_wait)?;
+            println!(
+                "Exported {} ranges to {}",
+                occupancy.ranges.len(),
+                output_path
+            );
+            Ok(())
+        }
+        ("check", Some(args)) => {
+            let template_path = args.value_of("template").unwrap();
+            let occupancy = occupancy::load(args.value_of("occupancy").unwrap())?;
+            let template = pseudo_mm_support::load_template_file(Path::new(template_path))?;
+            let label = occupancy::label_for(Path::new(template_path));
+            let proposed = OccupiedRange::from_template(
+                &template,
+                &occupancy::owner_for(Path::new(template_path)),
+                &label,
+                0,
+            );
+
+            let conflicts: Vec<(&OccupiedRange, &OccupiedRange)> = proposed
+                .iter()
+                .flat_map(|range| {
+                    occupancy
+                        .conflicts_with(range)
+                        .into_iter()
+                        .map(move |existing| (range, existing))
+                })
+                .collect();
+            if conflicts.is_empty() {
+                let ranges: Vec<String> = proposed
+                    .iter()
+                    .map(|range| format!("[{}, {})", range.start_pgoff, range.end_pgoff()))
+                    .collect();
+                println!(
+                    "'{}' {} does not conflict with {} recorded ranges",
+                    label,
+                    ranges.join(", "),
+                    occupancy.ranges.len()
+                );
+                return Ok(());
+            }
+
+            for (range, existing) in &conflicts {
+                println!(
+                    "  conflict: proposed '{}' [{}, {}) overlaps '{}' [{}, {}) owned by {}",
+                    range.label,
+                    range.start_pgoff,
+                    range.end_pgoff(),
+                    existing.label,
+                    existing.start_pgoff,
+                    existing.end_pgoff(),
+                    existing.owner
+                );
+            }
+            Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "template '{}' conflicts with {} occupied ranges",
+                    label,
+                    conflicts.len()
+                ),
+            )))
+        }
+        _ => unreachable!("occupancy requires a subcommand"),
+    }
+}
+
+fn run_check_freshness(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let template_path = matches.value_of("template").unwrap();
+    let snapshot_path = matches.value_of("snapshot").unwrap();
+    let mem_files = MemFiles::parse(matches.value_of("mem-file").unwrap())?;
+    let mode = if matches.is_present("quick") {
+        CheckMode::Quick
+    } else {
+        CheckMode::Content
+    };
+    let template = pseudo_mm_support::load_template_file(Path::new(template_path))?;
+    let freshness = freshness::check_template(&template, snapshot_path, &mem_files, mode)?;
+    println!("{}: {}", template_path, freshness);
+    match freshness {
+        Freshness::Fresh => Ok(()),
+        Freshness::Stale(why) => Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is stale: {}", template_path, why),
+        ))),
+    }
+}
+
+fn run_dedup_report(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let config_path = matches.value_of("batch-config").unwrap();
+    let config = load_batch_config(config_path, !matches.is_present("no-env-expand"))?;
+
+    // Files are opened as they are hashed, so large batches don't hold one
+    // descriptor per entry.
+    let mut inputs = Vec::with_capacity(config.templates.len());
+    for entry in &config.templates {
+        inputs.push(dedup::DedupInput {
+            label: entry.label().to_string(),
+            mem_file_path: entry.mem_file_path.to_string(),
+            reader: entry.mem_file_path.lazy_reader(),
+        });
+    }
+
+    let report = dedup::build_report(inputs)?;
+    match matches.value_of("format") {
+        Some("json") => println!("{}", serde_json::to_string_pretty(&report)?),
+        _ => println!("{}", report),
+    }
+    Ok(())
+}
+
+fn run_rebase(matches: &ArgMatches, lock_wait: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let reserved = parse_arg::<PageOffset>(matches, "reserved-pgoffs")?.map_or(0, PageOffset::raw);
+
+    if let Some(template_dir) = matches.value_of("template-dir") {
+        let moves = rebase::load_moves(matches.value_of("mapping").unwrap())?;
+        println!(
+            "Rebasing templates under {} using {} moves",
+            template_dir,
+            moves.len()
+        );
+        let count = rebase::rebase_dir(
+            Path::new(template_dir),
+            Path::new(matches.value_of("output-dir").unwrap()),
+            &moves,
+            reserved,
+            lock_wait,
+        )?;
+        println!("Rebased {} templates", count);
+        return Ok(());
+    }
+
+    let shift = match (
+        matches.value_of("pgoff-delta"),
+        parse_arg::<PageOffset>(matches, "new-base")?,
+    ) {
+        (Some(delta), _) => rebase::Shift::Delta(rebase::parse_delta(delta)?),
+        (None, Some(new_base)) => rebase::Shift::NewBase(new_base),
+        (None, None) => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rebase needs --pgoff-delta or --new-base",
+            )))
+        }
+    };
+    let template_path = matches.value_of("template").unwrap();
+    let output_path = matches.value_of("output").unwrap();
+    let template = rebase::rebase_file(Path::new(template_path), shift, reserved)?;
+
+    let json = serde_json::to_string_pretty(&template)?;
+    output_lock::write_locked(Path::new(output_path), json.as_bytes(), lock_wait)?;
+    println!(
+        "Rebased {} to rdma_base_pgoff={} -> {}",
+        template_path, template.rdma_base_pgoff, output_path
+    );
+    Ok(())
+}
+
+fn run_list(matches: &ArgMatches, registry_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let instances = instance_registry::list(registry_path)?;
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&instances)?);
+        return Ok(());
+    }
+    if instances.is_empty() {
+        println!(
+            "No pseudo_mm instances recorded in {} since boot",
+            registry_path.display()
+        );
+        return Ok(());
+    }
+    print!(
+        "{}",
+        instance_registry::format_table(&instances, |path| Path::new(path).exists())
+    );
+    Ok(())
+}
+
+fn run_registry(
+    matches: &ArgMatches,
+    registry_path: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let registry_path = registry_path.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "registry commands require --registry",
+        )
+    })?;
+    let sub_matches = matches.subcommand_matches("gc").unwrap();
+    let value = sub_matches.value_of("min-age-secs").unwrap();
+    let min_age: u64 = value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--min-age-secs: invalid value '{}'", value),
+        )
+    })?;
+
+    let mut registry = PgoffRegistry::lock(registry_path)?;
+    let now = occupancy::unix_secs(SystemTime::now());
+    let dropped = registry.gc(now, min_age, pseudo_mm_support::load_template_file);
+    for range in &dropped {
+        println!(
+            "Dropped [{}, {}) on {} of {}",
+            range.start_pgoff,
+            range.end_pgoff(),
+            range.target,
+            range.template_path
+        );
+    }
+    registry.save()?;
+    println!(
+        "{} range(s) dropped, {} kept in {}",
+        dropped.len(),
+        registry.ranges().len(),
+        registry_path.display()
+    );
+    Ok(())
+}
+
+fn run_inspect_memory(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let template_path = PathBuf::from(matches.value_of("template").unwrap());
+    let gpa: Option<Gpa> = parse_arg(matches, "gpa")?;
+    let sample = matches
+        .value_of("verify-hashes")
+        .map(HashSample::from_str)
+        .transpose()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let len = pseudo_mm_addr::parse_u64(matches.value_of("len").unwrap())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("--len: {}", err)))?;
+
+    // Regions are write-protected, so nothing here can dirty the template's
+    // CoW pages.
+    let memory =
+        pseudo_mm_restore::inspect_with_pseudo_mm(&template_path, &RestoreOptions::default())
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    match memory.vm_shape() {
+        Some(shape) => println!("VM shape: {}", shape),
+        None => println!("VM shape: not recorded"),
+    }
+    match memory.provenance() {
+        Some(provenance) => println!("Created: {}", provenance),
+        None => println!("Created: not recorded"),
+    }
+    if let Some(marker) = memory.marker() {
+        println!(
+            "Marker: page at gpa {} names image {}",
+            marker.gpa, marker.image_hash
+        );
+    }
+    let gpa = match (sample, gpa) {
+        (Some(sample), _) => return verify_region_hashes(&memory, sample),
+        (None, gpa) => gpa.expect("--gpa is required without --verify-hashes"),
+    };
+
+    let mut buf = vec![0u8; PAGE_SIZE as usize];
+    let mut done = 0;
+    while done < len {
+        let chunk = std::cmp::min(len - done, PAGE_SIZE) as usize;
+        let addr = Gpa(gpa.raw() + done);
+        memory.read(addr, &mut buf[..chunk]).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot read {} bytes at GPA {}: {}", chunk, addr, err),
+            )
+        })?;
+        print!("{}", inspect::hexdump(addr.raw(), &buf[..chunk]));
+        done += chunk as u64;
+    }
+    Ok(())
+}
+
+/// Re-hashes the `sample` of the hashed chunks of attached `memory`, failing
+/// at the first that differs from its content hash; see `region_hash`.
+fn verify_region_hashes(
+    memory: &ReadOnlyGuestMemory,
+    sample: HashSample,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let regions = memory.regions();
+    let hashed = regions
+        .iter()
+        .filter(|region| region.content_hashes.is_some())
+        .count();
+    if hashed == 0 {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the template records no content hashes",
+        )));
+    }
+    if hashed < regions.len() {
+        println!(
+            "  warning  : {} of {} regions have no content hashes, not checked",
+            regions.len() - hashed,
+            regions.len()
+        );
+    }
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |now| now.as_nanos() as u64);
+    let chunks = region_hash::chunks_to_verify(regions, sample, seed);
+    let mut buf = Vec::new();
+    let mut pages = 0;
+    for &(idx, chunk) in &chunks {
+        let region = &regions[idx];
+        let hashes = region
+            .content_hashes
+            .as_ref()
+            .expect("Only hashed regions are sampled");
+        let offset = chunk * hashes.chunk_size;
+        let len = std::cmp::min(hashes.chunk_size, region.size.saturating_sub(offset));
+        buf.resize(len as usize, 0);
+        let addr = Gpa(region.gpa.raw() + offset);
+        memory.read(addr, &mut buf).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot read {} bytes at GPA {}: {}", len, addr, err),
+            )
+        })?;
+        if region_hash::digest(&buf) != hashes.sha256[chunk as usize] {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "content hash mismatch: {} bytes at GPA {} differ from the memory file \
+                     the template was made from",
+                    len, addr
+                ),
+            )));
+        }
+        pages += len / PAGE_SIZE;
+    }
+    println!(
+        "Content hashes: {} chunks ({} pages, {}) of {} regions match",
+        chunks.len(),
+        pages,
+        sample,
+        hashed
+    );
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! Failures reported by kind, with stable exit codes.
+//!
+//! Most errors are only messages, but automation has to tell a bad snapshot
+//! from an unreachable server without parsing them. Failures of the steps
+//! below are reported as a `TemplateError` of that kind; the process exits
+//! with its code and prints one line, `error: <message> (kind=<Kind>,
+//! exit_code=<N>)`. Anything else, such as a bad argument, exits with 1 and
+//! kind `Other`. `--output-format json` gives the same as fields of the
+//! failure document, see `json_output`. A batch exits with the code of its
+//! first failed entry, and `--summary-output` gives each failed entry's kind
+//! in `error_kind`.
+//!
+//! | kind            | exit code | failure                                  |
+//! |-----------------|-----------|------------------------------------------|
+//! | `SnapshotParse` | 2         | the snapshot can't be read or loaded     |
+//! | `MemFile`       | 3         | the memory file doesn't fit the snapshot |
+//! | `RdmaTransport` | 4         | connecting to or talking to the server   |
+//! | `RdmaStatus`    | 5         | the server refused an image              |
+//! | `PseudoMm`      | 6         | a `/dev/pseudo_mm` ioctl failed          |
+//!
+//! Cancelled and timed out entries keep their own errors.
+
+use std::error::Error;
+use std::fmt;
+
+/// Exit code and kind of failures that aren't a `TemplateError`.
+pub const OTHER_EXIT_CODE: i32 = 1;
+pub const OTHER_KIND: &str = "Other";
+
+/// A failure of one of the steps of making a template.
+#[derive(Debug, Clone)]
+pub enum TemplateError {
+    SnapshotParse(String),
+    MemFile(String),
+    RdmaTransport(String),
+    /// `status` is the negated errno the server acked with.
+    RdmaStatus {
+        status: i32,
+        message: String,
+    },
+    PseudoMm(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::SnapshotParse(message)
+            | TemplateError::MemFile(message)
+            | TemplateError::RdmaTransport(message)
+            | TemplateError::RdmaStatus { message, .. }
+            | TemplateError::PseudoMm(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+impl TemplateError {
+    /// The variant's name, as printed and written to batch summaries.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TemplateError::SnapshotParse(_) => "SnapshotParse",
+            TemplateError::MemFile(_) => "MemFile",
+            TemplateError::RdmaTransport(_) => "RdmaTransport",
+            TemplateError::RdmaStatus { .. } => "RdmaStatus",
+            TemplateError::PseudoMm(_) => "PseudoMm",
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TemplateError::SnapshotParse(_) => 2,
+            TemplateError::MemFile(_) => 3,
+            TemplateError::RdmaTransport(_) => 4,
+            TemplateError::RdmaStatus { .. } => 5,
+            TemplateError::PseudoMm(_) => 6,
+        }
+    }
+
+    /// The same failure with `context` before its message.
+    pub fn context(self, context: &str) -> Self {
+        let prefix = |message: String| format!("{}: {}", context, message);
+        match self {
+            TemplateError::SnapshotParse(message) => TemplateError::SnapshotParse(prefix(message)),
+            TemplateError::MemFile(message) => TemplateError::MemFile(prefix(message)),
+            TemplateError::RdmaTransport(message) => TemplateError::RdmaTransport(prefix(message)),
+            TemplateError::RdmaStatus { status, message } => TemplateError::RdmaStatus {
+                status,
+                message: prefix(message),
+            },
+            TemplateError::PseudoMm(message) => TemplateError::PseudoMm(prefix(message)),
+        }
+    }
+}
+
+/// The `TemplateError` that `err` is, or carries as an `io::Error`.
+pub fn find<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a TemplateError> {
+    err.downcast_ref::<TemplateError>().or_else(|| {
+        err.downcast_ref::<std::io::Error>()
+            .and_then(|err| err.get_ref())
+            .and_then(|inner| inner.downcast_ref::<TemplateError>())
+    })
+}
+
+/// Kind and exit code of any failure.
+pub fn describe(err: &(dyn Error + 'static)) -> (&'static str, i32) {
+    find(err).map_or((OTHER_KIND, OTHER_EXIT_CODE), |err| {
+        (err.kind(), err.exit_code())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_describe_errors() {
+        let status = TemplateError::RdmaStatus {
+            status: -22,
+            message: "RDMA server returned error code -22".to_string(),
+        };
+        let boxed: Box<dyn Error> = Box::new(status.clone().context("upload stream 1/2 failed"));
+        assert_eq!(describe(boxed.as_ref()), ("RdmaStatus", 5));
+        assert_eq!(
+            boxed.to_string(),
+            "upload stream 1/2 failed: RDMA server returned error code -22"
+        );
+
+        // Carried across threads inside an io::Error.
+        let carried = io::Error::new(io::ErrorKind::Other, TemplateError::PseudoMm("no".into()));
+        assert_eq!(describe(&carried), ("PseudoMm", 6));
+
+        let other = io::Error::new(io::ErrorKind::InvalidInput, "bad argument");
+        assert_eq!(describe(&other), (OTHER_KIND, OTHER_EXIT_CODE));
+    }
+}
@@ -0,0 +1,40 @@
+//! Scratch files for tests.
+//!
+//! Names are made unique per process, so tests running in parallel and two
+//! runs of the suite don't share files; callers keep the names unique
+//! among the tests.
+
+use std::fs;
+use std::path::PathBuf;
+
+use vmm::pseudo_mm_support::PAGE_SIZE;
+
+/// Path of the scratch file or directory `name`; nothing is created.
+pub fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("pseudo_mm_{}_{}", name, std::process::id()))
+}
+
+/// Writes `contents` to the scratch file `name`.
+pub fn file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = path(name);
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Writes a scratch file of 4 KiB pages, each filled with its byte of
+/// `fills`.
+pub fn page_file(name: &str, fills: &[u8]) -> PathBuf {
+    let mut data = Vec::new();
+    for &fill in fills {
+        data.extend(vec![fill; PAGE_SIZE as usize]);
+    }
+    file(name, &data)
+}
+
+/// The scratch directory `name`, emptied of what an earlier run left.
+pub fn dir(name: &str) -> PathBuf {
+    let dir = path(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,51 @@
+//! Templates and regions for tests.
+//!
+//! Fixtures start from these and override what they test, usually with
+//! struct update syntax, so new template fields need no change to them.
+
+use vmm::pseudo_mm_addr::{Gpa, HvaAddr, PageOffset};
+use vmm::pseudo_mm_support::{
+    MemBackend, PageSize, PseudoMmTemplate, RegionMetadata, PAGE_SIZE, TEMPLATE_VERSION,
+};
+
+/// HVA the fixtures' regions are placed from.
+const HVA_BASE: u64 = 0x7000_0000_0000;
+
+/// An RDMA template of the current version for instance 1, with an empty
+/// image at pgoff 0 and nothing optional recorded.
+pub fn template() -> PseudoMmTemplate {
+    PseudoMmTemplate {
+        template_version: TEMPLATE_VERSION,
+        pseudo_mm_id: Some(1),
+        hva_base: HvaAddr(HVA_BASE),
+        rdma_base_pgoff: PageOffset(0),
+        rdma_image_size: 0,
+        rdma_image_extents: Vec::new(),
+        rdma_guarded_range: None,
+        regions: Vec::new(),
+        pgoff_namespace: None,
+        required_features: Vec::new(),
+        vm_shape: None,
+        mem_backend: MemBackend::Rdma,
+        dax_device: None,
+        base_template: None,
+        source: None,
+        provenance: None,
+        marker: None,
+    }
+}
+
+/// A region of `pages` base pages at `gpa`, mapped at the same offset from
+/// the fixtures' HVA base, with its image at `rdma_offset`.
+pub fn region(gpa: u64, pages: u64, rdma_offset: u64) -> RegionMetadata {
+    RegionMetadata {
+        gpa: Gpa(gpa),
+        hva: HvaAddr(HVA_BASE + gpa),
+        size: pages * PAGE_SIZE,
+        rdma_offset: PageOffset(rdma_offset),
+        page_size: PageSize::Base,
+        zero_ranges: Vec::new(),
+        extents: Vec::new(),
+        content_hashes: None,
+    }
+}
@@ -0,0 +1,2502 @@
+//! Uploading a memory image: to the RDMA server over TCP or a Unix socket,
+//! or by copying it into a DAX device.
+//!
+//! Each run of populated pages is sent as an image of its own at its pgoff
+//! and acked by the server; zero pages are skipped and mapped demand-zero
+//! instead. A large file is split over `--upload-streams` connections, and
+//! an attempt that fails with a retryable error is restarted per
+//! `--upload-retries`.
+
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Arg, ArgMatches};
+use vmm::pseudo_mm_addr::PageOffset;
+use vmm::pseudo_mm_cancel::{self, CancelToken};
+use vmm::pseudo_mm_support::{self, PageSize, RetryPolicy, PAGE_SIZE};
+
+use crate::dax;
+use crate::deadline::EntryDeadline;
+use crate::diff_snapshot;
+use crate::guard_pages;
+use crate::marker_page::Marker;
+use crate::mem_files::{MemFiles, MemImage};
+use crate::mem_reader::{self, ChunkReader, ReadStats, UploadStep};
+use crate::page_cache::CacheFootprint;
+use crate::pgoff_extents::ExtentPart;
+use crate::rate_limit::{Rate, RateLimits, Throttle};
+use crate::region_hash::{self, ChunkDigests, ImageHasher};
+use crate::regions::{self, ImageWindow};
+use crate::template_error::{self, TemplateError};
+use crate::zero_pages::{self, PageRuns};
+use crate::{
+    check_mem_size, open_memory_file, parse_byte_size, ImageTarget, TemplateArgs, UPLOAD_CHUNK,
+};
+
+pub struct UploadStats {
+    pub bytes: u64,
+    pub pages: u64,
+    /// Zero pages that weren't uploaded, relative to the image start.
+    pub zero_pages: PageRuns,
+    /// Time the last attempt spent waiting for the upload rate limits.
+    pub throttled: Duration,
+    pub cache_peak: Option<u64>,
+    /// How the memory file was read, for RDMA uploads from a file.
+    pub reads: Option<ReadStats>,
+    /// Chunks hashed for `UploadOptions::hash_regions`.
+    pub digests: ChunkDigests,
+}
+
+pub fn open_dax_device(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("DAX device {}: {}", path, err)))
+}
+
+/// How an RDMA upload is sent.
+#[derive(Clone)]
+pub struct UploadOptions {
+    pub drop_cache_behind: bool,
+    /// Reads the memory file with `O_DIRECT` where the filesystem allows,
+    /// see `mem_reader`.
+    pub direct_io: bool,
+    /// Zero pages are only skipped a whole page of this size at a time.
+    pub page_size: PageSize,
+    pub retry: RetryPolicy,
+    /// Bytes read and sent at a time, a multiple of `page_size`.
+    pub chunk_size: usize,
+    /// Applied to each connection, and to all of a batch's uploads.
+    pub rate_limits: RateLimits,
+    pub timeouts: ServerTimeouts,
+    /// Connections the file is split over, see `upload_slices`.
+    pub streams: usize,
+    /// Bounds each attempt's sends and ack wait by the entry's time left.
+    pub deadline: EntryDeadline,
+    /// `(image offset, size)` of the regions to hash as they are sent, when
+    /// the template records their content hashes; see `region_hash`.
+    pub hash_regions: Option<Vec<(u64, u64)>>,
+    /// Sent in place of its page of the image; see `marker_page`.
+    pub marker: Option<Marker>,
+}
+
+/// How the run's uploads are sent, from the command line; the same for each
+/// entry.
+#[derive(Clone)]
+pub struct UploadArgs {
+    pub drop_cache_behind: bool,
+    /// See `--direct-io`.
+    pub direct_io: bool,
+    /// Applied to each entry's upload on its own.
+    pub retry: RetryPolicy,
+    /// See `--upload-chunk-size`.
+    pub chunk_size: usize,
+    pub rate_limits: RateLimits,
+    pub timeouts: ServerTimeouts,
+    /// Connections per upload; each job opens its own.
+    pub streams: usize,
+}
+
+impl UploadArgs {
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self, Box<dyn std::error::Error>> {
+        let streams = match matches.value_of("upload-streams") {
+            Some(value) => value
+                .parse()
+                .ok()
+                .filter(|&streams| streams > 0)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("--upload-streams: invalid value '{}'", value),
+                    )
+                })?,
+            None => 1,
+        };
+        Ok(UploadArgs {
+            drop_cache_behind: matches.is_present("drop-cache-behind"),
+            direct_io: matches.is_present("direct-io"),
+            retry: parse_upload_retry(matches)?,
+            chunk_size: parse_upload_chunk_size(matches.value_of("upload-chunk-size"))?,
+            rate_limits: RateLimits::new(
+                parse_rate(matches, "max-upload-rate")?,
+                parse_rate(matches, "max-batch-upload-rate")?,
+            ),
+            timeouts: parse_server_timeouts(matches)?,
+            streams,
+        })
+    }
+}
+
+/// The options `UploadArgs` is read from.
+pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("connect-timeout")
+            .long("connect-timeout")
+            .value_name("SECONDS")
+            .help("Give up connecting to the RDMA server after SECONDS (default: 10)"),
+        Arg::with_name("write-timeout")
+            .long("write-timeout")
+            .value_name("SECONDS")
+            .help("Fail an upload whose sends stall for SECONDS (default: 60)"),
+        Arg::with_name("ack-timeout")
+            .long("ack-timeout")
+            .value_name("SECONDS")
+            .help("Fail an upload the RDMA server doesn't ack within SECONDS of a chunk (default: 60)"),
+        Arg::with_name("drop-cache-behind")
+            .long("drop-cache-behind")
+            .help("Drop already-uploaded ranges of the memory file from the page cache"),
+        Arg::with_name("direct-io")
+            .long("direct-io")
+            .help("Read the memory file for RDMA uploads with O_DIRECT, bypassing the page cache where the filesystem allows"),
+        Arg::with_name("upload-retries")
+            .long("upload-retries")
+            .value_name("N")
+            .help(
+                "Restart an RDMA upload up to N times after a retryable failure (default: 0)",
+            ),
+        Arg::with_name("upload-chunk-size")
+            .long("upload-chunk-size")
+            .value_name("BYTES")
+            .help("Bytes read and sent at a time during RDMA uploads, with an optional k/m suffix (default: 4m)"),
+        Arg::with_name("upload-streams")
+            .long("upload-streams")
+            .value_name("N")
+            .help("Split each RDMA upload over N connections at once (default: 1)"),
+        Arg::with_name("max-upload-rate")
+            .long("max-upload-rate")
+            .value_name("RATE")
+            .help("Cap each RDMA upload connection at RATE, e.g. 200MiB/s"),
+        Arg::with_name("max-batch-upload-rate")
+            .long("max-batch-upload-rate")
+            .value_name("RATE")
+            .requires("batch-config")
+            .help("Cap the batch's RDMA uploads at RATE in total, over all jobs"),
+        Arg::with_name("retry-backoff-ms")
+            .long("retry-backoff-ms")
+            .value_name("MS")
+            .requires("upload-retries")
+            .help("Delay before the first upload retry, doubled after each (default: 500)"),
+    ]
+}
+
+/// Parses `--upload-chunk-size`: bytes, or KiB or MiB with a `k` or `m`
+/// suffix, a non-zero multiple of 4 KiB.
+fn parse_upload_chunk_size(value: Option<&str>) -> Result<usize, Box<dyn std::error::Error>> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(UPLOAD_CHUNK),
+    };
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--upload-chunk-size: {} '{}'", reason, value),
+        )
+    };
+    let bytes = parse_byte_size(value)
+        // More than a GiB buys nothing and risks running out of memory
+        // with several jobs.
+        .filter(|&bytes| bytes <= 1 << 30)
+        .ok_or_else(|| invalid("invalid value"))?;
+    if bytes == 0 || bytes % PAGE_SIZE != 0 {
+        return Err(Box::new(invalid("must be a non-zero multiple of 4k, not")));
+    }
+    Ok(bytes as usize)
+}
+
+fn parse_server_timeouts(
+    matches: &ArgMatches,
+) -> Result<ServerTimeouts, Box<dyn std::error::Error>> {
+    let parse = |name: &str, default: Duration| -> Result<Duration, Box<dyn std::error::Error>> {
+        match matches.value_of(name) {
+            Some(value) => Ok(value
+                .parse()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("--{}: invalid value '{}'", name, value),
+                    )
+                })?),
+            None => Ok(default),
+        }
+    };
+    let defaults = ServerTimeouts::default();
+    Ok(ServerTimeouts {
+        connect: parse("connect-timeout", defaults.connect)?,
+        write: parse("write-timeout", defaults.write)?,
+        ack: parse("ack-timeout", defaults.ack)?,
+    })
+}
+
+fn parse_rate(
+    matches: &ArgMatches,
+    name: &str,
+) -> Result<Option<Rate>, Box<dyn std::error::Error>> {
+    match matches.value_of(name) {
+        Some(value) => Ok(Some(value.parse().map_err(|err: String| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("--{}: {}", name, err))
+        })?)),
+        None => Ok(None),
+    }
+}
+
+fn parse_upload_retry(matches: &ArgMatches) -> Result<RetryPolicy, Box<dyn std::error::Error>> {
+    let parse = |name: &str, default: u64| -> Result<u64, Box<dyn std::error::Error>> {
+        match matches.value_of(name) {
+            Some(value) => Ok(value.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--{}: invalid value '{}'", name, value),
+                )
+            })?),
+            None => Ok(default),
+        }
+    };
+    let retries = parse("upload-retries", 0)?;
+    let backoff_ms = parse("retry-backoff-ms", 500)?;
+    Ok(RetryPolicy {
+        attempts: std::cmp::min(retries, u64::from(u32::max_value() - 1)) as u32 + 1,
+        backoff: Duration::from_millis(backoff_ms),
+    })
+}
+
+/// Uploads each of `parts` of an image split across pgoff extents as an
+/// image of its own, one after another; see `pgoff_extents`. Zero pages are
+/// returned relative to the start of the whole image.
+pub fn upload_extents_to_rdma(
+    mem_files: &MemFiles,
+    parts: &[ExtentPart],
+    rdma_server: &str,
+    options: &UploadOptions,
+    progress: &mut dyn FnMut(u64) -> io::Result<()>,
+    on_retry: &mut dyn FnMut(u32, &dyn std::error::Error),
+) -> Result<UploadStats, Box<dyn std::error::Error>> {
+    let mut merged = UploadStats {
+        bytes: 0,
+        pages: 0,
+        zero_pages: PageRuns::default(),
+        throttled: Duration::from_secs(0),
+        cache_peak: None,
+        reads: None,
+        digests: ChunkDigests::new(),
+    };
+    for part in parts {
+        let done = merged.bytes;
+        let upload = upload_memory_to_rdma(
+            mem_files,
+            &part.windows,
+            rdma_server,
+            part.pgoff,
+            options,
+            &mut |bytes| progress(done + bytes),
+            &mut *on_retry,
+        )?;
+        merged.bytes += upload.bytes;
+        merged.pages += upload.pages;
+        for &(page, pages) in upload.zero_pages.as_slice() {
+            merged.zero_pages.push(part.first_page + page, pages);
+        }
+        merged.throttled += upload.throttled;
+        merged.cache_peak = std::cmp::max(merged.cache_peak, upload.cache_peak);
+        merged.reads = match (merged.reads, upload.reads) {
+            (Some(reads), Some(more)) => Some(reads.followed_by(more)),
+            (reads, more) => reads.or(more),
+        };
+        merged.digests.extend(upload.digests);
+    }
+    Ok(merged)
+}
+
+/// Streams the `windows` of a memory file to the RDMA server as one image
+/// at `rdma_pgoff`, over `options.streams` connections at once.
+///
+/// An upload that fails in a retryable way (see `is_retryable_upload`) is
+/// restarted from the start of its part of the image on a new connection,
+/// with the same pgoffs so the server overwrites the partial image.
+/// `on_retry` is called before each restart.
+pub fn upload_memory_to_rdma(
+    mem_files: &MemFiles,
+    windows: &[ImageWindow],
+    rdma_server: &str,
+    rdma_pgoff: PageOffset,
+    options: &UploadOptions,
+    progress: &mut dyn FnMut(u64) -> io::Result<()>,
+    on_retry: &mut dyn FnMut(u32, &dyn std::error::Error),
+) -> Result<UploadStats, Box<dyn std::error::Error>> {
+    check_chunk_size(options)?;
+    let mut image = open_for_upload(mem_files, options)?;
+    // Streams read the way the first open could.
+    let options = &UploadOptions {
+        direct_io: image.is_direct(),
+        ..options.clone()
+    };
+    let size = windows.iter().map(|window| window.size).sum();
+    let mut slices = upload_slices(size, options.page_size.bytes(), options.streams);
+    if let Some(ranges) = options.hash_regions.as_ref() {
+        slices = region_hash::align_slices(&slices, ranges);
+    }
+    let slices: Vec<Vec<ImageWindow>> = slices
+        .into_iter()
+        .map(|slice| regions::windows_within(windows, slice))
+        .collect();
+
+    let upload = if slices.len() > 1 {
+        println!(
+            "Connecting to RDMA server {} and streaming {} bytes over {} connections...",
+            rdma_server,
+            size,
+            slices.len()
+        );
+        upload_in_parallel(
+            mem_files,
+            rdma_server,
+            rdma_pgoff,
+            &slices,
+            options,
+            progress,
+            on_retry,
+        )?
+    } else {
+        println!(
+            "Connecting to RDMA server {} and streaming {} bytes...",
+            rdma_server, size
+        );
+        upload_range(
+            &mut image,
+            rdma_server,
+            rdma_pgoff,
+            windows,
+            options,
+            progress,
+            on_retry,
+        )?
+    };
+    println!("RDMA upload completed");
+
+    Ok(UploadStats {
+        bytes: size,
+        pages: size / PAGE_SIZE,
+        zero_pages: upload.zero_pages,
+        throttled: upload.throttled,
+        cache_peak: upload.cache_peak,
+        reads: Some(upload.reads),
+        digests: upload.digests,
+    })
+}
+
+/// Opens a memory file for an upload, with `O_DIRECT` if `options.direct_io`
+/// asks for it and every shard's filesystem supports it.
+fn open_for_upload(
+    mem_files: &MemFiles,
+    options: &UploadOptions,
+) -> Result<MemImage, Box<dyn std::error::Error>> {
+    if options.direct_io {
+        match mem_files.open_direct(options.page_size)? {
+            Some(image) => {
+                check_mem_size(image.size(), options.page_size)?;
+                return Ok(image);
+            }
+            None => println!(
+                "  warning  : {} can't be read with O_DIRECT, reading through the page cache",
+                mem_files
+            ),
+        }
+    }
+    open_memory_file(mem_files, options.page_size)
+}
+
+/// Streams the `windows` of a memory file of `size` bytes, read once and in
+/// order from `reader`, to the RDMA server as one image at `rdma_pgoff`.
+///
+/// With no file to go back to, zero pages are found by scanning, and the
+/// upload runs over one connection and isn't retried. `reader` must end
+/// exactly `size` bytes in.
+/// Uploads a diff snapshot merged with its `merge_base` as it is read.
+pub fn upload_merged_to_rdma(
+    args: &TemplateArgs,
+    windows: &[ImageWindow],
+    rdma_server: &str,
+    options: &UploadOptions,
+    progress: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> Result<UploadStats, Box<dyn std::error::Error>> {
+    let base = args
+        .merge_base
+        .expect("Only merged diffs are uploaded merged");
+    let (diff, base_image) = diff_snapshot::open_merged(args.mem_files, base, args.page_size)?;
+    let mut view = diff_snapshot::DiffView::new(diff, &base_image)?;
+    let size = view.size();
+    upload_stream_to_rdma(
+        &mut view,
+        &format!("{} merged with {}", args.mem_files, base),
+        size,
+        windows,
+        rdma_server,
+        args.rdma_pgoff,
+        options,
+        progress,
+    )
+}
+
+pub fn upload_stream_to_rdma(
+    reader: &mut dyn Read,
+    source: &str,
+    size: u64,
+    windows: &[ImageWindow],
+    rdma_server: &str,
+    rdma_pgoff: PageOffset,
+    options: &UploadOptions,
+    progress: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> Result<UploadStats, Box<dyn std::error::Error>> {
+    check_chunk_size(options)?;
+    let image_size = windows.iter().map(|window| window.size).sum();
+    println!(
+        "Connecting to RDMA server {} and streaming {} bytes from {}...",
+        rdma_server, image_size, source
+    );
+    let timeouts = options
+        .timeouts
+        .bounded_by(options.deadline.remaining_at(Instant::now()));
+    let (zero_pages, throttled) = RdmaClient::connect(rdma_server, timeouts)
+        .and_then(|mut client| {
+            client.write_snapshot_from_stream(
+                rdma_pgoff.raw(),
+                reader,
+                size,
+                windows,
+                options,
+                progress,
+            )
+        })
+        .map_err(server_failure)?;
+    println!("RDMA upload completed");
+
+    Ok(UploadStats {
+        bytes: image_size,
+        pages: image_size / PAGE_SIZE,
+        zero_pages,
+        throttled,
+        cache_peak: None,
+        reads: None,
+        digests: ChunkDigests::new(),
+    })
+}
+
+fn check_chunk_size(options: &UploadOptions) -> Result<(), Box<dyn std::error::Error>> {
+    if options.chunk_size as u64 % options.page_size.bytes() != 0 {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "--upload-chunk-size {} is not a multiple of the page size {}",
+                options.chunk_size, options.page_size
+            ),
+        )));
+    }
+    Ok(())
+}
+
+/// What uploading part of an image found.
+struct RangeUpload {
+    /// Zero pages skipped, relative to the start of the image.
+    zero_pages: PageRuns,
+    throttled: Duration,
+    cache_peak: Option<u64>,
+    /// Reads of the last attempt.
+    reads: ReadStats,
+    /// Chunks the last attempt hashed.
+    digests: ChunkDigests,
+}
+
+/// Splits an image of `size` bytes into at most `streams` contiguous
+/// slices of whole `page`s, uploaded over a connection each.
+///
+/// Every page keeps the pgoff of a serial upload, so the regions planned
+/// from the image map the same wherever a slice boundary falls.
+fn upload_slices(size: u64, page: u64, streams: usize) -> Vec<(u64, u64)> {
+    let pages = size / page;
+    let streams = std::cmp::max(streams as u64, 1);
+    let per_slice = std::cmp::max((pages + streams - 1) / streams, 1) * page;
+    let mut slices = Vec::new();
+    let mut start = 0;
+    while start < size {
+        let end = std::cmp::min(start + per_slice, size);
+        slices.push((start, end));
+        start = end;
+    }
+    if slices.is_empty() {
+        slices.push((0, 0));
+    }
+    slices
+}
+
+/// Uploads `windows` of `image` over one connection, restarting them as
+/// `options.retry` allows. `progress` gets the bytes of the windows done so
+/// far.
+fn upload_range(
+    image: &mut MemImage,
+    rdma_server: &str,
+    rdma_pgoff: PageOffset,
+    windows: &[ImageWindow],
+    options: &UploadOptions,
+    progress: &mut dyn FnMut(u64) -> io::Result<()>,
+    on_retry: &mut dyn FnMut(u32, &dyn std::error::Error),
+) -> Result<RangeUpload, Box<dyn std::error::Error>> {
+    let mut footprint = CacheFootprint::start();
+    let mut restarted = false;
+    let (zero_pages, throttled, reads, digests) =
+        pseudo_mm_support::retry_if::<_, Box<dyn std::error::Error>, _, _, _>(
+            &options.retry,
+            |err| is_retryable_upload(err.as_ref()),
+            || {
+                if restarted {
+                    // Stop here rather than reconnect if the entry was cancelled
+                    // or ran out of time during the backoff.
+                    progress(0)?;
+                }
+                restarted = true;
+                let timeouts = options
+                    .timeouts
+                    .bounded_by(options.deadline.remaining_at(Instant::now()));
+                let mut client = RdmaClient::connect(rdma_server, timeouts)?;
+                client.write_snapshot_from_reader(
+                    rdma_pgoff.raw(),
+                    image,
+                    windows,
+                    options,
+                    &mut footprint,
+                    progress,
+                )
+            },
+            |attempt, err| on_retry(attempt, err.as_ref()),
+        )
+        .map_err(server_failure)?;
+    Ok(RangeUpload {
+        zero_pages,
+        throttled,
+        cache_peak: footprint.peak(),
+        reads,
+        digests,
+    })
+}
+
+/// How often the caller's `progress` is called while streams run in
+/// parallel, which is also how soon cancellation reaches them.
+const STREAM_PROGRESS_TICK: Duration = Duration::from_millis(100);
+
+/// Reported by a stream uploading in parallel.
+enum StreamEvent {
+    Retry(usize, u32, String),
+    Done(usize, io::Result<RangeUpload>),
+}
+
+/// Uploads each of `slices` on its own thread and connection, returning
+/// once every stream has finished.
+///
+/// The first stream to fail, or `progress` failing, stops the others at
+/// their next chunk, and the upload fails with that first error. The zero
+/// pages of the streams are merged in image order.
+fn upload_in_parallel(
+    mem_files: &MemFiles,
+    rdma_server: &str,
+    rdma_pgoff: PageOffset,
+    slices: &[Vec<ImageWindow>],
+    options: &UploadOptions,
+    progress: &mut dyn FnMut(u64) -> io::Result<()>,
+    on_retry: &mut dyn FnMut(u32, &dyn std::error::Error),
+) -> Result<RangeUpload, Box<dyn std::error::Error>> {
+    let stop = CancelToken::new();
+    let done = Arc::new(AtomicU64::new(0));
+    let (events, received) = mpsc::channel();
+    for (idx, windows) in slices.iter().enumerate() {
+        let (mem_files, server, windows, options) = (
+            mem_files.clone(),
+            rdma_server.to_string(),
+            windows.clone(),
+            options.clone(),
+        );
+        let (stop, done, events) = (stop.clone(), done.clone(), events.clone());
+        thread::spawn(move || {
+            let retries = events.clone();
+            let mut reported = 0;
+            let result = open_for_upload(&mem_files, &options)
+                .and_then(|mut image| {
+                    upload_range(
+                        &mut image,
+                        &server,
+                        rdma_pgoff,
+                        &windows,
+                        &options,
+                        &mut |bytes| {
+                            // A restarted stream starts over from zero.
+                            if bytes >= reported {
+                                done.fetch_add(bytes - reported, Ordering::SeqCst);
+                            } else {
+                                done.fetch_sub(reported - bytes, Ordering::SeqCst);
+                            }
+                            reported = bytes;
+                            stop.check()
+                        },
+                        &mut |attempt, err| {
+                            let _ = retries.send(StreamEvent::Retry(idx, attempt, err.to_string()));
+                        },
+                    )
+                })
+                .map_err(|err| match err.downcast::<io::Error>() {
+                    Ok(err) => *err,
+                    Err(err) => match err.downcast::<TemplateError>() {
+                        Ok(err) => io::Error::new(io::ErrorKind::Other, *err),
+                        Err(err) => io::Error::new(io::ErrorKind::Other, err.to_string()),
+                    },
+                });
+            let _ = events.send(StreamEvent::Done(idx, result));
+        });
+    }
+    drop(events);
+
+    let streams = slices.len();
+    let mut uploads: Vec<Option<RangeUpload>> = (0..streams).map(|_| None).collect();
+    let mut failure: Option<Box<dyn std::error::Error>> = None;
+    let mut running = streams;
+    while running > 0 {
+        match received.recv_timeout(STREAM_PROGRESS_TICK) {
+            Ok(StreamEvent::Retry(idx, attempt, message)) => on_retry(
+                attempt,
+                &io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("stream {}/{}: {}", idx + 1, streams, message),
+                ),
+            ),
+            Ok(StreamEvent::Done(idx, result)) => {
+                running -= 1;
+                match result {
+                    Ok(upload) => uploads[idx] = Some(upload),
+                    // Streams stopped because of the first failure report
+                    // that, not their own.
+                    Err(ref err) if stop.is_cancelled() && pseudo_mm_cancel::is_cancelled(err) => {}
+                    Err(err) => {
+                        stop.cancel();
+                        let context = format!("upload stream {}/{} failed", idx + 1, streams);
+                        failure = failure.or_else(|| match template_error::find(&err) {
+                            Some(failed) => Some(Box::new(failed.clone().context(&context))),
+                            None => Some(Box::new(io::Error::new(
+                                err.kind(),
+                                format!("{}: {}", context, err),
+                            ))),
+                        });
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        if failure.is_none() {
+            if let Err(err) = progress(done.load(Ordering::SeqCst)) {
+                stop.cancel();
+                failure = Some(Box::new(err));
+            }
+        }
+    }
+    if let Some(err) = failure {
+        return Err(err);
+    }
+
+    let mut merged = RangeUpload {
+        zero_pages: PageRuns::default(),
+        throttled: Duration::from_secs(0),
+        cache_peak: None,
+        reads: ReadStats {
+            direct: options.direct_io,
+            ..Default::default()
+        },
+        digests: ChunkDigests::new(),
+    };
+    for upload in uploads.into_iter() {
+        let upload = upload.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "an upload stream exited without a result",
+            )
+        })?;
+        for &(first_page, pages) in upload.zero_pages.as_slice() {
+            merged.zero_pages.push(first_page, pages);
+        }
+        // The streams ran, waited and filled the page cache side by side.
+        merged.throttled = std::cmp::max(merged.throttled, upload.throttled);
+        merged.cache_peak = std::cmp::max(merged.cache_peak, upload.cache_peak);
+        merged.reads = merged.reads.merge(upload.reads);
+        merged.digests.extend(upload.digests);
+    }
+    Ok(merged)
+}
+
+/// Guard pages `--guard-pages` leaves around images on `target`: none on
+/// DAX devices, which the memory server doesn't manage.
+pub fn rdma_guard_pages(target: ImageTarget, guard_pages: u64) -> u64 {
+    match target {
+        ImageTarget::Rdma { .. } => guard_pages,
+        ImageTarget::Dax { .. } => 0,
+    }
+}
+
+/// Writes zeros over the guard pages `chunks` on `rdma_server`, see
+/// `guard_pages`.
+pub fn zero_guard_pages(
+    rdma_server: &str,
+    chunks: &[(u64, u64)],
+    timeouts: ServerTimeouts,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = RdmaClient::connect(rdma_server, timeouts)?;
+    let zeros = vec![0u8; (guard_pages::CHUNK_PAGES * PAGE_SIZE) as usize];
+    for &(pgoff, pages) in chunks {
+        client.send_image(pgoff, &zeros[..(pages * PAGE_SIZE) as usize])?;
+        client.read_ack()?;
+    }
+    Ok(())
+}
+
+/// Copies the `windows` of a memory file into a DAX device as one image at
+/// `pgoff`.
+pub fn copy_memory_to_dax(
+    mem_files: &MemFiles,
+    windows: &[ImageWindow],
+    dax_device: &str,
+    pgoff: PageOffset,
+    page_size: PageSize,
+    drop_cache_behind: bool,
+    progress: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> Result<UploadStats, Box<dyn std::error::Error>> {
+    let mut image = open_memory_file(mem_files, page_size)?;
+    let device = open_dax_device(dax_device)?;
+    let size = windows.iter().map(|window| window.size).sum();
+
+    println!(
+        "Copying {} bytes into DAX device {} at pgoff {}...",
+        size, dax_device, pgoff
+    );
+    let mut footprint = CacheFootprint::start();
+    for (shard, windows) in image.split(windows) {
+        for window in windows {
+            let image_pgoff = PageOffset(pgoff.raw() + window.image_offset / PAGE_SIZE);
+            dax::copy_to_dax(
+                image.shard(shard),
+                (window.file_offset, window.file_offset + window.size),
+                &device,
+                image_pgoff,
+                drop_cache_behind,
+                &mut footprint,
+                &mut |bytes| progress(window.image_offset + bytes),
+            )?;
+        }
+    }
+    println!("DAX copy completed");
+
+    Ok(UploadStats {
+        bytes: size,
+        pages: size / PAGE_SIZE,
+        zero_pages: PageRuns::default(),
+        throttled: Duration::from_secs(0),
+        cache_peak: footprint.peak(),
+        reads: None,
+        digests: ChunkDigests::new(),
+    })
+}
+
+/// Server statuses that report a temporary condition, as negated errnos:
+/// the server is busy with another image or out of registered memory for
+/// the moment. Other statuses, such as a pgoff out of range, are permanent.
+const RETRYABLE_STATUSES: [i32; 3] = [-libc::EAGAIN, -libc::EBUSY, -libc::ENOMEM];
+
+/// Failure on the RDMA server's side of an upload, as opposed to reading
+/// the memory file.
+#[derive(Debug)]
+enum ServerError {
+    /// Connecting, sending or waiting for the ack failed.
+    Io(io::Error),
+    /// The server acked with a non-zero status.
+    Status(i32),
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ServerError::Io(err) => write!(f, "RDMA server connection: {}", err),
+            ServerError::Status(status) => write!(f, "RDMA server returned error code {}", status),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Whether an upload that failed with `err` is worth restarting: the
+/// connection was refused, reset or closed early, a send or the ack timed
+/// out, or the server reported a temporary condition.
+///
+/// Errors reading the memory file, and cancellation or `--entry-timeout`
+/// raised from the progress callback, are permanent.
+/// `err` as a `TemplateError` if the server's side of an upload failed it.
+pub fn server_failure(err: Box<dyn std::error::Error>) -> Box<dyn std::error::Error> {
+    let failure = match err.downcast_ref::<ServerError>() {
+        Some(ServerError::Io(_)) => TemplateError::RdmaTransport(err.to_string()),
+        Some(&ServerError::Status(status)) => TemplateError::RdmaStatus {
+            status,
+            message: err.to_string(),
+        },
+        None => return err,
+    };
+    Box::new(failure)
+}
+
+fn is_retryable_upload(err: &(dyn std::error::Error + 'static)) -> bool {
+    match err.downcast_ref::<ServerError>() {
+        Some(ServerError::Io(err)) => match err.kind() {
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+            // Socket timeouts surface as EAGAIN on Linux.
+            | io::ErrorKind::WouldBlock => true,
+            _ => false,
+        },
+        Some(ServerError::Status(status)) => RETRYABLE_STATUSES.contains(status),
+        None => false,
+    }
+}
+
+/// Prefix of `--rdma-server` and `rdma_server` addresses naming a Unix
+/// domain socket instead of `host:port`.
+const UNIX_ADDR_PREFIX: &str = "unix:";
+
+/// Connection to the RDMA server's control plane. Every transport carries
+/// the same header/ack protocol.
+enum ControlStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl ControlStream {
+    /// Connects to `addr`, a Unix socket when it starts with `unix:`.
+    /// Errors name the transport tried.
+    ///
+    /// Sends time out after `timeouts.write` and reads after `timeouts.ack`.
+    fn connect(addr: &str, timeouts: ServerTimeouts) -> io::Result<Self> {
+        let failed = |transport: String| {
+            move |err: io::Error| {
+                io::Error::new(
+                    err.kind(),
+                    format!("cannot connect over {}: {}", transport, err),
+                )
+            }
+        };
+        if addr.starts_with(UNIX_ADDR_PREFIX) {
+            let path = &addr[UNIX_ADDR_PREFIX.len()..];
+            if path.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{}' names no socket path", addr),
+                ));
+            }
+            // Local sockets connect or fail at once.
+            let stream =
+                UnixStream::connect(path).map_err(failed(format!("Unix socket {}", path)))?;
+            stream.set_write_timeout(Some(timeouts.write))?;
+            stream.set_read_timeout(Some(timeouts.ack))?;
+            return Ok(ControlStream::Unix(stream));
+        }
+
+        let stream =
+            connect_tcp(addr, timeouts.connect).map_err(failed(format!("TCP {}", addr)))?;
+        stream.set_write_timeout(Some(timeouts.write))?;
+        stream.set_read_timeout(Some(timeouts.ack))?;
+        Ok(ControlStream::Tcp(stream))
+    }
+}
+
+/// Connects to each address `addr` resolves to in turn, giving each
+/// `timeout`.
+fn connect_tcp(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) if is_timeout(&err) => {
+                last_err = Some(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} did not answer within {:?}", socket_addr, timeout),
+                ))
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' resolves to no address", addr),
+        )
+    }))
+}
+
+/// Whether a socket call failed by running out of time; timeouts set on a
+/// socket surface as `WouldBlock` on Linux.
+fn is_timeout(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => true,
+        _ => false,
+    }
+}
+
+impl Read for ControlStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ControlStream::Tcp(stream) => stream.read(buf),
+            ControlStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ControlStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ControlStream::Tcp(stream) => stream.write(buf),
+            ControlStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ControlStream::Tcp(stream) => stream.flush(),
+            ControlStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// How long each phase of talking to the RDMA server may stall, from
+/// `--connect-timeout`, `--write-timeout` and `--ack-timeout`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ServerTimeouts {
+    pub connect: Duration,
+    /// For each send, which the server stalls by not reading.
+    pub write: Duration,
+    /// For each ack after the chunk it acks has been sent.
+    pub ack: Duration,
+}
+
+impl Default for ServerTimeouts {
+    fn default() -> Self {
+        ServerTimeouts {
+            connect: Duration::from_secs(10),
+            write: Duration::from_secs(60),
+            ack: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ServerTimeouts {
+    /// Shortened to `remaining`, the entry's time left, if that is less.
+    pub fn bounded_by(self, remaining: Option<Duration>) -> Self {
+        let bound = |timeout: Duration| {
+            let timeout = remaining.map_or(timeout, |left| std::cmp::min(timeout, left));
+            // A zero timeout means "none" to the socket calls, and the
+            // entry's own check has failed it by then anyway.
+            std::cmp::max(timeout, Duration::from_millis(1))
+        };
+        ServerTimeouts {
+            connect: bound(self.connect),
+            write: bound(self.write),
+            ack: bound(self.ack),
+        }
+    }
+}
+
+/// `err` as a `ServerError`, described by `describe` if it is a timeout. The
+/// kind stays `TimedOut`, so the upload can be retried.
+fn timed_out(err: io::Error, describe: impl FnOnce() -> String) -> ServerError {
+    if is_timeout(&err) {
+        ServerError::Io(io::Error::new(io::ErrorKind::TimedOut, describe()))
+    } else {
+        ServerError::Io(err)
+    }
+}
+
+struct RdmaClient {
+    stream: ControlStream,
+    timeouts: ServerTimeouts,
+}
+
+impl RdmaClient {
+    /// Connects to `addr`, `host:port` or `unix:PATH`. A stalled server
+    /// fails the connect, sends and ack waits once `timeouts` run out
+    /// instead of blocking the run.
+    fn connect(addr: &str, timeouts: ServerTimeouts) -> Result<Self, Box<dyn std::error::Error>> {
+        let stream = ControlStream::connect(addr, timeouts).map_err(ServerError::Io)?;
+        Ok(Self { stream, timeouts })
+    }
+
+    /// Sends a command header: `cmd`, then `size` bytes from `pgoff`.
+    fn send_header(&mut self, cmd: u32, size: u64, pgoff: u64) -> Result<(), ServerError> {
+        let mut header = [0u8; 24];
+        header[0..4].copy_from_slice(&cmd.to_le_bytes());
+        header[8..16].copy_from_slice(&size.to_le_bytes());
+        header[16..24].copy_from_slice(&pgoff.to_le_bytes());
+        self.send(&header)
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), ServerError> {
+        let write = self.timeouts.write;
+        self.stream.write_all(data).map_err(|err| {
+            timed_out(err, || {
+                format!("sending to the RDMA server stalled for {:?}", write)
+            })
+        })
+    }
+
+    /// Sends `data` to be stored from `pgoff`; the server acks each image
+    /// separately, see `read_ack`.
+    fn send_image(&mut self, pgoff: u64, data: &[u8]) -> Result<(), ServerError> {
+        const CMD_MAP_IMAGE: u32 = 0x1;
+        self.send_header(CMD_MAP_IMAGE, data.len() as u64, pgoff)?;
+        self.send(data)
+    }
+
+    /// Reads `what` the server sends into `buf`.
+    fn receive(&mut self, buf: &mut [u8], what: &str) -> Result<(), ServerError> {
+        let wait = self.timeouts.ack;
+        self.stream.read_exact(buf).map_err(|err| {
+            timed_out(err, || {
+                format!("no {} from the RDMA server within {:?}", what, wait)
+            })
+        })
+    }
+
+    fn read_ack(&mut self) -> Result<(), ServerError> {
+        let mut ack = [0u8; 4];
+        self.receive(&mut ack, "ack")?;
+        match i32::from_le_bytes(ack) {
+            0 => Ok(()),
+            status => Err(ServerError::Status(status)),
+        }
+    }
+
+    /// Uploads the populated pages of `windows` of `reader` to the image at
+    /// `rdma_pgoff`, returning the zero pages it skipped and the time spent
+    /// waiting for `options.rate_limits`. Pages are of `options.page_size`,
+    /// and windows are made of whole pages. `progress` gets the bytes of the
+    /// windows done so far.
+    ///
+    /// Each run of populated pages is sent as its own image at its own
+    /// pgoff over the one connection, so the server's layout is the same as
+    /// for a full upload. The file is read `options.chunk_size` bytes at a
+    /// time, and acks are collected after every chunk, which keeps them from
+    /// piling up unread. With `options.hash_regions`, the chunks of regions
+    /// hashed are returned too.
+    fn write_snapshot_from_reader(
+        &mut self,
+        rdma_pgoff: u64,
+        image: &mut MemImage,
+        windows: &[ImageWindow],
+        options: &UploadOptions,
+        footprint: &mut CacheFootprint,
+        progress: &mut dyn FnMut(u64) -> io::Result<()>,
+    ) -> Result<(PageRuns, Duration, ReadStats, ChunkDigests), Box<dyn std::error::Error>> {
+        let page = options.page_size.bytes();
+        let mut throttle = Throttle::new(&options.rate_limits);
+        let mut zero_pages = PageRuns::default();
+        let mut hasher = options
+            .hash_regions
+            .as_ref()
+            .map(|ranges| ImageHasher::new(ranges));
+        let steps = mem_reader::upload_steps(image, windows, page, options.chunk_size)?;
+        let mut chunks = ChunkReader::new(image, &steps, options.chunk_size)?;
+        for step in &steps {
+            match *step {
+                UploadStep::Skip {
+                    first_page,
+                    pages,
+                    done,
+                } => {
+                    let parts = match options.marker.as_ref() {
+                        Some(marker) => marker.split_zeros(first_page, pages, page),
+                        None => vec![(first_page, pages, false)],
+                    };
+                    for (first_page, pages, marked) in parts {
+                        if let (true, Some(marker)) = (marked, options.marker.as_ref()) {
+                            let zeros = vec![0u8; (pages * PAGE_SIZE) as usize];
+                            self.send_pages(
+                                rdma_pgoff,
+                                &marker.overlay(&zeros, first_page * PAGE_SIZE),
+                                first_page,
+                                page,
+                                &mut zero_pages,
+                                &mut throttle,
+                                hasher.as_mut(),
+                                &mut || progress(done),
+                            )?;
+                            continue;
+                        }
+                        zero_pages.push(first_page, pages);
+                        if let Some(hasher) = hasher.as_mut() {
+                            hasher.zeros(first_page * PAGE_SIZE, pages * PAGE_SIZE);
+                        }
+                    }
+                    progress(done + pages * PAGE_SIZE)?;
+                }
+                UploadStep::Send {
+                    ref read,
+                    first_page,
+                    done,
+                } => {
+                    let data = chunks.read(read)?;
+                    let data = match options.marker.as_ref() {
+                        Some(marker) => marker.overlay(data, first_page * PAGE_SIZE),
+                        None => Cow::Borrowed(data),
+                    };
+                    self.send_pages(
+                        rdma_pgoff,
+                        &data,
+                        first_page,
+                        page,
+                        &mut zero_pages,
+                        &mut throttle,
+                        hasher.as_mut(),
+                        &mut || progress(done),
+                    )?;
+                    footprint.sample();
+                    if options.drop_cache_behind {
+                        // The range has been handed to the socket; its file
+                        // pages won't be read again.
+                        chunks.drop_behind(read)?;
+                    }
+                    progress(done + read.len as u64)?;
+                }
+            }
+        }
+        let digests = hasher.map_or_else(ChunkDigests::new, ImageHasher::finish);
+        Ok((zero_pages, throttle.waited(), chunks.stats(), digests))
+    }
+
+    /// Like `write_snapshot_from_reader`, but reads the memory file once,
+    /// in order, from `reader`, which must hold exactly `size` bytes. Bytes
+    /// outside the windows are read and dropped.
+    fn write_snapshot_from_stream(
+        &mut self,
+        rdma_pgoff: u64,
+        reader: &mut dyn Read,
+        size: u64,
+        windows: &[ImageWindow],
+        options: &UploadOptions,
+        progress: &mut dyn FnMut(u64) -> io::Result<()>,
+    ) -> Result<(PageRuns, Duration), Box<dyn std::error::Error>> {
+        let page = options.page_size.bytes();
+        let mut throttle = Throttle::new(&options.rate_limits);
+        let mut zero_pages = PageRuns::default();
+        let mut buf = vec![0u8; options.chunk_size];
+        let mut windows = windows.to_vec();
+        windows.sort_by_key(|window| window.file_offset);
+        let ended = |read: u64| {
+            Box::new(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "the memory file ended after {} bytes, short of its --mem-size of {}",
+                    read, size
+                ),
+            ))
+        };
+        // Bytes read from `reader`, and done of the windows before `pos`.
+        let (mut pos, mut before) = (0, 0);
+        for window in &windows {
+            if window.file_offset < pos {
+                return Err(Box::new(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "memory file offset 0x{:x} is needed twice, but stdin can only be read once",
+                        window.file_offset
+                    ),
+                )));
+            }
+            let gap = window.file_offset - pos;
+            if io::copy(&mut reader.take(gap), &mut io::sink())? < gap {
+                return Err(ended(pos + gap));
+            }
+            pos = window.file_offset;
+            let mut done = 0;
+            while done < window.size {
+                let len = std::cmp::min(options.chunk_size as u64, window.size - done) as usize;
+                let read = zero_pages::read_full(reader, &mut buf[..len])?;
+                if read != len {
+                    return Err(ended(pos + done + read as u64));
+                }
+                self.send_pages(
+                    rdma_pgoff,
+                    &buf[..len],
+                    (window.image_offset + done) / PAGE_SIZE,
+                    page,
+                    &mut zero_pages,
+                    &mut throttle,
+                    None,
+                    &mut || progress(before + done),
+                )?;
+                done += len as u64;
+                progress(before + done)?;
+            }
+            pos += window.size;
+            before += window.size;
+        }
+        // One byte past `size` tells a longer file from an exact one.
+        let rest = io::copy(&mut reader.take(size - pos + 1), &mut io::sink())?;
+        if pos + rest < size {
+            return Err(ended(pos + rest));
+        }
+        if pos + rest > size {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the memory file is longer than its --mem-size of {}", size),
+            )));
+        }
+        Ok((zero_pages, throttle.waited()))
+    }
+
+    /// Sends the populated pages of `buf`, the image from 4 KiB page
+    /// `first_page` on, as images of their own, and waits for their acks.
+    /// Its zero pages are added to `zero_pages` instead. `hasher` hashes all
+    /// of `buf` while the server takes the images in.
+    #[allow(clippy::too_many_arguments)]
+    fn send_pages(
+        &mut self,
+        rdma_pgoff: u64,
+        buf: &[u8],
+        first_page: u64,
+        page: u64,
+        zero_pages: &mut PageRuns,
+        throttle: &mut Throttle,
+        hasher: Option<&mut ImageHasher>,
+        check: &mut dyn FnMut() -> io::Result<()>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sent = 0;
+        for (at, pages, zero) in zero_pages::split_runs(buf, first_page, page) {
+            if zero {
+                zero_pages.push(at, pages);
+                continue;
+            }
+            let offset = ((at - first_page) * PAGE_SIZE) as usize;
+            let data = &buf[offset..offset + (pages * PAGE_SIZE) as usize];
+            throttle.wait(data.len() as u64, check)?;
+            self.send_image(rdma_pgoff + at, data)?;
+            sent += 1;
+        }
+        if let Some(hasher) = hasher {
+            hasher.update(first_page * PAGE_SIZE, buf);
+        }
+        for _ in 0..sent {
+            self.read_ack()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+
+    use vmm::memory_snapshot::GuestMemoryRegionState;
+    use vmm::pseudo_mm_addr::HvaAddr;
+    use vmm::pseudo_mm_support::ImageExtent;
+
+    use crate::marker_page;
+    use crate::pgoff_extents;
+    use crate::test_files;
+    use crate::{image_ranges, is_cancelled};
+
+    /// Accepts one upload and acks it, returning the bytes received.
+    fn fake_server() -> (String, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0u8; 24];
+            if stream.read_exact(&mut header).is_err() {
+                return Vec::new();
+            }
+            let mut size = [0u8; 8];
+            size.copy_from_slice(&header[8..16]);
+            let mut image = vec![0u8; u64::from_le_bytes(size) as usize];
+            let read = stream.read(&mut image).unwrap_or(0);
+            let complete = read == image.len() || stream.read_exact(&mut image[read..]).is_ok();
+            if complete {
+                let _ = stream.write_all(&0i32.to_le_bytes());
+            }
+            image
+        });
+        (addr, server)
+    }
+
+    /// Images a test server acked, with their pgoffs.
+    type AckedImages = Vec<(u64, Vec<u8>)>;
+
+    /// Accepts one connection per entry of `acks`: a status to ack the
+    /// upload with, or `None` to drop the connection after the header.
+    fn flaky_server(acks: Vec<Option<i32>>) -> (String, thread::JoinHandle<AckedImages>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut acked = Vec::new();
+            for ack in acks {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut header = [0u8; 24];
+                stream.read_exact(&mut header).unwrap();
+                let status = match ack {
+                    Some(status) => status,
+                    None => continue,
+                };
+                let mut field = [0u8; 8];
+                field.copy_from_slice(&header[8..16]);
+                let mut image = vec![0u8; u64::from_le_bytes(field) as usize];
+                stream.read_exact(&mut image).unwrap();
+                field.copy_from_slice(&header[16..24]);
+                stream.write_all(&status.to_le_bytes()).unwrap();
+                if status == 0 {
+                    acked.push((u64::from_le_bytes(field), image));
+                }
+            }
+            acked
+        });
+        (addr, server)
+    }
+
+    /// Acks every image sent over one connection until the client closes it.
+    fn recording_server() -> (String, thread::JoinHandle<AckedImages>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut images = Vec::new();
+            let mut header = [0u8; 24];
+            while stream.read_exact(&mut header).is_ok() {
+                let mut field = [0u8; 8];
+                field.copy_from_slice(&header[8..16]);
+                let mut image = vec![0u8; u64::from_le_bytes(field) as usize];
+                stream.read_exact(&mut image).unwrap();
+                field.copy_from_slice(&header[16..24]);
+                images.push((u64::from_le_bytes(field), image));
+                stream.write_all(&0i32.to_le_bytes()).unwrap();
+            }
+            images
+        });
+        (addr, server)
+    }
+
+    /// Serves `connections` connections at once, acking every image except
+    /// one at `fail_pgoff`, which is refused with -EINVAL. Returns the
+    /// acked images in pgoff order.
+    fn parallel_server(
+        connections: usize,
+        fail_pgoff: Option<u64>,
+    ) -> (String, thread::JoinHandle<AckedImages>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let handlers: Vec<_> = (0..connections)
+                .map(|_| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    thread::spawn(move || {
+                        let mut images = Vec::new();
+                        let mut header = [0u8; 24];
+                        while stream.read_exact(&mut header).is_ok() {
+                            let mut field = [0u8; 8];
+                            field.copy_from_slice(&header[8..16]);
+                            let mut image = vec![0u8; u64::from_le_bytes(field) as usize];
+                            stream.read_exact(&mut image).unwrap();
+                            field.copy_from_slice(&header[16..24]);
+                            let pgoff = u64::from_le_bytes(field);
+                            let status = if Some(pgoff) == fail_pgoff {
+                                -libc::EINVAL
+                            } else {
+                                images.push((pgoff, image));
+                                0
+                            };
+                            if stream.write_all(&status.to_le_bytes()).is_err() {
+                                break;
+                            }
+                        }
+                        images
+                    })
+                })
+                .collect();
+            let mut images: AckedImages = handlers
+                .into_iter()
+                .flat_map(|handler| handler.join().unwrap())
+                .collect();
+            images.sort();
+            images
+        });
+        (addr, server)
+    }
+
+    /// Serves `connections` connections one after another, acking every
+    /// image. Returns the pages stored, by pgoff.
+    fn storing_server(connections: usize) -> (String, thread::JoinHandle<BTreeMap<u64, Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let page = PAGE_SIZE as usize;
+            let mut pages: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+            for _ in 0..connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut header = [0u8; 24];
+                while stream.read_exact(&mut header).is_ok() {
+                    let mut field = [0u8; 8];
+                    field.copy_from_slice(&header[8..16]);
+                    let size = u64::from_le_bytes(field) as usize;
+                    field.copy_from_slice(&header[16..24]);
+                    let pgoff = u64::from_le_bytes(field);
+                    let mut image = vec![0u8; size];
+                    stream.read_exact(&mut image).unwrap();
+                    for (idx, chunk) in image.chunks(page).enumerate() {
+                        pages.insert(pgoff + idx as u64, chunk.to_vec());
+                    }
+                    stream.write_all(&0i32.to_le_bytes()).unwrap();
+                }
+            }
+            pages
+        });
+        (addr, server)
+    }
+
+    fn fast_retry(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            backoff: Duration::from_millis(0),
+        }
+    }
+
+    fn upload_options(retry: RetryPolicy, timeout: Option<Duration>) -> UploadOptions {
+        UploadOptions {
+            drop_cache_behind: false,
+            direct_io: false,
+            page_size: PageSize::Base,
+            retry,
+            chunk_size: UPLOAD_CHUNK,
+            rate_limits: RateLimits::default(),
+            timeouts: ServerTimeouts::default(),
+            streams: 1,
+            deadline: EntryDeadline::new(Instant::now(), timeout),
+            hash_regions: None,
+            marker: None,
+        }
+    }
+
+    fn mem_file(name: &str, pages: u64) -> PathBuf {
+        let contents: Vec<u8> = (0..pages * PAGE_SIZE).map(|idx| idx as u8).collect();
+        test_files::file(&format!("upload_{}", name), &contents)
+    }
+
+    /// `path` as a memory file of one shard.
+    fn files(path: &Path) -> MemFiles {
+        MemFiles::new(vec![path.to_string_lossy().into_owned()])
+    }
+
+    /// The image of a memory file without padding: all of it.
+    fn whole_file(path: &Path) -> Vec<ImageWindow> {
+        vec![ImageWindow {
+            file_offset: 0,
+            size: std::fs::metadata(path).unwrap().len(),
+            image_offset: 0,
+        }]
+    }
+
+    #[test]
+    fn test_upload_to_fake_server() {
+        let path = mem_file("ok", 4);
+        let (addr, server) = fake_server();
+        let mut progress = Vec::new();
+        let stats = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &upload_options(RetryPolicy::none(), Some(Duration::from_secs(10))),
+            &mut |bytes| {
+                progress.push(bytes);
+                Ok(())
+            },
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        assert_eq!(stats.pages, 4);
+        assert_eq!(progress, vec![4 * PAGE_SIZE]);
+        assert_eq!(server.join().unwrap(), std::fs::read(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_skips_zero_pages() {
+        let page = PAGE_SIZE as usize;
+        let path = mem_file("zero", 0);
+        let mut contents = vec![0u8; 4 * page];
+        contents[..page].iter_mut().for_each(|b| *b = 0xaa);
+        // Pages 1-2 are written zeros; a single byte populates page 3.
+        contents[4 * page - 1] = 1;
+        std::fs::write(&path, &contents).unwrap();
+        // Pages 4-7 are a hole.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(8 * PAGE_SIZE).unwrap();
+
+        let (addr, server) = recording_server();
+        let mut progress = Vec::new();
+        let stats = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(100),
+            &upload_options(RetryPolicy::none(), None),
+            &mut |bytes| {
+                progress.push(bytes);
+                Ok(())
+            },
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        assert_eq!(stats.pages, 8);
+        assert_eq!(stats.zero_pages.as_slice(), &[(1, 2), (4, 4)]);
+        assert_eq!(progress.last(), Some(&(8 * PAGE_SIZE)));
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                (100, contents[..page].to_vec()),
+                (103, contents[3 * page..].to_vec()),
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_with_direct_io() {
+        let page = PAGE_SIZE as usize;
+        // O_DIRECT wants a real filesystem; tmpfs falls back to buffered
+        // reads, which must upload the same.
+        let path = mem_file("direct", 8);
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[3 * page..5 * page].iter_mut().for_each(|b| *b = 0);
+        std::fs::write(&path, &contents).unwrap();
+        let mut options = upload_options(RetryPolicy::none(), None);
+        options.chunk_size = 3 * page;
+        options.direct_io = true;
+
+        let (addr, server) = recording_server();
+        let stats = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(10),
+            &options,
+            &mut |_| Ok(()),
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        let reads = stats.reads.unwrap();
+        let direct = MemFiles::open_direct(&files(&path), PageSize::Base)
+            .unwrap()
+            .is_some();
+        assert_eq!(reads.direct, direct);
+        assert_eq!(reads.bytes, 8 * PAGE_SIZE);
+        assert_eq!(stats.zero_pages.as_slice(), &[(3, 2)]);
+        let mut sent = vec![0u8; 8 * page];
+        for (pgoff, image) in server.join().unwrap() {
+            let at = (pgoff - 10) as usize * page;
+            sent[at..at + image.len()].copy_from_slice(&image);
+        }
+        assert_eq!(sent, contents);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_of_sharded_memory_file() {
+        let page = PAGE_SIZE as usize;
+        let mut contents = vec![0u8; 6 * page];
+        for (idx, chunk) in contents.chunks_mut(page).enumerate() {
+            chunk.iter_mut().for_each(|b| *b = idx as u8 + 1);
+        }
+        // Shard 1 is a single zero page between two populated shards.
+        contents[2 * page..3 * page].iter_mut().for_each(|b| *b = 0);
+        let mut paths = Vec::new();
+        for (idx, (start, end)) in [(0, 2), (2, 3), (3, 6)].iter().enumerate() {
+            let path = test_files::file(
+                &format!("upload_shard{}", idx),
+                &contents[start * page..end * page],
+            );
+            paths.push(path.to_string_lossy().into_owned());
+        }
+        let files = MemFiles::new(paths.clone());
+        // The second window starts on the last page of shard 0 and runs
+        // into shard 2.
+        let windows = [
+            ImageWindow {
+                file_offset: 0,
+                size: PAGE_SIZE,
+                image_offset: 0,
+            },
+            ImageWindow {
+                file_offset: PAGE_SIZE,
+                size: 4 * PAGE_SIZE,
+                image_offset: PAGE_SIZE,
+            },
+        ];
+
+        let (addr, server) = recording_server();
+        let mut options = upload_options(RetryPolicy::none(), None);
+        options.chunk_size = 2 * page;
+        let mut progress = Vec::new();
+        let stats = upload_memory_to_rdma(
+            &files,
+            &windows,
+            &addr,
+            PageOffset(100),
+            &options,
+            &mut |bytes| {
+                progress.push(bytes);
+                Ok(())
+            },
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        assert_eq!(stats.pages, 5);
+        assert_eq!(stats.zero_pages.as_slice(), &[(2, 1)]);
+        assert_eq!(progress.last(), Some(&(5 * PAGE_SIZE)));
+        let mut sent = BTreeMap::new();
+        for (pgoff, image) in server.join().unwrap() {
+            for (idx, chunk) in image.chunks(page).enumerate() {
+                sent.insert(pgoff + idx as u64, chunk[0]);
+            }
+        }
+        // Each page lands at the pgoff of the concatenated file.
+        let expected: BTreeMap<u64, u8> = [(100, 1), (101, 2), (103, 4), (104, 5)]
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(sent, expected);
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_upload_from_stream() {
+        let page = PAGE_SIZE as usize;
+        let mut contents = vec![0u8; 6 * page];
+        for (idx, chunk) in contents.chunks_mut(page).enumerate() {
+            chunk.iter_mut().for_each(|b| *b = idx as u8 + 1);
+        }
+        contents[page..2 * page].iter_mut().for_each(|b| *b = 0);
+        // Listed out of file order, with pages 2 and 3 of the file in no
+        // window.
+        let windows = [
+            ImageWindow {
+                file_offset: 4 * PAGE_SIZE,
+                size: 2 * PAGE_SIZE,
+                image_offset: 0,
+            },
+            ImageWindow {
+                file_offset: 0,
+                size: 2 * PAGE_SIZE,
+                image_offset: 2 * PAGE_SIZE,
+            },
+        ];
+        let options = upload_options(RetryPolicy::none(), None);
+        let upload = |stream: &[u8]| {
+            let (addr, server) = recording_server();
+            let result = upload_stream_to_rdma(
+                &mut io::Cursor::new(stream),
+                "stdin",
+                6 * PAGE_SIZE,
+                &windows,
+                &addr,
+                PageOffset(100),
+                &options,
+                &mut |_| Ok(()),
+            );
+            (result, server.join().unwrap())
+        };
+
+        let (stats, images) = upload(&contents);
+        let stats = stats.unwrap();
+        assert_eq!(stats.pages, 4);
+        assert_eq!(stats.zero_pages.as_slice(), &[(3, 1)]);
+        let sent: Vec<(u64, u8)> = images
+            .iter()
+            .flat_map(|(pgoff, image)| {
+                image
+                    .chunks(page)
+                    .enumerate()
+                    .map(move |(idx, chunk)| (pgoff + idx as u64, chunk[0]))
+            })
+            .collect();
+        assert_eq!(sent, vec![(102, 1), (100, 5), (101, 6)]);
+
+        let err = upload(&contents[..5 * page]).0.err().unwrap();
+        assert!(
+            err.to_string().contains("ended after 20480 bytes"),
+            "{}",
+            err
+        );
+        let mut longer = contents.clone();
+        longer.push(0);
+        let err = upload(&longer).0.err().unwrap();
+        assert!(err.to_string().contains("longer than"), "{}", err);
+    }
+
+    #[test]
+    fn test_upload_packs_regions_around_file_holes() {
+        let page = PAGE_SIZE as usize;
+        // Two regions with two pages of padding between them in the file,
+        // listed out of file order. The second page of the upper region is
+        // zero.
+        let path = mem_file("packed", 6);
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[5 * page..].iter_mut().for_each(|b| *b = 0);
+        std::fs::write(&path, &contents).unwrap();
+        let states = [
+            GuestMemoryRegionState {
+                base_address: 0x10_0000,
+                size: 2 * page,
+                offset: 4 * PAGE_SIZE,
+            },
+            GuestMemoryRegionState {
+                base_address: 0,
+                size: 2 * page,
+                offset: 0,
+            },
+        ];
+        regions::check_layout(&states, 6 * PAGE_SIZE).unwrap();
+
+        let (addr, server) = recording_server();
+        let mut progress = Vec::new();
+        let stats = upload_memory_to_rdma(
+            &files(&path),
+            &regions::image_windows(&states),
+            &addr,
+            PageOffset(100),
+            &upload_options(RetryPolicy::none(), None),
+            &mut |bytes| {
+                progress.push(bytes);
+                Ok(())
+            },
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        // The padding takes no pages and is never sent.
+        assert_eq!((stats.bytes, stats.pages), (4 * PAGE_SIZE, 4));
+        assert_eq!(stats.zero_pages.as_slice(), &[(3, 1)]);
+        assert_eq!(progress.last(), Some(&(4 * PAGE_SIZE)));
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                (100, contents[..2 * page].to_vec()),
+                (102, contents[4 * page..5 * page].to_vec()),
+            ]
+        );
+
+        // The planned regions map the pages where they were sent.
+        let mut planned = regions::plan_regions(
+            &states,
+            HvaAddr(0x7000_0000_0000),
+            PageOffset(100),
+            PageSize::Base,
+        )
+        .unwrap();
+        regions::assign_zero_pages(&mut planned, PageOffset(100), stats.zero_pages.as_slice());
+        assert_eq!(planned[0].rdma_offset, PageOffset(102));
+        assert_eq!(planned[0].populated_ranges(), vec![(0, PAGE_SIZE)]);
+        assert_eq!(planned[1].rdma_offset, PageOffset(100));
+        assert!(planned[1].zero_ranges.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_shuffled_regions_keep_their_bytes() {
+        let page = PAGE_SIZE as usize;
+        let path = mem_file("shuffled", 6);
+        let contents: Vec<u8> = (0..6 * page)
+            .map(|idx| (idx / page * 31 + idx) as u8)
+            .collect();
+        std::fs::write(&path, &contents).unwrap();
+        // Listed in neither GPA nor file order, as a later Firecracker
+        // might serialize them.
+        let states = [
+            GuestMemoryRegionState {
+                base_address: 0x20_0000,
+                size: page,
+                offset: 3 * PAGE_SIZE,
+            },
+            GuestMemoryRegionState {
+                base_address: 0,
+                size: 2 * page,
+                offset: 4 * PAGE_SIZE,
+            },
+            GuestMemoryRegionState {
+                base_address: 0x10_0000,
+                size: 3 * page,
+                offset: 0,
+            },
+        ];
+        regions::check_layout(&states, 6 * PAGE_SIZE).unwrap();
+        let planned = regions::plan_regions(
+            &states,
+            HvaAddr(0x7000_0000_0000),
+            PageOffset(100),
+            PageSize::Base,
+        )
+        .unwrap();
+        let ranges = image_ranges(&planned, PageOffset(100));
+
+        let (addr, server) = recording_server();
+        let mut options = upload_options(RetryPolicy::none(), None);
+        options.hash_regions = Some(ranges.clone());
+        let stats = upload_memory_to_rdma(
+            &files(&path),
+            &regions::image_windows(&states),
+            &addr,
+            PageOffset(100),
+            &options,
+            &mut |_| Ok(()),
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        let mut image = vec![0u8; 6 * page];
+        for (pgoff, bytes) in server.join().unwrap() {
+            let at = (pgoff - 100) as usize * page;
+            image[at..at + bytes.len()].copy_from_slice(&bytes);
+        }
+
+        // Each region's rdma_offset, and the hashes taken from it, find the
+        // bytes of its own part of the memory file.
+        let hashes = region_hash::region_hashes(&ranges, stats.digests);
+        for ((state, region), hashes) in states.iter().zip(&planned).zip(hashes) {
+            let source = &contents[state.offset as usize..state.offset as usize + state.size];
+            let at = (region.rdma_offset.raw() - 100) as usize * page;
+            assert_eq!(&image[at..at + state.size], source);
+            assert_eq!(hashes.unwrap().sha256, vec![region_hash::digest(source)]);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_marker_replaces_its_page() {
+        let page = PAGE_SIZE as usize;
+        let path = mem_file("marker", 4);
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[2 * page..3 * page]
+            .iter_mut()
+            .for_each(|byte| *byte = 0);
+        std::fs::write(&path, &contents).unwrap();
+
+        // On the zero page, which is sent after all, and on a populated one.
+        for &marked in &[2, 1] {
+            let marker = Marker {
+                image_offset: marked * PAGE_SIZE,
+                page: marker_page::page(&"ab".repeat(32), 7),
+            };
+            let mut expected = contents.clone();
+            expected[marked as usize * page..(marked as usize + 1) * page]
+                .copy_from_slice(&marker.page);
+            let (addr, server) = recording_server();
+            let mut options = upload_options(RetryPolicy::none(), None);
+            options.hash_regions = Some(vec![(0, 4 * PAGE_SIZE)]);
+            options.marker = Some(marker);
+            let stats = upload_memory_to_rdma(
+                &files(&path),
+                &whole_file(&path),
+                &addr,
+                PageOffset(10),
+                &options,
+                &mut |_| Ok(()),
+                &mut |_, err| panic!("unexpected retry: {}", err),
+            )
+            .unwrap();
+            let mut image = vec![0u8; 4 * page];
+            for (pgoff, bytes) in server.join().unwrap() {
+                let at = (pgoff - 10) as usize * page;
+                image[at..at + bytes.len()].copy_from_slice(&bytes);
+            }
+            assert_eq!(image, expected);
+            let zero: &[(u64, u64)] = if marked == 2 { &[] } else { &[(2, 1)] };
+            assert_eq!(stats.zero_pages.as_slice(), zero);
+            let hashes = region_hash::region_hashes(&[(0, 4 * PAGE_SIZE)], stats.digests);
+            assert_eq!(
+                hashes[0].as_ref().unwrap().sha256,
+                vec![region_hash::digest(&expected)]
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_honors_chunk_size() {
+        let path = mem_file("chunks", 5);
+        let (addr, server) = recording_server();
+        let mut options = upload_options(RetryPolicy::none(), None);
+        options.chunk_size = 2 * PAGE_SIZE as usize;
+        let mut progress = Vec::new();
+        upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(10),
+            &options,
+            &mut |bytes| {
+                progress.push(bytes);
+                Ok(())
+            },
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        // Every chunk of populated pages is read and sent on its own.
+        assert_eq!(progress, vec![2 * PAGE_SIZE, 4 * PAGE_SIZE, 5 * PAGE_SIZE]);
+        let contents = std::fs::read(&path).unwrap();
+        let page = PAGE_SIZE as usize;
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                (10, contents[..2 * page].to_vec()),
+                (12, contents[2 * page..4 * page].to_vec()),
+                (14, contents[4 * page..].to_vec()),
+            ]
+        );
+
+        // Chunks must hold whole pages of the template's page size.
+        options.page_size = PageSize::Huge;
+        let err = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &options,
+            &mut |_| Ok(()),
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .err()
+        .expect("misaligned chunks should be rejected");
+        assert!(
+            err.to_string()
+                .contains("not a multiple of the page size 2m"),
+            "{}",
+            err
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_over_unix_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let path = mem_file("unix", 2);
+        let socket = test_files::path("cp").with_extension("sock");
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0u8; 24];
+            stream.read_exact(&mut header).unwrap();
+            let mut field = [0u8; 8];
+            field.copy_from_slice(&header[8..16]);
+            let mut image = vec![0u8; u64::from_le_bytes(field) as usize];
+            stream.read_exact(&mut image).unwrap();
+            stream.write_all(&0i32.to_le_bytes()).unwrap();
+            image
+        });
+
+        let addr = format!("unix:{}", socket.display());
+        upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &upload_options(RetryPolicy::none(), Some(Duration::from_secs(10))),
+            &mut |_| Ok(()),
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        assert_eq!(server.join().unwrap(), std::fs::read(&path).unwrap());
+
+        // Errors say which transport was tried.
+        std::fs::remove_file(&socket).unwrap();
+        let err = RdmaClient::connect(&addr, ServerTimeouts::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("over Unix socket"), "{}", err);
+        let err = RdmaClient::connect("unix:", ServerTimeouts::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("names no socket path"), "{}", err);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_slices() {
+        let page = PAGE_SIZE;
+        assert_eq!(upload_slices(8 * page, page, 1), vec![(0, 8 * page)]);
+        assert_eq!(
+            upload_slices(8 * page, page, 3),
+            vec![(0, 3 * page), (3 * page, 6 * page), (6 * page, 8 * page)]
+        );
+        // Never more slices than pages, and never empty ones.
+        assert_eq!(
+            upload_slices(2 * page, page, 4),
+            vec![(0, page), (page, 2 * page)]
+        );
+        assert_eq!(upload_slices(0, page, 4), vec![(0, 0)]);
+        // Slices hold whole huge pages.
+        let huge = 2 << 20;
+        assert_eq!(
+            upload_slices(3 * huge, huge, 2),
+            vec![(0, 2 * huge), (2 * huge, 3 * huge)]
+        );
+    }
+
+    #[test]
+    fn test_upload_in_parallel() {
+        let page = PAGE_SIZE as usize;
+        let path = mem_file("parallel", 8);
+        // Zero pages on both sides of the first slice boundary.
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[2 * page..4 * page].iter_mut().for_each(|b| *b = 0);
+        std::fs::write(&path, &contents).unwrap();
+
+        let (addr, server) = parallel_server(3, None);
+        let mut options = upload_options(RetryPolicy::none(), Some(Duration::from_secs(10)));
+        options.streams = 3;
+        let mut progress = Vec::new();
+        let stats = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(10),
+            &options,
+            &mut |bytes| {
+                progress.push(bytes);
+                Ok(())
+            },
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        assert_eq!(stats.zero_pages.as_slice(), &[(2, 2)]);
+        assert_eq!(progress.last(), Some(&(8 * PAGE_SIZE)));
+        // The images land where a serial upload puts them.
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                (10, contents[..2 * page].to_vec()),
+                (14, contents[4 * page..6 * page].to_vec()),
+                (16, contents[6 * page..].to_vec()),
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_hashes_regions() {
+        let chunk = region_hash::CHUNK_SIZE;
+        let path = mem_file("hashed", 5 * chunk / PAGE_SIZE);
+        // Every page different, and a zero page in the second chunk.
+        let mut contents: Vec<u8> = (0..5 * chunk)
+            .map(|idx| (idx / PAGE_SIZE * 7 + idx) as u8)
+            .collect();
+        let zero = (chunk + PAGE_SIZE) as usize;
+        contents[zero..zero + PAGE_SIZE as usize]
+            .iter_mut()
+            .for_each(|b| *b = 0);
+        std::fs::write(&path, &contents).unwrap();
+        let ranges = vec![
+            (0, 3 * chunk + PAGE_SIZE),
+            (3 * chunk + PAGE_SIZE, 2 * chunk - PAGE_SIZE),
+        ];
+
+        // Slices cut at the chunks of both regions.
+        let (addr, server) = parallel_server(3, None);
+        let mut options = upload_options(RetryPolicy::none(), Some(Duration::from_secs(10)));
+        options.streams = 3;
+        options.hash_regions = Some(ranges.clone());
+        let stats = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &options,
+            &mut |_| Ok(()),
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        server.join().unwrap();
+        assert_eq!(stats.zero_pages.as_slice(), &[(chunk / PAGE_SIZE + 1, 1)]);
+
+        let hashes = region_hash::region_hashes(&ranges, stats.digests);
+        for (&(start, size), hashes) in ranges.iter().zip(hashes) {
+            let expected: Vec<String> = contents[start as usize..(start + size) as usize]
+                .chunks(chunk as usize)
+                .map(region_hash::digest)
+                .collect();
+            assert_eq!(hashes.unwrap().sha256, expected);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_upload_fails_with_first_stream_error() {
+        let path = mem_file("parallel_fail", 4);
+        let (addr, server) = parallel_server(2, Some(2));
+        let mut options = upload_options(fast_retry(3), Some(Duration::from_secs(10)));
+        options.streams = 2;
+        let err = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &options,
+            &mut |_| Ok(()),
+            &mut |_, err| panic!("permanent status retried: {}", err),
+        )
+        .err()
+        .expect("upload should fail");
+        assert!(
+            err.to_string().contains(&format!(
+                "upload stream 2/2 failed: RDMA server returned error code {}",
+                -libc::EINVAL
+            )),
+            "{}",
+            err
+        );
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_upload_stops_when_cancelled() {
+        let path = mem_file("parallel_cancel", 4);
+        let (addr, server) = parallel_server(2, None);
+        let mut options = upload_options(RetryPolicy::none(), None);
+        options.streams = 2;
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let err = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &options,
+            &mut |_| cancel.check(),
+            &mut |_, err| panic!("cancelled upload retried: {}", err),
+        )
+        .err()
+        .expect("upload should be cancelled");
+        assert!(is_cancelled(err.as_ref()), "{}", err);
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cancelled_upload_closes_connection() {
+        let path = mem_file("cancel_eof", 4);
+        let (addr, server) = recording_server();
+        let mut options = upload_options(RetryPolicy::none(), None);
+        options.chunk_size = PAGE_SIZE as usize;
+        let cancel = CancelToken::new();
+        let err = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &options,
+            &mut |bytes| {
+                if bytes > 0 {
+                    cancel.cancel();
+                }
+                cancel.check()
+            },
+            &mut |_, err| panic!("cancelled upload retried: {}", err),
+        )
+        .err()
+        .expect("upload should be cancelled");
+        assert!(is_cancelled(err.as_ref()), "{}", err);
+        // The server only returns once it reads EOF.
+        let images = server.join().unwrap();
+        assert!(!images.is_empty() && images.len() < 4, "{:?}", images.len());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_of_all_zero_file() {
+        let path = mem_file("all_zero", 0);
+        std::fs::write(&path, vec![0u8; 3 * PAGE_SIZE as usize]).unwrap();
+        let (addr, server) = recording_server();
+        let stats = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &upload_options(RetryPolicy::none(), None),
+            &mut |_| Ok(()),
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        assert_eq!(stats.zero_pages.as_slice(), &[(0, 3)]);
+        assert!(server.join().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_cancelled_mid_stream() {
+        let path = mem_file("cancel", 4);
+        let (addr, server) = fake_server();
+        let cancel = CancelToken::new();
+        let err = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &upload_options(fast_retry(3), None),
+            &mut |_| {
+                cancel.cancel();
+                cancel.check()
+            },
+            &mut |_, err| panic!("cancelled upload retried: {}", err),
+        )
+        .err()
+        .expect("upload should be cancelled");
+        assert!(is_cancelled(err.as_ref()), "{}", err);
+        // Cancelled after the first chunk was acked; no template refers to it.
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_retries_after_dropped_connection() {
+        let path = mem_file("retry", 4);
+        let (addr, server) = flaky_server(vec![None, Some(-libc::EBUSY), Some(0)]);
+        let mut progress = Vec::new();
+        let mut retries = Vec::new();
+        let stats = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(42),
+            &upload_options(fast_retry(3), Some(Duration::from_secs(10))),
+            &mut |bytes| {
+                progress.push(bytes);
+                Ok(())
+            },
+            &mut |attempt, err| retries.push((attempt, err.to_string())),
+        )
+        .unwrap();
+        assert_eq!(stats.pages, 4);
+        assert_eq!(retries.len(), 2);
+        assert_eq!(retries[0].0, 1);
+        assert_eq!(
+            retries[1],
+            (
+                2,
+                format!("RDMA server returned error code {}", -libc::EBUSY)
+            )
+        );
+        // Each restart streams the whole file again.
+        assert_eq!(progress.last(), Some(&(4 * PAGE_SIZE)));
+        assert_eq!(
+            server.join().unwrap(),
+            vec![(42, std::fs::read(&path).unwrap())]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_times_out_waiting_for_ack() {
+        let path = mem_file("no_ack", 1);
+        // Reads everything but never acks, on both attempts.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut held = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut image = vec![0u8; 24 + PAGE_SIZE as usize];
+                stream.read_exact(&mut image).unwrap();
+                held.push(stream);
+            }
+            held
+        });
+
+        let mut options = upload_options(fast_retry(2), None);
+        options.timeouts.ack = Duration::from_millis(100);
+        let mut retries = Vec::new();
+        let err = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &options,
+            &mut |_| Ok(()),
+            &mut |attempt, err| retries.push((attempt, err.to_string())),
+        )
+        .err()
+        .expect("upload should time out");
+        let message = "no ack from the RDMA server within 100ms";
+        assert!(err.to_string().contains(message), "{}", err);
+        assert_eq!(retries.len(), 1);
+        assert!(retries[0].1.contains(message), "{}", retries[0].1);
+        drop(server.join().unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_server_timeouts_bounded_by_entry() {
+        let timeouts = ServerTimeouts::default();
+        assert_eq!(timeouts.bounded_by(None), timeouts);
+        let bounded = timeouts.bounded_by(Some(Duration::from_secs(30)));
+        assert_eq!(bounded.connect, Duration::from_secs(10));
+        assert_eq!(bounded.write, Duration::from_secs(30));
+        assert_eq!(bounded.ack, Duration::from_secs(30));
+        let expired = timeouts.bounded_by(Some(Duration::from_secs(0)));
+        assert_eq!(expired.ack, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_upload_gives_up_on_permanent_status() {
+        let path = mem_file("permanent", 1);
+        let (addr, server) = flaky_server(vec![Some(-libc::EINVAL)]);
+        let mut retries = 0;
+        let err = upload_memory_to_rdma(
+            &files(&path),
+            &whole_file(&path),
+            &addr,
+            PageOffset(0),
+            &upload_options(fast_retry(3), None),
+            &mut |_| Ok(()),
+            &mut |_, _| retries += 1,
+        )
+        .err()
+        .expect("upload should fail");
+        assert_eq!(retries, 0);
+        assert!(!is_retryable_upload(err.as_ref()), "{}", err);
+        assert!(server.join().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_split_across_extents() {
+        let page = PAGE_SIZE as usize;
+        let path = mem_file("extents", 8);
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[2 * page..4 * page].iter_mut().for_each(|b| *b = 0);
+        std::fs::write(&path, &contents).unwrap();
+
+        // A connection for each extent.
+        let (addr, server) = storing_server(2);
+        let options = upload_options(RetryPolicy::none(), Some(Duration::from_secs(10)));
+        let extents = [
+            ImageExtent {
+                pgoff: PageOffset(100),
+                pages: 3,
+            },
+            ImageExtent {
+                pgoff: PageOffset(500),
+                pages: 5,
+            },
+        ];
+        let parts = pgoff_extents::split(&extents, &whole_file(&path));
+        let mut reported = Vec::new();
+        let stats = upload_extents_to_rdma(
+            &files(&path),
+            &parts,
+            &addr,
+            &options,
+            &mut |bytes| {
+                reported.push(bytes);
+                Ok(())
+            },
+            &mut |_, err| panic!("unexpected retry: {}", err),
+        )
+        .unwrap();
+        assert_eq!(stats.bytes, 8 * PAGE_SIZE);
+        assert_eq!(stats.zero_pages.as_slice(), &[(2, 2)]);
+        assert_eq!(reported.last(), Some(&(8 * PAGE_SIZE)));
+
+        // Pages 0-2 at pgoff 100 and 3-7 at 500, without the zero pages.
+        let stored = server.join().unwrap();
+        let expected: Vec<(u64, &[u8])> = vec![
+            (100, &contents[..page]),
+            (101, &contents[page..2 * page]),
+            (501, &contents[4 * page..5 * page]),
+            (502, &contents[5 * page..6 * page]),
+            (503, &contents[6 * page..7 * page]),
+            (504, &contents[7 * page..]),
+        ];
+        let stored: Vec<(u64, &[u8])> = stored
+            .iter()
+            .map(|(&pgoff, data)| (pgoff, &data[..]))
+            .collect();
+        assert_eq!(stored, expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_zero_guard_pages() {
+        let (addr, server) = recording_server();
+        let guarded = guard_pages::guarded_range(PageOffset(100), 8, 4).unwrap();
+        let chunks = guard_pages::guard_chunks(&guarded, PageOffset(100), 8);
+        assert_eq!(chunks, vec![(96, 4), (108, 4)]);
+        zero_guard_pages(&addr, &chunks, ServerTimeouts::default()).unwrap();
+        let zeros = vec![0u8; (4 * PAGE_SIZE) as usize];
+        assert_eq!(
+            server.join().unwrap(),
+            vec![(96, zeros.clone()), (108, zeros)]
+        );
+    }
+
+    #[test]
+    fn test_is_retryable_upload() {
+        let server_io = |kind| ServerError::Io(io::Error::new(kind, "test"));
+        assert!(is_retryable_upload(&server_io(
+            io::ErrorKind::ConnectionReset
+        )));
+        assert!(is_retryable_upload(&server_io(io::ErrorKind::WouldBlock)));
+        assert!(is_retryable_upload(&ServerError::Status(-libc::EAGAIN)));
+        assert!(!is_retryable_upload(&server_io(
+            io::ErrorKind::InvalidInput
+        )));
+        assert!(!is_retryable_upload(&ServerError::Status(-libc::ENOSPC)));
+        // The same kinds from the memory file or the entry's own checks are
+        // not the server's fault.
+        assert!(!is_retryable_upload(&io::Error::new(
+            io::ErrorKind::TimedOut,
+            "entry exceeded --entry-timeout"
+        )));
+        assert!(!is_retryable_upload(&io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "memory file truncated"
+        )));
+    }
+
+    #[test]
+    fn test_parse_upload_chunk_size() {
+        let parse = |value| parse_upload_chunk_size(value).map_err(|err| err.to_string());
+        assert_eq!(parse(None), Ok(UPLOAD_CHUNK));
+        assert_eq!(parse(Some("1M")), Ok(1 << 20));
+        assert_eq!(parse(Some("64k")), Ok(64 << 10));
+        assert_eq!(parse(Some("8192")), Ok(8192));
+        for bad in &["0", "1000", "2g", "lots", "2048m"] {
+            assert!(parse(Some(bad)).is_err(), "{}", bad);
+        }
+    }
+}
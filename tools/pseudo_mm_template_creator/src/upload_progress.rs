@@ -0,0 +1,217 @@
+//! Progress and throughput reporting for memory uploads.
+//!
+//! Multi-GiB images take minutes to stream, so uploads report how far they
+//! got. On a terminal the report is a bar redrawn in place; otherwise, and
+//! for parallel batches whose bars would overwrite each other, it is a log
+//! line every `LOG_INTERVAL`.
+//!
+//! Rates are in MB/s (10^6 bytes per second), like most network tools.
+//! `update_at` takes the current time as an argument, so tests can inject
+//! clocks.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Time between log lines.
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+/// Time between redraws of the bar.
+const BAR_INTERVAL: Duration = Duration::from_millis(200);
+const BAR_WIDTH: usize = 30;
+
+/// How progress is shown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressStyle {
+    /// A bar redrawn in place, for interactive runs.
+    Bar,
+    /// Periodic log lines.
+    Log,
+}
+
+impl ProgressStyle {
+    /// `Bar` when stdout is a terminal and only one upload runs at a time.
+    pub fn detect(concurrent: bool) -> Self {
+        // Safe because isatty only inspects the descriptor.
+        if !concurrent && unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+            ProgressStyle::Bar
+        } else {
+            ProgressStyle::Log
+        }
+    }
+}
+
+/// Transfer rate of `bytes` over `elapsed`, in MB/s.
+pub fn rate(bytes: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        return 0.0;
+    }
+    bytes as f64 / secs / 1e6
+}
+
+/// Progress of one upload.
+pub struct UploadProgress {
+    label: String,
+    total: u64,
+    style: ProgressStyle,
+    start: Instant,
+    last_report: Instant,
+    last_bytes: u64,
+    drew_bar: bool,
+}
+
+impl UploadProgress {
+    pub fn new(label: &str, total: u64, style: ProgressStyle, now: Instant) -> Self {
+        UploadProgress {
+            label: label.to_string(),
+            total,
+            style,
+            start: now,
+            last_report: now,
+            last_bytes: 0,
+            drew_bar: false,
+        }
+    }
+
+    /// Records that `bytes` have been sent at `now`, returning the report to
+    /// print when one is due.
+    ///
+    /// The instantaneous rate covers the time since the previous report.
+    /// `bytes` going backwards means the upload restarted; the average still
+    /// counts from the first attempt.
+    pub fn update_at(&mut self, bytes: u64, now: Instant) -> Option<String> {
+        if bytes < self.last_bytes {
+            self.last_bytes = 0;
+        }
+        let since_report = now
+            .checked_duration_since(self.last_report)
+            .unwrap_or_default();
+        let interval = match self.style {
+            ProgressStyle::Bar => BAR_INTERVAL,
+            ProgressStyle::Log => LOG_INTERVAL,
+        };
+        let done = bytes == self.total;
+        if since_report < interval && !(done && self.style == ProgressStyle::Bar) {
+            return None;
+        }
+
+        let current = rate(bytes - self.last_bytes, since_report);
+        let average = rate(
+            bytes,
+            now.checked_duration_since(self.start).unwrap_or_default(),
+        );
+        let percent = if self.total == 0 {
+            100.0
+        } else {
+            bytes as f64 * 100.0 / self.total as f64
+        };
+        self.last_report = now;
+        self.last_bytes = bytes;
+
+        Some(match self.style {
+            ProgressStyle::Bar => {
+                self.drew_bar = true;
+                let filled = (percent / 100.0 * BAR_WIDTH as f64) as usize;
+                format!(
+                    "\r  [{}{}] {:5.1}% {}/{} MiB {:.1} MB/s (avg {:.1} MB/s)",
+                    "=".repeat(filled),
+                    " ".repeat(BAR_WIDTH - filled),
+                    percent,
+                    bytes >> 20,
+                    self.total >> 20,
+                    current,
+                    average
+                )
+            }
+            ProgressStyle::Log => format!(
+                "  [{}] progress: {}/{} bytes ({:.1}%), {:.1} MB/s now, {:.1} MB/s average\n",
+                self.label, bytes, self.total, percent, current, average
+            ),
+        })
+    }
+
+    /// Prints a report for `bytes` if one is due.
+    pub fn update(&mut self, bytes: u64) {
+        if let Some(report) = self.update_at(bytes, Instant::now()) {
+            print!("{}", report);
+            let _ = io::stdout().flush();
+        }
+    }
+
+    /// Ends the bar's line, whether or not the upload completed.
+    pub fn finish(&mut self) {
+        if self.drew_bar {
+            println!();
+            self.drew_bar = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1_000_000;
+
+    #[test]
+    fn test_rate() {
+        assert_eq!(rate(500 * MB, Duration::from_secs(2)), 250.0);
+        assert_eq!(rate(MB, Duration::from_secs(0)), 0.0);
+    }
+
+    #[test]
+    fn test_log_lines_are_periodic() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut progress = UploadProgress::new("batch-1", 400 * MB, ProgressStyle::Log, start);
+        assert_eq!(progress.update_at(50 * MB, at(1)), None);
+        assert_eq!(
+            progress.update_at(100 * MB, at(5)).unwrap(),
+            "  [batch-1] progress: 100000000/400000000 bytes (25.0%), 20.0 MB/s now, \
+             20.0 MB/s average\n"
+        );
+        assert_eq!(progress.update_at(150 * MB, at(9)), None);
+        // The current rate only covers the last interval.
+        assert_eq!(
+            progress.update_at(300 * MB, at(10)).unwrap(),
+            "  [batch-1] progress: 300000000/400000000 bytes (75.0%), 40.0 MB/s now, \
+             30.0 MB/s average\n"
+        );
+        // Completion is left to the caller's own summary line.
+        assert_eq!(progress.update_at(400 * MB, at(11)), None);
+    }
+
+    #[test]
+    fn test_restart_resets_current_rate() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut progress = UploadProgress::new("single", 400 * MB, ProgressStyle::Log, start);
+        progress.update_at(300 * MB, at(5)).unwrap();
+        let report = progress.update_at(100 * MB, at(10)).unwrap();
+        assert!(
+            report.contains("20.0 MB/s now, 10.0 MB/s average"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn test_bar() {
+        let start = Instant::now();
+        let mut progress = UploadProgress::new("single", 8 << 20, ProgressStyle::Bar, start);
+        assert_eq!(
+            progress.update_at(2 << 20, start + Duration::from_millis(100)),
+            None
+        );
+        let bar = progress
+            .update_at(2 << 20, start + Duration::from_millis(250))
+            .unwrap();
+        assert!(bar.starts_with(&format!("\r  [{}{}]", "=".repeat(7), " ".repeat(23))));
+        assert!(bar.contains(" 25.0% 2/8 MiB "), "{}", bar);
+        // The full bar is always drawn.
+        let bar = progress
+            .update_at(8 << 20, start + Duration::from_millis(260))
+            .unwrap();
+        assert!(bar.contains(&format!("[{}] 100.0%", "=".repeat(BAR_WIDTH))));
+        assert!(progress.drew_bar);
+    }
+}
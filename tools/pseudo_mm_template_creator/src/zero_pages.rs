@@ -0,0 +1,213 @@
+//! Zero page detection for uploads.
+//!
+//! Snapshot memory files are mostly zero pages, which the guest can fault in
+//! as demand-zero memory instead of fetching them from the memory server.
+//! Holes the filesystem reports through `SEEK_DATA`/`SEEK_HOLE` are zero
+//! without being read; the data in between is scanned a page at a time. A
+//! page only partly covered by a hole is read and scanned like any other.
+//!
+//! "Page" is the template's page size: a 2 MiB page is mapped as a whole,
+//! so it is only skipped if all of it is zero. Page runs are still counted
+//! in 4 KiB pages, like pgoffs.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+
+use vmm::pseudo_mm_support::PAGE_SIZE;
+
+/// Sorted, non-overlapping page runs `(first_page, pages)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PageRuns(Vec<(u64, u64)>);
+
+impl PageRuns {
+    /// Adds `pages` pages from `first_page`, which must not precede the
+    /// runs added so far, merging with the last run when adjacent.
+    pub fn push(&mut self, first_page: u64, pages: u64) {
+        if pages == 0 {
+            return;
+        }
+        if let Some(last) = self.0.last_mut() {
+            if last.0 + last.1 == first_page {
+                last.1 += pages;
+                return;
+            }
+        }
+        self.0.push((first_page, pages));
+    }
+
+    /// Total pages over all runs.
+    pub fn pages(&self) -> u64 {
+        self.0.iter().map(|run| run.1).sum()
+    }
+
+    pub fn as_slice(&self) -> &[(u64, u64)] {
+        &self.0
+    }
+}
+
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    // Safe because lseek only moves the file position.
+    let ret = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if ret >= 0 {
+        return Ok(Some(ret as u64));
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // No data past `offset`.
+        Some(libc::ENXIO) => Ok(None),
+        _ => Err(err),
+    }
+}
+
+/// Byte ranges `[start, end)`, aligned to `page` bytes, of the first `size`
+/// bytes of `file` that may hold data; everything between them reads as
+/// zero.
+///
+/// Moves the file position. The whole file is one range when the
+/// filesystem doesn't support `SEEK_DATA`.
+pub fn data_extents(file: &File, size: u64, page: u64) -> io::Result<Vec<(u64, u64)>> {
+    let mut extents: Vec<(u64, u64)> = Vec::new();
+    let mut pos = 0;
+    while pos < size {
+        let data = match seek(file, pos, libc::SEEK_DATA) {
+            Ok(Some(data)) if data < size => data,
+            Ok(_) => break,
+            Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) && pos == 0 => {
+                return Ok(vec![(0, size)]);
+            }
+            Err(err) => return Err(err),
+        };
+        let hole = seek(file, data, libc::SEEK_HOLE)?.unwrap_or(size);
+        // Widen to whole pages: a page holding any data is scanned.
+        let start = data & !(page - 1);
+        let end = std::cmp::min((hole + page - 1) & !(page - 1), size);
+        match extents.last_mut() {
+            Some(last) if last.1 >= start => last.1 = std::cmp::max(last.1, end),
+            _ => extents.push((start, end)),
+        }
+        pos = std::cmp::max(hole, data + 1);
+    }
+    Ok(extents)
+}
+
+/// Whether every byte of `page` is zero.
+pub fn is_zero(page: &[u8]) -> bool {
+    // Safe because any bit pattern is a valid u64.
+    let (head, words, tail) = unsafe { page.align_to::<u64>() };
+    head.iter().all(|&b| b == 0) && words.iter().all(|&w| w == 0) && tail.iter().all(|&b| b == 0)
+}
+
+/// Splits `buf`, a part of the image aligned to `page` bytes and starting
+/// at 4 KiB page `first_page`, into runs `(first_page, pages, zero)` of zero
+/// and populated pages.
+pub fn split_runs(buf: &[u8], first_page: u64, page: u64) -> Vec<(u64, u64, bool)> {
+    let pages = page / PAGE_SIZE;
+    let mut runs: Vec<(u64, u64, bool)> = Vec::new();
+    for (idx, chunk) in buf.chunks(page as usize).enumerate() {
+        let zero = is_zero(chunk);
+        match runs.last_mut() {
+            Some(last) if last.2 == zero => last.1 += pages,
+            _ => runs.push((first_page + idx as u64 * pages, pages, zero)),
+        }
+    }
+    runs
+}
+
+/// Fills `buf` from `reader`, returning fewer bytes only at end of file.
+pub fn read_full<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_page_runs() {
+        let mut runs = PageRuns::default();
+        runs.push(0, 2);
+        runs.push(2, 1);
+        runs.push(5, 0);
+        runs.push(7, 3);
+        assert_eq!(runs.as_slice(), &[(0, 3), (7, 3)]);
+        assert_eq!(runs.pages(), 6);
+    }
+
+    #[test]
+    fn test_split_runs() {
+        let page = PAGE_SIZE as usize;
+        let mut buf = vec![0u8; 5 * page];
+        // A single byte at the very end of a page populates it.
+        buf[2 * page - 1] = 1;
+        buf[4 * page] = 1;
+        assert_eq!(
+            split_runs(&buf, 10, PAGE_SIZE),
+            vec![(10, 1, true), (11, 1, false), (12, 2, true), (14, 1, false)]
+        );
+        assert_eq!(split_runs(&buf[..page], 0, PAGE_SIZE), vec![(0, 1, true)]);
+        assert!(is_zero(&buf[2 * page..4 * page]));
+        assert!(!is_zero(&buf[1..2 * page]));
+    }
+
+    #[test]
+    fn test_split_runs_of_huge_pages() {
+        let huge = 2 << 20;
+        let mut buf = vec![0u8; 3 * huge as usize];
+        // One byte keeps the whole 2 MiB page.
+        buf[huge as usize + 5] = 1;
+        assert_eq!(
+            split_runs(&buf, 512, huge),
+            vec![(512, 512, true), (1024, 512, false), (1536, 512, true)]
+        );
+    }
+
+    #[test]
+    fn test_data_extents_of_sparse_file() {
+        let path = test_files::path("zero_sparse");
+        let mut file = File::create(&path).unwrap();
+        file.set_len(16 * PAGE_SIZE).unwrap();
+        // Data straddling pages 3 and 4, and at the start of page 10.
+        file.seek(SeekFrom::Start(4 * PAGE_SIZE - 10)).unwrap();
+        file.write_all(&[1u8; 20]).unwrap();
+        file.seek(SeekFrom::Start(10 * PAGE_SIZE)).unwrap();
+        file.write_all(&[1u8; 1]).unwrap();
+        file.sync_all().unwrap();
+
+        let file = File::open(&path).unwrap();
+        let extents = data_extents(&file, 16 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        // Filesystems may report larger extents than were written, but
+        // every extent is page aligned and covers the data.
+        assert!(!extents.is_empty());
+        for &(start, end) in &extents {
+            assert_eq!(start % PAGE_SIZE, 0);
+            assert_eq!(end % PAGE_SIZE, 0);
+            assert!(start < end && end <= 16 * PAGE_SIZE);
+        }
+        let covered = |offset: u64| extents.iter().any(|&(s, e)| s <= offset && offset < e);
+        assert!(covered(3 * PAGE_SIZE) && covered(4 * PAGE_SIZE) && covered(10 * PAGE_SIZE));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_data_extents_of_empty_file() {
+        let path = test_files::path("zero_hole");
+        let file = File::create(&path).unwrap();
+        file.set_len(4 * PAGE_SIZE).unwrap();
+        let extents = data_extents(&file, 4 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        // Entirely a hole, unless the filesystem can't tell.
+        assert!(extents.is_empty() || extents == vec![(0, 4 * PAGE_SIZE)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}